@@ -0,0 +1,427 @@
+//! Writes the capture fixtures of the tests, a pairing followed by a few frames of the link, and
+//! a link on the Turbo slot profile, sealed and timed like the firmware does.
+//!
+//! ```console
+//! cargo run --example synthesize -- tests/fixtures
+//! ```
+
+use corne_decoder::{
+    capture_file::{self, Format, Record},
+    radio::Packet,
+    radio_protocol::{
+        crypto::{Direction, FrameNonce, LinkCipher},
+        hopping::{ChannelHopping, ChannelMap},
+        key_schedule::{self, KeySchedule},
+        pairing::frames::{Beacon, PairingStatus, Presentation, Response},
+        passkey::frames::{FrameKind, PasskeyFrame},
+        state::{keepalive_slot, AckFrame, Command, Downstream, StateFrame},
+        sync::SyncFrame,
+        Side, SlotProfile, Uid,
+    },
+};
+use std::{fs, path::Path};
+
+const SHARED_SECRET: [u8; 32] = [
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+    0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e, 0x1f,
+];
+const KEYBOARD_UID: Uid = Uid([0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]);
+const DONGLE_UID: Uid = Uid([0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8]);
+
+const PAIRING_FREQUENCY: u8 = 81;
+const PROFILE: SlotProfile = SlotProfile::Standard;
+/// `SlotProfile::master_frame_period` of the firmware.
+const MASTER_FRAME_PERIOD: u64 = 344_000;
+const TURBO_MASTER_FRAME_PERIOD: u64 = 92_500;
+/// When the half's frame starts in its slot, and the ACK after the frame's start.
+const FRAME_OFFSET: u64 = 30;
+const ACK_DELAY: u64 = 330;
+
+/// What the half sends in a slot, and whether the sniffer hears the dongle's ACK.
+struct Exchange {
+    slot: u8,
+    state: StateFrame,
+    ack: Option<AckFrame>,
+}
+
+/// One of the link's frames, `sync` is false if the sniffer missed it.
+struct LinkFrame {
+    frame_counter: u32,
+    sync: bool,
+    key_epoch: u8,
+    exchanges: Vec<Exchange>,
+    /// A slot with a packet that got corrupted.
+    corrupted_slot: Option<u8>,
+}
+
+fn state(sequence: u8, matrix: [u8; 3]) -> StateFrame {
+    StateFrame {
+        sequence,
+        matrix,
+        retransmission: false,
+        keepalive: false,
+        downstream_ack: None,
+    }
+}
+
+const ACK: AckFrame = AckFrame { downstream: None };
+
+fn link_frames() -> Vec<LinkFrame> {
+    let pressed = [0x00, 0x10, 0x00];
+    let released = [0x00; 3];
+
+    vec![
+        LinkFrame {
+            frame_counter: 100,
+            sync: true,
+            key_epoch: 0,
+            exchanges: vec![
+                Exchange {
+                    slot: 1,
+                    state: state(1, pressed),
+                    ack: Some(ACK),
+                },
+                Exchange {
+                    slot: 5,
+                    state: state(2, released),
+                    ack: None,
+                },
+                Exchange {
+                    slot: 6,
+                    state: StateFrame {
+                        retransmission: true,
+                        ..state(2, released)
+                    },
+                    ack: Some(AckFrame {
+                        downstream: Some(Downstream {
+                            sequence: 0,
+                            command: Command::HostLeds(2),
+                        }),
+                    }),
+                },
+                Exchange {
+                    slot: 7,
+                    state: StateFrame {
+                        downstream_ack: Some(0),
+                        ..state(2, released)
+                    },
+                    ack: Some(ACK),
+                },
+                Exchange {
+                    slot: keepalive_slot(100),
+                    state: StateFrame {
+                        keepalive: true,
+                        downstream_ack: Some(0),
+                        ..state(2, released)
+                    },
+                    ack: Some(ACK),
+                },
+            ],
+            corrupted_slot: None,
+        },
+        LinkFrame {
+            frame_counter: 101,
+            sync: true,
+            key_epoch: 0,
+            exchanges: vec![Exchange {
+                slot: 3,
+                state: state(3, pressed),
+                ack: Some(ACK),
+            }],
+            corrupted_slot: Some(2),
+        },
+        LinkFrame {
+            frame_counter: 102,
+            sync: false,
+            key_epoch: 0,
+            exchanges: vec![Exchange {
+                slot: 10,
+                state: state(4, released),
+                ack: Some(ACK),
+            }],
+            corrupted_slot: None,
+        },
+        LinkFrame {
+            frame_counter: 103,
+            sync: true,
+            key_epoch: 1,
+            exchanges: vec![Exchange {
+                slot: 1,
+                state: state(5, pressed),
+                ack: Some(ACK),
+            }],
+            corrupted_slot: None,
+        },
+    ]
+}
+
+fn pairing(time: u64) -> Vec<Record> {
+    let mut packet = Packet::new();
+    let mut records = Vec::new();
+    let mut push = |time, rssi, packet: &Packet| {
+        records.push(Record {
+            timestamp: time,
+            frequency: PAIRING_FREQUENCY,
+            rssi,
+            crc_ok: true,
+            pipe: 0,
+            payload: packet.to_vec(),
+        })
+    };
+
+    let mut public_key = [0x5a; 33];
+    public_key[0] = 0x02;
+
+    Beacon {
+        dongle_uid: DONGLE_UID,
+        pair_mode: true,
+        public_key,
+    }
+    .encode(&mut packet);
+    push(time, -48, &packet);
+
+    Presentation {
+        keyboard_uid: KEYBOARD_UID,
+        side: Side::Right,
+        reconnect: false,
+        public_key,
+    }
+    .encode(&mut packet);
+    push(time + 600, -60, &packet);
+
+    Response {
+        keyboard_uid: KEYBOARD_UID,
+        status: PairingStatus::Accepted,
+        confirm: None,
+    }
+    .encode(&mut packet);
+    push(time + 1_200, -48, &packet);
+
+    // The first round's commitments, from the half and then the dongle.
+    for (delay, rssi) in [(800_000, -60), (800_600, -48)] {
+        PasskeyFrame {
+            kind: FrameKind::Commit,
+            keyboard_uid: KEYBOARD_UID,
+            round: 0,
+            value: [0x33; 16],
+        }
+        .encode(&mut packet);
+        push(time + delay, rssi, &packet);
+    }
+
+    records
+}
+
+fn seal(keys: &KeySchedule, nonce: FrameNonce, payload: &[u8]) -> Vec<u8> {
+    let key = match nonce.direction {
+        Direction::Upstream => keys.upstream_key(),
+        Direction::Downstream => keys.downstream_key(),
+    };
+
+    let mut packet = Packet::new();
+    packet.copy_from_slice(payload);
+    LinkCipher::new(key).seal(nonce, &mut packet).unwrap();
+    packet.to_vec()
+}
+
+/// The sync as the dongle sends it, with its tag under the downstream key.
+fn sign(keys: &KeySchedule, sync: &SyncFrame) -> Vec<u8> {
+    let nonce = FrameNonce {
+        frame_counter: sync.frame_counter,
+        slot: 0,
+        direction: Direction::Downstream,
+    };
+
+    let mut packet = Packet::new();
+    packet.copy_from_slice(&sync.encode());
+    LinkCipher::new(keys.downstream_key())
+        .sign(nonce, &mut packet)
+        .unwrap();
+    packet.to_vec()
+}
+
+fn link(start: u64) -> Vec<Record> {
+    let (parameters, first_keys) = key_schedule::derive(&SHARED_SECRET, KEYBOARD_UID, DONGLE_UID);
+    let hopping = ChannelHopping::new(&parameters.hop_seed);
+    let channel = |slot: u8| {
+        let mut hopping = hopping.clone();
+        (0..slot).for_each(|_| hopping.next_channel());
+        hopping.current_channel()
+    };
+    let link_slot = 2 * PROFILE.slot_size().ticks();
+
+    let mut records = Vec::new();
+    for (i, frame) in link_frames().into_iter().enumerate() {
+        let frame_start = start + i as u64 * MASTER_FRAME_PERIOD;
+        let mut keys = first_keys.clone();
+        (0..frame.key_epoch).for_each(|_| keys = keys.next());
+
+        if frame.sync {
+            let sync = SyncFrame {
+                dongle_uid: DONGLE_UID,
+                frame_counter: frame.frame_counter,
+                timestamp: (frame_start - 1_234_567) as u32,
+                key_epoch: frame.key_epoch,
+                pair_mode: false,
+                channel_map: ChannelMap::ALL,
+                channel_map_instant: frame.frame_counter,
+                slot_profile: PROFILE,
+                downstream: None,
+            };
+            records.push(Record {
+                timestamp: frame_start,
+                frequency: channel(0),
+                rssi: -52,
+                crc_ok: true,
+                pipe: 1,
+                payload: sign(&keys, &sync),
+            });
+        }
+
+        if let Some(slot) = frame.corrupted_slot {
+            records.push(Record {
+                timestamp: frame_start + slot as u64 * link_slot + FRAME_OFFSET,
+                frequency: channel(slot),
+                rssi: -88,
+                crc_ok: false,
+                pipe: 1,
+                payload: vec![0xd5; StateFrame::LEN + 16],
+            });
+        }
+
+        for exchange in frame.exchanges {
+            let time = frame_start + exchange.slot as u64 * link_slot + FRAME_OFFSET;
+            let nonce = FrameNonce {
+                frame_counter: frame.frame_counter,
+                slot: exchange.slot,
+                direction: Direction::Upstream,
+            };
+
+            records.push(Record {
+                timestamp: time,
+                frequency: channel(exchange.slot),
+                rssi: -61,
+                crc_ok: true,
+                pipe: 1,
+                payload: seal(&keys, nonce, &exchange.state.encode()),
+            });
+
+            if let Some(ack) = exchange.ack {
+                let mut buf = [0; AckFrame::LEN_WITH_COMMAND];
+                let len = ack.encode(&mut buf);
+                let nonce = FrameNonce {
+                    direction: Direction::Downstream,
+                    ..nonce
+                };
+
+                records.push(Record {
+                    timestamp: time + ACK_DELAY,
+                    frequency: channel(exchange.slot),
+                    rssi: -52,
+                    crc_ok: true,
+                    pipe: 1,
+                    payload: seal(&keys, nonce, &buf[..len]),
+                });
+            }
+        }
+    }
+
+    records
+}
+
+/// Two frames of the left half's link on the Turbo slot profile, the first sync carries a
+/// command for the half.
+fn turbo(start: u64) -> Vec<Record> {
+    let (parameters, keys) = key_schedule::derive(&SHARED_SECRET, KEYBOARD_UID, DONGLE_UID);
+    let hopping = ChannelHopping::new(&parameters.hop_seed);
+    let channel = |slot: u8| {
+        let mut hopping = hopping.clone();
+        (0..slot).for_each(|_| hopping.next_channel());
+        hopping.current_channel()
+    };
+    let command = Downstream {
+        sequence: 0,
+        command: Command::Layer(1),
+    };
+
+    let mut records = Vec::new();
+    for (i, frame_counter) in [200, 201].into_iter().enumerate() {
+        let frame_start = start + i as u64 * TURBO_MASTER_FRAME_PERIOD;
+
+        let sync = SyncFrame {
+            dongle_uid: DONGLE_UID,
+            frame_counter,
+            timestamp: (frame_start - 1_234_567) as u32,
+            key_epoch: 0,
+            pair_mode: false,
+            channel_map: ChannelMap::ALL,
+            channel_map_instant: frame_counter,
+            slot_profile: SlotProfile::Turbo,
+            downstream: (i == 0).then_some(command),
+        };
+        records.push(Record {
+            timestamp: frame_start,
+            frequency: channel(0),
+            rssi: -52,
+            crc_ok: true,
+            pipe: 1,
+            payload: sign(&keys, &sync),
+        });
+
+        for slot in 1..=3 {
+            // The same state in every slot, with the command acknowledged from the first one.
+            let state = StateFrame {
+                retransmission: i > 0 || slot > 1,
+                downstream_ack: Some(command.sequence),
+                ..state(1, [0x00, 0x10, 0x00])
+            };
+            let nonce = FrameNonce {
+                frame_counter,
+                slot,
+                direction: Direction::Upstream,
+            };
+
+            let mut packet = Packet::new();
+            packet.copy_from_slice(&state.encode());
+            LinkCipher::new(keys.upstream_key())
+                .seal_short(nonce, &mut packet)
+                .unwrap();
+            records.push(Record {
+                timestamp: frame_start
+                    + SlotProfile::Turbo.slot_offset(Side::Left, slot).ticks()
+                    + FRAME_OFFSET,
+                frequency: channel(slot),
+                rssi: -61,
+                crc_ok: true,
+                pipe: 1,
+                payload: packet.to_vec(),
+            });
+        }
+    }
+
+    records
+}
+
+fn main() {
+    let dir = std::env::args().nth(1).unwrap_or("tests/fixtures".into());
+    let dir = Path::new(&dir);
+
+    let mut records = pairing(1_000_000);
+    records.extend(link(3_000_000));
+
+    fs::write(
+        dir.join("link.pcap"),
+        capture_file::encode(Format::Pcap, &records),
+    )
+    .unwrap();
+
+    // Started after the pairing and cut off in the last record, like a raw capture stopped early.
+    let raw = capture_file::encode(Format::Raw, &records[5..]);
+    fs::write(dir.join("link.raw"), &raw[..raw.len() - 3]).unwrap();
+
+    fs::write(
+        dir.join("turbo.pcap"),
+        capture_file::encode(Format::Pcap, &turbo(5_000_000)),
+    )
+    .unwrap();
+}
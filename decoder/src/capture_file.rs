@@ -0,0 +1,163 @@
+//! # Capture files
+//!
+//! The sniffer streams a pcap file, see [`crate::radio_protocol::capture`]. A capture started
+//! after the dongle sent the global header, e.g. by reading its serial port without Wireshark,
+//! has the records only, this is called a raw capture here. Both are read by [`parse`].
+
+use crate::radio_protocol::capture::{self, CaptureRecord};
+use std::{fs, io, path::Path};
+
+/// The kind of a capture file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// A pcap file, starting with the global header.
+    Pcap,
+    /// The records without the global header.
+    Raw,
+}
+
+/// One captured packet, see [`CaptureRecord`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    /// When the packet's address was received, in µs of the sniffer's timer.
+    pub timestamp: u64,
+    /// 2400 MHz + `0..=100`.
+    pub frequency: u8,
+    /// RSSI in dBm.
+    pub rssi: i8,
+    pub crc_ok: bool,
+    /// The pipe whose address matched.
+    pub pipe: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Record {
+    /// The capture record, for encoding.
+    pub fn as_capture_record(&self) -> CaptureRecord<'_> {
+        CaptureRecord {
+            timestamp: self.timestamp,
+            frequency: self.frequency,
+            rssi: self.rssi,
+            crc_ok: self.crc_ok,
+            pipe: self.pipe,
+            payload: &self.payload,
+        }
+    }
+}
+
+impl From<CaptureRecord<'_>> for Record {
+    fn from(record: CaptureRecord<'_>) -> Self {
+        Self {
+            timestamp: record.timestamp,
+            frequency: record.frequency,
+            rssi: record.rssi,
+            crc_ok: record.crc_ok,
+            pipe: record.pipe,
+            payload: record.payload.to_vec(),
+        }
+    }
+}
+
+/// The records of a capture file.
+#[derive(Clone, Debug)]
+pub struct CaptureFile {
+    pub format: Format,
+    pub records: Vec<Record>,
+    /// Number of bytes at the end that are not a record, e.g. of a capture cut off mid-record.
+    pub trailing: usize,
+}
+
+/// Parse a pcap or raw capture.
+///
+/// Records are read until the first one that does not decode, everything from there on is
+/// counted as trailing, as the record boundaries are lost.
+pub fn parse(data: &[u8]) -> CaptureFile {
+    let (format, mut rest) = if capture::is_global_header(data) {
+        (Format::Pcap, &data[capture::GLOBAL_HEADER_LEN..])
+    } else {
+        (Format::Raw, data)
+    };
+
+    let mut records = Vec::new();
+    while let Some((record, len)) = CaptureRecord::decode(rest) {
+        records.push(record.into());
+        rest = &rest[len..];
+    }
+
+    CaptureFile {
+        format,
+        records,
+        trailing: rest.len(),
+    }
+}
+
+/// Read and parse a capture file.
+pub fn read(path: impl AsRef<Path>) -> io::Result<CaptureFile> {
+    Ok(parse(&fs::read(path)?))
+}
+
+/// Encode records in the given format.
+pub fn encode(format: Format, records: &[Record]) -> Vec<u8> {
+    let mut data = Vec::new();
+    if format == Format::Pcap {
+        data.extend_from_slice(&capture::global_header());
+    }
+
+    let mut buf = [0; capture::MAX_RECORD_LEN];
+    for record in records {
+        let len = record.as_capture_record().encode(&mut buf);
+        data.extend_from_slice(&buf[..len]);
+    }
+
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records() -> Vec<Record> {
+        vec![
+            Record {
+                timestamp: 1_000_000,
+                frequency: 81,
+                rssi: -40,
+                crc_ok: true,
+                pipe: 0,
+                payload: vec![0xb0; 43],
+            },
+            Record {
+                timestamp: 1_000_250,
+                frequency: 12,
+                rssi: -85,
+                crc_ok: false,
+                pipe: 1,
+                payload: vec![0x12, 0x34],
+            },
+        ]
+    }
+
+    #[test]
+    fn pcap_and_raw() {
+        for format in [Format::Pcap, Format::Raw] {
+            let file = parse(&encode(format, &records()));
+
+            assert_eq!(file.format, format);
+            assert_eq!(file.records, records());
+            assert_eq!(file.trailing, 0);
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let data = encode(Format::Pcap, &records());
+        let file = parse(&data[..data.len() - 1]);
+
+        assert_eq!(file.records, records()[..1]);
+        assert_eq!(file.trailing, 16 + 4 + 1);
+
+        let file = parse(&[]);
+        assert_eq!(file.format, Format::Raw);
+        assert!(file.records.is_empty());
+    }
+}
@@ -0,0 +1,411 @@
+//! # Decoding captured frames
+//!
+//! Pairing frames and syncs are sent in the clear and decoded as they are. The state and ACK
+//! frames of a link are sealed with a nonce made from where in the link's frames they were sent,
+//! see [`crate::radio_protocol::crypto`], so opening them takes the link's shared secret and
+//! knowing each frame's position:
+//!
+//! - A sync gives the frame counter, slot profile and key epoch, and the time the link's frame
+//!   started. Its dongle ID, together with the keyboard half's ID and the shared secret, gives
+//!   the link's keys, see [`crate::radio_protocol::key_schedule`].
+//! - A frame's slot follows from the time since the sync, as the link gets every other slot.
+//!   The Turbo slot profile's layout depends on the side of the link's half, see
+//!   [`SlotProfile::slot_offset`], so its frames are tried at the slots of either side.
+//! - Frames after a missed sync are placed with the master frame period measured between the
+//!   syncs heard before.
+
+use crate::capture_file::Record;
+use crate::radio::Packet;
+use crate::radio_protocol::{
+    crypto::{Direction, FrameNonce, LinkCipher, SHORT_TAG_LEN},
+    hopping::SLOTS_PER_FRAME,
+    key_schedule::{self, KeySchedule},
+    pairing::frames::{Beacon, Confirm, Presentation, Response},
+    passkey::frames::PasskeyFrame,
+    state::{AckFrame, StateFrame},
+    sync::SyncFrame,
+    Side, SlotProfile, Uid,
+};
+use std::fmt;
+
+/// What it takes to open a link's frames.
+#[derive(Copy, Clone, Debug)]
+pub struct LinkSecret {
+    /// The ECDH shared secret of the link's session.
+    pub shared_secret: [u8; 32],
+    /// The ID of the link's keyboard half, the dongle's ID is taken from the syncs.
+    pub keyboard_uid: Uid,
+}
+
+/// Where in a link's frames a frame was sent.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Position {
+    pub frame_counter: u32,
+    /// The link's slot, `0..SLOTS_PER_FRAME`.
+    pub slot: u8,
+}
+
+/// A decoded frame.
+#[derive(Clone, Debug)]
+pub enum Frame {
+    /// The packet's CRC was wrong.
+    CrcError,
+    Sync(SyncFrame),
+    Beacon(Beacon),
+    Presentation(Presentation),
+    Response(Response),
+    Confirm(Confirm),
+    Passkey(PasskeyFrame),
+    /// An opened state frame.
+    State(Position, StateFrame),
+    /// An opened ACK.
+    Ack(Position, AckFrame),
+    /// A link frame that could not be opened, without the secret, before the first sync, or
+    /// because it does not authenticate at its position.
+    Sealed(Option<Position>, usize),
+    /// Too short to be a sealed frame.
+    Unknown(usize),
+}
+
+/// The start of the link's frame, from its sync.
+#[derive(Copy, Clone, Debug)]
+struct FrameStart {
+    frame_counter: u32,
+    time: u64,
+    profile: SlotProfile,
+}
+
+/// The ciphers of the link's current key epoch.
+struct Ciphers {
+    dongle_uid: Uid,
+    keys: KeySchedule,
+    upstream: LinkCipher,
+    downstream: LinkCipher,
+}
+
+impl Ciphers {
+    fn new(dongle_uid: Uid, keys: KeySchedule) -> Self {
+        Self {
+            dongle_uid,
+            upstream: LinkCipher::new(keys.upstream_key()),
+            downstream: LinkCipher::new(keys.downstream_key()),
+            keys,
+        }
+    }
+}
+
+/// Decodes the records of a capture in order, following one link.
+pub struct Decoder {
+    secret: Option<LinkSecret>,
+    ciphers: Option<Ciphers>,
+    frame_start: Option<FrameStart>,
+    /// The shortest master frame period seen, in µs. Handshakes can make frames longer.
+    period: Option<u64>,
+}
+
+impl Decoder {
+    /// A decoder that opens the frames of the link with `secret`, if given.
+    pub fn new(secret: Option<LinkSecret>) -> Self {
+        Self {
+            secret,
+            ciphers: None,
+            frame_start: None,
+            period: None,
+        }
+    }
+
+    /// Decode the next record of the capture.
+    pub fn decode(&mut self, record: &Record) -> Frame {
+        if !record.crc_ok {
+            return Frame::CrcError;
+        }
+
+        let buf = &record.payload[..];
+        if let Some(sync) = SyncFrame::decode(buf) {
+            self.follow_sync(&sync, record.timestamp);
+            Frame::Sync(sync)
+        } else if let Some(beacon) = Beacon::decode(buf) {
+            Frame::Beacon(beacon)
+        } else if let Some(presentation) = Presentation::decode(buf) {
+            Frame::Presentation(presentation)
+        } else if let Some(response) = Response::decode(buf) {
+            Frame::Response(response)
+        } else if let Some(confirm) = Confirm::decode(buf) {
+            Frame::Confirm(confirm)
+        } else if let Some(passkey) = PasskeyFrame::decode(buf) {
+            Frame::Passkey(passkey)
+        } else if buf.len() <= SHORT_TAG_LEN || buf.len() > Packet::CAPACITY as usize {
+            Frame::Unknown(buf.len())
+        } else {
+            let positions = self.positions(record.timestamp);
+            positions
+                .iter()
+                .find_map(|&position| self.open(position, buf))
+                .unwrap_or(Frame::Sealed(positions.first().copied(), buf.len()))
+        }
+    }
+
+    fn follow_sync(&mut self, sync: &SyncFrame, time: u64) {
+        if let Some(previous) = self.frame_start {
+            let frames = sync.frame_counter.wrapping_sub(previous.frame_counter) as u64;
+
+            // Across a gap in the capture the period is not worth much.
+            if (1..=16).contains(&frames) && time > previous.time {
+                let period = (time - previous.time) / frames;
+                self.period = Some(self.period.map_or(period, |p| p.min(period)));
+            }
+        }
+
+        self.frame_start = Some(FrameStart {
+            frame_counter: sync.frame_counter,
+            time,
+            profile: sync.slot_profile,
+        });
+
+        let Some(secret) = &self.secret else {
+            return;
+        };
+
+        let ciphers = match self.ciphers.take() {
+            Some(ciphers) if ciphers.dongle_uid == sync.dongle_uid => ciphers,
+            _ => {
+                let (_, keys) = key_schedule::derive(
+                    &secret.shared_secret,
+                    secret.keyboard_uid,
+                    sync.dongle_uid,
+                );
+                Ciphers::new(sync.dongle_uid, keys)
+            }
+        };
+
+        // Epochs only move forward, a capture can start after some rekeys.
+        let mut keys = ciphers.keys.clone();
+        for _ in 0..u8::MAX {
+            if keys.epoch() as u8 == sync.key_epoch {
+                break;
+            }
+            keys = keys.next();
+        }
+
+        self.ciphers = Some(if keys.epoch() == ciphers.keys.epoch() {
+            ciphers
+        } else {
+            Ciphers::new(sync.dongle_uid, keys)
+        });
+    }
+
+    /// The positions of a frame received at `time`, in the frame of the last sync or a later
+    /// one, on the slots of either side.
+    fn positions(&self, time: u64) -> Vec<Position> {
+        let Some(start) = self.frame_start else {
+            return Vec::new();
+        };
+        let profile = start.profile;
+        let slot_size = profile.slot_size().ticks();
+
+        // From half a link slot before the slot start, rounding to the nearest slot.
+        let Some(mut elapsed) = time.checked_sub(start.time) else {
+            return Vec::new();
+        };
+        elapsed += slot_size;
+        let mut frame_counter = start.frame_counter;
+
+        if let Some(period) = self.period {
+            frame_counter = frame_counter.wrapping_add((elapsed / period) as u32);
+            elapsed %= period;
+        }
+
+        let mut positions = Vec::new();
+        for side in Side::ALL {
+            let first = profile.slot_offset(side, 1).ticks();
+            let slot = elapsed
+                .checked_sub(first)
+                .map_or(0, |elapsed| 1 + elapsed / (2 * slot_size));
+            let position = Position {
+                frame_counter,
+                slot: slot as u8,
+            };
+
+            if slot < SLOTS_PER_FRAME as u64 && !positions.contains(&position) {
+                positions.push(position);
+            }
+        }
+
+        positions
+    }
+
+    /// Open a sealed frame sent from either end at `position`, state frames with a full or a
+    /// truncated tag.
+    fn open(&self, position: Position, buf: &[u8]) -> Option<Frame> {
+        let ciphers = self.ciphers.as_ref()?;
+
+        for (direction, cipher, short) in [
+            (Direction::Upstream, &ciphers.upstream, false),
+            (Direction::Downstream, &ciphers.downstream, false),
+            (Direction::Upstream, &ciphers.upstream, true),
+        ] {
+            let nonce = FrameNonce {
+                frame_counter: position.frame_counter,
+                slot: position.slot,
+                direction,
+            };
+
+            let mut packet = Packet::new();
+            packet.copy_from_slice(buf);
+            let opened = if short {
+                cipher.open_short(nonce, &mut packet)
+            } else {
+                cipher.open(nonce, &mut packet)
+            };
+            if opened.is_err() {
+                continue;
+            }
+
+            return Some(match direction {
+                Direction::Upstream => StateFrame::decode(&packet)
+                    .map_or(Frame::Unknown(buf.len()), |state| {
+                        Frame::State(position, state)
+                    }),
+                Direction::Downstream => AckFrame::decode(&packet)
+                    .map_or(Frame::Unknown(buf.len()), |ack| Frame::Ack(position, ack)),
+            });
+        }
+
+        None
+    }
+}
+
+/// Formats bytes as hex digits.
+pub struct Hex<'a>(pub &'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "frame {} slot {}", self.frame_counter, self.slot)
+    }
+}
+
+impl Frame {
+    /// What kind of frame it is.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Frame::CrcError => "CRC error",
+            Frame::Sync(_) => "sync",
+            Frame::Beacon(_) => "beacon",
+            Frame::Presentation(p) if p.reconnect => "reconnect",
+            Frame::Presentation(_) => "presentation",
+            Frame::Response(_) => "response",
+            Frame::Confirm(_) => "confirm",
+            Frame::Passkey(_) => "passkey",
+            Frame::State(..) => "state",
+            Frame::Ack(..) => "ACK",
+            Frame::Sealed(..) => "sealed",
+            Frame::Unknown(_) => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Frame::CrcError = self {
+            return write!(f, "{}", self.kind());
+        }
+        write!(f, "{:<12}", self.kind())?;
+
+        match self {
+            Frame::CrcError => Ok(()),
+            Frame::Sync(sync) => {
+                write!(
+                    f,
+                    " frame {} from {}, epoch {}, {:?} slots, {} channels from frame {}",
+                    sync.frame_counter,
+                    Hex(&sync.dongle_uid.0),
+                    sync.key_epoch,
+                    sync.slot_profile,
+                    sync.channel_map.num_used(),
+                    sync.channel_map_instant,
+                )?;
+                if let Some(downstream) = sync.downstream {
+                    write!(
+                        f,
+                        ", command {} {:?}",
+                        downstream.sequence, downstream.command
+                    )?;
+                }
+                if sync.pair_mode {
+                    write!(f, ", pair mode")?;
+                }
+                Ok(())
+            }
+            Frame::Beacon(beacon) => {
+                write!(f, " from {}", Hex(&beacon.dongle_uid.0))?;
+                if beacon.pair_mode {
+                    write!(f, ", pair mode")?;
+                }
+                Ok(())
+            }
+            Frame::Presentation(presentation) => write!(
+                f,
+                " from {}, {:?} half",
+                Hex(&presentation.keyboard_uid.0),
+                presentation.side,
+            ),
+            Frame::Response(response) => {
+                write!(
+                    f,
+                    " to {}, {:?}",
+                    Hex(&response.keyboard_uid.0),
+                    response.status,
+                )?;
+                if response.confirm.is_some() {
+                    write!(f, ", with confirm")?;
+                }
+                Ok(())
+            }
+            Frame::Confirm(confirm) => write!(f, " from {}", Hex(&confirm.keyboard_uid.0)),
+            Frame::Passkey(passkey) => write!(
+                f,
+                " {:?} of {}, round {}",
+                passkey.kind,
+                Hex(&passkey.keyboard_uid.0),
+                passkey.round,
+            ),
+            Frame::State(position, state) => {
+                write!(
+                    f,
+                    " {position}, sequence {}, matrix {}",
+                    state.sequence,
+                    Hex(&state.matrix),
+                )?;
+                if let Some(sequence) = state.downstream_ack {
+                    write!(f, ", got command {sequence}")?;
+                }
+                if state.keepalive {
+                    write!(f, ", keepalive")?;
+                }
+                if state.retransmission {
+                    write!(f, ", retransmission")?;
+                }
+                Ok(())
+            }
+            Frame::Ack(position, ack) => {
+                write!(f, " {position}")?;
+                if let Some(downstream) = ack.downstream {
+                    write!(
+                        f,
+                        ", command {} {:?}",
+                        downstream.sequence, downstream.command
+                    )?;
+                }
+                Ok(())
+            }
+            Frame::Sealed(Some(position), len) => write!(f, " {position}, {len} bytes"),
+            Frame::Sealed(None, len) | Frame::Unknown(len) => write!(f, " {len} bytes"),
+        }
+    }
+}
@@ -0,0 +1,42 @@
+//! # Decoder of the dongle's sniffer captures
+//!
+//! Reads what a dongle built with the `sniffer` feature streams, see [`capture_file`], and
+//! decodes it with the firmware's own frame codecs, which are included from `../firmware` below.
+//! Given a link's shared secret it follows the link's frames and key epochs, opens the sealed
+//! state and ACK frames, see [`decoder`], and keeps per-channel statistics, see [`stats`].
+
+pub mod capture_file;
+pub mod decoder;
+pub mod stats;
+
+// The firmware's modules below are written for the firmware's toolchain and lints.
+
+/// The firmware's radio packet, which the frame codecs encode into.
+#[path = "../../firmware/src/radio"]
+pub mod radio {
+    mod packet;
+
+    pub use packet::Packet;
+}
+
+/// The parts of the firmware's radio protocol that do not need the radio.
+#[path = "../../firmware/src/radio_protocol"]
+pub mod radio_protocol {
+    pub mod capture;
+    pub mod crypto;
+    pub mod hopping;
+    pub mod key_schedule;
+    pub mod state;
+    pub mod sync;
+    mod types;
+
+    pub use types::{Side, SlotProfile, Uid};
+
+    pub mod pairing {
+        pub mod frames;
+    }
+
+    pub mod passkey {
+        pub mod frames;
+    }
+}
@@ -0,0 +1,125 @@
+//! Prints the frames of a sniffer capture, and with a link's shared secret the link's opened
+//! frames and per-channel statistics.
+
+use corne_decoder::{
+    capture_file::{self, Format},
+    decoder::{Decoder, LinkSecret},
+    radio_protocol::Uid,
+    stats::Stats,
+};
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+Usage: corne-decoder [--secret <hex> --keyboard <uid>] <capture>
+
+Prints the frames of a pcap or raw capture of the dongle's sniffer.
+
+Options:
+  --secret <hex>    The link's ECDH shared secret, 32 bytes, to open its frames
+  --keyboard <uid>  The ID of the link's keyboard half, 8 bytes";
+
+struct Args {
+    secret: Option<LinkSecret>,
+    path: String,
+}
+
+fn parse_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != 2 * N || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut bytes = [0; N];
+    for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+
+    Some(bytes)
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut shared_secret = None;
+    let mut keyboard_uid = None;
+    let mut path = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--secret" => {
+                let hex = args.next().ok_or("--secret needs a value")?;
+                shared_secret = Some(parse_hex(&hex).ok_or("the secret is not 32 hex bytes")?);
+            }
+            "--keyboard" => {
+                let hex = args.next().ok_or("--keyboard needs a value")?;
+                keyboard_uid = Some(Uid(
+                    parse_hex(&hex).ok_or("the keyboard ID is not 8 hex bytes")?
+                ));
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option {arg}")),
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err("more than one capture given".into()),
+        }
+    }
+
+    let secret = match (shared_secret, keyboard_uid) {
+        (Some(shared_secret), Some(keyboard_uid)) => Some(LinkSecret {
+            shared_secret,
+            keyboard_uid,
+        }),
+        (None, None) => None,
+        _ => return Err("--secret and --keyboard go together".into()),
+    };
+
+    Ok(Args {
+        secret,
+        path: path.ok_or("no capture given")?,
+    })
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    let file = match capture_file::read(&args.path) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Could not read {}: {e}", args.path);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if file.format == Format::Raw {
+        println!("Raw capture, without the pcap header");
+    }
+
+    let opening = args.secret.is_some();
+    let mut decoder = Decoder::new(args.secret);
+    let mut stats = Stats::new();
+
+    for record in &file.records {
+        let frame = decoder.decode(record);
+        println!(
+            "{:>6}.{:06}  {} MHz  {:>4} dBm  pipe {}  {frame}",
+            record.timestamp / 1_000_000,
+            record.timestamp % 1_000_000,
+            2400 + record.frequency as u16,
+            record.rssi,
+            record.pipe,
+        );
+        stats.record(record, &frame);
+    }
+    stats.finish();
+
+    if file.trailing > 0 {
+        println!("{} bytes at the end are not a record", file.trailing);
+    }
+
+    if opening {
+        print!("\n{stats}");
+    }
+
+    ExitCode::SUCCESS
+}
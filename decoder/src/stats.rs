@@ -0,0 +1,275 @@
+//! # Per-channel statistics
+//!
+//! Counted from what the sniffer heard, which is not always what the link's ends heard: a state
+//! frame counts as lost when the sniffer did not hear the dongle's ACK right after it. The state
+//! frames of a slot profile without ACKs, see [`SlotProfile::acked`], do not count towards the
+//! loss.
+//!
+//! [`SlotProfile::acked`]: crate::radio_protocol::SlotProfile::acked
+
+use crate::capture_file::Record;
+use crate::decoder::{Frame, Position};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// The statistics of one channel.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChannelStats {
+    /// Every captured packet, intact or not.
+    pub packets: u32,
+    pub crc_errors: u32,
+    /// Opened state frames.
+    pub states: u32,
+    /// Opened state frames of a slot profile with ACKs.
+    pub ackable: u32,
+    /// State frames the half sent again, as it did not get an ACK for them before.
+    pub retransmissions: u32,
+    /// State frames without an ACK.
+    pub unacked: u32,
+    /// Link frames that could not be opened.
+    pub sealed: u32,
+}
+
+impl ChannelStats {
+    /// The share of state frames without an ACK, `None` without any state frames that get
+    /// ACKs.
+    pub fn loss(&self) -> Option<f64> {
+        (self.ackable > 0).then(|| self.unacked as f64 / self.ackable as f64)
+    }
+
+    fn add(&mut self, other: &ChannelStats) {
+        self.packets += other.packets;
+        self.crc_errors += other.crc_errors;
+        self.states += other.states;
+        self.ackable += other.ackable;
+        self.retransmissions += other.retransmissions;
+        self.unacked += other.unacked;
+        self.sealed += other.sealed;
+    }
+}
+
+/// Statistics per channel, by frequency.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    channels: BTreeMap<u8, ChannelStats>,
+    /// The last state frame, until its ACK is heard.
+    unacked: Option<(u8, Position)>,
+    /// Set when the last sync announced a slot profile without ACKs.
+    without_acks: bool,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a decoded record, in capture order.
+    pub fn record(&mut self, record: &Record, frame: &Frame) {
+        // The ACK is sent right after the state frame, in the same slot.
+        if let Frame::Ack(position, _) = frame {
+            if self.unacked == Some((record.frequency, *position)) {
+                self.unacked = None;
+            }
+        }
+        self.finish();
+
+        let channel = self.channels.entry(record.frequency).or_default();
+        channel.packets += 1;
+
+        match frame {
+            Frame::CrcError => channel.crc_errors += 1,
+            Frame::Sync(sync) => self.without_acks = !sync.slot_profile.acked(),
+            Frame::State(position, state) => {
+                channel.states += 1;
+                if state.retransmission {
+                    channel.retransmissions += 1;
+                }
+                if !self.without_acks {
+                    channel.ackable += 1;
+                    self.unacked = Some((record.frequency, *position));
+                }
+            }
+            Frame::Sealed(..) => channel.sealed += 1,
+            _ => {}
+        }
+    }
+
+    /// Count the last state frame as lost if its ACK has not been heard, at the end of a capture.
+    pub fn finish(&mut self) {
+        if let Some((frequency, _)) = self.unacked.take() {
+            self.channels.entry(frequency).or_default().unacked += 1;
+        }
+    }
+
+    /// The statistics of every channel a packet was captured on, by frequency.
+    pub fn channels(&self) -> impl Iterator<Item = (u8, &ChannelStats)> {
+        self.channels
+            .iter()
+            .map(|(&frequency, channel)| (frequency, channel))
+    }
+
+    /// The sum over all channels.
+    pub fn total(&self) -> ChannelStats {
+        let mut total = ChannelStats::default();
+        for channel in self.channels.values() {
+            total.add(channel);
+        }
+        total
+    }
+}
+
+fn write_row(f: &mut fmt::Formatter<'_>, label: &str, channel: &ChannelStats) -> fmt::Result {
+    write!(
+        f,
+        "{label:>5} {:>8} {:>6} {:>7} {:>6} {:>8} {:>7}",
+        channel.packets,
+        channel.crc_errors,
+        channel.sealed,
+        channel.states,
+        channel.retransmissions,
+        channel.unacked,
+    )?;
+
+    match channel.loss() {
+        Some(loss) => writeln!(f, " {:>5.1} %", 100. * loss),
+        None => writeln!(f, "       -"),
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "  MHz  packets    CRC  sealed states  retrans unacked    loss"
+        )?;
+        for (frequency, channel) in self.channels() {
+            write_row(f, &(2400 + frequency as u16).to_string(), channel)?;
+        }
+        write_row(f, "total", &self.total())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::radio_protocol::hopping::ChannelMap;
+    use crate::radio_protocol::state::{AckFrame, StateFrame};
+    use crate::radio_protocol::sync::SyncFrame;
+    use crate::radio_protocol::{SlotProfile, Uid};
+
+    const POSITION: Position = Position {
+        frame_counter: 7,
+        slot: 3,
+    };
+
+    const STATE: StateFrame = StateFrame {
+        sequence: 1,
+        matrix: [0; 3],
+        retransmission: false,
+        keepalive: false,
+        downstream_ack: None,
+    };
+
+    fn record(frequency: u8) -> Record {
+        Record {
+            timestamp: 0,
+            frequency,
+            rssi: -50,
+            crc_ok: true,
+            pipe: 1,
+            payload: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn acked_states() {
+        let mut stats = Stats::new();
+        let ack = Frame::Ack(POSITION, AckFrame { downstream: None });
+
+        stats.record(&record(10), &Frame::State(POSITION, STATE));
+        stats.record(&record(10), &ack);
+        stats.finish();
+
+        let channel = stats.channels().next().unwrap().1;
+        assert_eq!(channel.packets, 2);
+        assert_eq!(channel.states, 1);
+        assert_eq!(channel.unacked, 0);
+        assert_eq!(channel.loss(), Some(0.));
+    }
+
+    #[test]
+    fn lost_acks_and_retransmissions() {
+        let mut stats = Stats::new();
+        let retransmission = StateFrame {
+            retransmission: true,
+            ..STATE
+        };
+        let next = Position {
+            slot: 4,
+            ..POSITION
+        };
+
+        stats.record(&record(10), &Frame::State(POSITION, STATE));
+        stats.record(&record(10), &Frame::CrcError);
+        stats.record(&record(20), &Frame::State(next, retransmission));
+        // An ACK from another slot does not count.
+        stats.record(
+            &record(20),
+            &Frame::Ack(POSITION, AckFrame { downstream: None }),
+        );
+        stats.finish();
+
+        let channels: Vec<_> = stats.channels().collect();
+        assert_eq!(channels[0].0, 10);
+        assert_eq!(channels[0].1.crc_errors, 1);
+        assert_eq!(channels[0].1.unacked, 1);
+        assert_eq!(channels[1].0, 20);
+        assert_eq!(channels[1].1.retransmissions, 1);
+        assert_eq!(channels[1].1.unacked, 1);
+
+        let total = stats.total();
+        assert_eq!(total.packets, 4);
+        assert_eq!(total.states, 2);
+        assert_eq!(total.loss(), Some(1.));
+    }
+
+    #[test]
+    fn states_without_acks_are_not_lost() {
+        let mut stats = Stats::new();
+        let sync = |slot_profile| {
+            Frame::Sync(SyncFrame {
+                dongle_uid: Uid([0; 8]),
+                frame_counter: 7,
+                timestamp: 0,
+                key_epoch: 0,
+                pair_mode: false,
+                channel_map: ChannelMap::ALL,
+                channel_map_instant: 7,
+                slot_profile,
+                downstream: None,
+            })
+        };
+
+        stats.record(&record(10), &sync(SlotProfile::Turbo));
+        stats.record(&record(20), &Frame::State(POSITION, STATE));
+        stats.finish();
+
+        let channel = stats.channels().nth(1).unwrap().1;
+        assert_eq!(
+            (channel.states, channel.ackable, channel.unacked),
+            (1, 0, 0)
+        );
+        assert_eq!(channel.loss(), None);
+
+        stats.record(&record(10), &sync(SlotProfile::Fast));
+        stats.record(&record(20), &Frame::State(POSITION, STATE));
+        stats.finish();
+
+        let channel = stats.channels().nth(1).unwrap().1;
+        assert_eq!(
+            (channel.states, channel.ackable, channel.unacked),
+            (2, 1, 1)
+        );
+        assert_eq!(channel.loss(), Some(1.));
+    }
+}
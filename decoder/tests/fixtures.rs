@@ -0,0 +1,204 @@
+//! Decodes the captures in `tests/fixtures`, written by `cargo run --example synthesize`.
+
+use corne_decoder::{
+    capture_file::{self, Format},
+    decoder::{Decoder, Frame, LinkSecret, Position},
+    radio_protocol::{state::Command, Uid},
+    stats::Stats,
+};
+use std::process::Command as Process;
+
+const SHARED_SECRET: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+const KEYBOARD_UID: &str = "0102030405060708";
+
+fn secret() -> LinkSecret {
+    let mut shared_secret = [0; 32];
+    for (i, byte) in shared_secret.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+
+    LinkSecret {
+        shared_secret,
+        keyboard_uid: Uid([1, 2, 3, 4, 5, 6, 7, 8]),
+    }
+}
+
+fn decode(path: &str, secret: Option<LinkSecret>) -> (Vec<Frame>, Stats) {
+    let file = capture_file::read(path).unwrap();
+    let mut decoder = Decoder::new(secret);
+    let mut stats = Stats::new();
+
+    let frames = file
+        .records
+        .iter()
+        .map(|record| {
+            let frame = decoder.decode(record);
+            stats.record(record, &frame);
+            frame
+        })
+        .collect();
+    stats.finish();
+
+    (frames, stats)
+}
+
+fn count(frames: &[Frame], kind: &str) -> usize {
+    frames.iter().filter(|frame| frame.kind() == kind).count()
+}
+
+#[test]
+fn pcap_and_raw() {
+    let pcap = capture_file::read("tests/fixtures/link.pcap").unwrap();
+    assert_eq!(pcap.format, Format::Pcap);
+    assert_eq!(pcap.records.len(), 24);
+    assert_eq!(pcap.trailing, 0);
+
+    // The raw capture starts at the link and misses the end of the last record.
+    let raw = capture_file::read("tests/fixtures/link.raw").unwrap();
+    assert_eq!(raw.format, Format::Raw);
+    assert_eq!(raw.records, pcap.records[5..23]);
+    assert_eq!(raw.trailing, 16 + 4 + 17 - 3);
+}
+
+#[test]
+fn without_secret() {
+    let (frames, stats) = decode("tests/fixtures/link.pcap", None);
+
+    assert_eq!(count(&frames, "beacon"), 1);
+    assert_eq!(count(&frames, "presentation"), 1);
+    assert_eq!(count(&frames, "response"), 1);
+    assert_eq!(count(&frames, "passkey"), 2);
+    assert_eq!(count(&frames, "sync"), 3);
+    assert_eq!(count(&frames, "CRC error"), 1);
+    assert_eq!(count(&frames, "sealed"), 15);
+    assert_eq!(stats.total().sealed, 15);
+    assert_eq!(stats.total().states, 0);
+}
+
+#[test]
+fn opens_frames() {
+    let (frames, _) = decode("tests/fixtures/link.pcap", Some(secret()));
+
+    assert_eq!(count(&frames, "sealed"), 0);
+    assert_eq!(count(&frames, "state"), 8);
+    assert_eq!(count(&frames, "ACK"), 7);
+
+    let Frame::Ack(position, ack) = &frames[10] else {
+        panic!("not an ACK: {:?}", frames[10]);
+    };
+    assert_eq!(
+        *position,
+        Position {
+            frame_counter: 100,
+            slot: 6
+        }
+    );
+    assert_eq!(ack.downstream.unwrap().command, Command::HostLeds(2));
+
+    // Placed with the period between the syncs before it.
+    let Frame::State(position, state) = &frames[19] else {
+        panic!("not a state frame: {:?}", frames[19]);
+    };
+    assert_eq!(
+        *position,
+        Position {
+            frame_counter: 102,
+            slot: 10
+        }
+    );
+    assert_eq!(state.sequence, 4);
+
+    // After the rekey.
+    let Frame::State(_, state) = &frames[22] else {
+        panic!("not a state frame: {:?}", frames[22]);
+    };
+    assert_eq!(state.sequence, 5);
+}
+
+#[test]
+fn opens_turbo_frames() {
+    let (frames, stats) = decode("tests/fixtures/turbo.pcap", Some(secret()));
+
+    assert_eq!(count(&frames, "sealed"), 0);
+    assert_eq!(count(&frames, "state"), 6);
+
+    let Frame::Sync(sync) = &frames[0] else {
+        panic!("not a sync: {:?}", frames[0]);
+    };
+    assert_eq!(sync.downstream.unwrap().command, Command::Layer(1));
+
+    // The left half's slots, with truncated tags.
+    let Frame::State(position, state) = &frames[7] else {
+        panic!("not a state frame: {:?}", frames[7]);
+    };
+    assert_eq!(
+        *position,
+        Position {
+            frame_counter: 201,
+            slot: 3
+        }
+    );
+    assert_eq!(state.downstream_ack, Some(0));
+
+    // Nothing is ACKed, and nothing is lost.
+    let total = stats.total();
+    assert_eq!((total.states, total.unacked), (6, 0));
+    assert_eq!(total.loss(), None);
+}
+
+#[test]
+fn wrong_secret() {
+    let secret = LinkSecret {
+        keyboard_uid: Uid([8; 8]),
+        ..secret()
+    };
+    let (frames, _) = decode("tests/fixtures/link.pcap", Some(secret));
+
+    assert_eq!(count(&frames, "state") + count(&frames, "ACK"), 0);
+    assert_eq!(count(&frames, "sealed"), 15);
+}
+
+#[test]
+fn statistics() {
+    let (_, stats) = decode("tests/fixtures/link.pcap", Some(secret()));
+    let total = stats.total();
+
+    assert_eq!(total.packets, 24);
+    assert_eq!(total.crc_errors, 1);
+    assert_eq!(total.states, 8);
+    assert_eq!(total.retransmissions, 1);
+    assert_eq!(total.unacked, 1);
+
+    // The state frame in slot 5 of frame 100 did not get an ACK.
+    let (frequency, channel) = stats
+        .channels()
+        .find(|(_, channel)| channel.unacked > 0)
+        .unwrap();
+    assert_eq!(frequency, 26);
+    assert_eq!(channel.loss(), Some(1.));
+}
+
+#[test]
+fn output() {
+    let output = Process::new(env!("CARGO_BIN_EXE_corne-decoder"))
+        .args(["--secret", SHARED_SECRET, "--keyboard", KEYBOARD_UID])
+        .arg("tests/fixtures/link.pcap")
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        include_str!("fixtures/link.txt")
+    );
+}
+
+#[test]
+fn usage() {
+    let output = Process::new(env!("CARGO_BIN_EXE_corne-decoder"))
+        .args(["--secret", SHARED_SECRET, "tests/fixtures/link.pcap"])
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(2));
+}
@@ -1,5 +1,13 @@
+/* Flash layout of the nRF52833, 512K:
+ *
+ *   0x00000 - 0x72000  the firmware
+ *   0x72000 - 0x74000  two 4K pages for the pairing bonds, see `BOND_STORAGE_OFFSET`
+ *   0x74000 - 0x7e000  the UF2 bootloader, which `enter_bootloader` resets into
+ *   0x7e000 - 0x7f000  the bootloader's MBR parameters
+ *   0x7f000 - 0x80000  the bootloader's settings
+ */
 MEMORY
 {
-  FLASH : ORIGIN = 0x00000000, LENGTH = 512K
+  FLASH : ORIGIN = 0x00000000, LENGTH = 456K
   RAM : ORIGIN = 0x20000000, LENGTH = 128K
 }
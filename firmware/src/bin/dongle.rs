@@ -18,8 +18,13 @@ defmt::timestamp!("{=u64:us}", {
 #[rtic::app(device = embassy_nrf::pac, dispatchers = [SWI0_EGU0], peripherals = false)]
 mod dongle_app {
     use crate::dongle_tasks::*;
+    #[cfg(feature = "sniffer")]
+    use corne_firmware::bsp::dongle::UsbDriver;
     use corne_firmware::{
-        bsp::{dongle::init_dongle, dongle::DongleBsp},
+        bsp::{
+            dongle::{init_dongle, Button, DongleBsp, DongleLed},
+            Flash, HwRng,
+        },
         radio::Radio,
     };
 
@@ -27,22 +32,44 @@ mod dongle_app {
     struct Shared {}
 
     #[local]
-    struct Local {}
+    struct Local {
+        led: DongleLed,
+    }
 
     #[init]
     fn init(cx: init::Context) -> (Shared, Local) {
         defmt::info!("pre init");
 
-        let DongleBsp { led, button, radio } = init_dongle(cx.core);
+        let DongleBsp {
+            led,
+            button,
+            radio,
+            rng,
+            flash,
+            usb,
+        } = init_dongle(cx.core);
+
+        radio_task::spawn(radio, button, rng, flash).ok();
+        led_task::spawn().ok();
 
-        radio_task::spawn(radio).ok();
-        // usb_task::spawn().ok();
+        // The sniffer streams its captures to the host.
+        #[cfg(feature = "sniffer")]
+        usb_task::spawn(usb).ok();
+        #[cfg(not(feature = "sniffer"))]
+        let _ = usb;
 
-        (Shared {}, Local {})
+        (Shared {}, Local { led })
     }
 
     extern "Rust" {
+        #[task(local = [led])]
+        async fn led_task(_: led_task::Context);
+
         #[task(priority = 3)]
-        async fn radio_task(_: radio_task::Context, _: Radio);
+        async fn radio_task(_: radio_task::Context, _: Radio, _: Button, _: HwRng, _: Flash);
+
+        #[cfg(feature = "sniffer")]
+        #[task]
+        async fn usb_task(_: usb_task::Context, _: UsbDriver);
     }
 }
@@ -1,14 +1,172 @@
 use crate::dongle_app::*;
-use corne_firmware::radio::Radio;
+#[cfg(feature = "sniffer")]
+use corne_firmware::{
+    bsp::dongle::UsbDriver,
+    radio_protocol::{capture, sniffer},
+};
+use corne_firmware::{
+    bsp::{device_uid, dongle::Button, Flash, HwRng, BOND_STORAGE_OFFSET},
+    radio::Radio,
+    radio_protocol::bonds::BondStore,
+};
+#[cfg(feature = "sniffer")]
+use embassy_usb::{
+    class::cdc_acm::{CdcAcmClass, State},
+    driver::EndpointError,
+    Builder,
+};
 
-#[cfg(feature = "dongle_radio")]
-pub async fn radio_task(_: radio_task::Context<'_>, radio: Radio) -> ! {
-    corne_firmware::radio_protocol::dongle_radio_runner(radio).await
+// The sniffer replaces the link's radio task, the dongle and keyboard links need their own
+// builds.
+#[cfg(all(feature = "dongle_radio", feature = "keyboard_radio"))]
+compile_error!("The `dongle_radio` and `keyboard_radio` features both define the radio task");
+
+pub async fn led_task(cx: led_task::Context<'_>) -> ! {
+    corne_firmware::radio_protocol::passkey::led_runner::<corne_firmware::bsp::Mono, _>(
+        cx.local.led,
+    )
+    .await
+}
+
+#[cfg(all(feature = "dongle_radio", not(feature = "sniffer")))]
+pub async fn radio_task(
+    _: radio_task::Context<'_>,
+    radio: Radio,
+    button: Button,
+    rng: HwRng,
+    flash: Flash,
+) -> ! {
+    corne_firmware::radio_protocol::dongle_radio_runner::<_, corne_firmware::bsp::Mono, _, _, _>(
+        radio,
+        button,
+        rng,
+        BondStore::new(flash, BOND_STORAGE_OFFSET),
+        device_uid(),
+    )
+    .await
+}
+
+#[cfg(all(feature = "keyboard_radio", not(feature = "sniffer")))]
+pub async fn radio_task(
+    _: radio_task::Context<'_>,
+    radio: Radio,
+    _: Button,
+    rng: HwRng,
+    flash: Flash,
+) -> ! {
+    use corne_firmware::radio_protocol::Side;
+
+    corne_firmware::radio_protocol::keyboard_radio_runner::<_, corne_firmware::bsp::Mono, _, _>(
+        radio,
+        Side::Right,
+        rng,
+        BondStore::new(flash, BOND_STORAGE_OFFSET),
+        device_uid(),
+        corne_firmware::bsp::enter_bootloader,
+    )
+    .await
+}
+
+#[cfg(feature = "sniffer")]
+pub async fn radio_task(
+    _: radio_task::Context<'_>,
+    radio: Radio,
+    _: Button,
+    _: HwRng,
+    _: Flash,
+) -> ! {
+    use corne_firmware::radio_protocol::{pairing::PAIRING_FREQUENCY, sniffer::SniffTarget};
+
+    // To follow a link, use `SniffTarget::Link` with the parameters of its session and its side.
+    let target = SniffTarget::Frequency {
+        frequency: PAIRING_FREQUENCY,
+        link: None,
+    };
+
+    sniffer::sniffer_runner(radio, target).await
+}
+
+/// Max packet size of the CDC endpoints.
+#[cfg(feature = "sniffer")]
+const USB_PACKET_SIZE: usize = 64;
+
+/// Streams the sniffer's captures as a pcap file over USB CDC, from the start every time the
+/// host opens the port.
+#[cfg(feature = "sniffer")]
+pub async fn usb_task(_: usb_task::Context<'_>, driver: UsbDriver) -> ! {
+    let mut config = embassy_usb::Config::new(0x16c0, 0x27dd);
+    config.manufacturer = Some("Corne");
+    config.product = Some("Corne sniffer");
+    config.max_power = 100;
+    config.max_packet_size_0 = USB_PACKET_SIZE as u8;
+
+    // Windows needs the IAD for CDC.
+    config.device_class = 0xef;
+    config.device_sub_class = 0x02;
+    config.device_protocol = 0x01;
+    config.composite_with_iads = true;
+
+    let mut device_descriptor = [0; 256];
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut msos_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    let mut state = State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut device_descriptor,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut msos_descriptor,
+        &mut control_buf,
+    );
+    let mut class = CdcAcmClass::new(&mut builder, &mut state, USB_PACKET_SIZE as u16);
+    let mut usb = builder.build();
+
+    let stream = async {
+        loop {
+            class.wait_connection().await;
+            defmt::info!("Host connected, streaming captures");
+
+            if stream_captures(&mut class).await.is_err() {
+                defmt::info!("Host disconnected");
+            }
+        }
+    };
+
+    embassy_futures::join::join(usb.run(), stream).await;
+    unreachable!()
+}
+
+#[cfg(feature = "sniffer")]
+async fn stream_captures(class: &mut CdcAcmClass<'_, UsbDriver>) -> Result<(), EndpointError> {
+    write_all(class, &capture::global_header()).await?;
+
+    let mut buf = [0; capture::MAX_RECORD_LEN];
+    loop {
+        let record = sniffer::next_capture().await;
+        let len = sniffer::capture_record(&record).encode(&mut buf);
+        write_all(class, &buf[..len]).await?;
+
+        let dropped = sniffer::take_dropped_captures();
+        if dropped > 0 {
+            defmt::warn!("Dropped {} captures, the host is not keeping up", dropped);
+        }
+    }
 }
 
-#[cfg(feature = "keyboard_radio")]
-pub async fn radio_task(_: radio_task::Context<'_>, radio: Radio) -> ! {
-    corne_firmware::radio_protocol::keyboard_radio_runner(radio, true).await
+#[cfg(feature = "sniffer")]
+async fn write_all(
+    class: &mut CdcAcmClass<'_, UsbDriver>,
+    data: &[u8],
+) -> Result<(), EndpointError> {
+    for packet in data.chunks(USB_PACKET_SIZE) {
+        class.write_packet(packet).await?;
+    }
+
+    Ok(())
 }
 
 // OLD CODE
@@ -61,7 +219,7 @@ pub async fn radio_task(_: radio_task::Context<'_>, radio: Radio) -> ! {
 //         desired_time
 //     );
 
-//     radio.set_freqeuency(current_channel);
+//     radio.set_frequency(current_channel);
 //     //let start = Mono::now();
 //     packet.copy_from_slice(&[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
 //     let timestamp = radio.send(&mut packet).await.0;
@@ -19,8 +19,9 @@ defmt::timestamp!("{=u64:us}", {
 mod keyboard_app {
     use crate::keyboard_tasks::*;
     use corne_firmware::{
-        bsp::keyboard::{
-            init_keyboard, BatteryVoltage, ChargerStatus, KeyMatrix, KeyboardBsp, Led,
+        bsp::{
+            keyboard::{init_keyboard, BatteryVoltage, ChargerStatus, KeyMatrix, KeyboardBsp, Led},
+            Flash, HwRng,
         },
         radio::Radio,
     };
@@ -46,11 +47,13 @@ mod keyboard_app {
             charger_status,
             key_matrix,
             is_right_half,
+            rng,
+            flash,
         } = init_keyboard(cx.core);
 
         key_matrix::spawn().ok();
         battery_handling::spawn().ok();
-        radio_task::spawn(radio, is_right_half).ok();
+        radio_task::spawn(radio, is_right_half, rng, flash).ok();
 
         (
             Shared {},
@@ -70,6 +73,6 @@ mod keyboard_app {
         async fn battery_handling(_: battery_handling::Context);
 
         #[task(priority = 3)]
-        async fn radio_task(_: radio_task::Context, _: Radio, _: bool);
+        async fn radio_task(_: radio_task::Context, _: Radio, _: bool, _: HwRng, _: Flash);
     }
 }
@@ -1,5 +1,9 @@
 use crate::keyboard_app::*;
-use corne_firmware::{bsp::keyboard::Mono, radio::Radio, radio_protocol::keyboard_radio_runner};
+use corne_firmware::{
+    bsp::{device_uid, enter_bootloader, keyboard::Mono, Flash, HwRng, BOND_STORAGE_OFFSET},
+    radio::Radio,
+    radio_protocol::{bonds::BondStore, keyboard_radio_runner, passkey, state, Side},
+};
 use keyberon::{debounce::Debouncer, layout::Event};
 use rtic_monotonics::nrf::timer::ExtU64;
 
@@ -20,10 +24,18 @@ pub async fn key_matrix(cx: key_matrix::Context<'_>) -> ! {
     loop {
         let keys = keys.get_with_delay(|| cortex_m::asm::delay(20)).unwrap();
 
-        if events.update(keys) {
-            let new = pack_bools(events.get());
+        let mut changed = false;
+        for event in events.events(keys) {
+            changed = true;
 
-            // TODO: Send an update
+            // The top row doubles as the digits when typing a pairing passkey.
+            if let Event::Press(0, column) = event {
+                passkey::key_pressed(column);
+            }
+        }
+
+        if changed {
+            state::update(pack_bools(events.get()));
         }
 
         // let e = events.events(keys);
@@ -39,8 +51,28 @@ pub async fn key_matrix(cx: key_matrix::Context<'_>) -> ! {
     }
 }
 
-pub async fn radio_task(_: radio_task::Context<'_>, radio: Radio, is_right_half: bool) -> ! {
-    keyboard_radio_runner(radio).await
+pub async fn radio_task(
+    _: radio_task::Context<'_>,
+    radio: Radio,
+    is_right_half: bool,
+    rng: HwRng,
+    flash: Flash,
+) -> ! {
+    let side = if is_right_half {
+        Side::Right
+    } else {
+        Side::Left
+    };
+
+    keyboard_radio_runner::<_, Mono, _, _>(
+        radio,
+        side,
+        rng,
+        BondStore::new(flash, BOND_STORAGE_OFFSET),
+        device_uid(),
+        enter_bootloader,
+    )
+    .await
 }
 
 #[inline(always)]
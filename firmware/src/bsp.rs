@@ -1,85 +1,409 @@
-use core::{mem, ptr::NonNull};
+use core::{
+    mem,
+    ptr::NonNull,
+    sync::atomic::{AtomicU32, Ordering},
+};
 
+use crate::radio::extend_capture;
+use crate::radio_protocol::Uid;
 use embassy_nrf::{
+    nvmc::Nvmc,
     pac,
-    peripherals::PPI_CH0,
+    peripherals::{
+        PPI_CH0, PPI_CH1, PPI_CH2, PPI_CH3, PPI_CH4, PPI_CH5, PPI_CH6, RNG, TIMER1, TIMER3,
+    },
     ppi::{Event, Ppi, Task},
+    rng::Rng,
 };
 use rtic_monotonics::{
-    nrf::timer::{fugit::TimerInstantU32, Timer0},
+    nrf::timer::{
+        fugit::{TimerDurationU64, TimerInstantU64},
+        Timer0,
+    },
     Monotonic,
 };
 
 pub type Mono = Timer0;
+pub type HwRng = Rng<'static, RNG>;
+pub type Flash = Nvmc<'static>;
+
+// Not imported, `Mono::now()` would be ambiguous with the monotonic's.
+impl crate::clock::Clock for Mono {
+    fn now() -> TimerInstantU64<1_000_000> {
+        <Mono as Monotonic>::now()
+    }
+
+    async fn delay_until(instant: TimerInstantU64<1_000_000>) {
+        <Mono as Monotonic>::delay_until(instant).await
+    }
+}
+
+/// The two flash pages reserved for pairing bonds, right below the UF2 bootloader, which keeps
+/// its own pages at the end of flash (see `memory.x`).
+pub const BOND_STORAGE_OFFSET: u32 = 0x72000;
+
 pub mod dongle;
 pub mod keyboard;
 
-fn start_timer0_monotonic(ppi0: PPI_CH0) {
-    // Timer0 monotonic uses CC 0, 1 and 2. We use 3, 4, 5 for capturing radio events.
+/// The device's unique ID from FICR.
+pub fn device_uid() -> Uid {
+    let ficr = unsafe { &*pac::FICR::PTR };
+    let low = ficr.deviceid[0].read().bits().to_le_bytes();
+    let high = ficr.deviceid[1].read().bits().to_le_bytes();
+
+    Uid([
+        low[0], low[1], low[2], low[3], high[0], high[1], high[2], high[3],
+    ])
+}
+
+/// GPREGRET value that makes the nRF52 UF2 bootloader stay in DFU mode after a reset.
+const DFU_MAGIC_UF2_RESET: u32 = 0x57;
+
+/// Reset into the bootloader's DFU mode, without a bootloader this is just a reset.
+pub fn enter_bootloader() -> ! {
+    let power = unsafe { &*pac::POWER::PTR };
+    power
+        .gpregret
+        .write(|w| unsafe { w.bits(DFU_MAGIC_UF2_RESET) });
+
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// The PPI channels used to capture radio events to the monotonic.
+pub struct RadioTimestampChannels {
+    pub ready: PPI_CH0,
+    pub address: PPI_CH1,
+    pub phy_end: PPI_CH2,
+}
+
+fn start_timer0_monotonic(channels: RadioTimestampChannels, timer: TIMER3) {
+    // Timer0 monotonic uses CC 0, 1 and 2 of its 4, the radio events are captured on TIMER3.
     let systick_token = rtic_monotonics::create_nrf_timer0_monotonic_token!();
     Timer0::start(unsafe { core::mem::transmute(()) }, systick_token);
 
-    RadioTimestamps::start(ppi0);
+    RadioTimestamps::start(channels, timer);
+}
+
+/// The TIMER3 capture register of each radio event.
+const READY_CC: usize = 0;
+const ADDRESS_CC: usize = 1;
+const PHY_END_CC: usize = 2;
+
+/// The TIMER0 compare register that starts TIMER3 in lock-step with the monotonic.
+const SYNC_CC: usize = 3;
+
+/// How far ahead TIMER3 is started, enough to set up the PPI channel first.
+const SYNC_MARGIN: u32 = 50;
+
+/// The monotonic's low 32 bits when TIMER3 was started, added to TIMER3's captures.
+static CAPTURE_OFFSET: AtomicU32 = AtomicU32::new(0);
+
+/// The TIMER0 compare register that starts the radio at a scheduled instant, free once TIMER3
+/// has been started.
+const START_CC: usize = SYNC_CC;
+
+/// TIMER0 has CC 0 to 3, of which the monotonic uses 0, 1 and 2. TIMER3 has CC 0 to 5.
+const _: () = {
+    assert!(SYNC_CC == 3 && START_CC == 3);
+    assert!(READY_CC < 6 && ADDRESS_CC < 6 && PHY_END_CC < 6);
+    assert!(READY_CC != ADDRESS_CC && ADDRESS_CC != PHY_END_CC && PHY_END_CC != READY_CC);
+};
+
+/// The PPI channels used to start and stop the radio at exact instants.
+pub struct RadioTriggerChannels {
+    pub start: PPI_CH3,
+    pub timeout: PPI_CH4,
+    pub address: PPI_CH5,
+    pub end: PPI_CH6,
+}
+
+/// Starts the radio at an exact monotonic instant through a TIMER0 compare and PPI, and closes
+/// RX windows with TIMER1 as a one-shot timeout.
+///
+/// The start channel fires TXEN or RXEN, and for RX also starts TIMER1. TIMER1's compare
+/// disables the radio, unless an ADDRESS event has stopped it first. For the RX window of a
+/// response the END of the sent packet starts TIMER1 instead.
+pub struct RadioTrigger {
+    _start: PPI_CH3,
+    _timeout: Ppi<'static, PPI_CH4, 1, 1>,
+    _address: Ppi<'static, PPI_CH5, 1, 1>,
+    end: Ppi<'static, PPI_CH6, 1, 1>,
+    _timer: TIMER1,
 }
 
-/// Hacky way to timestamp in monotonic time.
+impl RadioTrigger {
+    pub fn new(channels: RadioTriggerChannels, timer: TIMER1) -> Self {
+        let tim0 = unsafe { &*pac::TIMER0::PTR };
+        let tim1 = unsafe { &*pac::TIMER1::PTR };
+        let radio = unsafe { &*pac::RADIO::PTR };
+        let ppi = unsafe { &*pac::PPI::PTR };
+
+        // TIMER1 counts µs like the monotonic, and stops at the timeout.
+        tim1.tasks_stop.write(|w| unsafe { w.bits(1) });
+        tim1.mode.write(|w| w.mode().timer());
+        tim1.bitmode.write(|w| w.bitmode()._32bit());
+        tim1.prescaler.write(|w| unsafe { w.prescaler().bits(4) });
+        tim1.shorts
+            .write(|w| w.compare0_stop().enabled().compare0_clear().enabled());
+
+        let timeout = unsafe {
+            Event::new_unchecked(NonNull::new_unchecked(
+                tim1.events_compare[0].as_ptr() as *const _ as *mut _,
+            ))
+        };
+        let disable = unsafe {
+            Task::new_unchecked(NonNull::new_unchecked(
+                radio.tasks_disable.as_ptr() as *const _ as *mut _,
+            ))
+        };
+        let mut timeout = Ppi::new_one_to_one(channels.timeout, timeout, disable);
+        timeout.enable();
+
+        let address = radio_events().address;
+        let stop = unsafe {
+            Task::new_unchecked(NonNull::new_unchecked(
+                tim1.tasks_stop.as_ptr() as *const _ as *mut _
+            ))
+        };
+        let mut address = Ppi::new_one_to_one(channels.address, address, stop);
+        address.enable();
+
+        let end = unsafe {
+            Event::new_unchecked(NonNull::new_unchecked(
+                radio.events_end.as_ptr() as *const _ as *mut _,
+            ))
+        };
+        let start = unsafe {
+            Task::new_unchecked(NonNull::new_unchecked(
+                tim1.tasks_start.as_ptr() as *const _ as *mut _,
+            ))
+        };
+        let end = Ppi::new_one_to_one(channels.end, end, start);
+
+        // The start channel's tasks depend on the direction, it is configured when armed.
+        ppi.ch[3]
+            .eep
+            .write(|w| unsafe { w.bits(tim0.events_compare[START_CC].as_ptr() as u32) });
+
+        Self {
+            _start: channels.start,
+            _timeout: timeout,
+            _address: address,
+            end,
+            _timer: timer,
+        }
+    }
+
+    /// Arm the trigger to start TX ramp-up at `at`, the radio must be disabled until then.
+    ///
+    /// Returns `false`, with the trigger disarmed, if `at` has already passed.
+    pub fn arm_tx(&mut self, at: TimerInstantU64<1_000_000>) -> bool {
+        let radio = unsafe { &*pac::RADIO::PTR };
+
+        self.arm(at, radio.tasks_txen.as_ptr() as u32, 0);
+
+        if Self::missed(at) {
+            Self::disarm();
+            return false;
+        }
+
+        true
+    }
+
+    /// Arm the trigger to start RX ramp-up at `at`, and to disable the radio at `close_at` unless
+    /// an address has been received by then. The radio must be disabled until `at`.
+    ///
+    /// RX is started right away if `at` has already passed. Returns `false`, with the trigger
+    /// disarmed, if `close_at` has already passed.
+    pub fn arm_rx(
+        &mut self,
+        at: TimerInstantU64<1_000_000>,
+        close_at: TimerInstantU64<1_000_000>,
+    ) -> bool {
+        let radio = unsafe { &*pac::RADIO::PTR };
+        let tim1 = unsafe { &*pac::TIMER1::PTR };
+
+        Self::set_timeout(close_at.checked_duration_since(at).map_or(0, |d| d.ticks()));
+        self.arm(
+            at,
+            radio.tasks_rxen.as_ptr() as u32,
+            tim1.tasks_start.as_ptr() as u32,
+        );
+
+        if Self::missed(at) {
+            Self::disarm();
+
+            let now = RadioTimestamps::now();
+            let Some(window) = close_at.checked_duration_since(now) else {
+                return false;
+            };
+
+            Self::set_timeout(window.ticks());
+            tim1.tasks_start.write(|w| unsafe { w.bits(1) });
+            radio.tasks_rxen.write(|w| w.tasks_rxen().set_bit());
+        }
+
+        true
+    }
+
+    /// Arm the timeout of the RX window that follows a sent packet, `window` after its END.
+    pub fn arm_response_timeout(&mut self, window: TimerDurationU64<1_000_000>) {
+        Self::set_timeout(window.ticks());
+        self.end.enable();
+    }
+
+    /// Whether the last RX window was closed by the timeout.
+    pub fn timed_out() -> bool {
+        let tim1 = unsafe { &*pac::TIMER1::PTR };
+        tim1.events_compare[0].read().bits() != 0
+    }
+
+    /// Disarm the trigger and the RX timeout, this can be called from a drop guard.
+    ///
+    /// The trigger has to be disarmed once it has fired, the compare matches again when the
+    /// timer wraps.
+    pub fn disarm() {
+        let ppi = unsafe { &*pac::PPI::PTR };
+        let tim1 = unsafe { &*pac::TIMER1::PTR };
+
+        ppi.chenclr.write(|w| w.ch3().clear().ch6().clear());
+        tim1.tasks_stop.write(|w| unsafe { w.bits(1) });
+    }
+
+    fn arm(&mut self, at: TimerInstantU64<1_000_000>, task: u32, fork_task: u32) {
+        let tim0 = unsafe { &*pac::TIMER0::PTR };
+        let ppi = unsafe { &*pac::PPI::PTR };
+
+        Self::disarm();
+        ppi.ch[3].tep.write(|w| unsafe { w.bits(task) });
+        ppi.fork[3].tep.write(|w| unsafe { w.bits(fork_task) });
+
+        tim0.events_compare[START_CC].reset();
+        tim0.cc[START_CC].write(|w| unsafe { w.cc().bits(at.ticks() as u32) });
+        ppi.chenset.write(|w| w.ch3().set());
+    }
+
+    /// Whether `at` passed before the trigger was armed. Once it has fired the radio is not
+    /// disabled anymore.
+    fn missed(at: TimerInstantU64<1_000_000>) -> bool {
+        let radio = unsafe { &*pac::RADIO::PTR };
+
+        RadioTimestamps::now() >= at && radio.state.read().state().is_disabled()
+    }
+
+    fn set_timeout(ticks: u64) {
+        let tim1 = unsafe { &*pac::TIMER1::PTR };
+
+        tim1.tasks_stop.write(|w| unsafe { w.bits(1) });
+        tim1.tasks_clear.write(|w| unsafe { w.bits(1) });
+        tim1.events_compare[0].reset();
+        // A compare at 0 would only match after a wrap, time out right away instead.
+        tim1.cc[0].write(|w| unsafe { w.cc().bits((ticks as u32).max(1)) });
+    }
+}
+
+/// The radio's latest READY, ADDRESS and PHYEND events in monotonic time.
 #[derive(Copy, Clone, Debug, defmt::Format)]
 pub struct RadioTimestamps {
-    pub ready: TimerInstantU32<1_000_000>,
-    pub address: TimerInstantU32<1_000_000>,
-    pub phyend: TimerInstantU32<1_000_000>,
+    pub ready: TimerInstantU64<1_000_000>,
+    pub address: TimerInstantU64<1_000_000>,
+    pub phy_end: TimerInstantU64<1_000_000>,
 }
 
 impl RadioTimestamps {
-    fn start(ppi_ch0: PPI_CH0) {
-        let Tim0CaptureTasks { cc3, .. } = tim0_capture_tasks();
-        let RadioEvents { address, .. } = radio_events();
+    fn start(mut channels: RadioTimestampChannels, _timer: TIMER3) {
+        let tim0 = unsafe { &*pac::TIMER0::PTR };
+        let tim3 = unsafe { &*pac::TIMER3::PTR };
+
+        // TIMER3 counts µs like the monotonic, from the same clock.
+        tim3.tasks_stop.write(|w| unsafe { w.bits(1) });
+        tim3.tasks_clear.write(|w| unsafe { w.bits(1) });
+        tim3.mode.write(|w| w.mode().timer());
+        tim3.bitmode.write(|w| w.bitmode()._32bit());
+        tim3.prescaler.write(|w| unsafe { w.prescaler().bits(4) });
+
+        // Start it on a compare of TIMER0, from then on TIMER0's low 32 bits are TIMER3's count
+        // plus the compare value, within a tick. This runs in init with interrupts disabled, so
+        // the compare is set before it is reached.
+        let at = (Self::now().ticks() as u32).wrapping_add(SYNC_MARGIN);
+        let sync = unsafe {
+            Event::new_unchecked(NonNull::new_unchecked(
+                tim0.events_compare[SYNC_CC].as_ptr() as *const _ as *mut _,
+            ))
+        };
+        let start = unsafe {
+            Task::new_unchecked(NonNull::new_unchecked(
+                tim3.tasks_start.as_ptr() as *const _ as *mut _,
+            ))
+        };
+        let mut ppi = Ppi::new_one_to_one(&mut channels.ready, sync, start);
+        tim0.events_compare[SYNC_CC].reset();
+        tim0.cc[SYNC_CC].write(|w| unsafe { w.cc().bits(at) });
+        ppi.enable();
+        while tim0.events_compare[SYNC_CC].read().bits() == 0 {}
+        tim0.events_compare[SYNC_CC].reset();
+        drop(ppi);
+        CAPTURE_OFFSET.store(at, Ordering::Relaxed);
+
+        let Tim3CaptureTasks { cc0, cc1, cc2 } = tim3_capture_tasks();
+        let RadioEvents {
+            ready,
+            address,
+            phy_end,
+        } = radio_events();
 
-        // Make PPI capture radio events to unused CC channels of the monotonic.
-        let mut ppi = Ppi::new_one_to_one(ppi_ch0, address, cc3);
+        // Make PPI capture radio events to TIMER3.
+        let mut ppi = Ppi::new_one_to_one(channels.ready, ready, cc0);
         ppi.enable();
         mem::forget(ppi);
 
-        // let mut ppi = Ppi::new_one_to_one(ppi_ch1, address, cc4);
-        // ppi.enable();
-        // mem::forget(ppi);
+        let mut ppi = Ppi::new_one_to_one(channels.address, address, cc1);
+        ppi.enable();
+        mem::forget(ppi);
 
-        // let mut ppi = Ppi::new_one_to_one(ppi_ch2, phy_end, cc5);
-        // ppi.enable();
-        // mem::forget(ppi);
+        let mut ppi = Ppi::new_one_to_one(channels.phy_end, phy_end, cc2);
+        ppi.enable();
+        mem::forget(ppi);
     }
 
     pub fn now() -> <Timer0 as Monotonic>::Instant {
         Timer0::now()
     }
 
-    // /// The Radio's events timestamped to the low 32 bits of the monotonic.
-    // /// About once every 4200 seconds this will glitch.
-    // pub fn timestamps() -> Self {
-    //     RadioTimestamps {
-    //         ready: Self::ready_timestamp(),
-    //         address: Self::address_timestamp(),
-    //         phyend: Self::phy_end_timestamp(),
-    //     }
-    // }
-
-    // /// The Radio's READY event timestamped to the low 32 bits of the monotonic.
-    // /// About once every 4200 seconds this will glitch.
-    // pub fn ready_timestamp() -> TimerInstantU32<1_000_000> {
-    //     TimerInstantU32::from_ticks(unsafe { &*pac::TIMER0::PTR }.cc[3].read().cc().bits())
-    // }
-
-    /// The Radio's ADDRESS event timestamped to the low 32 bits of the monotonic.
-    /// About once every 4200 seconds this will glitch.
-    pub fn address_timestamp() -> TimerInstantU32<1_000_000> {
-        TimerInstantU32::from_ticks(unsafe { &*pac::TIMER0::PTR }.cc[3].read().cc().bits())
-    }
-
-    // /// The Radio's PHYEND event timestamped to the low 32 bits of the monotonic.
-    // /// About once every 4200 seconds this will glitch.
-    // pub fn phy_end_timestamp() -> TimerInstantU32<1_000_000> {
-    //     TimerInstantU32::from_ticks(unsafe { &*pac::TIMER0::PTR }.cc[5].read().cc().bits())
-    // }
+    /// The radio's latest events.
+    pub fn captured() -> Self {
+        RadioTimestamps {
+            ready: Self::ready_timestamp(),
+            address: Self::address_timestamp(),
+            phy_end: Self::phy_end_timestamp(),
+        }
+    }
+
+    /// The radio's latest READY event.
+    pub fn ready_timestamp() -> TimerInstantU64<1_000_000> {
+        Self::capture(READY_CC)
+    }
+
+    /// The radio's latest ADDRESS event.
+    pub fn address_timestamp() -> TimerInstantU64<1_000_000> {
+        Self::capture(ADDRESS_CC)
+    }
+
+    /// The radio's latest PHYEND event.
+    pub fn phy_end_timestamp() -> TimerInstantU64<1_000_000> {
+        Self::capture(PHY_END_CC)
+    }
+
+    /// Extend a capture register, which with the offset only has the low 32 bits of the
+    /// monotonic, to a full instant, see [`extend_capture`].
+    fn capture(cc: usize) -> TimerInstantU64<1_000_000> {
+        let captured = unsafe { &*pac::TIMER3::PTR }.cc[cc].read().cc().bits();
+        extend_capture(
+            Self::now(),
+            captured,
+            CAPTURE_OFFSET.load(Ordering::Relaxed),
+        )
+    }
 }
 
 pub struct RadioEvents {
@@ -110,29 +434,29 @@ fn radio_events() -> RadioEvents {
     }
 }
 
-pub struct Tim0CaptureTasks {
-    pub cc3: Task<'static>,
-    pub cc4: Task<'static>,
-    pub cc5: Task<'static>,
+pub struct Tim3CaptureTasks {
+    pub cc0: Task<'static>,
+    pub cc1: Task<'static>,
+    pub cc2: Task<'static>,
 }
 
-fn tim0_capture_tasks() -> Tim0CaptureTasks {
-    let tim = unsafe { &*pac::TIMER0::PTR };
+fn tim3_capture_tasks() -> Tim3CaptureTasks {
+    let tim = unsafe { &*pac::TIMER3::PTR };
 
-    Tim0CaptureTasks {
-        cc3: unsafe {
-            Task::new_unchecked(NonNull::new_unchecked(
-                tim.tasks_capture[3].as_ptr() as *const _ as *mut _,
-            ))
+    Tim3CaptureTasks {
+        cc0: unsafe {
+            Task::new_unchecked(NonNull::new_unchecked(tim.tasks_capture[READY_CC].as_ptr()
+                as *const _
+                as *mut _))
         },
-        cc4: unsafe {
+        cc1: unsafe {
             Task::new_unchecked(NonNull::new_unchecked(
-                tim.tasks_capture[4].as_ptr() as *const _ as *mut _,
+                tim.tasks_capture[ADDRESS_CC].as_ptr() as *const _ as *mut _,
             ))
         },
-        cc5: unsafe {
+        cc2: unsafe {
             Task::new_unchecked(NonNull::new_unchecked(
-                tim.tasks_capture[5].as_ptr() as *const _ as *mut _,
+                tim.tasks_capture[PHY_END_CC].as_ptr() as *const _ as *mut _,
             ))
         },
     }
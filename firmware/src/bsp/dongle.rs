@@ -1,13 +1,19 @@
-use super::start_timer0_monotonic;
+use super::{
+    start_timer0_monotonic, Flash, HwRng, RadioTimestampChannels, RadioTrigger,
+    RadioTriggerChannels,
+};
 use crate::radio::Radio;
+use crate::radio_protocol::PairButton;
 use ccm::AeadInPlace;
 use embassy_nrf::{
     bind_interrupts,
     config::HfclkSource,
     gpio::{Input, Level, Output, OutputDrive, Pull},
+    nvmc::Nvmc,
     pac,
-    peripherals::{self, P0_00, P0_03},
+    peripherals::{self, P0_00, P0_03, USBD},
     rng::{self, Rng},
+    usb::{self, vbus_detect::HardwareVbusDetect},
 };
 
 use p256_cortex_m4::{Keypair, PublicKey};
@@ -18,15 +24,28 @@ pub use super::Mono;
 
 pub type DongleLed = Output<'static, P0_00>;
 pub type Button = Input<'static, P0_03>;
+pub type UsbDriver = usb::Driver<'static, USBD, HardwareVbusDetect>;
+
+impl PairButton for Button {
+    fn is_pressed(&mut self) -> bool {
+        // Pulled up, the button pulls it low.
+        self.is_low()
+    }
+}
 
 pub struct DongleBsp {
     pub led: DongleLed,
     pub button: Button,
     pub radio: Radio,
+    pub rng: HwRng,
+    pub flash: Flash,
+    pub usb: UsbDriver,
 }
 
 bind_interrupts!(struct Irqs {
     RNG => rng::InterruptHandler<peripherals::RNG>;
+    USBD => usb::InterruptHandler<peripherals::USBD>;
+    POWER_CLOCK => usb::vbus_detect::InterruptHandler;
 });
 
 #[inline(always)]
@@ -37,16 +56,36 @@ pub fn init_dongle(_: cortex_m::Peripherals) -> DongleBsp {
     config.hfclk_source = HfclkSource::ExternalXtal;
     let p = embassy_nrf::init(config);
 
-    start_timer0_monotonic(p.PPI_CH0);
+    start_timer0_monotonic(
+        RadioTimestampChannels {
+            ready: p.PPI_CH0,
+            address: p.PPI_CH1,
+            phy_end: p.PPI_CH2,
+        },
+        p.TIMER3,
+    );
 
     // SAFETY: Embassy does not support radio, so we conjure it from the PAC.
     let radio: pac::RADIO = unsafe { core::mem::transmute(()) };
-    let radio = Radio::init(radio);
+    let trigger = RadioTrigger::new(
+        RadioTriggerChannels {
+            start: p.PPI_CH3,
+            timeout: p.PPI_CH4,
+            address: p.PPI_CH5,
+            end: p.PPI_CH6,
+        },
+        p.TIMER1,
+    );
+    let radio = Radio::init(radio, trigger);
 
-    // Testing crypto
     let mut rng = Rng::new(p.RNG, Irqs);
     rng.set_bias_correction(true);
 
+    let flash = Nvmc::new(p.NVMC);
+
+    let usb = usb::Driver::new(p.USBD, Irqs, HardwareVbusDetect::new(Irqs));
+
+    // Testing crypto
     let mut seed = [0; 32];
     rng.blocking_fill_bytes(&mut seed);
     let mut rng2 = rand_chacha::ChaCha8Rng::from_seed(seed);
@@ -269,5 +308,8 @@ pub fn init_dongle(_: cortex_m::Peripherals) -> DongleBsp {
         led: Output::new(p.P0_00, Level::Low, OutputDrive::Standard),
         button: Input::new(p.P0_03, Pull::Up),
         radio,
+        rng,
+        flash,
+        usb,
     }
 }
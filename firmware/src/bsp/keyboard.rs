@@ -1,12 +1,17 @@
 use crate::radio::Radio;
 
-use super::start_timer0_monotonic;
+use super::{
+    start_timer0_monotonic, Flash, HwRng, RadioTimestampChannels, RadioTrigger,
+    RadioTriggerChannels,
+};
 use embassy_nrf::{
     bind_interrupts,
     config::HfclkSource,
     gpio::{AnyPin, Input, Level, Output, OutputDrive, Pin, Pull},
+    nvmc::Nvmc,
     pac,
-    peripherals::{P0_00, P0_20},
+    peripherals::{self, P0_00, P0_20},
+    rng::{self, Rng},
     saadc::{self, Saadc},
 };
 use keyberon::matrix::Matrix;
@@ -15,6 +20,7 @@ pub use super::Mono;
 
 bind_interrupts!(struct Irqs {
     SAADC => saadc::InterruptHandler;
+    RNG => rng::InterruptHandler<peripherals::RNG>;
 });
 
 pub type KeyMatrix = Matrix<Input<'static, AnyPin>, Output<'static, AnyPin>, 6, 4>;
@@ -27,6 +33,8 @@ pub struct KeyboardBsp {
     pub charger_status: ChargerStatus,
     pub key_matrix: KeyMatrix,
     pub is_right_half: bool,
+    pub rng: HwRng,
+    pub flash: Flash,
 }
 
 pub fn init_keyboard(_: cortex_m::Peripherals) -> KeyboardBsp {
@@ -36,11 +44,32 @@ pub fn init_keyboard(_: cortex_m::Peripherals) -> KeyboardBsp {
     config.hfclk_source = HfclkSource::ExternalXtal;
     let p = embassy_nrf::init(config);
 
-    start_timer0_monotonic(p.PPI_CH0);
+    start_timer0_monotonic(
+        RadioTimestampChannels {
+            ready: p.PPI_CH0,
+            address: p.PPI_CH1,
+            phy_end: p.PPI_CH2,
+        },
+        p.TIMER3,
+    );
 
     // SAFETY: Embassy does not support radio, so we conjure it from the PAC.
     let radio: pac::RADIO = unsafe { core::mem::transmute(()) };
-    let radio = Radio::init(radio);
+    let trigger = RadioTrigger::new(
+        RadioTriggerChannels {
+            start: p.PPI_CH3,
+            timeout: p.PPI_CH4,
+            address: p.PPI_CH5,
+            end: p.PPI_CH6,
+        },
+        p.TIMER1,
+    );
+    let radio = Radio::init(radio, trigger);
+
+    let mut rng = Rng::new(p.RNG, Irqs);
+    rng.set_bias_correction(true);
+
+    let flash = Nvmc::new(p.NVMC);
 
     //
     // Right or left?
@@ -136,6 +165,8 @@ pub fn init_keyboard(_: cortex_m::Peripherals) -> KeyboardBsp {
         charger_status,
         key_matrix,
         is_right_half,
+        rng,
+        flash,
     }
 }
 
@@ -0,0 +1,54 @@
+//! # The clock the radio protocol runs on
+//!
+//! Like the monotonic's, with associated functions only, so the radio protocol can be generic
+//! over it. Implemented by [`crate::bsp::Mono`], and by a simulated clock on the host.
+
+use core::future::{poll_fn, Future};
+use core::pin::pin;
+use core::task::Poll;
+use fugit::{TimerDurationU64, TimerInstantU64};
+
+/// The deadline passed before the future completed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub struct Timeout;
+
+/// A clock with µs ticks.
+#[allow(async_fn_in_trait)]
+pub trait Clock {
+    /// The current time.
+    fn now() -> TimerInstantU64<1_000_000>;
+
+    /// Wait until `instant`, returning right away if it has passed.
+    async fn delay_until(instant: TimerInstantU64<1_000_000>);
+
+    /// Wait for `duration`.
+    async fn delay(duration: TimerDurationU64<1_000_000>) {
+        Self::delay_until(Self::now() + duration).await
+    }
+
+    /// Run `future` until `deadline`, dropping it if it has not completed by then.
+    async fn timeout_at<F: Future>(
+        deadline: TimerInstantU64<1_000_000>,
+        future: F,
+    ) -> Result<F::Output, Timeout> {
+        let mut future = pin!(future);
+        let mut delay = pin!(Self::delay_until(deadline));
+
+        poll_fn(|cx| {
+            if let Poll::Ready(output) = future.as_mut().poll(cx) {
+                return Poll::Ready(Ok(output));
+            }
+
+            delay.as_mut().poll(cx).map(|()| Err(Timeout))
+        })
+        .await
+    }
+
+    /// Run `future` for at most `duration`.
+    async fn timeout_after<F: Future>(
+        duration: TimerDurationU64<1_000_000>,
+        future: F,
+    ) -> Result<F::Output, Timeout> {
+        Self::timeout_at(Self::now() + duration, future).await
+    }
+}
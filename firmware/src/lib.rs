@@ -12,6 +12,7 @@ use defmt_rtt as _; // global logger
 use panic_probe as _;
 
 pub mod bsp;
+pub mod clock;
 pub mod radio;
 pub mod radio_protocol;
 pub mod waker_registration;
@@ -1,10 +1,9 @@
-//! IEEE 802.15.4 radio
+//! 2.4 GHz radio, in the Nordic proprietary, BLE or IEEE 802.15.4 PHY, see [`Mode`]
 
-use crate::bsp::RadioTimestamps;
+use crate::bsp::{HwRng, Mono, RadioTimestamps, RadioTrigger};
 use crate::waker_registration::CriticalSectionWakerRegistration;
 use core::{
-    ops::{self, RangeFrom},
-    sync::atomic::{self, Ordering},
+    sync::atomic::{self, AtomicU32, Ordering},
     task::Poll,
 };
 use cortex_m::peripheral::NVIC;
@@ -13,7 +12,23 @@ use embassy_nrf::pac::{
     radio::{state::STATE_A, txpower::TXPOWER_A},
     Interrupt, RADIO,
 };
-use rtic_monotonics::nrf::timer::fugit::TimerInstantU32;
+use rtic_monotonics::nrf::timer::fugit::{TimerDurationU64, TimerInstantU64};
+
+pub mod continuous;
+mod driver;
+mod mode;
+mod packet;
+mod ring;
+mod timestamp;
+
+pub use driver::{
+    airtime, Addresses, EnergyLevel, Error, Pipe, RadioDriver, Rssi, Timestamp, TxPower,
+    ADDRESS_AIRTIME, DEFAULT_ADDRESSES, NUM_FREQUENCIES, RX_RAMP_UP, TURNAROUND, TX_RAMP_UP,
+};
+pub use mode::{Mode, ModeConfig};
+pub use packet::Packet;
+pub use ring::RxRing;
+pub use timestamp::extend_capture;
 
 struct OnDrop<F: FnOnce()> {
     f: core::mem::MaybeUninit<F>,
@@ -37,29 +52,84 @@ impl<F: FnOnce()> Drop for OnDrop<F> {
     }
 }
 
-/// IEEE 802.15.4 radio
+/// Gives access to the radio, and puts it back in the mode it was in when dropped, also when an
+/// operation in another mode is cancelled
+struct RestoreMode<'a> {
+    inner: &'a mut Radio,
+    saved_mode: Mode,
+}
+
+impl<'a> RestoreMode<'a> {
+    fn new(radio: &'a mut Radio) -> Self {
+        Self {
+            saved_mode: radio.mode,
+            inner: radio,
+        }
+    }
+}
+
+impl core::ops::Deref for RestoreMode<'_> {
+    type Target = Radio;
+
+    fn deref(&self) -> &Radio {
+        self.inner
+    }
+}
+
+impl core::ops::DerefMut for RestoreMode<'_> {
+    fn deref_mut(&mut self) -> &mut Radio {
+        self.inner
+    }
+}
+
+impl Drop for RestoreMode<'_> {
+    fn drop(&mut self) {
+        if self.inner.mode != self.saved_mode {
+            self.inner.set_mode(self.saved_mode);
+        }
+    }
+}
+
+/// 2.4 GHz radio
 pub struct Radio {
     radio: RADIO,
     // RADIO needs to be (re-)enabled to pick up new settings
     needs_enable: bool,
+    trigger: RadioTrigger,
+    csma: Csma,
+    mode: Mode,
+    tx_pipe: u8,
+    rx_pipes: u8,
 }
 
-/// Timestamp for when the `address` portion of the packet was sent or received.
-#[derive(Copy, Clone, Debug, defmt::Format, PartialEq, Eq, PartialOrd, Ord)]
-pub struct Timestamp(pub TimerInstantU32<1_000_000>);
-
-/// RSSI value in dBm.
-#[derive(Copy, Clone, Debug, defmt::Format, PartialEq, Eq, PartialOrd, Ord)]
-pub struct Rssi(pub i8);
-
 static WAKER: CriticalSectionWakerRegistration = CriticalSectionWakerRegistration::new();
 
+/// The pending automatic turnaround: [`NO_TURNAROUND`], [`TURNAROUND_TO_RX`] or the address of
+/// the response to send.
+static PENDING_TURNAROUND: AtomicU32 = AtomicU32::new(NO_TURNAROUND);
+const NO_TURNAROUND: u32 = 0;
+const TURNAROUND_TO_RX: u32 = 1;
+
 // Bind the radio interrupt.
 #[no_mangle]
 #[allow(non_snake_case)]
 unsafe extern "C" fn RADIO() {
     let radio = unsafe { &*pac::RADIO::PTR };
 
+    // The continuous receiver swaps its buffers at every END, and keeps its interrupt enabled.
+    if continuous::STREAMING.load(Ordering::Relaxed) {
+        continuous::on_end(radio);
+        WAKER.wake();
+        return;
+    }
+
+    // The turnaround can not wait for the executor, it has to be done before the ramp-up.
+    let turnaround = PENDING_TURNAROUND.load(Ordering::Relaxed);
+    if turnaround != NO_TURNAROUND && radio.events_disabled.read().events_disabled().bit_is_set() {
+        PENDING_TURNAROUND.store(NO_TURNAROUND, Ordering::Relaxed);
+        turn_around(radio, turnaround);
+    }
+
     // We got an event, clear interrupts and wake the waker.
     radio.intenclr.write(|w| w.bits(0xffffffff));
 
@@ -68,9 +138,59 @@ unsafe extern "C" fn RADIO() {
     WAKER.wake()
 }
 
+/// Runs at the DISABLED event that turns the radio around, the DISABLED_TXEN or DISABLED_RXEN
+/// short has already started the ramp-up.
+fn turn_around(radio: &pac::radio::RegisterBlock, turnaround: u32) {
+    // Only turn around once, the next DISABLED ends the exchange.
+    radio
+        .shorts
+        .modify(|_, w| w.disabled_txen().disabled().disabled_rxen().disabled());
+    radio.events_disabled.reset();
+
+    if turnaround == TURNAROUND_TO_RX {
+        // The END of the received packet tells it apart from the sent one.
+        radio.events_end.reset();
+        return;
+    }
+
+    let intact = radio.events_end.read().events_end().bit_is_set()
+        && radio.crcstatus.read().crcstatus().bit_is_set();
+
+    if intact {
+        // NOTE(unsafe) the RX DMA transfer has ended, the TX one starts at READY
+        radio.packetptr.write(|w| unsafe { w.bits(turnaround) });
+    } else {
+        // The window closed or the packet is corrupt, abort the ramp-up.
+        radio.tasks_disable.write(|w| w.tasks_disable().set_bit());
+    }
+}
+
+/// Default PHY mode = Nordic proprietary 2 Mbit/s
+pub const DEFAULT_MODE: Mode = Mode::Nrf2Mbit;
+
+/// Duration of one energy detection sample, 8 symbols of 16 µs
+pub const ED_SAMPLE_PERIOD: TimerDurationU64<1_000_000> = TimerDurationU64::micros(128);
+
+// ED_RSSISCALE and ED_RSSIOFFS of the nRF52833-PS, dBm = ED_RSSIOFFS + ED_RSSISCALE * EDSAMPLE
+const ED_RSSISCALE: i16 = 4;
+const ED_RSSIOFFS: i16 = -93;
+
 /// Default Clear Channel Assessment method = Carrier sense
 pub const DEFAULT_CCA: Cca = Cca::CarrierSense;
 
+/// Default CSMA-CA parameters = the defaults of the IEEE spec
+pub const DEFAULT_CSMA: Csma = Csma {
+    min_be: 3,
+    max_be: 5,
+    max_backoffs: 4,
+};
+
+/// The unit of the CSMA-CA backoffs (aUnitBackoffPeriod), 20 symbols of 16 µs
+pub const UNIT_BACKOFF_PERIOD: TimerDurationU64<1_000_000> = TimerDurationU64::micros(320);
+
+/// The largest backoff exponent the IEEE spec allows for macMaxBe
+const MAX_BE: u8 = 8;
+
 /// Default radio channel = Channel 11 (`2_405` MHz)
 pub const DEFAULT_CHANNEL: Channel = Channel::_11;
 
@@ -80,20 +200,38 @@ pub const DEFAULT_TXPOWER: TxPower = TxPower::_0dBm;
 /// Default Start of Frame Delimiter = `0xA7` (IEEE compliant)
 pub const DEFAULT_SFD: u8 = 0xA7;
 
-// TODO expose the other variants in `pac::CCAMODE_A`
+/// How long before the response the receiver is ready in [`Radio::send_at_and_recv`]
+const RX_TURNAROUND_MARGIN: TimerDurationU64<1_000_000> = TimerDurationU64::micros(10);
+
 /// Clear Channel Assessment method
+///
+/// The `ed_threshold` of the energy detecting methods is compared with the energy measurements,
+/// which are above it when the channel is assumed to be busy. Note the the measurement range is
+/// 0..0xFF - where 0 means that the received power was less than 10 dB above the selected
+/// receiver sensitivity. This value is not given in dBm, but can be converted. See the nrf52840
+/// Product Specification Section 6.20.12.4 for details.
 pub enum Cca {
     /// Carrier sense
     CarrierSense,
     /// Energy Detection / Energy Above Threshold
-    EnergyDetection {
-        /// Energy measurements above this value mean that the channel is assumed to be busy.
-        /// Note the the measurement range is 0..0xFF - where 0 means that the received power was
-        /// less than 10 dB above the selected receiver sensitivity. This value is not given in dBm,
-        /// but can be converted. See the nrf52840 Product Specification Section 6.20.12.4
-        /// for details.
-        ed_threshold: u8,
-    },
+    EnergyDetection { ed_threshold: u8 },
+    /// Busy if a carrier is detected and the energy is above the threshold
+    CarrierAndEnergyDetection { ed_threshold: u8 },
+    /// Busy if a carrier is detected or the energy is above the threshold
+    CarrierOrEnergyDetection { ed_threshold: u8 },
+    /// Energy detection test mode, busy as soon as a single measurement is above the threshold
+    EnergyDetectionTest1 { ed_threshold: u8 },
+}
+
+/// CSMA-CA parameters of [`Radio::send`], see IEEE 802.15.4-2015 section 6.2.5.1
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub struct Csma {
+    /// The backoff exponent of the first backoff (macMinBe)
+    pub min_be: u8,
+    /// The largest backoff exponent (macMaxBe), at most 8
+    pub max_be: u8,
+    /// How many times the channel may be found busy before giving up (macMaxCsmaBackoffs)
+    pub max_backoffs: u8,
 }
 
 /// IEEE 802.15.4 channels
@@ -134,40 +272,6 @@ pub enum Channel {
     _26 = 80,
 }
 
-/// Transmission power in dBm (decibel milliwatt)
-// TXPOWERA enum minus the deprecated Neg30dBm variant and with better docs
-#[derive(Clone, Copy, PartialEq)]
-pub enum TxPower {
-    /// +8 dBm
-    Pos8dBm,
-    /// +7 dBm
-    Pos7dBm,
-    /// +6 dBm (~4 mW)
-    Pos6dBm,
-    /// +5 dBm
-    Pos5dBm,
-    /// +4 dBm
-    Pos4dBm,
-    /// +3 dBm (~2 mW)
-    Pos3dBm,
-    /// +2 dBm
-    Pos2dBm,
-    /// 0 dBm (1 mW)
-    _0dBm,
-    /// -4 dBm
-    Neg4dBm,
-    /// -8 dBm
-    Neg8dBm,
-    /// -12 dBm
-    Neg12dBm,
-    /// -16 dBm
-    Neg16dBm,
-    /// -20 dBm (10 μW)
-    Neg20dBm,
-    /// -40 dBm (0.1 μW)
-    Neg40dBm,
-}
-
 impl TxPower {
     fn _into(self) -> TXPOWER_A {
         match self {
@@ -190,11 +294,16 @@ impl TxPower {
 }
 
 impl Radio {
-    /// Initializes the radio for IEEE 802.15.4 operation
-    pub fn init(radio: RADIO) -> Self {
+    /// Initializes the radio in [`DEFAULT_MODE`]
+    pub fn init(radio: RADIO, trigger: RadioTrigger) -> Self {
         let mut radio = Self {
             needs_enable: false,
             radio,
+            trigger,
+            csma: DEFAULT_CSMA,
+            mode: DEFAULT_MODE,
+            tx_pipe: 0,
+            rx_pipes: 0xff,
         };
 
         // shortcuts will be kept off by default and only be temporarily enabled within blocking
@@ -211,73 +320,13 @@ impl Radio {
         radio.radio.events_address.reset();
         radio.radio.events_ready.reset();
 
-        radio.radio.mode.write(|w| w.mode().nrf_2mbit());
+        radio.set_addresses(&DEFAULT_ADDRESSES);
 
-        let base0 = [0xE7, 0xE7, 0xE7, 0xE7];
-        let base1 = [0xC2, 0xC2, 0xC2, 0xC2];
-        let prefix0 = [0xE7, 0xC2, 0xC3, 0xC4];
-        let prefix1 = [0xC5, 0xC6, 0xC7, 0xC8];
-
-        radio
-            .radio
-            .base0
-            .write(|w| unsafe { w.bits(u32::from_le_bytes(base0)) });
-        radio
-            .radio
-            .base1
-            .write(|w| unsafe { w.bits(u32::from_le_bytes(base1)) });
-
-        radio
-            .radio
-            .prefix0
-            .write(|w| unsafe { w.bits(u32::from_le_bytes(prefix0)) });
-        radio
-            .radio
-            .prefix1
-            .write(|w| unsafe { w.bits(u32::from_le_bytes(prefix1)) });
-
-        // NOTE(unsafe) radio is currently disabled
-        unsafe {
-            radio.radio.pcnf0.write(|w| {
-                w.s1incl()
-                    .clear_bit() // S1 not included in RAM
-                    .plen()
-                    ._8bit()
-                    .crcinc()
-                    .include() // the LENGTH field (the value) also accounts for the CRC (2 bytes)
-                    .cilen()
-                    .bits(0) // no code indicator
-                    .lflen()
-                    .bits(7) // length = 8 bits (but highest bit is reserved and must be `0`)
-                    .s0len()
-                    .clear_bit() // no S0
-                    .s1len()
-                    .bits(0) // no S1
-            });
-
-            radio.radio.pcnf1.write(|w| {
-                w.maxlen()
-                    .bits(Packet::MAX_PSDU_LEN) // payload length
-                    .statlen()
-                    .bits(0) // no static length
-                    .balen()
-                    .bits(4) // no base address
-                    .endian()
-                    .clear_bit() // little endian
-                    .whiteen()
-                    .clear_bit() // no data whitening
-            });
-
-            // Fast ramp-up
-            radio.radio.modecnf0.modify(|_, w| w.ru().fast());
-
-            // CRC configuration required by the IEEE spec: x**16 + x**12 + x**5 + 1
-            radio.radio.crccnf.write(|w| w.len().two());
-            radio.radio.crcpoly.write(|w| w.crcpoly().bits(0x11021));
-            radio.radio.crcinit.write(|w| w.crcinit().bits(0));
-        }
+        // Fast ramp-up
+        radio.radio.modecnf0.modify(|_, w| w.ru().fast());
 
         // set default settings
+        radio.set_mode(DEFAULT_MODE);
         radio.set_channel(DEFAULT_CHANNEL);
         radio.set_cca(DEFAULT_CCA);
         radio.set_sfd(DEFAULT_SFD);
@@ -292,6 +341,74 @@ impl Radio {
         radio
     }
 
+    /// The current PHY mode
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Changes the PHY mode, with its packet format, CRC and whitening
+    pub fn set_mode(&mut self, mode: Mode) {
+        let config = mode.config();
+
+        // The packet configuration may only be changed while the radio is disabled.
+        self.disable();
+        self.needs_enable = true;
+        self.mode = mode;
+
+        // NOTE(unsafe) radio is currently disabled, and the values are valid for the registers
+        unsafe {
+            self.radio.mode.write(|w| w.bits(config.mode));
+            self.radio.pcnf0.write(|w| w.bits(config.pcnf0));
+            self.radio.pcnf1.write(|w| w.bits(config.pcnf1));
+            self.radio.crccnf.write(|w| w.bits(config.crccnf));
+            self.radio.crcpoly.write(|w| w.bits(config.crcpoly));
+            self.radio.crcinit.write(|w| w.bits(config.crcinit));
+            self.radio.datawhiteiv.write(|w| w.bits(config.datawhiteiv));
+        }
+    }
+
+    /// Changes the addresses of all pipes
+    pub fn set_addresses(&mut self, addresses: &Addresses) {
+        let [p0, p1, p2, p3, p4, p5, p6, p7] = addresses.prefixes;
+
+        self.needs_enable = true;
+        // NOTE(unsafe) any value is a valid address
+        unsafe {
+            self.radio
+                .base0
+                .write(|w| w.bits(u32::from_le_bytes(addresses.base0)));
+            self.radio
+                .base1
+                .write(|w| w.bits(u32::from_le_bytes(addresses.base1)));
+            self.radio
+                .prefix0
+                .write(|w| w.bits(u32::from_le_bytes([p0, p1, p2, p3])));
+            self.radio
+                .prefix1
+                .write(|w| w.bits(u32::from_le_bytes([p4, p5, p6, p7])));
+        }
+    }
+
+    /// Changes the pipe (0..=7) packets are sent on
+    ///
+    /// # Panics
+    ///
+    /// If `pipe` is not a pipe.
+    pub fn set_tx_pipe(&mut self, pipe: u8) {
+        if pipe > 7 {
+            panic!("Invalid pipe");
+        }
+
+        self.tx_pipe = pipe;
+    }
+
+    /// Changes the pipes packets are received on, bit `n` of `pipes` enables pipe `n`
+    ///
+    /// Packets on other addresses are dropped by the radio without waking the CPU.
+    pub fn set_rx_pipes(&mut self, pipes: u8) {
+        self.rx_pipes = pipes;
+    }
+
     /// Changes the radio channel
     pub fn set_channel(&mut self, channel: Channel) {
         self.needs_enable = true;
@@ -302,8 +419,12 @@ impl Radio {
         }
     }
 
-    /// Changes the radio frequency in 2400 MHz + `val` where `val = 0..=100`.
-    pub fn set_freqeuency(&mut self, frequency: u8) {
+    /// Changes the radio frequency in 2400 MHz + `frequency` where `frequency = 0..=100`.
+    ///
+    /// # Panics
+    ///
+    /// If `frequency` is out of range.
+    pub fn set_frequency(&mut self, frequency: u8) {
         if frequency > 100 {
             panic!("Invalid frequency setting");
         }
@@ -328,9 +449,35 @@ impl Radio {
                     .ccactrl
                     .write(|w| unsafe { w.ccamode().ed_mode().ccaedthres().bits(ed_threshold) });
             }
+            Cca::CarrierAndEnergyDetection { ed_threshold } => {
+                self.radio.ccactrl.write(|w| unsafe {
+                    w.ccamode()
+                        .carrier_and_ed_mode()
+                        .ccaedthres()
+                        .bits(ed_threshold)
+                });
+            }
+            Cca::CarrierOrEnergyDetection { ed_threshold } => {
+                self.radio.ccactrl.write(|w| unsafe {
+                    w.ccamode()
+                        .carrier_or_ed_mode()
+                        .ccaedthres()
+                        .bits(ed_threshold)
+                });
+            }
+            Cca::EnergyDetectionTest1 { ed_threshold } => {
+                self.radio.ccactrl.write(|w| unsafe {
+                    w.ccamode().ed_mode_test1().ccaedthres().bits(ed_threshold)
+                });
+            }
         }
     }
 
+    /// Changes the CSMA-CA parameters of [`Radio::send`]
+    pub fn set_csma(&mut self, csma: Csma) {
+        self.csma = csma;
+    }
+
     /// Changes the Start of Frame Delimiter
     pub fn set_sfd(&mut self, sfd: u8) {
         // self.needs_enable = true; // this appears to not be needed
@@ -348,16 +495,17 @@ impl Radio {
     /// Receives one radio packet and copies its contents into the given `packet` buffer
     ///
     /// This methods returns the `Ok` variant if the CRC included the packet was successfully
-    /// validated by the hardware; otherwise it returns the `Err` variant. In either case, `packet`
-    /// will be updated with the received packet's data
-    pub async fn recv(&mut self, packet: &mut Packet) -> Result<(Timestamp, Rssi), u16> {
+    /// validated by the hardware; otherwise it returns [`Error::Crc`]. In either case, `packet`
+    /// will be updated with the received packet's data. Only the pipes enabled with
+    /// [`Radio::set_rx_pipes`] are received, `Ok` carries the one that matched.
+    pub async fn recv(&mut self, packet: &mut Packet) -> Result<(Timestamp, Rssi, Pipe), Error> {
         // Start the read
         // NOTE(unsafe) We block until reception completes or errors
         unsafe {
             self.start_recv(packet);
         }
 
-        let dropper = OnDrop::new(|| Self::cancel_recv());
+        let dropper = OnDrop::new(|| Self::cancel());
 
         // wait until we have received something
         core::future::poll_fn(|cx| {
@@ -380,22 +528,7 @@ impl Radio {
         dma_end_fence();
         dropper.defuse();
 
-        let timestamp = RadioTimestamps::address_timestamp();
-        let rssi = self.radio.rssisample.read().rssisample().bits() as i8;
-
-        defmt::trace!(
-            "RX complete, address received at {}, rssi = -{} dBm",
-            timestamp,
-            rssi
-        );
-
-        let crc = self.radio.rxcrc.read().rxcrc().bits() as u16;
-        if self.radio.crcstatus.read().crcstatus().bit_is_set() {
-            defmt::trace!("RX CRC OK");
-            Ok((Timestamp(timestamp), Rssi(-rssi)))
-        } else {
-            Err(crc)
-        }
+        self.received(packet)
     }
 
     unsafe fn start_recv(&mut self, packet: &mut Packet) {
@@ -423,25 +556,366 @@ impl Radio {
         defmt::trace!("Start receiving");
     }
 
-    fn cancel_recv() {
+    /// Checks a packet received into `packet`, and returns when its address was received and
+    /// its RSSI
+    ///
+    /// A packet whose length field does not even cover the CRC is emptied.
+    fn received(&self, packet: &mut Packet) -> Result<(Timestamp, Rssi, Pipe), Error> {
+        let timestamp = RadioTimestamps::address_timestamp();
+        let rssi = self.radio.rssisample.read().rssisample().bits() as i8;
+        let pipe = self.radio.rxmatch.read().rxmatch().bits();
+
+        defmt::trace!(
+            "RX complete, address received at {} on pipe {}, rssi = -{} dBm",
+            timestamp,
+            pipe,
+            rssi
+        );
+
+        let crc = self.radio.rxcrc.read().rxcrc().bits() as u16;
+        if !self.radio.crcstatus.read().crcstatus().bit_is_set() {
+            return Err(Error::Crc(crc));
+        }
+
+        if !packet.has_valid_len() {
+            packet.set_len(0);
+            return Err(Error::InvalidLength);
+        }
+
+        Ok((Timestamp(timestamp), Rssi(-rssi), Pipe(pipe)))
+    }
+
+    /// Receives one radio packet whose address arrives between `open_at` and `close_at`
+    ///
+    /// The receiver is started through PPI so it listens from `open_at`, or right away if that has
+    /// passed, and is disabled through PPI if no address has been received by `close_at`. Returns
+    /// [`Error::Timeout`] in that case, [`Error::TooLate`] if `close_at` had already passed and
+    /// [`Error::Cancelled`] if the receiver was disabled otherwise. A packet that has started by
+    /// `close_at` is received in full.
+    pub async fn recv_window(
+        &mut self,
+        packet: &mut Packet,
+        open_at: TimerInstantU64<1_000_000>,
+        close_at: TimerInstantU64<1_000_000>,
+    ) -> Result<(Timestamp, Rssi, Pipe), Error> {
+        self.prepare_scheduled(packet);
+
+        // the DMA transfer will start when the trigger fires so we place the compiler fence here
+        dma_start_fence();
+        if !self.trigger.arm_rx(open_at - RX_RAMP_UP, close_at) {
+            self.radio.shorts.reset();
+            return Err(Error::TooLate);
+        }
+
+        let dropper = OnDrop::new(|| Self::cancel());
+
+        // wait until we have received something, or the window closed
+        let received = core::future::poll_fn(|cx| {
+            WAKER.register(cx.waker());
+
+            if self.event_happened_and_reset(Event::End) {
+                self.disable_interrupt(Event::End);
+                self.disable_interrupt(Event::Disabled);
+                Poll::Ready(true)
+            } else if self.event_happened_and_reset(Event::Disabled) {
+                self.disable_interrupt(Event::End);
+                self.disable_interrupt(Event::Disabled);
+                Poll::Ready(false)
+            } else {
+                self.enable_interrupt(Event::End);
+                self.enable_interrupt(Event::Disabled);
+                Poll::Pending
+            }
+        })
+        .await;
+
+        dma_end_fence();
+        dropper.defuse();
+        let timed_out = RadioTrigger::timed_out();
+        RadioTrigger::disarm();
+        self.radio.shorts.reset();
+
+        if !received {
+            if !timed_out {
+                return Err(Error::Cancelled);
+            }
+
+            defmt::trace!("RX window closed at {}", close_at);
+            return Err(Error::Timeout);
+        }
+
+        self.received(packet)
+    }
+
+    /// Receives one radio packet like [`Radio::recv_window`], and answers it with `response`
+    ///
+    /// The radio turns around by itself with the END to DISABLE and DISABLED to TXEN shorts, so
+    /// the response starts [`TURNAROUND`] after the end of the received packet. It is only sent
+    /// if the packet's CRC is correct, but before the caller gets to look at the packet.
+    ///
+    /// Returns [`Error::Cancelled`] if the receiver was disabled without a packet or a timeout.
+    ///
+    /// The window is opened and closed by the [`RadioTrigger`] like for [`Radio::recv_window`],
+    /// the dongle's data slots depend on its TIMER0 compare firing.
+    ///
+    /// NOTE this method will *not* modify the `response` argument. The mutable reference is used
+    /// to ensure the `response` buffer is allocated in RAM, which is required by the RADIO
+    /// peripheral
+    pub async fn recv_and_respond(
+        &mut self,
+        packet: &mut Packet,
+        response: &mut Packet,
+        open_at: TimerInstantU64<1_000_000>,
+        close_at: TimerInstantU64<1_000_000>,
+    ) -> Result<(), Error> {
+        self.prepare_scheduled(packet);
+
+        self.radio
+            .txaddress
+            .write(|w| unsafe { w.txaddress().bits(self.tx_pipe) });
+        self.radio
+            .tifs
+            .write(|w| unsafe { w.tifs().bits(TURNAROUND.ticks() as _) });
+        self.radio.shorts.modify(|_, w| {
+            w.end_disable()
+                .enabled()
+                .disabled_txen()
+                .enabled()
+                .txready_start()
+                .enabled()
+        });
+
+        // The interrupt points the radio at the response once the packet is in
+        PENDING_TURNAROUND.store(response.buffer.as_ptr() as u32, Ordering::Relaxed);
+        self.enable_interrupt(Event::Disabled);
+
+        // the DMA transfer will start when the trigger fires so we place the compiler fence here
+        dma_start_fence();
+        if !self.trigger.arm_rx(open_at - RX_RAMP_UP, close_at) {
+            PENDING_TURNAROUND.store(NO_TURNAROUND, Ordering::Relaxed);
+            self.disable_interrupt(Event::Disabled);
+            self.radio.shorts.reset();
+            return Err(Error::TooLate);
+        }
+
+        let dropper = OnDrop::new(|| Self::cancel());
+        self.wait_for_turnaround().await;
+        dropper.defuse();
+
+        let timed_out = RadioTrigger::timed_out();
+        RadioTrigger::disarm();
+        self.radio.shorts.reset();
+
+        if timed_out {
+            return Err(Error::Timeout);
+        }
+
+        // Without a packet the DISABLED comes from somewhere else.
+        if !self.event_happened_and_reset(Event::End) {
+            return Err(Error::Cancelled);
+        }
+
+        let crc = self.radio.rxcrc.read().rxcrc().bits() as u16;
+        if !self.radio.crcstatus.read().crcstatus().bit_is_set() {
+            return Err(Error::Crc(crc));
+        }
+
+        if !packet.has_valid_len() {
+            packet.set_len(0);
+            return Err(Error::InvalidLength);
+        }
+
+        defmt::trace!("RX complete, response sent");
+        Ok(())
+    }
+
+    /// Sends the given `packet` at exactly `at` like [`Radio::send_at`], then receives the
+    /// response into `packet`
+    ///
+    /// The radio turns around by itself with the END to DISABLE and DISABLED to RXEN shorts, so
+    /// it is ready for a response sent [`TURNAROUND`] after the packet. Returns
+    /// [`Error::Timeout`] if no response has started `window` after the end of the packet.
+    pub async fn send_at_and_recv(
+        &mut self,
+        packet: &mut Packet,
+        at: TimerInstantU64<1_000_000>,
+        window: TimerDurationU64<1_000_000>,
+    ) -> Result<(Timestamp, Rssi, Pipe), Error> {
+        // The response is received into the same buffer once the packet is sent.
+        self.prepare_scheduled(packet);
+
+        self.radio
+            .txaddress
+            .write(|w| unsafe { w.txaddress().bits(self.tx_pipe) });
+        self.radio.tifs.write(|w| unsafe {
+            w.tifs()
+                .bits((TURNAROUND - RX_TURNAROUND_MARGIN).ticks() as _)
+        });
+        self.radio.shorts.modify(|_, w| {
+            w.end_disable()
+                .enabled()
+                .disabled_rxen()
+                .enabled()
+                .txready_start()
+                .enabled()
+        });
+
+        PENDING_TURNAROUND.store(TURNAROUND_TO_RX, Ordering::Relaxed);
+        self.enable_interrupt(Event::Disabled);
+
+        // the DMA transfer will start when the trigger fires so we place the compiler fence here
+        dma_start_fence();
+        if !self.trigger.arm_tx(at - TX_RAMP_UP) {
+            PENDING_TURNAROUND.store(NO_TURNAROUND, Ordering::Relaxed);
+            self.disable_interrupt(Event::Disabled);
+            self.radio.shorts.reset();
+            return Err(Error::TooLate);
+        }
+        self.trigger.arm_response_timeout(window);
+
+        let dropper = OnDrop::new(|| Self::cancel());
+        self.wait_for_turnaround().await;
+        dropper.defuse();
+
+        RadioTrigger::disarm();
+        self.radio.shorts.reset();
+
+        // The turnaround cleared the END of the sent packet.
+        if !self.event_happened_and_reset(Event::End) {
+            return Err(Error::Timeout);
+        }
+
+        self.received(packet)
+    }
+
+    /// Moves the radio to DISABLED and prepares a scheduled RX into `packet`, or a TX from it
+    fn prepare_scheduled(&mut self, packet: &mut Packet) {
+        // The ramp-up only takes a known time from DISABLED, it also picks up new settings.
+        self.disable();
+        self.needs_enable = false;
+
+        // clear related events
+        self.radio.events_phyend.reset();
+        self.radio.events_end.reset();
+        self.radio.events_ready.reset();
+        self.radio.events_address.reset();
+        self.radio.events_disabled.reset();
+
+        self.radio
+            .rxaddresses
+            .write(|w| unsafe { w.bits(self.rx_pipes as u32) });
+
+        // NOTE(unsafe) DMA transfer has not yet started
+        // set up RX buffer
+        unsafe {
+            self.radio
+                .packetptr
+                .write(|w| w.packetptr().bits(packet.buffer.as_mut_ptr() as u32));
+        }
+
+        // start receiving as soon as the ramp-up is done
+        self.radio.shorts.write(|w| {
+            w.rxready_start()
+                .set_bit()
+                .address_rssistart()
+                .enabled()
+                .disabled_rssistop()
+                .enabled()
+        });
+    }
+
+    /// Waits for the DISABLED event that ends an exchange with an automatic turnaround
+    async fn wait_for_turnaround(&mut self) {
+        core::future::poll_fn(|cx| {
+            WAKER.register(cx.waker());
+
+            // The interrupt has handled the DISABLED event of the turnaround itself.
+            if PENDING_TURNAROUND.load(Ordering::Relaxed) == NO_TURNAROUND
+                && self.event_happened_and_reset(Event::Disabled)
+            {
+                self.disable_interrupt(Event::Disabled);
+                Poll::Ready(())
+            } else {
+                self.enable_interrupt(Event::Disabled);
+                Poll::Pending
+            }
+        })
+        .await;
+
+        dma_end_fence();
+    }
+
+    /// Stops whatever the radio is doing and leaves it DISABLED, for the drop guards of the
+    /// operations
+    fn cancel() {
+        PENDING_TURNAROUND.store(NO_TURNAROUND, Ordering::Relaxed);
+        RadioTrigger::disarm();
+
         let radio = unsafe { &*pac::RADIO::PTR };
-        radio.tasks_stop.write(|w| w.tasks_stop().set_bit());
-        while radio.state.read().state().variant().unwrap() != STATE_A::RX_IDLE {}
+        radio.intenclr.write(|w| unsafe { w.bits(0xffffffff) });
+        radio.shorts.reset();
+        radio.tasks_ccastop.write(|w| w.tasks_ccastop().set_bit());
+        radio.tasks_edstop.write(|w| w.tasks_edstop().set_bit());
+        radio.tasks_disable.write(|w| w.tasks_disable().set_bit());
+        while radio.state.read().state().variant().unwrap() != STATE_A::DISABLED {}
         // DMA transfer may have been in progress so synchronize with its memory operations
         dma_end_fence();
     }
 
-    /// Sends the given `packet`
+    /// Sends the given `packet` with unslotted CSMA-CA, see IEEE 802.15.4-2015 section 6.2.5.1
     ///
-    /// This is utility method that *consecutively* calls the `try_send` method until it succeeds.
-    /// Note that this approach is *not* IEEE spec compliant -- there must be delay between failed
-    /// CCA attempts to be spec compliant
+    /// Every CCA comes after a random backoff of up to `2^BE - 1` [`UNIT_BACKOFF_PERIOD`]s drawn
+    /// from `rng`, where the backoff exponent BE starts at [`Csma::min_be`] and grows by one up
+    /// to [`Csma::max_be`] every time the channel is busy. Returns
+    /// [`Error::ChannelAccessFailure`] if the channel is still busy after
+    /// [`Csma::max_backoffs`] backoffs.
     ///
     /// NOTE this method will *not* modify the `packet` argument. The mutable reference is used to
     /// ensure the `packet` buffer is allocated in RAM, which is required by the RADIO peripheral
     // NOTE we do NOT check the address of `packet` because the mutable reference ensures it's
     // allocated in RAM
-    pub async fn send(&mut self, packet: &mut Packet) -> Timestamp {
+    pub async fn send(&mut self, packet: &mut Packet, rng: &mut HwRng) -> Result<Timestamp, Error> {
+        let csma = self.csma;
+        let mut be = csma.min_be.min(csma.max_be);
+        let mut busy = 0;
+
+        while !self.try_send(packet, Self::backoff(rng, be)).await {
+            busy += 1;
+            if busy > csma.max_backoffs {
+                defmt::trace!("Channel still busy after {} backoffs", csma.max_backoffs);
+                return Err(Error::ChannelAccessFailure);
+            }
+
+            be = (be + 1).min(csma.max_be);
+            defmt::trace!("Collision, CCA again with BE = {}", be);
+        }
+
+        let timestamp = RadioTimestamps::address_timestamp();
+
+        defmt::trace!("TX complete, address sent at: {}", timestamp);
+
+        Ok(Timestamp(timestamp))
+    }
+
+    /// A random backoff of up to `2^be - 1` unit backoff periods
+    fn backoff(rng: &mut HwRng, be: u8) -> TimerDurationU64<1_000_000> {
+        let mut random = [0; 2];
+        rng.blocking_fill_bytes(&mut random);
+
+        let periods = u16::from_le_bytes(random) & ((1 << be.min(MAX_BE)) - 1);
+        UNIT_BACKOFF_PERIOD * periods as u32
+    }
+
+    /// Backs off for `backoff`, then sends `packet` if a CCA finds the channel idle
+    ///
+    /// Returns `false` without sending if the channel is busy.
+    async fn try_send(
+        &mut self,
+        packet: &mut Packet,
+        backoff: TimerDurationU64<1_000_000>,
+    ) -> bool {
+        Mono::delay(backoff).await;
+
         // enable radio to perform cca
         self.put_in_rx_mode();
         defmt::trace!("In RX mode to find CCA");
@@ -450,6 +924,7 @@ impl Radio {
         self.radio.events_phyend.reset();
         self.radio.events_end.reset();
         self.radio.events_ready.reset();
+        self.radio.events_ccabusy.reset();
 
         // immediately start transmission if the channel is idle
         self.radio.shorts.modify(|_, w| {
@@ -471,6 +946,10 @@ impl Radio {
                 .write(|w| w.packetptr().bits(packet.buffer.as_ptr() as u32));
         }
 
+        self.radio
+            .txaddress
+            .write(|w| unsafe { w.txaddress().bits(self.tx_pipe) });
+
         // start CCA (+ sending if channel is clear)
         self.radio
             .tasks_ccastart
@@ -478,52 +957,50 @@ impl Radio {
 
         defmt::trace!("Search for CCA...");
 
-        core::future::poll_fn(|cx| {
+        let dropper = OnDrop::new(|| Self::cancel());
+
+        let sent = core::future::poll_fn(|cx| {
             WAKER.register(cx.waker());
 
             if self.event_happened_and_reset(Event::PhyEnd) {
                 self.disable_interrupt(Event::PhyEnd);
                 self.disable_interrupt(Event::CcaBusy);
-
-                return Poll::Ready(());
+                Poll::Ready(true)
             } else if self.event_happened_and_reset(Event::CcaBusy) {
-                // Try CCA again
-                self.radio
-                    .tasks_ccastart
-                    .write(|w| w.tasks_ccastart().set_bit());
-                defmt::trace!("Collision, CCA again...");
+                self.disable_interrupt(Event::PhyEnd);
+                self.disable_interrupt(Event::CcaBusy);
+                Poll::Ready(false)
+            } else {
+                self.enable_interrupt(Event::PhyEnd);
+                self.enable_interrupt(Event::CcaBusy);
+                Poll::Pending
             }
-
-            self.enable_interrupt(Event::PhyEnd);
-            self.enable_interrupt(Event::CcaBusy);
-
-            Poll::Pending
         })
         .await;
 
-        let timestamp = RadioTimestamps::address_timestamp();
-
-        defmt::trace!("TX complete, address sent at: {}", timestamp);
+        dropper.defuse();
 
         self.radio.shorts.reset();
 
-        Timestamp(timestamp)
+        sent
     }
 
     /// Sends the specified `packet` without first performing CCA
     ///
-    /// Acknowledgment packets must be sent using this method
+    /// Acknowledgment packets must be sent using this method. Returns [`Error::Cancelled`] if the
+    /// transmitter was disabled before the packet was out.
     ///
     /// NOTE this method will *not* modify the `packet` argument. The mutable reference is used to
     /// ensure the `packet` buffer is allocated in RAM, which is required by the RADIO peripheral
     // NOTE we do NOT check the address of `packet` because the mutable reference ensures it's
     // allocated in RAM
-    pub async fn send_no_cca(&mut self, packet: &mut Packet) -> Timestamp {
+    pub async fn send_no_cca(&mut self, packet: &mut Packet) -> Result<Timestamp, Error> {
         self.put_in_tx_mode();
 
         // clear related events
         self.radio.events_phyend.reset();
         self.radio.events_end.reset();
+        self.radio.events_disabled.reset();
 
         // NOTE(unsafe) DMA transfer has not yet started
         unsafe {
@@ -539,24 +1016,197 @@ impl Radio {
         dma_start_fence();
         self.radio.tasks_start.write(|w| w.tasks_start().set_bit());
 
-        core::future::poll_fn(|cx| {
+        let dropper = OnDrop::new(|| Self::cancel());
+        let sent = self.wait_for_sent().await;
+        dropper.defuse();
+
+        self.radio.shorts.reset();
+        sent?;
+
+        Ok(Timestamp(RadioTimestamps::address_timestamp()))
+    }
+
+    /// Waits until the packet being sent is out, or returns [`Error::Cancelled`] if the
+    /// transmitter was disabled first
+    async fn wait_for_sent(&mut self) -> Result<(), Error> {
+        let sent = core::future::poll_fn(|cx| {
             WAKER.register(cx.waker());
 
+            // The END to DISABLE short disables the transmitter right after PHYEND.
             if self.event_happened_and_reset(Event::PhyEnd) {
                 self.disable_interrupt(Event::PhyEnd);
-                Poll::Ready(())
+                self.disable_interrupt(Event::Disabled);
+                Poll::Ready(true)
+            } else if self.event_happened_and_reset(Event::Disabled) {
+                self.disable_interrupt(Event::PhyEnd);
+                self.disable_interrupt(Event::Disabled);
+                Poll::Ready(false)
             } else {
                 self.enable_interrupt(Event::PhyEnd);
+                self.enable_interrupt(Event::Disabled);
                 Poll::Pending
             }
         })
         .await;
 
+        if !sent {
+            return Err(Error::Cancelled);
+        }
+
+        Ok(())
+    }
+
+    /// Sends the given `packet` at exactly `at`, without CCA
+    ///
+    /// A TIMER0 compare starts the ramp-up [`TX_RAMP_UP`] ahead through PPI, so the preamble
+    /// starts at `at` no matter how late the executor gets to run. Returns [`Error::TooLate`]
+    /// without sending if there is not enough time left to ramp up, and [`Error::Cancelled`] if
+    /// the transmitter was disabled before the packet was out.
+    ///
+    /// NOTE this method will *not* modify the `packet` argument. The mutable reference is used to
+    /// ensure the `packet` buffer is allocated in RAM, which is required by the RADIO peripheral
+    // NOTE we do NOT check the address of `packet` because the mutable reference ensures it's
+    // allocated in RAM
+    pub async fn send_at(
+        &mut self,
+        packet: &mut Packet,
+        at: TimerInstantU64<1_000_000>,
+    ) -> Result<Timestamp, Error> {
+        // The ramp-up only takes a known time from DISABLED, it also picks up new settings.
+        self.disable();
+        self.needs_enable = false;
+
+        self.radio
+            .txaddress
+            .write(|w| unsafe { w.txaddress().bits(self.tx_pipe) });
+
+        // clear related events
+        self.radio.events_phyend.reset();
+        self.radio.events_end.reset();
+        self.radio.events_ready.reset();
+        self.radio.events_disabled.reset();
+
+        // NOTE(unsafe) DMA transfer has not yet started
+        unsafe {
+            self.radio
+                .packetptr
+                .write(|w| w.packetptr().bits(packet.buffer.as_ptr() as u32));
+        }
+
+        // start sending as soon as the ramp-up is done, and disable the transmitter once the
+        // packet is sent
+        self.radio
+            .shorts
+            .modify(|_, w| w.txready_start().set_bit().end_disable().set_bit());
+
+        // the DMA transfer will start when the trigger fires so we place the compiler fence here
+        dma_start_fence();
+        if !self.trigger.arm_tx(at - TX_RAMP_UP) {
+            self.radio.shorts.reset();
+            return Err(Error::TooLate);
+        }
+
+        let dropper = OnDrop::new(|| Self::cancel());
+        let sent = self.wait_for_sent().await;
+        dropper.defuse();
+
+        RadioTrigger::disarm();
+        if let Err(e) = sent {
+            self.radio.shorts.reset();
+            return Err(e);
+        }
+
         let timestamp = RadioTimestamps::address_timestamp();
 
+        defmt::trace!(
+            "Scheduled TX at {} complete, address sent at: {}",
+            at,
+            timestamp
+        );
+
         self.radio.shorts.reset();
 
-        Timestamp(timestamp)
+        Ok(Timestamp(timestamp))
+    }
+
+    /// Measures the energy on `frequency` (2400 MHz + `0..=100`) for `duration`
+    ///
+    /// The measurement runs in the IEEE 802.15.4 PHY, in [`ED_SAMPLE_PERIOD`] samples, and the
+    /// previous mode is restored afterwards, also if the measurement is cancelled. The radio is
+    /// left on `frequency`.
+    pub async fn energy_detect(
+        &mut self,
+        frequency: u8,
+        duration: TimerDurationU64<1_000_000>,
+    ) -> EnergyLevel {
+        // Dropped after the cancelling guard below, so the mode is restored on a stopped radio.
+        let mut this = RestoreMode::new(self);
+        if this.mode != Mode::Ieee802154_250Kbit {
+            this.set_mode(Mode::Ieee802154_250Kbit);
+        }
+        this.set_frequency(frequency);
+
+        // ED starts from RXIDLE
+        this.put_in_rx_mode();
+        this.radio.edcnt.write(|w| unsafe { w.edcnt().bits(0) });
+
+        let samples = (duration.ticks() / ED_SAMPLE_PERIOD.ticks()).max(1);
+        let mut peak = i16::MIN;
+        let mut sum = 0i32;
+
+        let dropper = OnDrop::new(|| Self::cancel());
+
+        for _ in 0..samples {
+            this.radio.events_edend.reset();
+            this.radio
+                .tasks_edstart
+                .write(|w| w.tasks_edstart().set_bit());
+
+            core::future::poll_fn(|cx| {
+                WAKER.register(cx.waker());
+
+                if this.event_happened_and_reset(Event::EdEnd) {
+                    this.disable_interrupt(Event::EdEnd);
+                    Poll::Ready(())
+                } else {
+                    this.enable_interrupt(Event::EdEnd);
+                    Poll::Pending
+                }
+            })
+            .await;
+
+            let sample = this.radio.edsample.read().edlvl().bits() as i16;
+            let dbm = ED_RSSIOFFS + ED_RSSISCALE * sample;
+            peak = peak.max(dbm);
+            sum += dbm as i32;
+        }
+
+        dropper.defuse();
+
+        EnergyLevel {
+            peak: peak.clamp(i8::MIN as i16, i8::MAX as i16) as i8,
+            average: (sum / samples as i32).clamp(i8::MIN as i32, i8::MAX as i32) as i8,
+        }
+    }
+
+    /// Measures the energy on every frequency, for `dwell` each
+    ///
+    /// Takes a little over [`NUM_FREQUENCIES`] times `dwell`, the radio is left on the last
+    /// frequency.
+    pub async fn survey(
+        &mut self,
+        dwell: TimerDurationU64<1_000_000>,
+    ) -> [EnergyLevel; NUM_FREQUENCIES] {
+        let mut levels = [EnergyLevel {
+            peak: i8::MIN,
+            average: i8::MIN,
+        }; NUM_FREQUENCIES];
+
+        for (frequency, level) in levels.iter_mut().enumerate() {
+            *level = self.energy_detect(frequency as u8, dwell).await;
+        }
+
+        levels
     }
 
     /// Moves the radio from any state to the DISABLED state
@@ -608,7 +1258,9 @@ impl Radio {
             State::TxIdle => (true, true),
         };
 
-        self.radio.rxaddresses.write(|w| unsafe { w.bits(0xff) });
+        self.radio
+            .rxaddresses
+            .write(|w| unsafe { w.bits(self.rx_pipes as u32) });
         self.radio.shorts.modify(|_, w| {
             w.address_rssistart()
                 .enabled()
@@ -636,15 +1288,18 @@ impl Radio {
 
         self.radio
             .txaddress
-            .write(|w| unsafe { w.txaddress().bits(0) });
+            .write(|w| unsafe { w.txaddress().bits(self.tx_pipe) });
 
-        if state != State::Disabled || self.needs_enable {
+        // A disabled transmitter would ignore the START, e.g. after a send's END to DISABLE.
+        if state != State::TxIdle || self.needs_enable {
             self.needs_enable = false;
 
-            self.radio
-                .tasks_disable
-                .write(|w| w.tasks_disable().set_bit());
-            self.wait_for_state_a(STATE_A::DISABLED);
+            if state != State::Disabled {
+                self.radio
+                    .tasks_disable
+                    .write(|w| w.tasks_disable().set_bit());
+                self.wait_for_state_a(STATE_A::DISABLED);
+            }
 
             self.radio.tasks_txen.write(|w| w.tasks_txen().set_bit());
             self.wait_for_state_a(STATE_A::TX_IDLE);
@@ -680,6 +1335,12 @@ impl Radio {
             Event::CcaBusy => {
                 self.radio.intenset.write(|w| w.ccabusy().set_bit());
             }
+            Event::Disabled => {
+                self.radio.intenset.write(|w| w.disabled().set_bit());
+            }
+            Event::EdEnd => {
+                self.radio.intenset.write(|w| w.edend().set_bit());
+            }
         }
     }
 
@@ -693,7 +1354,13 @@ impl Radio {
                 self.radio.intenclr.write(|w| w.phyend().set_bit());
             }
             Event::CcaBusy => {
-                self.radio.intenclr.write(|w| w.phyend().set_bit());
+                self.radio.intenclr.write(|w| w.ccabusy().set_bit());
+            }
+            Event::Disabled => {
+                self.radio.intenclr.write(|w| w.disabled().set_bit());
+            }
+            Event::EdEnd => {
+                self.radio.intenclr.write(|w| w.edend().set_bit());
             }
         }
     }
@@ -725,6 +1392,24 @@ impl Radio {
                     return true;
                 }
             }
+            Event::Disabled => {
+                if self
+                    .radio
+                    .events_disabled
+                    .read()
+                    .events_disabled()
+                    .bit_is_set()
+                {
+                    self.radio.events_disabled.reset();
+                    return true;
+                }
+            }
+            Event::EdEnd => {
+                if self.radio.events_edend.read().events_edend().bit_is_set() {
+                    self.radio.events_edend.reset();
+                    return true;
+                }
+            }
         }
 
         false
@@ -736,13 +1421,81 @@ impl Radio {
     }
 }
 
-/// Error
-#[derive(Copy, Clone, Debug, PartialEq, defmt::Format)]
-pub enum Error {
-    /// Incorrect CRC
-    Crc(u16),
-    /// Timeout
-    Timeout,
+impl RadioDriver for Radio {
+    fn mode(&self) -> Mode {
+        Radio::mode(self)
+    }
+
+    fn set_frequency(&mut self, frequency: u8) {
+        Radio::set_frequency(self, frequency)
+    }
+
+    fn set_addresses(&mut self, addresses: &Addresses) {
+        Radio::set_addresses(self, addresses)
+    }
+
+    fn set_tx_pipe(&mut self, pipe: u8) {
+        Radio::set_tx_pipe(self, pipe)
+    }
+
+    fn set_rx_pipes(&mut self, pipes: u8) {
+        Radio::set_rx_pipes(self, pipes)
+    }
+
+    fn set_txpower(&mut self, power: TxPower) {
+        Radio::set_txpower(self, power)
+    }
+
+    async fn send_no_cca(&mut self, packet: &mut Packet) -> Result<Timestamp, Error> {
+        Radio::send_no_cca(self, packet).await
+    }
+
+    async fn send_at(
+        &mut self,
+        packet: &mut Packet,
+        at: TimerInstantU64<1_000_000>,
+    ) -> Result<Timestamp, Error> {
+        Radio::send_at(self, packet, at).await
+    }
+
+    async fn recv(&mut self, packet: &mut Packet) -> Result<(Timestamp, Rssi, Pipe), Error> {
+        Radio::recv(self, packet).await
+    }
+
+    async fn recv_window(
+        &mut self,
+        packet: &mut Packet,
+        open_at: TimerInstantU64<1_000_000>,
+        close_at: TimerInstantU64<1_000_000>,
+    ) -> Result<(Timestamp, Rssi, Pipe), Error> {
+        Radio::recv_window(self, packet, open_at, close_at).await
+    }
+
+    async fn recv_and_respond(
+        &mut self,
+        packet: &mut Packet,
+        response: &mut Packet,
+        open_at: TimerInstantU64<1_000_000>,
+        close_at: TimerInstantU64<1_000_000>,
+    ) -> Result<(), Error> {
+        Radio::recv_and_respond(self, packet, response, open_at, close_at).await
+    }
+
+    async fn send_at_and_recv(
+        &mut self,
+        packet: &mut Packet,
+        at: TimerInstantU64<1_000_000>,
+        window: TimerDurationU64<1_000_000>,
+    ) -> Result<(Timestamp, Rssi, Pipe), Error> {
+        Radio::send_at_and_recv(self, packet, at, window).await
+    }
+
+    async fn survey(
+        &mut self,
+        dwell: TimerDurationU64<1_000_000>,
+    ) -> [EnergyLevel; NUM_FREQUENCIES] {
+        Radio::survey(self, dwell).await
+    }
 }
 
 /// Driver state
@@ -770,83 +1523,6 @@ enum Event {
     End,
     PhyEnd,
     CcaBusy,
-}
-
-/// An IEEE 802.15.4 packet
-///
-/// This `Packet` is a PHY layer packet. It's made up of the physical header (PHR) and the PSDU
-/// (PHY service data unit). The PSDU of this `Packet` will always include the MAC level CRC, AKA
-/// the FCS (Frame Control Sequence) -- the CRC is fully computed in hardware and automatically
-/// appended on transmission and verified on reception.
-///
-/// The API lets users modify the usable part (not the CRC) of the PSDU via the `deref` and
-/// `copy_from_slice` methods. These methods will automatically update the PHR.
-///
-/// See figure 119 in the Product Specification of the nRF52840 for more details
-pub struct Packet {
-    buffer: [u8; Self::SIZE],
-}
-
-// See figure 124 in nRF52840-PS
-impl Packet {
-    // for indexing purposes
-    const PHY_HDR: usize = 0;
-    const DATA: RangeFrom<usize> = 1..;
-
-    /// Maximum amount of usable payload (CRC excluded) a single packet can contain, in bytes
-    pub const CAPACITY: u8 = 125;
-    const CRC: u8 = 2; // size of the CRC, which is *never* copied to / from RAM
-    const MAX_PSDU_LEN: u8 = Self::CAPACITY + Self::CRC;
-    const SIZE: usize = 1 /* PHR */ + Self::MAX_PSDU_LEN as usize;
-
-    /// Returns an empty packet (length = 0)
-    pub fn new() -> Self {
-        let mut packet = Self {
-            buffer: [0; Self::SIZE],
-        };
-        packet.set_len(0);
-        packet
-    }
-
-    /// Fills the packet payload with given `src` data
-    ///
-    /// # Panics
-    ///
-    /// This function panics if `src` is larger than `Self::CAPACITY`
-    pub fn copy_from_slice(&mut self, src: &[u8]) {
-        assert!(src.len() <= Self::CAPACITY as usize);
-        let len = src.len() as u8;
-        self.buffer[Self::DATA][..len as usize].copy_from_slice(&src[..len.into()]);
-        self.set_len(len);
-    }
-
-    /// Returns the size of this packet's payload
-    pub fn len(&self) -> u8 {
-        self.buffer[Self::PHY_HDR] - Self::CRC
-    }
-
-    /// Changes the size of the packet's payload
-    ///
-    /// # Panics
-    ///
-    /// This function panics if `len` is larger than `Self::CAPACITY`
-    pub fn set_len(&mut self, len: u8) {
-        assert!(len <= Self::CAPACITY);
-        self.buffer[Self::PHY_HDR] = len + Self::CRC;
-    }
-}
-
-impl ops::Deref for Packet {
-    type Target = [u8];
-
-    fn deref(&self) -> &[u8] {
-        &self.buffer[Self::DATA][..self.len() as usize]
-    }
-}
-
-impl ops::DerefMut for Packet {
-    fn deref_mut(&mut self) -> &mut [u8] {
-        let len = self.len();
-        &mut self.buffer[Self::DATA][..len as usize]
-    }
+    Disabled,
+    EdEnd,
 }
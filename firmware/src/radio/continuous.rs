@@ -0,0 +1,169 @@
+//! # Continuous RX
+//!
+//! [`Radio::recv_continuous`] keeps the receiver running across packets: the END to START short
+//! starts the next reception as soon as a packet ends, into the other of two DMA buffers. The
+//! RADIO interrupt swaps the buffers at every END, and queues the packet that ended in an
+//! [`RxRing`] together with its timestamp, RSSI, CRC status and frequency. [`ContinuousRx::next`]
+//! drains the ring from a task.
+//!
+//! The interrupt has to run before the address of the next packet is received, at least the
+//! preamble and address of a packet after the END, or that packet's timestamp and RSSI are the
+//! next one's.
+
+use super::{
+    dma_end_fence, dma_start_fence, Event, Packet, Pipe, Radio, Rssi, RxRing, Timestamp, WAKER,
+};
+use crate::bsp::RadioTimestamps;
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::Poll,
+};
+use embassy_nrf::pac;
+
+/// Number of received packets the ring holds until the task drains it.
+pub const RING_SIZE: usize = 8;
+
+/// A packet received by [`Radio::recv_continuous`].
+pub struct RxRecord {
+    /// When the packet's address was received.
+    pub timestamp: Timestamp,
+    pub rssi: Rssi,
+    /// The pipe whose address matched.
+    pub pipe: Pipe,
+    /// Whether the packet's CRC was correct, a corrupt packet is queued as well.
+    pub crc_ok: bool,
+    /// The frequency the packet was received on, 2400 MHz + `0..=100`.
+    pub frequency: u8,
+    /// The packet, emptied if its length field does not even cover the CRC.
+    pub packet: Packet,
+}
+
+/// The DMA buffers the receiver alternates between.
+struct Buffers(UnsafeCell<[[u8; Packet::SIZE]; 2]>);
+
+// NOTE(unsafe) a buffer is only accessed by the DMA, or by the RADIO interrupt once its packet
+// has ended
+unsafe impl Sync for Buffers {}
+
+static BUFFERS: Buffers = Buffers(UnsafeCell::new([[0; Packet::SIZE]; 2]));
+
+/// Which buffer the packet being received goes into.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether the RADIO interrupt is handing packets to [`RING`].
+pub(super) static STREAMING: AtomicBool = AtomicBool::new(false);
+
+static RING: RxRing<RxRecord, RING_SIZE> = RxRing::new();
+
+/// Runs in the RADIO interrupt while streaming: queues the packet that ended and gives its
+/// buffer back to the DMA.
+pub(super) fn on_end(radio: &pac::radio::RegisterBlock) {
+    if !radio.events_end.read().events_end().bit_is_set() {
+        return;
+    }
+    radio.events_end.reset();
+    dma_end_fence();
+
+    // The END to START short has already moved the DMA on to the other buffer.
+    let done = ACTIVE.load(Ordering::Relaxed);
+    ACTIVE.store(1 - done, Ordering::Relaxed);
+
+    // NOTE(unsafe) the DMA is done with this buffer until the next START
+    let buffer = unsafe { &mut (*BUFFERS.0.get())[done] };
+    let mut packet = Packet { buffer: *buffer };
+    if !packet.has_valid_len() {
+        packet.set_len(0);
+    }
+
+    RING.push(RxRecord {
+        timestamp: Timestamp(RadioTimestamps::address_timestamp()),
+        rssi: Rssi(-(radio.rssisample.read().rssisample().bits() as i8)),
+        pipe: Pipe(radio.rxmatch.read().rxmatch().bits()),
+        crc_ok: radio.crcstatus.read().crcstatus().bit_is_set(),
+        frequency: radio.frequency.read().frequency().bits(),
+        packet,
+    });
+
+    // The packet after the one that has just started goes into this buffer again.
+    dma_start_fence();
+    radio
+        .packetptr
+        .write(|w| unsafe { w.packetptr().bits(buffer.as_mut_ptr() as u32) });
+}
+
+/// The receiver running with [`Radio::recv_continuous`], stopped when dropped.
+pub struct ContinuousRx<'a> {
+    // Nothing else may use the radio while it streams.
+    _radio: &'a mut Radio,
+}
+
+impl ContinuousRx<'_> {
+    /// Waits for the next received packet.
+    pub async fn next(&mut self) -> RxRecord {
+        core::future::poll_fn(|cx| {
+            WAKER.register(cx.waker());
+
+            match RING.pop() {
+                Some(record) => Poll::Ready(record),
+                None => Poll::Pending,
+            }
+        })
+        .await
+    }
+
+    /// The number of packets lost because the ring was full, since the last call.
+    pub fn take_dropped(&mut self) -> u32 {
+        RING.take_dropped()
+    }
+}
+
+impl Drop for ContinuousRx<'_> {
+    fn drop(&mut self) {
+        STREAMING.store(false, Ordering::Relaxed);
+        Radio::cancel();
+    }
+}
+
+impl Radio {
+    /// Starts receiving continuously on the current frequency, see the [module
+    /// documentation](self)
+    ///
+    /// The receiver runs, and packets are queued, until the returned [`ContinuousRx`] is dropped.
+    pub fn recv_continuous(&mut self) -> ContinuousRx<'_> {
+        self.disable();
+        RING.clear();
+        RING.take_dropped();
+
+        // clear related events
+        self.radio.events_phyend.reset();
+        self.radio.events_end.reset();
+        self.radio.events_ready.reset();
+        self.radio.events_address.reset();
+
+        self.radio.shorts.reset();
+        self.put_in_rx_mode();
+        self.radio.shorts.modify(|_, w| w.end_start().enabled());
+
+        // NOTE(unsafe) the radio is idle, the DMA is not using the buffers
+        let buffers = unsafe { &mut *BUFFERS.0.get() };
+        ACTIVE.store(0, Ordering::Relaxed);
+        STREAMING.store(true, Ordering::Relaxed);
+        self.enable_interrupt(Event::End);
+
+        dma_start_fence();
+        // NOTE(unsafe) PACKETPTR is double-buffered, the second buffer is used from the next
+        // START on
+        unsafe {
+            self.radio
+                .packetptr
+                .write(|w| w.packetptr().bits(buffers[0].as_mut_ptr() as u32));
+            self.radio.tasks_start.write(|w| w.tasks_start().set_bit());
+            self.radio
+                .packetptr
+                .write(|w| w.packetptr().bits(buffers[1].as_mut_ptr() as u32));
+        }
+
+        ContinuousRx { _radio: self }
+    }
+}
@@ -0,0 +1,234 @@
+//! # The radio's operations
+//!
+//! What the radio protocol needs from a radio, implemented by [`super::Radio`]. Kept apart from
+//! the driver together with the types of the operations, so a simulated radio on the host can
+//! implement them too.
+
+use super::{Mode, Packet};
+use fugit::{TimerDurationU64, TimerInstantU64};
+
+/// Timestamp for when the `address` portion of the packet was sent or received.
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp(pub TimerInstantU64<1_000_000>);
+
+/// RSSI value in dBm.
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Rssi(pub i8);
+
+/// Logical address (0..=7) a packet was sent or received on.
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pipe(pub u8);
+
+/// The on-air addresses of the eight pipes
+///
+/// Pipe 0 is `base0` followed by `prefixes[0]`, pipes 1 to 7 are `base1` followed by their
+/// prefix.
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq, Eq)]
+pub struct Addresses {
+    pub base0: [u8; 4],
+    pub base1: [u8; 4],
+    pub prefixes: [u8; 8],
+}
+
+/// Energy measured on a frequency, in dBm.
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq, Eq)]
+pub struct EnergyLevel {
+    /// The highest sample.
+    pub peak: i8,
+    /// The mean of the samples.
+    pub average: i8,
+}
+
+/// Default addresses = the ones of Nordic's Enhanced ShockBurst examples
+pub const DEFAULT_ADDRESSES: Addresses = Addresses {
+    base0: [0xE7, 0xE7, 0xE7, 0xE7],
+    base1: [0xC2, 0xC2, 0xC2, 0xC2],
+    prefixes: [0xE7, 0xC2, 0xC3, 0xC4, 0xC5, 0xC6, 0xC7, 0xC8],
+};
+
+/// Number of frequencies [`RadioDriver::survey`] measures, 2400 MHz + `0..=100`
+pub const NUM_FREQUENCIES: usize = 101;
+
+/// Time from TXEN to READY with fast ramp-up, see tTXEN,FAST in the nRF52833-PS
+pub const TX_RAMP_UP: TimerDurationU64<1_000_000> = TimerDurationU64::micros(40);
+
+/// Time from RXEN to READY with fast ramp-up, see tRXEN,FAST in the nRF52833-PS
+pub const RX_RAMP_UP: TimerDurationU64<1_000_000> = TimerDurationU64::micros(40);
+
+/// Time from the end of a packet to the start of the automatic response (TIFS), see
+/// [`RadioDriver::recv_and_respond`]
+///
+/// Only a little longer than the ramp-up, the interrupt points the radio at the response before
+/// it is ready.
+pub const TURNAROUND: TimerDurationU64<1_000_000> = TimerDurationU64::micros(50);
+
+/// Time on air of the preamble and address in [`Mode::Nrf2Mbit`], a packet's [`Timestamp`] is
+/// this long after it started
+pub const ADDRESS_AIRTIME: TimerDurationU64<1_000_000> = TimerDurationU64::micros((1 + 5) * 4);
+
+/// Time on air of a packet with `len` bytes of payload: preamble, address, length, payload and
+/// CRC in [`Mode::Nrf2Mbit`]
+pub const fn airtime(len: usize) -> TimerDurationU64<1_000_000> {
+    TimerDurationU64::micros((1 + 5 + 1 + len as u64 + 2) * 4)
+}
+
+/// Transmission power in dBm (decibel milliwatt)
+// TXPOWERA enum minus the deprecated Neg30dBm variant and with better docs
+#[derive(Clone, Copy, PartialEq)]
+pub enum TxPower {
+    /// +8 dBm
+    Pos8dBm,
+    /// +7 dBm
+    Pos7dBm,
+    /// +6 dBm (~4 mW)
+    Pos6dBm,
+    /// +5 dBm
+    Pos5dBm,
+    /// +4 dBm
+    Pos4dBm,
+    /// +3 dBm (~2 mW)
+    Pos3dBm,
+    /// +2 dBm
+    Pos2dBm,
+    /// 0 dBm (1 mW)
+    _0dBm,
+    /// -4 dBm
+    Neg4dBm,
+    /// -8 dBm
+    Neg8dBm,
+    /// -12 dBm
+    Neg12dBm,
+    /// -16 dBm
+    Neg16dBm,
+    /// -20 dBm (10 μW)
+    Neg20dBm,
+    /// -40 dBm (0.1 μW)
+    Neg40dBm,
+}
+
+impl TxPower {
+    /// The setting for a power in dBm, if there is one.
+    pub fn from_dbm(dbm: i8) -> Option<Self> {
+        Some(match dbm {
+            8 => TxPower::Pos8dBm,
+            7 => TxPower::Pos7dBm,
+            6 => TxPower::Pos6dBm,
+            5 => TxPower::Pos5dBm,
+            4 => TxPower::Pos4dBm,
+            3 => TxPower::Pos3dBm,
+            2 => TxPower::Pos2dBm,
+            0 => TxPower::_0dBm,
+            -4 => TxPower::Neg4dBm,
+            -8 => TxPower::Neg8dBm,
+            -12 => TxPower::Neg12dBm,
+            -16 => TxPower::Neg16dBm,
+            -20 => TxPower::Neg20dBm,
+            -40 => TxPower::Neg40dBm,
+            _ => return None,
+        })
+    }
+}
+
+/// Error
+#[derive(Copy, Clone, Debug, PartialEq, defmt::Format)]
+pub enum Error {
+    /// Incorrect CRC
+    Crc(u16),
+    /// Timeout
+    Timeout,
+    /// The time to send at had already passed
+    TooLate,
+    /// The radio was disabled before the operation completed
+    Cancelled,
+    /// The received packet's length field is too short for the CRC
+    InvalidLength,
+    /// The channel stayed busy through all CSMA-CA backoffs
+    ChannelAccessFailure,
+}
+
+/// The radio operations the radio protocol runs on
+///
+/// Times are instants of the [`crate::clock::Clock`] the radio protocol runs with, and the
+/// timestamps are taken when a packet's address is sent or received. The radio protocol runs on
+/// a single-threaded executor, so the futures do not need to be `Send`.
+///
+/// [`airtime`] and [`ADDRESS_AIRTIME`] are those of [`Mode::Nrf2Mbit`], the mode the links' slots
+/// are laid out for.
+#[allow(async_fn_in_trait)]
+pub trait RadioDriver {
+    /// The PHY mode the radio sends and receives in.
+    fn mode(&self) -> Mode;
+
+    /// Changes the radio frequency in 2400 MHz + `frequency` where `frequency = 0..=100`.
+    ///
+    /// # Panics
+    ///
+    /// If `frequency` is out of range.
+    fn set_frequency(&mut self, frequency: u8);
+
+    /// Changes the addresses of all pipes.
+    fn set_addresses(&mut self, addresses: &Addresses);
+
+    /// Changes the pipe (0..=7) packets are sent on.
+    ///
+    /// # Panics
+    ///
+    /// If `pipe` is not a pipe.
+    fn set_tx_pipe(&mut self, pipe: u8);
+
+    /// Changes the pipes packets are received on, bit `n` of `pipes` enables pipe `n`.
+    fn set_rx_pipes(&mut self, pipes: u8);
+
+    /// Changes the TX power.
+    fn set_txpower(&mut self, power: TxPower);
+
+    /// Sends `packet` right away, without CCA, or returns [`Error::Cancelled`] if the
+    /// transmitter was disabled before the packet was out.
+    async fn send_no_cca(&mut self, packet: &mut Packet) -> Result<Timestamp, Error>;
+
+    /// Sends `packet` at exactly `at`, without CCA, or returns [`Error::TooLate`] without sending
+    /// if there is not enough time left to ramp up.
+    async fn send_at(
+        &mut self,
+        packet: &mut Packet,
+        at: TimerInstantU64<1_000_000>,
+    ) -> Result<Timestamp, Error>;
+
+    /// Receives one packet on the enabled pipes into `packet`, [`Error::Crc`] if it is corrupt.
+    async fn recv(&mut self, packet: &mut Packet) -> Result<(Timestamp, Rssi, Pipe), Error>;
+
+    /// Receives one packet whose address arrives between `open_at` and `close_at`, or returns
+    /// [`Error::Timeout`] at `close_at`.
+    async fn recv_window(
+        &mut self,
+        packet: &mut Packet,
+        open_at: TimerInstantU64<1_000_000>,
+        close_at: TimerInstantU64<1_000_000>,
+    ) -> Result<(Timestamp, Rssi, Pipe), Error>;
+
+    /// Receives one packet like [`RadioDriver::recv_window`], and answers it with `response`
+    /// [`TURNAROUND`] after its end if its CRC is correct.
+    async fn recv_and_respond(
+        &mut self,
+        packet: &mut Packet,
+        response: &mut Packet,
+        open_at: TimerInstantU64<1_000_000>,
+        close_at: TimerInstantU64<1_000_000>,
+    ) -> Result<(), Error>;
+
+    /// Sends `packet` at exactly `at` like [`RadioDriver::send_at`], then receives the response
+    /// into `packet`, or returns [`Error::Timeout`] if none has started `window` after the end of
+    /// the packet.
+    async fn send_at_and_recv(
+        &mut self,
+        packet: &mut Packet,
+        at: TimerInstantU64<1_000_000>,
+        window: TimerDurationU64<1_000_000>,
+    ) -> Result<(Timestamp, Rssi, Pipe), Error>;
+
+    /// Measures the energy on every frequency, for `dwell` each.
+    async fn survey(
+        &mut self,
+        dwell: TimerDurationU64<1_000_000>,
+    ) -> [EnergyLevel; NUM_FREQUENCIES];
+}
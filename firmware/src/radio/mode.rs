@@ -0,0 +1,237 @@
+//! # PHY modes
+//!
+//! The register values of every [`Mode`], kept apart from the driver so they can be checked on
+//! the host.
+
+use super::Packet;
+
+/// PHY mode
+///
+/// The packets keep the same format in every mode: an 8-bit length that includes a 16-bit CRC,
+/// which is what [`Packet`] expects. The BLE modes use the BLE PHY with its preamble, access
+/// address size and whitening, but do not send BLE PDUs.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Mode {
+    /// Nordic proprietary 1 Mbit/s
+    Nrf1Mbit,
+    /// Nordic proprietary 2 Mbit/s
+    Nrf2Mbit,
+    /// BLE 1 Mbit/s
+    Ble1Mbit,
+    /// BLE 2 Mbit/s
+    Ble2Mbit,
+    /// BLE Long Range 125 kbit/s, coded with S=8
+    BleLr125Kbit,
+    /// BLE Long Range 500 kbit/s, coded with S=2
+    BleLr500Kbit,
+    /// IEEE 802.15.4 250 kbit/s
+    Ieee802154_250Kbit,
+}
+
+// Register fields of the mode configuration, see the RADIO registers in the nRF52833-PS
+const PCNF0_LFLEN_POS: u32 = 0;
+const PCNF0_CILEN_POS: u32 = 22;
+const PCNF0_PLEN_POS: u32 = 24;
+const PCNF0_CRCINC: u32 = 1 << 26;
+const PCNF0_TERMLEN_POS: u32 = 29;
+const PLEN_8BIT: u32 = 0;
+const PLEN_16BIT: u32 = 1;
+const PLEN_32BIT_ZERO: u32 = 2;
+const PLEN_LONG_RANGE: u32 = 3;
+const PCNF1_MAXLEN_POS: u32 = 0;
+const PCNF1_BALEN_POS: u32 = 16;
+const PCNF1_WHITEEN: u32 = 1 << 25;
+const CRCCNF_LEN_TWO: u32 = 2;
+const CRCCNF_SKIPADDR_IEEE802154: u32 = 2 << 8;
+
+/// CRC-16-CCITT, x**16 + x**12 + x**5 + 1, as required by the IEEE spec
+const CRC16_POLY: u32 = 0x11021;
+
+/// Whitening seed of the BLE modes, bit 6 is always set
+const WHITENING_IV: u32 = 0x40 | 37;
+
+/// The register values of a [`Mode`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ModeConfig {
+    pub mode: u32,
+    pub pcnf0: u32,
+    pub pcnf1: u32,
+    pub crccnf: u32,
+    pub crcpoly: u32,
+    pub crcinit: u32,
+    pub datawhiteiv: u32,
+}
+
+impl Mode {
+    /// The register values of the mode
+    pub fn config(self) -> ModeConfig {
+        let nordic = ModeConfig {
+            mode: 0,
+            // LENGTH is 7 bits, the highest bit of the byte is reserved and must be `0`, and
+            // also accounts for the CRC. No S0, S1 or code indicator.
+            pcnf0: 7 << PCNF0_LFLEN_POS | PLEN_8BIT << PCNF0_PLEN_POS | PCNF0_CRCINC,
+            // 4 byte base address and a prefix, little endian, no static length
+            pcnf1: (Packet::MAX_PSDU_LEN as u32) << PCNF1_MAXLEN_POS | 4 << PCNF1_BALEN_POS,
+            crccnf: CRCCNF_LEN_TWO,
+            crcpoly: CRC16_POLY,
+            crcinit: 0,
+            datawhiteiv: 0x40,
+        };
+
+        // BLE uses a 4 byte access address and whitening.
+        let ble = ModeConfig {
+            pcnf0: 8 << PCNF0_LFLEN_POS | PLEN_8BIT << PCNF0_PLEN_POS | PCNF0_CRCINC,
+            pcnf1: (Packet::MAX_PSDU_LEN as u32) << PCNF1_MAXLEN_POS
+                | 3 << PCNF1_BALEN_POS
+                | PCNF1_WHITEEN,
+            datawhiteiv: WHITENING_IV,
+            ..nordic
+        };
+
+        // The coded PHY adds a 2 bit coding indicator and a 3 bit terminator to the long
+        // preamble, the receiver follows the coding the indicator announces.
+        let long_range = ModeConfig {
+            pcnf0: 8 << PCNF0_LFLEN_POS
+                | 2 << PCNF0_CILEN_POS
+                | PLEN_LONG_RANGE << PCNF0_PLEN_POS
+                | 3 << PCNF0_TERMLEN_POS
+                | PCNF0_CRCINC,
+            ..ble
+        };
+
+        match self {
+            Mode::Nrf1Mbit => nordic,
+            Mode::Nrf2Mbit => ModeConfig { mode: 1, ..nordic },
+            Mode::Ble1Mbit => ModeConfig { mode: 3, ..ble },
+            Mode::Ble2Mbit => ModeConfig {
+                mode: 4,
+                pcnf0: 8 << PCNF0_LFLEN_POS | PLEN_16BIT << PCNF0_PLEN_POS | PCNF0_CRCINC,
+                ..ble
+            },
+            Mode::BleLr125Kbit => ModeConfig {
+                mode: 5,
+                ..long_range
+            },
+            Mode::BleLr500Kbit => ModeConfig {
+                mode: 6,
+                ..long_range
+            },
+            // The PHR is the length, the SFD takes the place of the address and the CRC does
+            // not cover it.
+            Mode::Ieee802154_250Kbit => ModeConfig {
+                mode: 15,
+                pcnf0: 8 << PCNF0_LFLEN_POS | PLEN_32BIT_ZERO << PCNF0_PLEN_POS | PCNF0_CRCINC,
+                pcnf1: (Packet::MAX_PSDU_LEN as u32) << PCNF1_MAXLEN_POS,
+                crccnf: CRCCNF_LEN_TWO | CRCCNF_SKIPADDR_IEEE802154,
+                ..nordic
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nordic_modes() {
+        let nrf_1mbit = ModeConfig {
+            mode: 0,
+            pcnf0: 0x0400_0007,
+            pcnf1: 0x0004_007f,
+            crccnf: 0x0000_0002,
+            crcpoly: 0x0001_1021,
+            crcinit: 0,
+            datawhiteiv: 0x40,
+        };
+
+        assert_eq!(Mode::Nrf1Mbit.config(), nrf_1mbit);
+        assert_eq!(
+            Mode::Nrf2Mbit.config(),
+            ModeConfig {
+                mode: 1,
+                ..nrf_1mbit
+            }
+        );
+    }
+
+    #[test]
+    fn ble_modes() {
+        let ble_1mbit = ModeConfig {
+            mode: 3,
+            pcnf0: 0x0400_0008,
+            pcnf1: 0x0203_007f,
+            crccnf: 0x0000_0002,
+            crcpoly: 0x0001_1021,
+            crcinit: 0,
+            datawhiteiv: 0x65,
+        };
+
+        assert_eq!(Mode::Ble1Mbit.config(), ble_1mbit);
+        assert_eq!(
+            Mode::Ble2Mbit.config(),
+            ModeConfig {
+                mode: 4,
+                pcnf0: 0x0500_0008,
+                ..ble_1mbit
+            }
+        );
+    }
+
+    #[test]
+    fn ble_long_range_modes() {
+        let lr_125kbit = ModeConfig {
+            mode: 5,
+            pcnf0: 0x6780_0008,
+            pcnf1: 0x0203_007f,
+            crccnf: 0x0000_0002,
+            crcpoly: 0x0001_1021,
+            crcinit: 0,
+            datawhiteiv: 0x65,
+        };
+
+        assert_eq!(Mode::BleLr125Kbit.config(), lr_125kbit);
+        assert_eq!(
+            Mode::BleLr500Kbit.config(),
+            ModeConfig {
+                mode: 6,
+                ..lr_125kbit
+            }
+        );
+    }
+
+    #[test]
+    fn ieee802154_mode() {
+        assert_eq!(
+            Mode::Ieee802154_250Kbit.config(),
+            ModeConfig {
+                mode: 15,
+                pcnf0: 0x0600_0008,
+                pcnf1: 0x0000_007f,
+                crccnf: 0x0000_0202,
+                crcpoly: 0x0001_1021,
+                crcinit: 0,
+                datawhiteiv: 0x40,
+            }
+        );
+    }
+
+    #[test]
+    fn every_mode_keeps_packet_format() {
+        // Every mode counts the 16-bit CRC in the length, as `Packet` does.
+        for mode in [
+            Mode::Nrf1Mbit,
+            Mode::Nrf2Mbit,
+            Mode::Ble1Mbit,
+            Mode::Ble2Mbit,
+            Mode::BleLr125Kbit,
+            Mode::BleLr500Kbit,
+            Mode::Ieee802154_250Kbit,
+        ] {
+            let config = mode.config();
+            assert_ne!(config.pcnf0 & PCNF0_CRCINC, 0);
+            assert_eq!(config.crccnf & 0b11, 2);
+            assert_eq!(config.pcnf1 & 0xff, Packet::MAX_PSDU_LEN as u32);
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! # Packet ring
+//!
+//! The queue between the RADIO interrupt and the tasks draining it, free of the radio's registers
+//! so it runs on the host as well.
+
+use core::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+};
+
+/// Lock-free single producer, single consumer ring
+///
+/// The RADIO interrupt pushes, one task pops. `N` must be a power of two.
+pub struct RxRing<T, const N: usize> {
+    slots: [UnsafeCell<MaybeUninit<T>>; N],
+    /// Total number of pushes, only written by the producer.
+    head: AtomicUsize,
+    /// Total number of pops, only written by the consumer.
+    tail: AtomicUsize,
+    /// Number of items dropped because the ring was full.
+    dropped: AtomicU32,
+}
+
+// NOTE(unsafe) a slot is only accessed by the producer before `head` passes it, and by the
+// consumer before `tail` passes it
+unsafe impl<T: Send, const N: usize> Sync for RxRing<T, N> {}
+
+impl<T, const N: usize> RxRing<T, N> {
+    pub const fn new() -> Self {
+        assert!(N.is_power_of_two());
+
+        Self {
+            slots: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicU32::new(0),
+        }
+    }
+
+    /// Producer: queues `item`, or drops it and counts it if the ring is full.
+    pub fn push(&self, item: T) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+
+        if head.wrapping_sub(tail) == N {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        // NOTE(unsafe) the slot is free until `head` is published
+        unsafe { (*self.slots[head % N].get()).write(item) };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Consumer: takes the oldest item.
+    pub fn pop(&self) -> Option<T> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+
+        if head == tail {
+            return None;
+        }
+
+        // NOTE(unsafe) the slot was filled before `head` was published, and is not written again
+        // until `tail` is
+        let item = unsafe { (*self.slots[tail % N].get()).assume_init_read() };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(item)
+    }
+
+    /// Consumer: drops all queued items.
+    pub fn clear(&self) {
+        while self.pop().is_some() {}
+    }
+
+    /// The number of items dropped because the ring was full, since the last call.
+    pub fn take_dropped(&self) -> u32 {
+        self.dropped.swap(0, Ordering::Relaxed)
+    }
+}
+
+impl<T, const N: usize> Default for RxRing<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pops_in_order() {
+        let ring = RxRing::<u32, 4>::new();
+
+        assert_eq!(ring.pop(), None);
+        assert!(ring.push(1));
+        assert!(ring.push(2));
+        assert_eq!(ring.pop(), Some(1));
+        assert_eq!(ring.pop(), Some(2));
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test]
+    fn drops_when_full() {
+        let ring = RxRing::<u32, 4>::new();
+
+        for i in 0..4 {
+            assert!(ring.push(i));
+        }
+        assert!(!ring.push(4));
+        assert!(!ring.push(5));
+        assert_eq!(ring.take_dropped(), 2);
+        assert_eq!(ring.take_dropped(), 0);
+
+        assert_eq!(ring.pop(), Some(0));
+        assert!(ring.push(6));
+        assert_eq!(ring.pop(), Some(1));
+        assert_eq!(ring.pop(), Some(2));
+        assert_eq!(ring.pop(), Some(3));
+        assert_eq!(ring.pop(), Some(6));
+    }
+
+    #[test]
+    fn wraps_around() {
+        let ring = RxRing::<usize, 2>::new();
+
+        for i in 0..10 {
+            assert!(ring.push(i));
+            assert_eq!(ring.pop(), Some(i));
+        }
+
+        ring.push(10);
+        ring.clear();
+        assert_eq!(ring.pop(), None);
+    }
+}
@@ -0,0 +1,98 @@
+//! # Timestamps of the radio's events
+//!
+//! The radio's events are captured by TIMER3, which counts µs like the monotonic but only has 32
+//! bits, so both wrap around every 2^32 µs (about 71 minutes). Extending a capture to an instant
+//! of the monotonic is kept apart from the driver so it can be checked on the host.
+
+use fugit::TimerInstantU64;
+
+/// The instant of an event captured by TIMER3 as `captured`, read at `now`.
+///
+/// TIMER3 was started when the monotonic's low 32 bits were `offset`, so the capture plus the
+/// offset are the event's low 32 bits. The event is in the past, so it is the latest instant
+/// with these low bits that is not after `now`. This is correct as long as the event is less
+/// than 2^32 µs old.
+pub fn extend_capture(
+    now: TimerInstantU64<1_000_000>,
+    captured: u32,
+    offset: u32,
+) -> TimerInstantU64<1_000_000> {
+    let age = (now.ticks() as u32).wrapping_sub(captured.wrapping_add(offset));
+
+    // An event captured before the monotonic has run 2^32 µs would wrap to before the epoch.
+    TimerInstantU64::from_ticks(now.ticks().saturating_sub(age as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WRAP: u64 = 1 << 32;
+    const OFFSET: u32 = 1_234_567;
+
+    fn instant(ticks: u64) -> TimerInstantU64<1_000_000> {
+        TimerInstantU64::from_ticks(ticks)
+    }
+
+    /// What TIMER3 captures for an event at `ticks` of the monotonic.
+    fn capture(ticks: u64) -> u32 {
+        (ticks as u32).wrapping_sub(OFFSET)
+    }
+
+    #[test]
+    fn recent_events() {
+        for event in [0, 1_000, 5 * WRAP + 77] {
+            for age in [0, 1, 300, 1_000_000] {
+                assert_eq!(
+                    extend_capture(instant(event + age), capture(event), OFFSET),
+                    instant(event),
+                    "event {} read {} µs later",
+                    event,
+                    age
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn timer3_wraps_around_the_capture() {
+        // TIMER3 wraps when the monotonic's low 32 bits reach the offset.
+        let wrap = 3 * WRAP + OFFSET as u64;
+        assert_eq!(capture(wrap), 0);
+
+        for event in [wrap - 2, wrap - 1, wrap, wrap + 1] {
+            assert_eq!(
+                extend_capture(instant(event + 40), capture(event), OFFSET),
+                instant(event),
+                "event at {}",
+                event
+            );
+        }
+    }
+
+    #[test]
+    fn wraps_between_capture_and_read() {
+        // Around the wrap of TIMER3 and of the monotonic's low 32 bits.
+        for wrap in [3 * WRAP + OFFSET as u64, 4 * WRAP] {
+            let event = wrap - 10;
+
+            for read in [wrap, wrap + 5, wrap + 1_000_000] {
+                assert_eq!(
+                    extend_capture(instant(read), capture(event), OFFSET),
+                    instant(event),
+                    "read at {}",
+                    read
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn events_before_the_epoch_saturate() {
+        // Only a stale capture from before a reset can be further back than the monotonic.
+        assert_eq!(
+            extend_capture(instant(100), capture(200), OFFSET),
+            instant(0)
+        );
+    }
+}
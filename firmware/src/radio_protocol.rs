@@ -3,302 +3,282 @@
 //! ## Registering keyboard to dongle
 //!
 //! 1. Dongle waits until button held for 3 sec, this will cause it to go into pair mode.
-//!        - When in pair mode a periodic "ready to pair" message will be send until 2 keyboard
-//!          halves have connected.
+//!    - When in pair mode a "ready to pair" message will be sent after every master frame
+//!      until 2 keyboard halves have connected.
 //! 2. A keyboard half is then allowed to try to connect by performing an ECDH key exchange.
-//!        - A presentation packet is sent from the keyboard to the dongle (ID + right/left)
-//!        - Dongle ACKs or rejects due to collision (right/left already paired).
-//!        - Public keys are exchanged.
-//!        - Shared secret is established and ChaCha8Poly1305 is used for symmetric encryption.
-//!        - TODO: Do we need some signature verification?
+//!    - A presentation packet is sent from the keyboard to the dongle (ID + right/left)
+//!    - Dongle ACKs or rejects due to collision (right/left already paired).
+//!    - Public keys are exchanged.
+//!    - The user types the passkey blinked by the dongle on the half, see [`passkey`].
+//!    - Shared secret is established and ChaCha8Poly1305 is used for symmetric encryption.
 //!
 //! ## Reconnecting keyboard to dongle when already paired
 //!
 //! 1. A keyboard half is then allowed to try to connect by performing an ECDH key exchange.
-//!        - A presentation packet is sent from the keyboard to the dongle (ID + right/left)
-//!        - Dongle ACKs or rejects due to wrong id (already paired to different ID).
-//!        - Public keys are exchanged.
-//!        - Shared secret is established and ChaCha8Poly1305 is used for symmetric encryption.
+//!    - A presentation packet is sent from the keyboard to the dongle (ID + right/left)
+//!    - Dongle ACKs or rejects due to wrong id (already paired to different ID).
+//!    - Public keys are exchanged.
+//!    - Shared secret is established and ChaCha8Poly1305 is used for symmetric encryption.
+//!    - Both ends prove knowledge of the stored link key, see [`bonds`] and [`pairing`].
 //!
 //! ## After handshake between keyboard and dongle
 //!
+//! Both ends of this are in [`link`], which runs on any [`RadioDriver`] and [`Clock`].
+//!
 //! 1. The dongle will be sending "sync" frames at the start of rounds, this is when we are at a known channel.
+//!     - The sync carries the dongle's ID, the frame counter and the link's state, see [`sync`].
 //!     - All messages in each frame will be frequency hopping according to a known pattern.
+//!     - Each half's link has its own pattern seeded from the session, see [`hopping`], and its
+//!       own sync in the first of its slots.
+//!     - A keyboard half keeps its schedule across a few missed syncs, see [`drift`].
 //! 2. After sync is received, the keyboard halves will send their state in predetermined slots.
-//!     - Each slot will be 1 ms, where even slots is the right half's and odd slots is the left's.
-//!     - If there has been a state change in the keyboard input, the new full state will be sent.
-//!     - It will be sent, expecting an ACK from the dongle.
+//!     - Each slot is 1 ms, 500 µs or 250 µs, depending on the dongle's [`SlotProfile`] which
+//!       the sync announces. Even slots are the right half's and odd slots the left's.
+//!     - On the 250 µs profile there is no time for an ACK, the half sends its state in every
+//!       slot and gets its commands from the syncs.
+//!     - If there has been a state change in the keyboard input, the new full state will be sent,
+//!       see [`state`].
+//!     - It will be sent, expecting an ACK from the dongle. The dongle's radio sends the ACK by
+//!       itself right after the state, prepared before the slot.
+//!     - The ACK can carry a command from the dongle to the half, e.g. the host's LEDs.
 //!     - If no ACK is received, the state will be retransmitted until an ACK is received, or
 //!       until the keyboard gets a new state.
 //!     - If there is no new data for a full frame, the keyboard will send out its state anyways.
+//!     - All slot payloads and ACKs are sealed with the session key, see [`crypto`].
+//!     - Each link uses its own on-air address derived from the session, so the radio drops the
+//!       traffic of unrelated dongles. Pairing and reconnects use the default address.
+//!     - Channels with consistent loss are dropped from the links' hop sequences, see [`afh`].
+//!       The dongle surveys the band at boot, or when requested, to start out without the busy
+//!       ones.
 //! 3. Keyboards can "disconnect" to save power... somehow...
 
-// use crate::bsp::dongle::DongleLed;
-use crate::bsp::Mono;
-use crate::radio::{Packet, Radio};
-use rtic_monotonics::nrf::timer::fugit::{TimerDurationU64, TimerInstantU64};
-use rtic_monotonics::{nrf::timer::*, Monotonic};
-
-/// A channel hopping selector implementation.
-pub struct ChannelHopping {
-    state: u8,
+use crate::clock::Clock;
+use crate::radio::{RadioDriver, TxPower};
+use bonds::BondStore;
+use core::sync::atomic::{AtomicU8, Ordering};
+use embedded_storage::nor_flash::NorFlash;
+use fugit::{ExtU64, TimerDurationU64, TimerInstantU64};
+use link::{DongleLinks, KeyboardLink};
+use pairing::DonglePairing;
+use rand_chacha::rand_core::{CryptoRng, RngCore};
+use session::Session;
+use state::Command;
+
+pub mod afh;
+pub mod bonds;
+pub mod capture;
+pub mod crypto;
+pub mod drift;
+pub mod hopping;
+pub mod key_schedule;
+pub mod link;
+pub mod pairing;
+pub mod passkey;
+pub mod session;
+#[cfg(feature = "sniffer")]
+pub mod sniffer;
+pub mod state;
+pub mod sync;
+mod types;
+
+pub use link::{BEACON_GAP, LINK_TIMEOUT_FRAMES, SYNC_LOSS_FRAMES, SYNC_TIMEOUT};
+pub use types::{Side, SlotProfile, Uid};
+
+/// The slot profile the dongle uses from the next master frame, see [`SlotProfile`].
+static SLOT_PROFILE: AtomicU8 = AtomicU8::new(SlotProfile::Standard as u8);
+
+/// Switch the dongle to another slot profile at the next master frame.
+///
+/// The halves follow the profile announced in the syncs, a half that misses the syncs around the
+/// switch loses its schedule and searches for the sync again.
+pub fn set_slot_profile(profile: SlotProfile) {
+    SLOT_PROFILE.store(profile as u8, Ordering::Relaxed);
 }
 
-impl ChannelHopping {
-    // Randomly generated at: https://www.random.org/sequences/?min=0&max=83&col=1&format=html&rnd=new
-    // and slightly modified to make adjacent hops not be adjacent channels.
-    //
-    // This will use all channels in the 2.400 - 2.483 GHz band twice.
-    const CHANNEL_HOPPING_SEQUENCE: [u8; 167] = [
-        5, 38, 24, 36, 15, 26, 51, 7, 63, 82, 11, 40, 65, 21, 80, 81, 32, 74, 50, 48, 70, 42, 76,
-        8, 20, 75, 53, 77, 6, 79, 46, 71, 62, 14, 29, 19, 2, 17, 59, 35, 83, 56, 34, 61, 22, 66,
-        54, 67, 44, 78, 52, 68, 4, 45, 27, 31, 18, 0, 60, 43, 12, 55, 13, 9, 58, 16, 47, 10, 25,
-        72, 3, 33, 69, 1, 39, 57, 28, 73, 37, 64, 49, 23, 41, 30, // first round (0-83)
-        11, 23, 66, 0, 21, 74, 52, 28, 45, 14, 65, 18, 30, 43, 57, 71, 31, 63, 2, 50, 1, 42, 10,
-        72, 9, 76, 33, 64, 54, 49, 62, 15, 44, 25, 51, 40, 58, 68, 37, 67, 22, 47, 19, 55, 13, 36,
-        17, 56, 27, 46, 70, 20, 78, 24, 79, 59, 39, 81, 8, 34, 82, 41, 69, 38, 26, 3, 75, 35, 60,
-        77, 7, 48, 4, 32, 5, 80, 53, 6, 61, 29, 12, 83, 16, // 73, // second round (0-83)
-    ];
-
-    /// Create a new channel hopping selector.
-    pub const fn new() -> Self {
-        Self { state: 0 }
-    }
-
-    /// Get the current channel.
-    pub fn current_channel(&self) -> u8 {
-        Self::CHANNEL_HOPPING_SEQUENCE[self.state as usize]
-    }
-
-    /// Move to the next channel.
-    pub fn next_channel(&mut self) {
-        self.state = (self.state + 1) % Self::CHANNEL_HOPPING_SEQUENCE.len() as u8;
-    }
-
-    /// Check if the current channel is the initial state.
-    pub fn is_initial_state(&self) -> bool {
-        self.state == 0
-    }
-
-    /// Reset to initial state.
-    pub fn reset(&mut self) {
-        self.state = 0;
-    }
-
-    /// Return the current timeslot.
-    pub fn state(&self) -> u8 {
-        self.state
-    }
+fn slot_profile() -> SlotProfile {
+    SlotProfile::from_u8(SLOT_PROFILE.load(Ordering::Relaxed)).unwrap_or(SlotProfile::Standard)
 }
 
-/// The size of an slot in the protocol in microseconds (empirically this needs to be > 600 us)>
-pub const SLOT_SIZE: TimerDurationU64<1_000_000> = TimerDurationU64::micros(1_000);
-
-/// Main runner for the dongle's radio communication.
-pub async fn dongle_radio_runner(mut radio: Radio) -> ! {
-    // 1. Pairing stage
-
-    // 2. Connected stage
-
-    let mut packet = Packet::new();
-    let mut slot_start_time = Mono::now() + 200.millis();
-    let mut channel_hopping = ChannelHopping::new();
-
-    loop {
-        //
-        // 1. Send the sync packet at the desired time.
-        //
-        Mono::delay_until(slot_start_time).await;
-
-        // defmt::info!(
-        //     "Trying to send on channel {} ({}) at {}...",
-        //     channel_hopping.current_channel(),
-        //     channel_hopping.state,
-        //     slot_start_time
-        // );
+/// How long the dongle's button needs to be held to enter pair mode.
+pub const PAIR_BUTTON_HOLD: TimerDurationU64<1_000_000> = TimerDurationU64::secs(3);
 
-        radio.set_freqeuency(channel_hopping.current_channel());
-        // TODO: Actually send something as sync
-        packet.copy_from_slice(&[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
-        let sync_timestamp = radio.send_no_cca(&mut packet).await.0;
+/// How long the dongle stays in pair mode if not both halves get paired.
+pub const PAIR_MODE_TIMEOUT: TimerDurationU64<1_000_000> = TimerDurationU64::secs(60);
 
-        //
-        // 2. Receive and channel hop, look for keyboard halves responses.
-        //
-        channel_hopping.next_channel();
-        slot_start_time += SLOT_SIZE;
-
-        let mut correct_rxes = 0;
-        let mut missed_rxes = 0;
-
-        while !channel_hopping.is_initial_state() {
-            radio.set_freqeuency(channel_hopping.current_channel());
-
-            // Look for packets, stop receiving a little before the next round.
-            match Mono::timeout_at(
-                slot_start_time + SLOT_SIZE - 200.micros(),
-                radio.recv(&mut packet),
-            )
-            .await
-            {
-                Ok(ts) => {
-                    if let Ok((ts, rssi)) = ts {
-                        // defmt::debug!(
-                        //     "Got data, channel {} ({}): {}",
-                        //     channel_hopping.state(),
-                        //     rssi,
-                        //     *packet,
-                        // );
-                        correct_rxes += 1;
+/// The dongle's button, held to enter pair mode.
+pub trait PairButton {
+    /// If the button is held down right now.
+    fn is_pressed(&mut self) -> bool;
+}
 
-                        // TODO: Send ack.
-                        packet.copy_from_slice(&[10, 11, 12, 13, 14, 15, 16, 17, 18, 19]);
-                        radio.send_no_cca(&mut packet).await;
-                    }
-                }
-                Err(_timeout) => {
-                    missed_rxes += 1;
-                    //defmt::warn!("No data, channel {}", channel_hopping.state())
-                }
-            };
+/// The pipe of pairing and reconnects, on the default address every device knows.
+const PAIRING_PIPE: u8 = 0;
 
-            channel_hopping.next_channel();
-            slot_start_time += SLOT_SIZE;
-        }
-
-        defmt::info!(
-            "This master frame got {} successful RXes and {} missed",
-            correct_rxes,
-            missed_rxes
-        );
-    }
+/// Send and receive on the pairing pipe only.
+fn use_pairing_address<R: RadioDriver>(radio: &mut R) {
+    radio.set_tx_pipe(PAIRING_PIPE);
+    radio.set_rx_pipes(1 << PAIRING_PIPE);
 }
 
 #[derive(Copy, Clone, Debug, defmt::Format)]
-enum KeyboardRadioState {
-    LookingForSync,
-    Synchronized {
-        sync_time: TimerInstantU64<1_000_000>,
-        slot_start_time: TimerInstantU64<1_000_000>,
+enum DongleRadioState {
+    PairMode {
+        deadline: TimerInstantU64<1_000_000>,
     },
+    Connected,
 }
 
-/// Main runner for a keyboard half's radio communication.
-pub async fn keyboard_radio_runner(mut radio: Radio, is_right_half: bool) -> ! {
-    let mut packet = Packet::new();
-    let mut channel_hopping = ChannelHopping::new();
-
-    let mut state = KeyboardRadioState::LookingForSync;
-
-    let mut i = 0;
+/// Main runner for the dongle's radio communication, on the radio `R` and the clock `C`.
+///
+/// Key pairs and nonces are drawn from `rng`, and the halves' bonds are kept in `bonds`.
+pub async fn dongle_radio_runner<R, C, B, G, F>(
+    mut radio: R,
+    mut button: B,
+    mut rng: G,
+    mut bonds: BondStore<F>,
+    dongle_uid: Uid,
+) -> !
+where
+    R: RadioDriver,
+    C: Clock,
+    B: PairButton,
+    G: RngCore + CryptoRng,
+    F: NorFlash,
+{
+    let mut pairing = DonglePairing::new(dongle_uid, &mut rng);
+    let mut links = DongleLinks::new(dongle_uid, C::now() + 200.millis());
+    let mut button_pressed_at = None;
+
+    let mut state = DongleRadioState::Connected;
+
+    links.survey(&mut radio).await;
 
-    // RX code:
     loop {
-        // if led.is_set_high() {
-        //     led.set_low();
-        // } else {
-        //     led.set_high();
-        // }
+        let profile = slot_profile();
+        links.master_frame::<_, C>(&mut radio, profile).await;
 
-        match state {
-            KeyboardRadioState::LookingForSync => {
-                defmt::info!("Got {} acks last round", i);
-                i = 0;
+        let forgotten = bonds.handle_requests();
+        for side in Side::ALL {
+            if forgotten[side.index()] {
+                links.sessions_mut()[side.index()] = None;
+            }
+        }
 
-                channel_hopping.reset();
-                radio.set_freqeuency(channel_hopping.current_channel());
-                let (timestamp, rssi) = if let Ok(v) = radio.recv(&mut packet).await {
-                    v
-                } else {
-                    defmt::info!("Radio receive error");
-                    continue;
-                };
+        // Pair mode runs between the master frames, so connected halves keep their links.
+        if let DongleRadioState::PairMode { deadline } = state {
+            if C::now() >= deadline || links.sessions().iter().all(Option::is_some) {
+                defmt::info!("Pair mode ended");
+                links.set_pair_mode(false);
+                state = DongleRadioState::Connected;
+            }
+        }
 
-                // defmt::info!(
-                //     "Radio receive ts {}, rssi {}, packet: {}",
-                //     timestamp,
-                //     rssi,
-                //     *packet
-                // );
+        let pair_mode = matches!(state, DongleRadioState::PairMode { .. });
+
+        // Give bonded halves that are not connected a chance to reconnect, and a new half
+        // typing the passkey a chance to go on.
+        if pair_mode
+            || pairing.entry_pending()
+            || bonds
+                .bonds()
+                .any(|bond| links.sessions()[bond.side.index()].is_none())
+        {
+            pairing
+                .beacon_window::<_, C, _, _>(
+                    &mut radio,
+                    &mut rng,
+                    pair_mode,
+                    &mut bonds,
+                    links.sessions_mut(),
+                )
+                .await;
+
+            // A handshake, or a step of a passkey entry, can run into the next master frame,
+            // push it back.
+            links.postpone(C::now() + profile.slot_size());
+        }
 
-                if channel_hopping.is_initial_state() && &*packet == [0, 1, 2, 3, 4, 5, 6, 7, 8, 9]
-                {
-                    defmt::info!("Sync found at {}", timestamp.0);
+        if afh::take_survey_request() {
+            links.survey(&mut radio).await;
 
-                    // Hack to get RX timestamp in mono time...
-                    let now = TimerInstantU64::from_ticks(
-                        Mono::now().ticks() & 0xffff_ffff_0000_0000 | timestamp.0.ticks() as u64,
-                    );
+            // The survey takes a good part of a master frame, push the next one back.
+            links.postpone(C::now() + profile.slot_size());
+        }
 
-                    if is_right_half {
-                        // Right half gets the odd slots.
-                        channel_hopping.next_channel();
-                        state = KeyboardRadioState::Synchronized {
-                            sync_time: now,
-                            slot_start_time: now + SLOT_SIZE,
-                        };
-                    } else {
-                        // Left half gets the even slots.
-                        channel_hopping.next_channel();
-                        channel_hopping.next_channel();
-                        state = KeyboardRadioState::Synchronized {
-                            sync_time: now,
-                            slot_start_time: now + 2 * SLOT_SIZE,
-                        };
-                    }
-                }
+        // The button is checked once per master frame, which is plenty for a 3 s hold.
+        if pair_mode {
+            button_pressed_at = None;
+        } else if button.is_pressed() {
+            let pressed_at = *button_pressed_at.get_or_insert(C::now());
+
+            if C::now() - pressed_at >= PAIR_BUTTON_HOLD {
+                defmt::info!("Pair mode started");
+                button_pressed_at = None;
+                links.set_pair_mode(true);
+                state = DongleRadioState::PairMode {
+                    deadline: C::now() + PAIR_MODE_TIMEOUT,
+                };
             }
-            KeyboardRadioState::Synchronized {
-                sync_time,
-                mut slot_start_time,
-            } => {
-                loop {
-                    radio.set_freqeuency(channel_hopping.current_channel());
-
-                    // TODO: Send data and wait for ack.
-                    packet.copy_from_slice(&[channel_hopping.state()]);
-
-                    Mono::delay_until(slot_start_time).await;
-                    let timestamp = radio.send_no_cca(&mut packet).await;
+        } else {
+            button_pressed_at = None;
+        }
+    }
+}
 
-                    // defmt::info!(
-                    //     "Sent at {}, sync = {}, diff = {} ms",
-                    //     timestamp.0,
-                    //     slot_start_time,
-                    //     (slot_start_time - sync_time).to_millis(),
-                    // );
+/// Main runner for a keyboard half's radio communication, on the radio `R` and the clock `C`.
+///
+/// Like [`dongle_radio_runner`], with the bond to the dongle kept in `bonds`. The dongle's
+/// [`Command::EnterBootloader`] calls `enter_bootloader`.
+pub async fn keyboard_radio_runner<R, C, G, F>(
+    mut radio: R,
+    side: Side,
+    mut rng: G,
+    mut bonds: BondStore<F>,
+    keyboard_uid: Uid,
+    enter_bootloader: fn() -> !,
+) -> !
+where
+    R: RadioDriver,
+    C: Clock,
+    G: RngCore + CryptoRng,
+    F: NorFlash,
+{
+    let mut link = KeyboardLink::new();
 
-                    // Look for ACK.
-                    match Mono::timeout_at(slot_start_time + 1800.micros(), radio.recv(&mut packet))
-                        .await
-                    {
-                        Ok(_) => {
-                            i += 1;
-                            // defmt::info!("Got ack, channel {}", channel_hopping.current_channel());
-                        }
-                        Err(_timeout) => {
-                            // defmt::warn!("No ack, channel {}", channel_hopping.current_channel(),)
-                        }
-                    };
+    loop {
+        if bonds.handle_requests()[side.index()] {
+            link.disconnect();
+        }
 
-                    // Jump 2 channels as every keyboard half gets half of the slots.
-                    slot_start_time += SLOT_SIZE;
-                    channel_hopping.next_channel();
-                    if channel_hopping.is_initial_state() {
-                        break;
-                    }
+        if link.session().is_none() {
+            let peer = pairing::keyboard_connect::<_, C, _, _>(
+                &mut radio,
+                &mut rng,
+                keyboard_uid,
+                side,
+                &mut bonds,
+            )
+            .await;
+            link.connect::<_, C>(&mut radio, Session::keyboard(peer, keyboard_uid));
+        }
 
-                    slot_start_time += SLOT_SIZE;
-                    channel_hopping.next_channel();
-                    if channel_hopping.is_initial_state() {
-                        break;
-                    }
-                }
+        link.step::<_, C>(&mut radio, |radio, command| {
+            handle_command(radio, command, enter_bootloader)
+        })
+        .await;
+    }
+}
 
-                // TODO: Check for sync instead of dropping back to "initial sync search", that
-                // should only happen if we've lost sync for multiple frames.
-                state = KeyboardRadioState::LookingForSync;
-            }
-        }
+/// Carry out a command from the dongle on a keyboard half.
+fn handle_command<R: RadioDriver>(radio: &mut R, command: Command, enter_bootloader: fn() -> !) {
+    defmt::info!("Command from the dongle: {}", command);
+
+    match command {
+        Command::TxPower(dbm) => match TxPower::from_dbm(dbm) {
+            Some(power) => radio.set_txpower(power),
+            None => defmt::warn!("Unsupported TX power {} dBm", dbm),
+        },
+        Command::EnterBootloader => enter_bootloader(),
+        _ => state::apply_command(command),
     }
 }
@@ -0,0 +1,297 @@
+//! # Adaptive frequency hopping
+//!
+//! The dongle keeps a loss estimate per channel from the slots it listens in, and drops channels
+//! that are consistently bad, e.g. under a Wi-Fi network or BLE advertising, from the links'
+//! channel maps. Like BLE's channel map update, the new map is announced in the sync together
+//! with the frame counter it takes effect at, so both ends switch their hop sequence at the same
+//! master frame.
+//!
+//! Excluded channels are not measured anymore, their loss estimate slowly decays while a link is
+//! alive so they are tried again after a while.
+//!
+//! The estimates can be seeded from an energy survey of the band, see
+//! [`crate::radio::RadioDriver::survey`], so the links start out avoiding channels that are
+//! already busy.
+
+use super::hopping::{ChannelMap, MIN_USED_CHANNELS, NUM_CHANNELS};
+use crate::radio::EnergyLevel;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Fixed-point 100 % loss.
+const FULL_LOSS: u16 = 1 << 12;
+
+/// A channel is excluded once its loss estimate is above this.
+const BAD_LOSS: u16 = FULL_LOSS / 2;
+
+/// An excluded channel is used again once its loss estimate is below this.
+const GOOD_LOSS: u16 = FULL_LOSS / 4;
+
+/// Weight of a master frame's loss in the estimate, as a shift: 1/16.
+const LOSS_AVERAGING_SHIFT: u32 = 4;
+
+/// How fast an excluded channel's estimate decays per master frame, as a shift: 1/128.
+const FORGIVENESS_SHIFT: u32 = 7;
+
+/// How many master frames ahead of the current one a channel map update is scheduled.
+pub const CHANNEL_MAP_UPDATE_DELAY_FRAMES: u32 = 8;
+
+/// A channel whose peak energy in a survey reaches this starts out as lost.
+const NOISY_PEAK_DBM: i8 = -70;
+
+/// Pending survey request for the dongle.
+static SURVEY_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Ask the dongle to survey the band after the next master frame.
+pub fn request_survey() {
+    SURVEY_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Take a pending [`request_survey`].
+pub fn take_survey_request() -> bool {
+    SURVEY_REQUESTED.swap(false, Ordering::Relaxed)
+}
+
+/// Per-channel loss statistics, kept across master frames.
+pub struct ChannelAssessment {
+    /// Exponentially weighted average of the loss, [`FULL_LOSS`] is 100 %.
+    loss: [u16; NUM_CHANNELS],
+    /// Slots listened in this master frame.
+    attempts: [u8; NUM_CHANNELS],
+    /// Slots without an authenticated frame this master frame.
+    losses: [u8; NUM_CHANNELS],
+}
+
+impl Default for ChannelAssessment {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChannelAssessment {
+    pub const fn new() -> Self {
+        Self {
+            loss: [0; NUM_CHANNELS],
+            attempts: [0; NUM_CHANNELS],
+            losses: [0; NUM_CHANNELS],
+        }
+    }
+
+    /// Record the outcome of a slot on `channel`.
+    pub fn record(&mut self, channel: u8, received: bool) {
+        let Some(attempts) = self.attempts.get_mut(channel as usize) else {
+            return;
+        };
+
+        *attempts = attempts.saturating_add(1);
+        if !received {
+            self.losses[channel as usize] = self.losses[channel as usize].saturating_add(1);
+        }
+    }
+
+    /// Seed the estimates from an energy survey, indexed by frequency like
+    /// [`crate::radio::RadioDriver::survey`]'s.
+    ///
+    /// Channels with a noisy peak count as fully lost, the others keep their estimate.
+    pub fn seed(&mut self, survey: &[EnergyLevel]) {
+        for (loss, level) in self.loss.iter_mut().zip(survey) {
+            if level.peak >= NOISY_PEAK_DBM {
+                *loss = FULL_LOSS;
+            }
+        }
+    }
+
+    /// Fold the master frame's outcomes into the estimates.
+    ///
+    /// Frames where no half was heard at all say nothing about the channels, only pass `true`
+    /// for `link_alive` if at least one half was.
+    pub fn end_frame(&mut self, link_alive: bool) {
+        for channel in 0..NUM_CHANNELS {
+            let loss = &mut self.loss[channel];
+            let attempts = self.attempts[channel] as u32;
+
+            if !link_alive {
+                continue;
+            }
+
+            match (self.losses[channel] as u32 * FULL_LOSS as u32).checked_div(attempts) {
+                // Not used in this frame.
+                None => *loss -= *loss >> FORGIVENESS_SHIFT,
+                Some(frame_loss) => {
+                    let frame_loss = frame_loss as u16;
+                    *loss = *loss - (*loss >> LOSS_AVERAGING_SHIFT)
+                        + (frame_loss >> LOSS_AVERAGING_SHIFT);
+                }
+            }
+        }
+
+        self.attempts = [0; NUM_CHANNELS];
+        self.losses = [0; NUM_CHANNELS];
+    }
+
+    /// The channel map the estimates suggest, starting from the `current` one.
+    ///
+    /// Channels only move in or out of the map past their threshold, and the least bad channels
+    /// are kept if too few are good.
+    pub fn channel_map(&self, current: &ChannelMap) -> ChannelMap {
+        let mut map = *current;
+
+        for channel in 0..NUM_CHANNELS as u8 {
+            let loss = self.loss[channel as usize];
+
+            if map.is_used(channel) && loss > BAD_LOSS {
+                map.set_used(channel, false);
+            } else if !map.is_used(channel) && loss < GOOD_LOSS {
+                map.set_used(channel, true);
+            }
+        }
+
+        while map.num_used() < MIN_USED_CHANNELS {
+            let best = (0..NUM_CHANNELS as u8)
+                .filter(|&channel| !map.is_used(channel))
+                .min_by_key(|&channel| self.loss[channel as usize]);
+
+            match best {
+                Some(channel) => map.set_used(channel, true),
+                None => break,
+            }
+        }
+
+        map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    const QUIET: EnergyLevel = EnergyLevel {
+        peak: -100,
+        average: -100,
+    };
+
+    /// A master frame with two slots on every channel of `map`, `lost` in both on `bad`.
+    fn frame(assessment: &mut ChannelAssessment, map: &ChannelMap, bad: &[u8]) {
+        for channel in (0..NUM_CHANNELS as u8).filter(|&channel| map.is_used(channel)) {
+            for _ in 0..2 {
+                assessment.record(channel, !bad.contains(&channel));
+            }
+        }
+        assessment.end_frame(true);
+    }
+
+    fn unused(map: &ChannelMap) -> Vec<u8> {
+        (0..NUM_CHANNELS as u8)
+            .filter(|&channel| !map.is_used(channel))
+            .collect()
+    }
+
+    #[test]
+    fn lossy_channel_is_excluded() {
+        let mut assessment = ChannelAssessment::new();
+
+        // The estimate passes half loss after 11 lost frames.
+        for _ in 0..10 {
+            frame(&mut assessment, &ChannelMap::ALL, &[5]);
+        }
+        assert!(unused(&assessment.channel_map(&ChannelMap::ALL)).is_empty());
+
+        frame(&mut assessment, &ChannelMap::ALL, &[5]);
+        assert_eq!(unused(&assessment.channel_map(&ChannelMap::ALL)), [5]);
+    }
+
+    #[test]
+    fn occasional_loss_is_tolerated() {
+        let mut assessment = ChannelAssessment::new();
+
+        for _ in 0..200 {
+            assessment.record(7, false);
+            for _ in 0..3 {
+                assessment.record(7, true);
+            }
+            assessment.end_frame(true);
+        }
+
+        assert!(unused(&assessment.channel_map(&ChannelMap::ALL)).is_empty());
+    }
+
+    #[test]
+    fn excluded_channel_is_tried_again() {
+        let mut assessment = ChannelAssessment::new();
+        let mut survey = [QUIET; NUM_CHANNELS];
+        survey[40].peak = NOISY_PEAK_DBM;
+        assessment.seed(&survey);
+
+        let map = assessment.channel_map(&ChannelMap::ALL);
+        assert_eq!(unused(&map), [40]);
+
+        // Frames where no half was heard do not count.
+        for _ in 0..500 {
+            assessment.end_frame(false);
+        }
+        assert_eq!(unused(&assessment.channel_map(&map)), [40]);
+
+        // The estimate decays under a quarter loss after 183 frames, the channel stays out
+        // until then.
+        for _ in 0..182 {
+            frame(&mut assessment, &map, &[]);
+        }
+        assert_eq!(unused(&assessment.channel_map(&map)), [40]);
+
+        frame(&mut assessment, &map, &[]);
+        assert!(unused(&assessment.channel_map(&map)).is_empty());
+    }
+
+    #[test]
+    fn least_bad_channels_are_kept() {
+        let mut assessment = ChannelAssessment::new();
+        assessment.seed(
+            &[EnergyLevel {
+                peak: -40,
+                average: -60,
+            }; NUM_CHANNELS],
+        );
+
+        // Only the top channels got through once.
+        let top = NUM_CHANNELS as u8 - 24..NUM_CHANNELS as u8;
+        for channel in top.clone() {
+            assessment.record(channel, true);
+        }
+        assessment.end_frame(true);
+
+        let map = assessment.channel_map(&ChannelMap::ALL);
+        assert_eq!(map.num_used(), MIN_USED_CHANNELS);
+        assert!(map.is_valid());
+        assert!((0..NUM_CHANNELS as u8)
+            .filter(|&channel| map.is_used(channel))
+            .all(|channel| top.contains(&channel)));
+    }
+
+    #[test]
+    fn survey_seeds_noisy_peaks() {
+        let mut assessment = ChannelAssessment::new();
+        for _ in 0..20 {
+            frame(&mut assessment, &ChannelMap::ALL, &[3]);
+        }
+
+        let mut survey = [QUIET; NUM_CHANNELS];
+        survey[10].peak = NOISY_PEAK_DBM;
+        survey[11].peak = NOISY_PEAK_DBM - 1;
+        // A busy average alone is a neighbour's packets, not an interferer.
+        survey[12].average = -50;
+        assessment.seed(&survey);
+
+        // Quiet channels keep their estimate.
+        assert_eq!(unused(&assessment.channel_map(&ChannelMap::ALL)), [3, 10]);
+        assert_eq!(assessment.loss[10], FULL_LOSS);
+    }
+
+    #[test]
+    fn out_of_band_slots_are_ignored() {
+        let mut assessment = ChannelAssessment::new();
+        assessment.record(NUM_CHANNELS as u8, false);
+        assessment.end_frame(true);
+
+        assert_eq!(assessment.loss, [0; NUM_CHANNELS]);
+    }
+}
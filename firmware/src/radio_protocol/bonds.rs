@@ -0,0 +1,538 @@
+//! # Persistent pairing bonds
+//!
+//! Bonds are kept in two flash pages, both on the dongle (one bond per side) and on a keyboard
+//! half (one bond for its own side). The page in use is a log: every stored bond and every
+//! forgotten side appends a record after the header, and loading replays the records in order. A
+//! record's first word is written last, so a record cut short by a reset is skipped.
+//!
+//! Appending a record only takes a few words of flash writes, which fits between the master
+//! frames. When the page is full, the current bonds are written to the other page, and its
+//! header with the next generation goes last. Loading takes the valid page of the newest
+//! generation, so a reset while the bonds move leaves the old page in use.
+//!
+//! The radio runners own the [`BondStore`], other tasks use [`list`], [`forget`] and
+//! [`forget_all`] which are picked up by the runners at their next frame boundary.
+
+use super::{Side, Uid};
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU8, Ordering};
+use critical_section::Mutex;
+use embedded_storage::nor_flash::NorFlash;
+
+/// A bond between a keyboard half and a dongle.
+#[derive(Clone)]
+pub struct Bond {
+    /// The ID of the other end.
+    pub peer_uid: Uid,
+    /// The side of the keyboard half in this bond.
+    pub side: Side,
+    /// Long term key material established when pairing.
+    pub link_key: [u8; 32],
+}
+
+/// The non-secret part of a bond.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub struct BondInfo {
+    pub peer_uid: Uid,
+    pub side: Side,
+}
+
+impl Bond {
+    const RECORD_LEN: usize = 48;
+    const VALID: u8 = 0xb0;
+    /// A record that forgets the bond of its side.
+    const FORGOTTEN: u8 = 0xb1;
+
+    fn info(&self) -> BondInfo {
+        BondInfo {
+            peer_uid: self.peer_uid,
+            side: self.side,
+        }
+    }
+
+    fn encode(&self, buf: &mut [u8; Self::RECORD_LEN]) {
+        buf.fill(0);
+        buf[0] = Self::VALID;
+        buf[1] = self.side as u8;
+        buf[4..12].copy_from_slice(&self.peer_uid.0);
+        buf[12..44].copy_from_slice(&self.link_key);
+    }
+
+    fn encode_forgotten(side: Side, buf: &mut [u8; Self::RECORD_LEN]) {
+        buf.fill(0);
+        buf[0] = Self::FORGOTTEN;
+        buf[1] = side as u8;
+    }
+
+    /// Apply a record to `bonds`, returns `false` if it is not a valid record.
+    fn replay(buf: &[u8; Self::RECORD_LEN], bonds: &mut [Option<Bond>; 2]) -> bool {
+        let Some(side) = Side::from_u8(buf[1]) else {
+            return false;
+        };
+
+        bonds[side.index()] = match buf[0] {
+            Self::VALID => Some(Self {
+                peer_uid: Uid(buf[4..12].try_into().unwrap()),
+                side,
+                link_key: buf[12..44].try_into().unwrap(),
+            }),
+            Self::FORGOTTEN => None,
+            _ => return false,
+        };
+
+        true
+    }
+}
+
+/// Pending forget requests, one bit per [`Side::index`].
+static FORGET_REQUESTS: AtomicU8 = AtomicU8::new(0);
+
+/// Snapshot of the stored bonds for [`list`].
+static BOND_LIST: Mutex<Cell<[Option<BondInfo>; 2]>> = Mutex::new(Cell::new([None, None]));
+
+/// List the stored bonds, indexed by [`Side::index`].
+pub fn list() -> [Option<BondInfo>; 2] {
+    critical_section::with(|cs| BOND_LIST.borrow(cs).get())
+}
+
+/// Forget the bond for `side`.
+pub fn forget(side: Side) {
+    FORGET_REQUESTS.fetch_or(1 << side.index(), Ordering::Relaxed);
+}
+
+/// Forget all bonds.
+pub fn forget_all() {
+    for side in Side::ALL {
+        forget(side);
+    }
+}
+
+/// Marks a page holding bonds, followed by [`VERSION`] and the page's generation.
+const MAGIC: [u8; 4] = *b"BOND";
+const VERSION: u8 = 3;
+const HEADER_LEN: usize = 8;
+/// The part of a record that is written last.
+const COMMIT_LEN: usize = 4;
+
+/// The generation of the page at `offset`, `None` if it does not hold bonds.
+fn page_generation<F: NorFlash>(flash: &mut F, offset: u32) -> Option<u16> {
+    let mut header = [0; HEADER_LEN];
+    flash.read(offset, &mut header).ok()?;
+
+    (header[..4] == MAGIC && header[4] == VERSION)
+        .then(|| u16::from_le_bytes([header[6], header[7]]))
+}
+
+/// Flash backed storage of bonds, indexed by side.
+pub struct BondStore<F> {
+    flash: F,
+    offset: u32,
+    bonds: [Option<Bond>; 2],
+    /// Offset of the page in use, or of the second page if neither holds bonds.
+    page: u32,
+    generation: u16,
+    /// Offset of the next record in the page, `None` if the page has no valid header.
+    next: Option<u32>,
+}
+
+impl<F: NorFlash> BondStore<F> {
+    /// Load the bonds from the two flash pages at `offset`.
+    pub fn new(mut flash: F, offset: u32) -> Self {
+        let mut bonds = [None, None];
+        let mut next = None;
+
+        let pages = [offset, offset + F::ERASE_SIZE as u32]
+            .map(|page| page_generation(&mut flash, page).map(|generation| (page, generation)));
+        let newest = match pages {
+            // Generations wrap around, the pages are only ever one apart.
+            [Some(first), Some(second)] if (second.1.wrapping_sub(first.1) as i16) > 0 => {
+                Some(second)
+            }
+            [Some(first), _] => Some(first),
+            [None, second] => second,
+        };
+
+        let (page, generation) = newest.unwrap_or((offset + F::ERASE_SIZE as u32, 0));
+
+        if newest.is_some() {
+            let mut record = [0; Bond::RECORD_LEN];
+            let mut at = page + HEADER_LEN as u32;
+            let mut end = at;
+
+            while at + Bond::RECORD_LEN as u32 <= page + F::ERASE_SIZE as u32 {
+                if flash.read(at, &mut record).is_err() {
+                    break;
+                }
+
+                // Erased slots are only left at the end, cut short records anywhere.
+                if record.iter().any(|&byte| byte != 0xff) {
+                    if !Bond::replay(&record, &mut bonds) {
+                        defmt::warn!("Skipping bond record at {:x}", at);
+                    }
+                    end = at + Bond::RECORD_LEN as u32;
+                }
+                at += Bond::RECORD_LEN as u32;
+            }
+
+            next = Some(end);
+        } else {
+            defmt::info!("No bonds stored");
+        }
+
+        let store = Self {
+            flash,
+            offset,
+            bonds,
+            page,
+            generation,
+            next,
+        };
+        store.publish();
+        store
+    }
+
+    /// The bond for `side`, if any.
+    pub fn get(&self, side: Side) -> Option<&Bond> {
+        self.bonds[side.index()].as_ref()
+    }
+
+    /// All stored bonds.
+    pub fn bonds(&self) -> impl Iterator<Item = &Bond> {
+        self.bonds.iter().flatten()
+    }
+
+    /// Store a bond, replacing any bond for the same side.
+    pub fn store(&mut self, bond: Bond) -> Result<(), F::Error> {
+        let mut record = [0; Bond::RECORD_LEN];
+        bond.encode(&mut record);
+
+        let index = bond.side.index();
+        self.bonds[index] = Some(bond);
+        self.append(&record)
+    }
+
+    /// Forget the bond for `side`.
+    pub fn forget(&mut self, side: Side) -> Result<(), F::Error> {
+        if self.bonds[side.index()].is_none() {
+            return Ok(());
+        }
+
+        let mut record = [0; Bond::RECORD_LEN];
+        Bond::encode_forgotten(side, &mut record);
+
+        self.bonds[side.index()] = None;
+        self.append(&record)
+    }
+
+    /// Forget all bonds.
+    pub fn forget_all(&mut self) -> Result<(), F::Error> {
+        for side in Side::ALL {
+            self.forget(side)?;
+        }
+
+        Ok(())
+    }
+
+    /// Execute pending [`forget`] and [`forget_all`] requests, returning the sides that were
+    /// forgotten.
+    ///
+    /// Meant to be called between frames, this usually only appends a record for each side.
+    pub fn handle_requests(&mut self) -> [bool; 2] {
+        let requests = FORGET_REQUESTS.swap(0, Ordering::Relaxed);
+        let forgotten = Side::ALL.map(|side| requests & (1 << side.index()) != 0);
+
+        for side in Side::ALL {
+            if forgotten[side.index()] {
+                defmt::info!("Forgetting bond for {} side", side);
+
+                if self.forget(side).is_err() {
+                    defmt::error!("Failed to write bonds to flash");
+                }
+            }
+        }
+
+        forgotten
+    }
+
+    /// Append a record that takes the bonds to their current state, or move them to the other
+    /// page if it is full.
+    fn append(&mut self, record: &[u8; Bond::RECORD_LEN]) -> Result<(), F::Error> {
+        let page_end = self.page + F::ERASE_SIZE as u32;
+
+        match self.next {
+            Some(at) if at + Bond::RECORD_LEN as u32 <= page_end => {
+                self.flash
+                    .write(at + COMMIT_LEN as u32, &record[COMMIT_LEN..])?;
+                self.flash.write(at, &record[..COMMIT_LEN])?;
+                self.next = Some(at + Bond::RECORD_LEN as u32);
+            }
+            _ => self.rewrite()?,
+        }
+
+        self.publish();
+
+        Ok(())
+    }
+
+    /// Erase the page not in use, write the current bonds to it and make it the page in use.
+    ///
+    /// The page in use stays valid on flash until the other page's header is written.
+    fn rewrite(&mut self) -> Result<(), F::Error> {
+        let page = if self.page == self.offset {
+            self.offset + F::ERASE_SIZE as u32
+        } else {
+            self.offset
+        };
+        let generation = self.generation.wrapping_add(1);
+
+        defmt::info!("Moving the bonds to the page at {:x}", page);
+
+        // The log in memory is invalid until the header is written.
+        self.next = None;
+        self.flash.erase(page, page + F::ERASE_SIZE as u32)?;
+
+        let mut at = page + HEADER_LEN as u32;
+        let mut record = [0; Bond::RECORD_LEN];
+        for bond in self.bonds.iter().flatten() {
+            bond.encode(&mut record);
+            self.flash.write(at, &record)?;
+            at += Bond::RECORD_LEN as u32;
+        }
+
+        // The magic is written last, like the first word of a record.
+        let mut header = [0; HEADER_LEN];
+        header[..4].copy_from_slice(&MAGIC);
+        header[4] = VERSION;
+        header[6..8].copy_from_slice(&generation.to_le_bytes());
+        self.flash.write(page + 4, &header[4..])?;
+        self.flash.write(page, &header[..4])?;
+
+        self.page = page;
+        self.generation = generation;
+        self.next = Some(at);
+
+        Ok(())
+    }
+
+    fn publish(&self) {
+        let list = [0, 1].map(|index| self.bonds[index].as_ref().map(Bond::info));
+        critical_section::with(|cs| BOND_LIST.borrow(cs).set(list));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_storage::nor_flash::{ErrorType, NorFlashErrorKind, ReadNorFlash};
+    use std::vec::Vec;
+
+    const PAGE: usize = 4096;
+
+    /// Two flash pages in RAM, writes can only clear bits like on the NVMC.
+    #[derive(Clone)]
+    struct RamFlash {
+        pages: Vec<u8>,
+        erases: usize,
+        /// Fail all writes after the next erase, like a reset right after it.
+        fail_after_erase: bool,
+        failing: bool,
+    }
+
+    impl RamFlash {
+        fn new() -> Self {
+            Self {
+                pages: vec![0xff; 2 * PAGE],
+                erases: 0,
+                fail_after_erase: false,
+                failing: false,
+            }
+        }
+    }
+
+    impl ErrorType for RamFlash {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl ReadNorFlash for RamFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.pages[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            2 * PAGE
+        }
+    }
+
+    impl NorFlash for RamFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = PAGE;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            self.pages[from as usize..to as usize].fill(0xff);
+            self.erases += 1;
+            self.failing |= self.fail_after_erase;
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            if self.failing {
+                return Err(NorFlashErrorKind::Other);
+            }
+
+            let offset = offset as usize;
+            for (word, byte) in self.pages[offset..].iter_mut().zip(bytes) {
+                *word &= byte;
+            }
+            Ok(())
+        }
+    }
+
+    fn bond(side: Side, byte: u8) -> Bond {
+        Bond {
+            peer_uid: Uid([byte; 8]),
+            side,
+            link_key: [byte; 32],
+        }
+    }
+
+    fn stored(store: &BondStore<RamFlash>) -> Vec<(Uid, Side, [u8; 32])> {
+        store
+            .bonds()
+            .map(|bond| (bond.peer_uid, bond.side, bond.link_key))
+            .collect()
+    }
+
+    /// Load the bonds again from the store's flash, as after a reset.
+    fn reload(store: &BondStore<RamFlash>) -> BondStore<RamFlash> {
+        BondStore::new(store.flash.clone(), 0)
+    }
+
+    #[test]
+    fn bonds_survive_a_reset() {
+        let mut store = BondStore::new(RamFlash::new(), 0);
+        assert_eq!(store.bonds().count(), 0);
+
+        store.store(bond(Side::Right, 1)).unwrap();
+        store.store(bond(Side::Left, 2)).unwrap();
+        store.store(bond(Side::Right, 3)).unwrap();
+        store.forget(Side::Left).unwrap();
+
+        let reloaded = reload(&store);
+        assert_eq!(stored(&reloaded), [(Uid([3; 8]), Side::Right, [3; 32])]);
+        assert_eq!(stored(&reloaded), stored(&store));
+    }
+
+    #[test]
+    fn changes_append_until_the_page_is_full() {
+        let mut store = BondStore::new(RamFlash::new(), 0);
+        // The first change writes the header.
+        store.store(bond(Side::Right, 0)).unwrap();
+        assert_eq!(store.flash.erases, 1);
+
+        let slots = (PAGE - 8) / Bond::RECORD_LEN;
+        for i in 1..slots {
+            store.store(bond(Side::Left, i as u8)).unwrap();
+        }
+        assert_eq!(store.flash.erases, 1);
+
+        store.forget(Side::Right).unwrap();
+        assert_eq!(store.flash.erases, 2);
+
+        let last = (slots - 1) as u8;
+        assert_eq!(stored(&store), [(Uid([last; 8]), Side::Left, [last; 32])]);
+        assert_eq!(stored(&reload(&store)), stored(&store));
+
+        // The pages take turns.
+        assert_eq!(store.page, PAGE as u32);
+        for i in 0..slots {
+            store.store(bond(Side::Right, i as u8)).unwrap();
+        }
+        assert_eq!(store.flash.erases, 3);
+        assert_eq!(store.page, 0);
+        assert_eq!(stored(&reload(&store)), stored(&store));
+    }
+
+    #[test]
+    fn reset_while_moving_keeps_the_bonds() {
+        let mut store = BondStore::new(RamFlash::new(), 0);
+        store.store(bond(Side::Right, 1)).unwrap();
+        store.store(bond(Side::Left, 2)).unwrap();
+
+        let slots = (PAGE - 8) / Bond::RECORD_LEN;
+        for _ in 2..slots {
+            store.store(bond(Side::Left, 2)).unwrap();
+        }
+        let before = stored(&store);
+
+        // The page is full, the next change moves the bonds and the reset hits after the erase.
+        store.flash.fail_after_erase = true;
+        assert!(store.forget(Side::Right).is_err());
+
+        let mut flash = store.flash.clone();
+        flash.fail_after_erase = false;
+        flash.failing = false;
+        let mut reloaded = BondStore::new(flash, 0);
+        assert_eq!(stored(&reloaded), before);
+
+        // Moving them again after the reset works.
+        reloaded.forget(Side::Right).unwrap();
+        assert_eq!(stored(&reloaded), [(Uid([2; 8]), Side::Left, [2; 32])]);
+        assert_eq!(stored(&reload(&reloaded)), stored(&reloaded));
+    }
+
+    #[test]
+    fn generations_wrap_around() {
+        let mut store = BondStore::new(RamFlash::new(), 0);
+        store.generation = u16::MAX - 1;
+        store.store(bond(Side::Right, 1)).unwrap();
+        assert_eq!(store.generation, u16::MAX);
+
+        store.rewrite().unwrap();
+        store.store(bond(Side::Right, 2)).unwrap();
+        assert_eq!((store.page, store.generation), (PAGE as u32, 0));
+        assert_eq!(
+            stored(&reload(&store)),
+            [(Uid([2; 8]), Side::Right, [2; 32])]
+        );
+    }
+
+    #[test]
+    fn cut_short_record_is_skipped() {
+        let mut store = BondStore::new(RamFlash::new(), 0);
+        store.store(bond(Side::Right, 1)).unwrap();
+
+        // A reset after the body of the next record, before its first word.
+        let mut record = [0; Bond::RECORD_LEN];
+        bond(Side::Right, 2).encode(&mut record);
+        let at = store.next.unwrap();
+        store.flash.write(at + 4, &record[4..]).unwrap();
+
+        let mut reloaded = reload(&store);
+        assert_eq!(stored(&reloaded), [(Uid([1; 8]), Side::Right, [1; 32])]);
+
+        // Later records go after it.
+        reloaded.store(bond(Side::Left, 3)).unwrap();
+        assert_eq!(reloaded.next, Some(at + 2 * Bond::RECORD_LEN as u32));
+        assert_eq!(stored(&reload(&reloaded)), stored(&reloaded));
+    }
+
+    #[test]
+    fn other_versions_read_as_no_bonds() {
+        let mut flash = RamFlash::new();
+        flash.write(0, b"BOND\x01\0\0\0").unwrap();
+        flash
+            .write(8, &[Bond::VALID, Side::Left as u8, 0, 0])
+            .unwrap();
+
+        let mut store = BondStore::new(flash, 0);
+        assert_eq!(store.bonds().count(), 0);
+
+        store.store(bond(Side::Left, 1)).unwrap();
+        assert_eq!(store.flash.erases, 1);
+        assert_eq!(stored(&reload(&store)), stored(&store));
+    }
+}
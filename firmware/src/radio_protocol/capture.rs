@@ -0,0 +1,195 @@
+//! # Capture records
+//!
+//! The sniffer (see [`super::sniffer`]) streams what it receives as a pcap file: the global
+//! header once, then one record per packet with the time its address was received. Records use
+//! the `LINKTYPE_USER0` link type, and the packet's payload follows a pseudo-header. All fields
+//! are little endian:
+//!
+//! | Offset | Size | Field                                               |
+//! |--------|------|-----------------------------------------------------|
+//! | 0      | 4    | pcap: timestamp, seconds                            |
+//! | 4      | 4    | pcap: timestamp, microseconds                       |
+//! | 8      | 4    | pcap: captured length, from offset 16               |
+//! | 12     | 4    | pcap: original length, the same                     |
+//! | 16     | 1    | Frequency, 2400 MHz + `0..=100`                     |
+//! | 17     | 1    | RSSI in dBm, signed                                 |
+//! | 18     | 1    | Flags, bit 0 is CRC OK                              |
+//! | 19     | 1    | Pipe the address matched                            |
+//! | 20     | ..   | Payload, without the CRC                            |
+
+/// pcap magic number of microsecond timestamps.
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+
+/// The pcap link type of the records, reserved for private use.
+pub const LINKTYPE_USER0: u32 = 147;
+
+/// Flag bit set when the packet's CRC was correct.
+const FLAG_CRC_OK: u8 = 1 << 0;
+
+/// Largest payload of a packet, [`crate::radio::Packet::CAPACITY`].
+pub const MAX_PAYLOAD_LEN: usize = 125;
+
+/// Size of the pcap file's global header.
+pub const GLOBAL_HEADER_LEN: usize = 24;
+
+/// Size of the pcap record header.
+const PCAP_RECORD_HEADER_LEN: usize = 16;
+
+/// Size of the pseudo-header in front of the payload.
+const PSEUDO_HEADER_LEN: usize = 4;
+
+/// Largest captured data of a record.
+const SNAPLEN: usize = PSEUDO_HEADER_LEN + MAX_PAYLOAD_LEN;
+
+/// Largest encoded record.
+pub const MAX_RECORD_LEN: usize = PCAP_RECORD_HEADER_LEN + SNAPLEN;
+
+/// The pcap file's global header, version 2.4.
+pub fn global_header() -> [u8; GLOBAL_HEADER_LEN] {
+    let mut buf = [0; GLOBAL_HEADER_LEN];
+
+    buf[0..4].copy_from_slice(&PCAP_MAGIC.to_le_bytes());
+    buf[4..6].copy_from_slice(&2u16.to_le_bytes());
+    buf[6..8].copy_from_slice(&4u16.to_le_bytes());
+    // Time zone and timestamp accuracy stay 0.
+    buf[16..20].copy_from_slice(&(SNAPLEN as u32).to_le_bytes());
+    buf[20..24].copy_from_slice(&LINKTYPE_USER0.to_le_bytes());
+
+    buf
+}
+
+/// Check that `buf` starts with the global header of a capture.
+pub fn is_global_header(buf: &[u8]) -> bool {
+    buf.len() >= GLOBAL_HEADER_LEN
+        && buf[0..4] == PCAP_MAGIC.to_le_bytes()
+        && buf[20..24] == LINKTYPE_USER0.to_le_bytes()
+}
+
+/// One captured packet.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CaptureRecord<'a> {
+    /// When the packet's address was received, in µs of the sniffer's monotonic timer.
+    pub timestamp: u64,
+    pub frequency: u8,
+    pub rssi: i8,
+    pub crc_ok: bool,
+    pub pipe: u8,
+    pub payload: &'a [u8],
+}
+
+impl<'a> CaptureRecord<'a> {
+    /// Encode into `buf`, returns the length. Payloads past [`MAX_PAYLOAD_LEN`] are cut off.
+    pub fn encode(&self, buf: &mut [u8; MAX_RECORD_LEN]) -> usize {
+        let payload = &self.payload[..self.payload.len().min(MAX_PAYLOAD_LEN)];
+        let data_len = (PSEUDO_HEADER_LEN + payload.len()) as u32;
+
+        buf[0..4].copy_from_slice(&((self.timestamp / 1_000_000) as u32).to_le_bytes());
+        buf[4..8].copy_from_slice(&((self.timestamp % 1_000_000) as u32).to_le_bytes());
+        buf[8..12].copy_from_slice(&data_len.to_le_bytes());
+        buf[12..16].copy_from_slice(&data_len.to_le_bytes());
+        buf[16] = self.frequency;
+        buf[17] = self.rssi as u8;
+        buf[18] = if self.crc_ok { FLAG_CRC_OK } else { 0 };
+        buf[19] = self.pipe;
+        buf[20..20 + payload.len()].copy_from_slice(payload);
+
+        PCAP_RECORD_HEADER_LEN + data_len as usize
+    }
+
+    /// Decode the record at the start of `buf`, and return it with its encoded length.
+    pub fn decode(buf: &'a [u8]) -> Option<(Self, usize)> {
+        if buf.len() < PCAP_RECORD_HEADER_LEN + PSEUDO_HEADER_LEN {
+            return None;
+        }
+
+        let seconds = u32::from_le_bytes(buf[0..4].try_into().unwrap()) as u64;
+        let micros = u32::from_le_bytes(buf[4..8].try_into().unwrap()) as u64;
+        let data_len = u32::from_le_bytes(buf[8..12].try_into().unwrap()) as usize;
+
+        if !(PSEUDO_HEADER_LEN..=SNAPLEN).contains(&data_len)
+            || buf.len() < PCAP_RECORD_HEADER_LEN + data_len
+        {
+            return None;
+        }
+
+        let record = Self {
+            timestamp: seconds * 1_000_000 + micros,
+            frequency: buf[16],
+            rssi: buf[17] as i8,
+            crc_ok: buf[18] & FLAG_CRC_OK != 0,
+            pipe: buf[19],
+            payload: &buf[20..PCAP_RECORD_HEADER_LEN + data_len],
+        };
+
+        Some((record, PCAP_RECORD_HEADER_LEN + data_len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RECORD: CaptureRecord = CaptureRecord {
+        timestamp: 12_345_678,
+        frequency: 42,
+        rssi: -60,
+        crc_ok: true,
+        pipe: 1,
+        payload: &[0xd0, 0x01, 0x02],
+    };
+
+    #[test]
+    fn global_header_encoding() {
+        assert_eq!(
+            global_header(),
+            [
+                0xd4, 0xc3, 0xb2, 0xa1, 0x02, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x81, 0x00, 0x00, 0x00, 0x93, 0x00, 0x00, 0x00,
+            ]
+        );
+        assert!(is_global_header(&global_header()));
+        assert!(!is_global_header(&global_header()[..GLOBAL_HEADER_LEN - 1]));
+    }
+
+    #[test]
+    fn record_encoding() {
+        let mut buf = [0; MAX_RECORD_LEN];
+        let len = RECORD.encode(&mut buf);
+
+        assert_eq!(
+            buf[..len],
+            [
+                0x0c, 0x00, 0x00, 0x00, 0x4e, 0x46, 0x05, 0x00, 0x07, 0x00, 0x00, 0x00, 0x07, 0x00,
+                0x00, 0x00, 0x2a, 0xc4, 0x01, 0x01, 0xd0, 0x01, 0x02,
+            ]
+        );
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut buf = [0; MAX_RECORD_LEN];
+        let len = RECORD.encode(&mut buf);
+        assert_eq!(CaptureRecord::decode(&buf[..len]), Some((RECORD, len)));
+
+        let record = CaptureRecord {
+            crc_ok: false,
+            payload: &[0x55; MAX_PAYLOAD_LEN],
+            ..RECORD
+        };
+        let len = record.encode(&mut buf);
+        assert_eq!(len, MAX_RECORD_LEN);
+        assert_eq!(CaptureRecord::decode(&buf), Some((record, len)));
+    }
+
+    #[test]
+    fn rejects_truncated_records() {
+        let mut buf = [0; MAX_RECORD_LEN];
+        let len = RECORD.encode(&mut buf);
+
+        assert_eq!(CaptureRecord::decode(&buf[..len - 1]), None);
+        assert_eq!(CaptureRecord::decode(&[]), None);
+
+        buf[8] = 0;
+        assert_eq!(CaptureRecord::decode(&buf), None);
+    }
+}
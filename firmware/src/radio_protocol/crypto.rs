@@ -0,0 +1,510 @@
+//! # Authenticated encryption of slot payloads
+//!
+//! Every keyboard state and ACK frame is sealed with ChaCha8Poly1305. The nonce is never sent,
+//! both ends know it from the frame counter in the sync and the slot the frame is sent in, and
+//! the direction of the frame makes sure that a slot's frame and its ACK never share a nonce.
+//!
+//! The sync is sent in the clear, as observers have to follow it too, and only carries a tag.
+//!
+//! The Turbo slot profile's state frames carry the tag truncated to [`SHORT_TAG_LEN`], as a full
+//! one does not fit in its slots. A forgery still has to guess 64 bits, and a frame only counts
+//! in the slot it was sealed for.
+
+use crate::radio::Packet;
+use chacha20poly1305::{AeadInPlace, ChaCha8Poly1305, Key, KeyInit, Nonce, Tag};
+
+/// Size of the authentication tag appended to every sealed frame.
+pub const TAG_LEN: usize = 16;
+
+/// Size of the truncated tag of [`LinkCipher::seal_short`].
+pub const SHORT_TAG_LEN: usize = 8;
+
+/// The largest payload that can be sealed into a single packet.
+pub const MAX_PAYLOAD_LEN: usize = Packet::CAPACITY as usize - TAG_LEN;
+
+/// Errors when sealing or opening a frame.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+    /// The payload does not fit in a packet together with the tag, or the packet is too short
+    /// to contain a tag.
+    InvalidLength,
+    /// The frame was not sealed with this key and nonce, or it has been tampered with.
+    Authentication,
+    /// The frame is older than, or a duplicate of, an already accepted frame.
+    Replayed,
+}
+
+/// Direction of a frame.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Direction {
+    /// From a keyboard half to the dongle.
+    Upstream = 0,
+    /// From the dongle to a keyboard half.
+    Downstream = 1,
+}
+
+/// The position of a frame in the protocol, which the nonce is built from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub struct FrameNonce {
+    pub frame_counter: u32,
+    pub slot: u8,
+    pub direction: Direction,
+}
+
+impl FrameNonce {
+    /// The frame's position as a strictly increasing sequence number.
+    pub fn sequence(&self) -> u64 {
+        (self.frame_counter as u64) << 8 | self.slot as u64
+    }
+
+    fn to_nonce(self) -> Nonce {
+        let mut nonce = [0; 12];
+        nonce[0..4].copy_from_slice(&self.frame_counter.to_le_bytes());
+        nonce[4] = self.slot;
+        nonce[5] = self.direction as u8;
+        nonce.into()
+    }
+}
+
+/// ChaCha8Poly1305 with the frame nonce scheme of the protocol.
+pub struct LinkCipher {
+    cipher: ChaCha8Poly1305,
+}
+
+impl LinkCipher {
+    /// Create a cipher from a 256-bit key.
+    pub fn new(key: &[u8; 32]) -> Self {
+        Self {
+            cipher: ChaCha8Poly1305::new(Key::from_slice(key)),
+        }
+    }
+
+    /// Encrypt the packet's payload in place and append the tag.
+    pub fn seal(&self, nonce: FrameNonce, packet: &mut Packet) -> Result<(), Error> {
+        let len = packet.len() as usize;
+        if len > MAX_PAYLOAD_LEN {
+            return Err(Error::InvalidLength);
+        }
+
+        packet.set_len((len + TAG_LEN) as u8);
+        let (payload, tag) = packet.split_at_mut(len);
+
+        let t = self
+            .cipher
+            .encrypt_in_place_detached(&nonce.to_nonce(), &[], payload)
+            .map_err(|_| Error::InvalidLength)?;
+        tag.copy_from_slice(&t);
+
+        Ok(())
+    }
+
+    /// Verify the tag and decrypt the packet's payload in place, removing the tag.
+    ///
+    /// The payload is left untouched if the verification fails.
+    pub fn open(&self, nonce: FrameNonce, packet: &mut Packet) -> Result<(), Error> {
+        let len = (packet.len() as usize)
+            .checked_sub(TAG_LEN)
+            .ok_or(Error::InvalidLength)?;
+
+        let (payload, tag) = packet.split_at_mut(len);
+
+        self.cipher
+            .decrypt_in_place_detached(&nonce.to_nonce(), &[], payload, Tag::from_slice(tag))
+            .map_err(|_| Error::Authentication)?;
+
+        packet.set_len(len as u8);
+
+        Ok(())
+    }
+
+    /// Like [`Self::seal`], but only append the first [`SHORT_TAG_LEN`] bytes of the tag.
+    pub fn seal_short(&self, nonce: FrameNonce, packet: &mut Packet) -> Result<(), Error> {
+        self.seal(nonce, packet)?;
+        packet.set_len(packet.len() - (TAG_LEN - SHORT_TAG_LEN) as u8);
+
+        Ok(())
+    }
+
+    /// Verify a tag truncated by [`Self::seal_short`] and decrypt the packet's payload in place,
+    /// removing the tag.
+    ///
+    /// The AEAD only checks full tags, so the tag is recomputed by encrypting the decrypted
+    /// payload again. The payload is left untouched if the verification fails.
+    pub fn open_short(&self, nonce: FrameNonce, packet: &mut Packet) -> Result<(), Error> {
+        let len = (packet.len() as usize)
+            .checked_sub(SHORT_TAG_LEN)
+            .filter(|&len| len <= MAX_PAYLOAD_LEN)
+            .ok_or(Error::InvalidLength)?;
+
+        let nonce = nonce.to_nonce();
+        let (payload, tag) = packet.split_at_mut(len);
+
+        // The keystream is XORed in, encrypting the frame decrypts it.
+        self.cipher
+            .encrypt_in_place_detached(&nonce, &[], payload)
+            .map_err(|_| Error::InvalidLength)?;
+        // And encrypting a copy of that yields the frame again, with its full tag.
+        let mut ciphertext = [0; MAX_PAYLOAD_LEN];
+        let ciphertext = &mut ciphertext[..len];
+        ciphertext.copy_from_slice(payload);
+        let full_tag = self
+            .cipher
+            .encrypt_in_place_detached(&nonce, &[], ciphertext)
+            .map_err(|_| Error::InvalidLength)?;
+
+        // Compare without an early exit, like the AEAD does.
+        let difference = full_tag
+            .iter()
+            .zip(tag.iter())
+            .fold(0, |difference, (a, b)| difference | (a ^ b));
+        if difference != 0 {
+            payload.copy_from_slice(ciphertext);
+            return Err(Error::Authentication);
+        }
+
+        packet.set_len(len as u8);
+
+        Ok(())
+    }
+
+    /// Append a tag authenticating the packet's payload, which is sent in the clear.
+    pub fn sign(&self, nonce: FrameNonce, packet: &mut Packet) -> Result<(), Error> {
+        let len = packet.len() as usize;
+        if len > MAX_PAYLOAD_LEN {
+            return Err(Error::InvalidLength);
+        }
+
+        packet.set_len((len + TAG_LEN) as u8);
+        let (payload, tag) = packet.split_at_mut(len);
+
+        let t = self
+            .cipher
+            .encrypt_in_place_detached(&nonce.to_nonce(), payload, &mut [])
+            .map_err(|_| Error::InvalidLength)?;
+        tag.copy_from_slice(&t);
+
+        Ok(())
+    }
+
+    /// Verify the tag of a packet sent in the clear, and remove it.
+    pub fn verify(&self, nonce: FrameNonce, packet: &mut Packet) -> Result<(), Error> {
+        let len = (packet.len() as usize)
+            .checked_sub(TAG_LEN)
+            .ok_or(Error::InvalidLength)?;
+
+        let (payload, tag) = packet.split_at_mut(len);
+
+        self.cipher
+            .decrypt_in_place_detached(&nonce.to_nonce(), payload, &mut [], Tag::from_slice(tag))
+            .map_err(|_| Error::Authentication)?;
+
+        packet.set_len(len as u8);
+
+        Ok(())
+    }
+}
+
+/// Sliding window over the sequence numbers of received frames.
+///
+/// Frames older than the window, or already seen in it, are rejected.
+#[derive(Copy, Clone, Debug, defmt::Format)]
+pub struct ReplayWindow {
+    /// Highest sequence number accepted so far.
+    highest: Option<u64>,
+    /// Bit `n` is set if `highest - n` has been accepted.
+    seen: u64,
+}
+
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReplayWindow {
+    /// How far behind the highest sequence number a frame can be and still be accepted.
+    pub const SIZE: u64 = 64;
+
+    pub const fn new() -> Self {
+        Self {
+            highest: None,
+            seen: 0,
+        }
+    }
+
+    /// Check that a sequence number has not been seen, without marking it.
+    pub fn check(&self, sequence: u64) -> Result<(), Error> {
+        let Some(highest) = self.highest else {
+            return Ok(());
+        };
+
+        if sequence > highest {
+            return Ok(());
+        }
+
+        let age = highest - sequence;
+        if age >= Self::SIZE || self.seen & (1 << age) != 0 {
+            Err(Error::Replayed)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Mark a sequence number as seen, only do this after the frame has been authenticated.
+    pub fn accept(&mut self, sequence: u64) {
+        match self.highest {
+            Some(highest) if sequence <= highest => {
+                let age = highest - sequence;
+                if age < Self::SIZE {
+                    self.seen |= 1 << age;
+                }
+            }
+            Some(highest) => {
+                let shift = sequence - highest;
+                self.seen = if shift < Self::SIZE {
+                    self.seen << shift | 1
+                } else {
+                    1
+                };
+                self.highest = Some(sequence);
+            }
+            None => {
+                self.seen = 1;
+                self.highest = Some(sequence);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [0x42; 32];
+    const PAYLOAD: &[u8] = b"key state";
+
+    fn nonce(frame_counter: u32, slot: u8, direction: Direction) -> FrameNonce {
+        FrameNonce {
+            frame_counter,
+            slot,
+            direction,
+        }
+    }
+
+    fn sealed(nonce: FrameNonce) -> Packet {
+        let mut packet = Packet::new();
+        packet.copy_from_slice(PAYLOAD);
+        LinkCipher::new(&KEY).seal(nonce, &mut packet).unwrap();
+        packet
+    }
+
+    #[test]
+    fn sealed_frame_opens() {
+        let nonce = nonce(7, 3, Direction::Upstream);
+        let mut packet = sealed(nonce);
+        assert_eq!(packet.len() as usize, PAYLOAD.len() + TAG_LEN);
+        assert_ne!(&packet[..PAYLOAD.len()], PAYLOAD);
+
+        LinkCipher::new(&KEY).open(nonce, &mut packet).unwrap();
+        assert_eq!(&packet[..], PAYLOAD);
+    }
+
+    #[test]
+    fn tampered_frame_is_rejected() {
+        let nonce = nonce(7, 3, Direction::Upstream);
+        let cipher = LinkCipher::new(&KEY);
+
+        for index in [0, PAYLOAD.len()] {
+            let mut packet = sealed(nonce);
+            packet[index] ^= 1;
+            let tampered = packet.to_vec();
+
+            assert_eq!(cipher.open(nonce, &mut packet), Err(Error::Authentication));
+            assert_eq!(&packet[..], &tampered[..], "byte {}", index);
+        }
+
+        let mut packet = sealed(nonce);
+        assert_eq!(
+            LinkCipher::new(&[0x43; 32]).open(nonce, &mut packet),
+            Err(Error::Authentication)
+        );
+    }
+
+    #[test]
+    fn nonce_binds_slot_and_direction() {
+        let sent = nonce(7, 3, Direction::Upstream);
+        let cipher = LinkCipher::new(&KEY);
+
+        for other in [
+            nonce(7, 4, Direction::Upstream),
+            nonce(8, 3, Direction::Upstream),
+            nonce(7, 3, Direction::Downstream),
+        ] {
+            let mut packet = sealed(sent);
+            assert_eq!(
+                cipher.open(other, &mut packet),
+                Err(Error::Authentication),
+                "{:?}",
+                other
+            );
+        }
+
+        // A frame and its ACK never share a keystream.
+        assert_ne!(
+            &sealed(sent)[..PAYLOAD.len()],
+            &sealed(nonce(7, 3, Direction::Downstream))[..PAYLOAD.len()]
+        );
+    }
+
+    #[test]
+    fn signed_frame_verifies() {
+        let nonce = nonce(7, 0, Direction::Downstream);
+        let cipher = LinkCipher::new(&KEY);
+
+        let mut packet = Packet::new();
+        packet.copy_from_slice(PAYLOAD);
+        cipher.sign(nonce, &mut packet).unwrap();
+        assert_eq!(&packet[..PAYLOAD.len()], PAYLOAD);
+
+        for index in [0, PAYLOAD.len()] {
+            let mut tampered = Packet::new();
+            tampered.copy_from_slice(&packet);
+            tampered[index] ^= 1;
+            assert_eq!(
+                cipher.verify(nonce, &mut tampered),
+                Err(Error::Authentication),
+                "byte {}",
+                index
+            );
+        }
+
+        let mut other = Packet::new();
+        other.copy_from_slice(&packet);
+        assert_eq!(
+            cipher.verify(
+                FrameNonce {
+                    frame_counter: 8,
+                    ..nonce
+                },
+                &mut other
+            ),
+            Err(Error::Authentication)
+        );
+
+        cipher.verify(nonce, &mut packet).unwrap();
+        assert_eq!(&packet[..], PAYLOAD);
+    }
+
+    #[test]
+    fn lengths_are_checked() {
+        let nonce = nonce(0, 0, Direction::Downstream);
+        let cipher = LinkCipher::new(&KEY);
+
+        let mut packet = Packet::new();
+        packet.set_len(MAX_PAYLOAD_LEN as u8 + 1);
+        assert_eq!(cipher.seal(nonce, &mut packet), Err(Error::InvalidLength));
+
+        packet.set_len(MAX_PAYLOAD_LEN as u8);
+        cipher.seal(nonce, &mut packet).unwrap();
+        assert_eq!(packet.len(), Packet::CAPACITY);
+
+        packet.set_len(TAG_LEN as u8 - 1);
+        assert_eq!(cipher.open(nonce, &mut packet), Err(Error::InvalidLength));
+    }
+
+    #[test]
+    fn short_tags_open() {
+        let nonce = nonce(7, 3, Direction::Upstream);
+        let cipher = LinkCipher::new(&KEY);
+
+        let mut packet = Packet::new();
+        packet.copy_from_slice(PAYLOAD);
+        cipher.seal_short(nonce, &mut packet).unwrap();
+        assert_eq!(packet.len() as usize, PAYLOAD.len() + SHORT_TAG_LEN);
+        // The same frame as sealed with the full tag, cut short.
+        assert_eq!(&packet[..], &sealed(nonce)[..PAYLOAD.len() + SHORT_TAG_LEN]);
+
+        for index in [0, PAYLOAD.len()] {
+            let mut tampered = Packet::new();
+            tampered.copy_from_slice(&packet);
+            tampered[index] ^= 1;
+            let before = tampered.to_vec();
+
+            assert_eq!(
+                cipher.open_short(nonce, &mut tampered),
+                Err(Error::Authentication),
+                "byte {}",
+                index
+            );
+            assert_eq!(&tampered[..], &before[..], "byte {}", index);
+        }
+
+        let mut other = Packet::new();
+        other.copy_from_slice(&packet);
+        assert_eq!(
+            cipher.open_short(FrameNonce { slot: 4, ..nonce }, &mut other),
+            Err(Error::Authentication)
+        );
+
+        cipher.open_short(nonce, &mut packet).unwrap();
+        assert_eq!(&packet[..], PAYLOAD);
+
+        packet.set_len(SHORT_TAG_LEN as u8 - 1);
+        assert_eq!(
+            cipher.open_short(nonce, &mut packet),
+            Err(Error::InvalidLength)
+        );
+    }
+
+    #[test]
+    fn window_rejects_duplicates() {
+        let mut window = ReplayWindow::new();
+        for sequence in [10, 12, 11, 5] {
+            assert_eq!(window.check(sequence), Ok(()), "{}", sequence);
+            window.accept(sequence);
+        }
+
+        for sequence in [10, 12, 11, 5] {
+            assert_eq!(window.check(sequence), Err(Error::Replayed), "{}", sequence);
+        }
+        // Not seen yet, out of order but in the window.
+        assert_eq!(window.check(9), Ok(()));
+        assert_eq!(window.check(13), Ok(()));
+    }
+
+    #[test]
+    fn window_rejects_old_frames() {
+        let mut window = ReplayWindow::new();
+        window.accept(100);
+
+        assert_eq!(window.check(100 - ReplayWindow::SIZE + 1), Ok(()));
+        assert_eq!(window.check(100 - ReplayWindow::SIZE), Err(Error::Replayed));
+        assert_eq!(window.check(0), Err(Error::Replayed));
+    }
+
+    #[test]
+    fn window_slides() {
+        let mut window = ReplayWindow::new();
+        window.accept(1);
+        window.accept(3);
+
+        // Within the window, what has been seen moves along.
+        window.accept(3 + ReplayWindow::SIZE - 2);
+        assert_eq!(window.check(3), Err(Error::Replayed));
+        assert_eq!(window.check(2), Ok(()));
+        assert_eq!(window.check(1), Err(Error::Replayed));
+
+        // Past the window, only the new highest has been seen.
+        let highest = 1000;
+        window.accept(highest);
+        assert_eq!(window.check(highest), Err(Error::Replayed));
+        for age in 1..ReplayWindow::SIZE {
+            assert_eq!(window.check(highest - age), Ok(()), "age {}", age);
+        }
+        assert_eq!(
+            window.check(highest - ReplayWindow::SIZE),
+            Err(Error::Replayed)
+        );
+    }
+}
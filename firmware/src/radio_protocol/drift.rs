@@ -0,0 +1,231 @@
+//! # Sync tracking on a keyboard half
+//!
+//! The dongle starts a master frame every [`super::SlotProfile::master_frame_period`] of its own
+//! clock, and puts its scheduled TX time in every sync. Comparing how far apart two syncs are on
+//! the dongle's clock and on the half's gives the offset between the two crystals, which lets the
+//! half predict when the next sync arrives even after missing a few.
+//!
+//! The half only listens in a window around the predicted time. The window grows with the time
+//! since the last sync heard, by the drift estimate's uncertainty.
+//!
+//! The dongle's timestamp is when the sync starts, while the half's radio timestamps a packet
+//! when its address has been received. The half passes in the start of the sync, its timestamp
+//! less [`crate::radio::ADDRESS_AIRTIME`], so both clocks tell the time of the same event.
+
+use fugit::{TimerDurationU64, TimerInstantU64};
+
+/// Crystals are specified to ±50 ppm or better, so the two ends are at most this far apart.
+const MAX_DRIFT_PPM: i64 = 100;
+
+/// How far off the drift estimate can still be once it has settled.
+const SETTLED_DRIFT_PPM: i64 = 10;
+
+/// How many syncs the drift estimate needs before it is considered settled.
+const SETTLING_SYNCS: u32 = 4;
+
+/// Timing jitter of the sync's RX timestamp that is not explained by drift.
+const SYNC_JITTER: TimerDurationU64<1_000_000> = TimerDurationU64::micros(50);
+
+/// Syncs further apart than this are not used for the drift estimate, the dongle's timestamps
+/// wrap and a long gap is likely a restarted dongle.
+const MAX_DRIFT_INTERVAL_US: u64 = 10_000_000;
+
+/// Where and how wide to listen for an expected sync.
+#[derive(Copy, Clone, Debug, defmt::Format)]
+pub struct SyncWindow {
+    /// The predicted start of the sync.
+    pub expected: TimerInstantU64<1_000_000>,
+    /// How far before and after `expected` the sync may start.
+    pub half_width: TimerDurationU64<1_000_000>,
+}
+
+impl SyncWindow {
+    /// The earliest start of the sync.
+    pub fn start(&self) -> TimerInstantU64<1_000_000> {
+        self.expected - self.half_width
+    }
+
+    /// The latest start of the sync.
+    pub fn end(&self) -> TimerInstantU64<1_000_000> {
+        self.expected + self.half_width
+    }
+}
+
+/// Keeps a keyboard half's schedule in step with the dongle's clock.
+pub struct SyncTracker {
+    /// The last sync heard: the dongle's TX timestamp and the local time it started at.
+    anchor: Option<(u32, TimerInstantU64<1_000_000>)>,
+    /// The local clock's rate relative to the dongle's, in parts per billion for precision.
+    drift_ppb: i64,
+    /// Number of drift measurements so far, saturating.
+    measurements: u32,
+}
+
+impl Default for SyncTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SyncTracker {
+    pub const fn new() -> Self {
+        Self {
+            anchor: None,
+            drift_ppb: 0,
+            measurements: 0,
+        }
+    }
+
+    /// The current drift estimate in ppm, positive when the local clock runs fast.
+    pub fn drift_ppm(&self) -> i32 {
+        (self.drift_ppb / 1000) as i32
+    }
+
+    /// Update the schedule and drift estimate from a sync sent at `dongle_timestamp`, which
+    /// started at `rx_time` on the local clock.
+    pub fn sync_received(&mut self, dongle_timestamp: u32, rx_time: TimerInstantU64<1_000_000>) {
+        if let Some((last_timestamp, last_rx_time)) = self.anchor {
+            let dongle_elapsed = dongle_timestamp.wrapping_sub(last_timestamp) as u64;
+            let local_elapsed = rx_time
+                .checked_duration_since(last_rx_time)
+                .map(|d| d.ticks())
+                .unwrap_or(0);
+
+            if dongle_elapsed > 0 && dongle_elapsed <= MAX_DRIFT_INTERVAL_US && local_elapsed > 0 {
+                let measured = (local_elapsed as i64 - dongle_elapsed as i64) * 1_000_000_000
+                    / dongle_elapsed as i64;
+                let measured = measured.clamp(-MAX_DRIFT_PPM * 1000, MAX_DRIFT_PPM * 1000);
+
+                // Average the measurements while settling, then follow slowly.
+                let weight = (self.measurements as i64 + 1).min(8);
+                self.drift_ppb += (measured - self.drift_ppb) / weight;
+                self.measurements = self.measurements.saturating_add(1);
+            }
+        }
+
+        self.anchor = Some((dongle_timestamp, rx_time));
+    }
+
+    /// How long `duration` of the dongle's clock takes on the local clock, from the drift
+    /// estimate.
+    pub fn local_duration(
+        &self,
+        duration: TimerDurationU64<1_000_000>,
+    ) -> TimerDurationU64<1_000_000> {
+        let correction = duration.ticks() as i64 * self.drift_ppb / 1_000_000_000;
+        TimerDurationU64::micros((duration.ticks() as i64 + correction) as u64)
+    }
+
+    /// Where to listen for the sync `frames` master frames of `period` after the last one heard.
+    ///
+    /// Returns `None` if no sync has been heard yet.
+    pub fn window(&self, frames: u32, period: TimerDurationU64<1_000_000>) -> Option<SyncWindow> {
+        let (_, rx_time) = self.anchor?;
+
+        let dongle_elapsed = period.ticks() * frames as u64;
+
+        let uncertainty_ppm = if self.measurements >= SETTLING_SYNCS {
+            SETTLED_DRIFT_PPM
+        } else {
+            MAX_DRIFT_PPM
+        };
+        let uncertainty = dongle_elapsed * uncertainty_ppm as u64 / 1_000_000;
+
+        Some(SyncWindow {
+            expected: rx_time + self.local_duration(TimerDurationU64::micros(dongle_elapsed)),
+            half_width: SYNC_JITTER + TimerDurationU64::micros(uncertainty),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD: TimerDurationU64<1_000_000> = TimerDurationU64::secs(1);
+
+    /// Feed `syncs` syncs one `PERIOD` apart, from a local clock running `ppm` fast.
+    fn track(tracker: &mut SyncTracker, ppm: u64, syncs: u64) -> TimerInstantU64<1_000_000> {
+        let mut rx_time = TimerInstantU64::from_ticks(0);
+
+        for i in 0..syncs {
+            let dongle = 1_000 + i * PERIOD.ticks();
+            rx_time = TimerInstantU64::from_ticks(dongle + dongle * ppm / 1_000_000);
+            tracker.sync_received(dongle as u32, rx_time);
+        }
+
+        rx_time
+    }
+
+    #[test]
+    fn no_window_before_the_first_sync() {
+        assert!(SyncTracker::new().window(1, PERIOD).is_none());
+    }
+
+    #[test]
+    fn follows_a_fixed_drift() {
+        let mut tracker = SyncTracker::new();
+        let last = track(&mut tracker, 40, 6);
+        assert_eq!(tracker.drift_ppm(), 40);
+
+        let window = tracker.window(1, PERIOD).unwrap();
+        assert_eq!(window.expected, last + TimerDurationU64::micros(1_000_040));
+        assert_eq!(
+            tracker
+                .local_duration(TimerDurationU64::millis(500))
+                .ticks(),
+            500_020
+        );
+    }
+
+    #[test]
+    fn window_narrows_once_settled() {
+        let mut tracker = SyncTracker::new();
+        track(&mut tracker, 0, 2);
+        let unsettled = tracker.window(1, PERIOD).unwrap().half_width;
+        assert_eq!(unsettled, SYNC_JITTER + TimerDurationU64::micros(100));
+
+        track(&mut tracker, 0, SETTLING_SYNCS as u64 + 1);
+        let settled = tracker.window(1, PERIOD).unwrap().half_width;
+        assert_eq!(settled, SYNC_JITTER + TimerDurationU64::micros(10));
+    }
+
+    #[test]
+    fn missed_syncs_widen_the_window() {
+        let mut tracker = SyncTracker::new();
+        let last = track(&mut tracker, 20, 6);
+
+        let one = tracker.window(1, PERIOD).unwrap();
+        let three = tracker.window(3, PERIOD).unwrap();
+        assert_eq!(three.expected, last + TimerDurationU64::micros(3_000_060));
+        assert_eq!(
+            three.half_width - SYNC_JITTER,
+            (one.half_width - SYNC_JITTER) * 3
+        );
+        assert_eq!(three.half_width, SYNC_JITTER + TimerDurationU64::micros(30));
+    }
+
+    #[test]
+    fn drift_is_clamped() {
+        let mut tracker = SyncTracker::new();
+        track(&mut tracker, 500, 10);
+
+        assert_eq!(tracker.drift_ppm(), MAX_DRIFT_PPM as i32);
+    }
+
+    #[test]
+    fn long_gaps_are_not_measured() {
+        let mut tracker = SyncTracker::new();
+        track(&mut tracker, 30, 3);
+
+        // A restarted dongle, its timestamps start over.
+        let last = tracker.anchor.unwrap().1;
+        tracker.sync_received(5_000, last + TimerDurationU64::secs(20));
+        assert_eq!(tracker.drift_ppm(), 30);
+        assert_eq!(tracker.measurements, 2);
+        assert_eq!(
+            tracker.anchor,
+            Some((5_000, last + TimerDurationU64::secs(20)))
+        );
+    }
+}
@@ -0,0 +1,312 @@
+//! # Channel hopping
+//!
+//! Every keyboard half and dongle pair hops according to its own pseudo-random sequence, seeded
+//! from the session's hop seed (see [`super::key_schedule`]). A sequence has one entry per slot of
+//! a link's frame and:
+//!
+//! - uses every channel in the 2.400 - 2.483 GHz band exactly twice,
+//! - never hops to the same or an adjacent channel, also when wrapping around to the next frame.
+//!
+//! With adaptive frequency hopping (see [`super::afh`]) only the channels in the link's
+//! [`ChannelMap`] are used, each about equally often, and the sequence is regenerated from the
+//! same seed when the map changes.
+
+use rand_chacha::{
+    rand_core::{RngCore, SeedableRng},
+    ChaCha8Rng,
+};
+
+/// Number of channels, 2400 MHz + `0..NUM_CHANNELS`.
+pub const NUM_CHANNELS: usize = 84;
+
+/// Number of slots in a link's frame, every channel twice.
+pub const SLOTS_PER_FRAME: usize = 2 * NUM_CHANNELS;
+
+/// The fewest channels a channel map may use.
+pub const MIN_USED_CHANNELS: usize = 20;
+
+/// Which channels are used for hopping, one bit per channel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub struct ChannelMap(pub [u8; ChannelMap::LEN]);
+
+impl ChannelMap {
+    /// Size of the map on-air.
+    pub const LEN: usize = NUM_CHANNELS.div_ceil(8);
+
+    /// All channels in use.
+    pub const ALL: Self = {
+        let mut map = [0xff; Self::LEN];
+        map[Self::LEN - 1] = 0xff >> (8 * Self::LEN - NUM_CHANNELS);
+        Self(map)
+    };
+
+    /// Check if a channel is used.
+    pub fn is_used(&self, channel: u8) -> bool {
+        (channel as usize) < NUM_CHANNELS
+            && self.0[channel as usize / 8] & (1 << (channel % 8)) != 0
+    }
+
+    /// Add or remove a channel.
+    pub fn set_used(&mut self, channel: u8, used: bool) {
+        if (channel as usize) < NUM_CHANNELS {
+            if used {
+                self.0[channel as usize / 8] |= 1 << (channel % 8);
+            } else {
+                self.0[channel as usize / 8] &= !(1 << (channel % 8));
+            }
+        }
+    }
+
+    /// Number of used channels.
+    pub fn num_used(&self) -> usize {
+        self.0.iter().map(|byte| byte.count_ones() as usize).sum()
+    }
+
+    /// Check that the map has enough channels and none out of range.
+    pub fn is_valid(&self) -> bool {
+        self.num_used() >= MIN_USED_CHANNELS
+            && self.0[Self::LEN - 1] & !Self::ALL.0[Self::LEN - 1] == 0
+    }
+}
+
+/// A channel hopping selector implementation.
+#[derive(Clone)]
+pub struct ChannelHopping {
+    seed: [u8; 32],
+    channel_map: ChannelMap,
+    sequence: [u8; SLOTS_PER_FRAME],
+    state: u8,
+}
+
+impl ChannelHopping {
+    /// Create a new channel hopping selector with the sequence for a hop seed, using all
+    /// channels.
+    pub fn new(seed: &[u8; 32]) -> Self {
+        Self {
+            seed: *seed,
+            channel_map: ChannelMap::ALL,
+            sequence: generate_sequence(&mut ChaCha8Rng::from_seed(*seed), &ChannelMap::ALL),
+            state: 0,
+        }
+    }
+
+    /// The channels in use.
+    pub fn channel_map(&self) -> &ChannelMap {
+        &self.channel_map
+    }
+
+    /// Only hop on the channels in `channel_map` from now on.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the channel map is not valid.
+    pub fn set_channel_map(&mut self, channel_map: ChannelMap) {
+        assert!(channel_map.is_valid());
+
+        self.channel_map = channel_map;
+        self.sequence = generate_sequence(&mut ChaCha8Rng::from_seed(self.seed), &channel_map);
+    }
+
+    /// Get the current channel.
+    pub fn current_channel(&self) -> u8 {
+        self.sequence[self.state as usize]
+    }
+
+    /// Move to the next channel.
+    pub fn next_channel(&mut self) {
+        self.state = ((self.state as usize + 1) % SLOTS_PER_FRAME) as u8;
+    }
+
+    /// Check if the current channel is the initial state.
+    pub fn is_initial_state(&self) -> bool {
+        self.state == 0
+    }
+
+    /// Reset to initial state.
+    pub fn reset(&mut self) {
+        self.state = 0;
+    }
+
+    /// Return the current timeslot.
+    pub fn state(&self) -> u8 {
+        self.state
+    }
+}
+
+/// Uniform-enough pick in `0..n`, the bias is at most `n / 2^32`.
+fn pick(rng: &mut ChaCha8Rng, n: usize) -> usize {
+    ((rng.next_u32() as u64 * n as u64) >> 32) as usize
+}
+
+fn far_apart(a: u8, b: u8) -> bool {
+    a.abs_diff(b) > 1
+}
+
+/// Pick each slot's channel among the allowed ones, weighted by how many uses each channel has
+/// left, starting over on the rare dead end.
+fn generate_sequence(rng: &mut ChaCha8Rng, channel_map: &ChannelMap) -> [u8; SLOTS_PER_FRAME] {
+    // Spread the slots over the used channels, the lowest channels get the leftover slots.
+    let mut uses = [0u8; NUM_CHANNELS];
+    let num_used = channel_map.num_used();
+    let mut leftover = SLOTS_PER_FRAME % num_used;
+
+    for channel in 0..NUM_CHANNELS as u8 {
+        if channel_map.is_used(channel) {
+            uses[channel as usize] = (SLOTS_PER_FRAME / num_used) as u8;
+
+            if leftover > 0 {
+                uses[channel as usize] += 1;
+                leftover -= 1;
+            }
+        }
+    }
+
+    'attempt: loop {
+        let mut remaining = uses;
+        let mut sequence = [0u8; SLOTS_PER_FRAME];
+
+        for i in 0..SLOTS_PER_FRAME {
+            let allowed = |channel: u8| {
+                (i == 0 || far_apart(sequence[i - 1], channel))
+                    && (i != SLOTS_PER_FRAME - 1 || far_apart(sequence[0], channel))
+            };
+
+            let candidates: usize = (0..NUM_CHANNELS as u8)
+                .filter(|&channel| allowed(channel))
+                .map(|channel| remaining[channel as usize] as usize)
+                .sum();
+            if candidates == 0 {
+                continue 'attempt;
+            }
+
+            let mut pick = pick(rng, candidates);
+            let mut channel = 0;
+            for candidate in (0..NUM_CHANNELS as u8).filter(|&channel| allowed(channel)) {
+                let weight = remaining[candidate as usize] as usize;
+                if pick < weight {
+                    channel = candidate;
+                    break;
+                }
+                pick -= weight;
+            }
+
+            sequence[i] = channel;
+            remaining[channel as usize] -= 1;
+        }
+
+        return sequence;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sequence(seed: u8) -> [u8; SLOTS_PER_FRAME] {
+        let mut hopping = ChannelHopping::new(&[seed; 32]);
+        let mut sequence = [0; SLOTS_PER_FRAME];
+
+        for channel in &mut sequence {
+            *channel = hopping.current_channel();
+            hopping.next_channel();
+        }
+
+        assert!(hopping.is_initial_state());
+        sequence
+    }
+
+    #[test]
+    fn every_channel_twice() {
+        for seed in 0..32 {
+            let mut uses = [0; NUM_CHANNELS];
+            for channel in sequence(seed) {
+                uses[channel as usize] += 1;
+            }
+
+            assert_eq!(uses, [2; NUM_CHANNELS], "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn no_adjacent_hops() {
+        for seed in 0..32 {
+            let sequence = sequence(seed);
+
+            // Including the wrap around to the next frame.
+            for i in 0..SLOTS_PER_FRAME {
+                let a = sequence[i];
+                let b = sequence[(i + 1) % SLOTS_PER_FRAME];
+                assert!(
+                    a.abs_diff(b) > 1,
+                    "seed {}, slot {}: {} -> {}",
+                    seed,
+                    i,
+                    a,
+                    b
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn reduced_channel_map() {
+        // Channels under a Wi-Fi network on channel 6 and the BLE advertising channels.
+        let mut map = ChannelMap::ALL;
+        for channel in (26..48).chain([2, 26, 80]) {
+            map.set_used(channel, false);
+        }
+        assert!(map.is_valid());
+
+        for seed in 0..32 {
+            let mut hopping = ChannelHopping::new(&[seed; 32]);
+            hopping.set_channel_map(map);
+
+            let mut uses = [0; NUM_CHANNELS];
+            let mut previous = None;
+            for _ in 0..2 * SLOTS_PER_FRAME {
+                let channel = hopping.current_channel();
+                uses[channel as usize] += 1;
+
+                assert!(map.is_used(channel), "seed {}: channel {}", seed, channel);
+                if let Some(previous) = previous {
+                    assert!(far_apart(previous, channel), "seed {}", seed);
+                }
+
+                previous = Some(channel);
+                hopping.next_channel();
+            }
+
+            // Every used channel gets about the same share of the slots.
+            let fewest = uses.iter().filter(|&&n| n > 0).min().unwrap();
+            let most = uses.iter().max().unwrap();
+            assert!(most - fewest <= 2, "seed {}: {:?}", seed, uses);
+        }
+    }
+
+    #[test]
+    fn too_few_channels_is_invalid() {
+        let mut map = ChannelMap([0; ChannelMap::LEN]);
+        for channel in 0..MIN_USED_CHANNELS as u8 - 1 {
+            map.set_used(2 * channel, true);
+        }
+
+        assert!(!map.is_valid());
+        map.set_used(83, true);
+        assert!(map.is_valid());
+    }
+
+    #[test]
+    fn same_seed_same_sequence() {
+        assert_eq!(sequence(7), sequence(7));
+    }
+
+    #[test]
+    fn different_seeds_different_sequences() {
+        let a = sequence(1);
+        let b = sequence(2);
+        let same_slots = a.iter().zip(b.iter()).filter(|(a, b)| a == b).count();
+
+        // Two random sequences share about 2 slots.
+        assert!(same_slots < 16, "{} slots collide", same_slots);
+    }
+}
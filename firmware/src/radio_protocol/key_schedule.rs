@@ -0,0 +1,219 @@
+//! # Session key schedule
+//!
+//! Everything a session needs is derived from the ECDH shared secret and both devices' IDs with
+//! HKDF-SHA256:
+//!
+//! 1. `prk = HKDF-Extract(salt = keyboard UID || dongle UID, ikm = shared secret)`
+//! 2. The hop sequence seed, on-air address and first chain key are expanded from `prk`.
+//! 3. Each key epoch expands its keyboard→dongle and dongle→keyboard keys from the chain key.
+//!    A rekey replaces the chain key with one expanded from itself, so old keys can not be
+//!    recovered from new ones.
+
+use super::Uid;
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+const HOP_SEED_INFO: &[u8] = b"corne hop seed";
+const ADDRESS_INFO: &[u8] = b"corne address";
+const CHAIN_KEY_INFO: &[u8] = b"corne chain key";
+const UPSTREAM_KEY_INFO: &[u8] = b"corne upstream key";
+const DOWNSTREAM_KEY_INFO: &[u8] = b"corne downstream key";
+const REKEY_INFO: &[u8] = b"corne rekey";
+
+/// How many master frames a key epoch lasts before the dongle rekeys.
+pub const REKEY_INTERVAL_FRAMES: u32 = 1 << 16;
+
+/// On-air address of a session, in the radio's base + prefix form.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub struct Address {
+    pub base: u32,
+    pub prefix: u8,
+}
+
+/// Session constants derived once from the shared secret.
+#[derive(Clone)]
+pub struct LinkParameters {
+    /// Seed of the session's channel hopping sequence.
+    pub hop_seed: [u8; 32],
+    /// The session's on-air address.
+    pub address: Address,
+}
+
+/// The keys of one key epoch, and the chain key to the next epoch.
+#[derive(Clone)]
+pub struct KeySchedule {
+    epoch: u32,
+    chain_key: [u8; 32],
+    upstream_key: [u8; 32],
+    downstream_key: [u8; 32],
+}
+
+/// Derive the session's constant parameters and its first key epoch.
+pub fn derive(
+    shared_secret: &[u8; 32],
+    keyboard_uid: Uid,
+    dongle_uid: Uid,
+) -> (LinkParameters, KeySchedule) {
+    let mut salt = [0; 2 * Uid::LEN];
+    salt[..Uid::LEN].copy_from_slice(&keyboard_uid.0);
+    salt[Uid::LEN..].copy_from_slice(&dongle_uid.0);
+
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared_secret);
+
+    let mut hop_seed = [0; 32];
+    expand(&hkdf, HOP_SEED_INFO, &mut hop_seed);
+
+    let mut address = [0; 5];
+    expand(&hkdf, ADDRESS_INFO, &mut address);
+
+    let mut chain_key = [0; 32];
+    expand(&hkdf, CHAIN_KEY_INFO, &mut chain_key);
+
+    let parameters = LinkParameters {
+        hop_seed,
+        address: Address {
+            base: u32::from_le_bytes(address[..4].try_into().unwrap()),
+            prefix: address[4],
+        },
+    };
+
+    (parameters, KeySchedule::from_chain_key(0, chain_key))
+}
+
+fn expand(hkdf: &Hkdf<Sha256>, info: &[u8], okm: &mut [u8]) {
+    // Only fails for outputs longer than 255 hashes.
+    hkdf.expand(info, okm).unwrap();
+}
+
+impl KeySchedule {
+    fn from_chain_key(epoch: u32, chain_key: [u8; 32]) -> Self {
+        let hkdf = Hkdf::<Sha256>::from_prk(&chain_key).unwrap();
+
+        let mut upstream_key = [0; 32];
+        expand(&hkdf, UPSTREAM_KEY_INFO, &mut upstream_key);
+
+        let mut downstream_key = [0; 32];
+        expand(&hkdf, DOWNSTREAM_KEY_INFO, &mut downstream_key);
+
+        Self {
+            epoch,
+            chain_key,
+            upstream_key,
+            downstream_key,
+        }
+    }
+
+    /// The key epoch, 0 for the keys derived directly from the shared secret.
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    /// Key for frames from the keyboard half to the dongle.
+    pub fn upstream_key(&self) -> &[u8; 32] {
+        &self.upstream_key
+    }
+
+    /// Key for frames from the dongle to the keyboard half.
+    pub fn downstream_key(&self) -> &[u8; 32] {
+        &self.downstream_key
+    }
+
+    /// The keys of the next epoch.
+    pub fn next(&self) -> Self {
+        let hkdf = Hkdf::<Sha256>::from_prk(&self.chain_key).unwrap();
+
+        let mut chain_key = [0; 32];
+        expand(&hkdf, REKEY_INFO, &mut chain_key);
+
+        Self::from_chain_key(self.epoch.wrapping_add(1), chain_key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHARED_SECRET: [u8; 32] = [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e,
+        0x0f, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d,
+        0x1e, 0x1f,
+    ];
+    const KEYBOARD_UID: Uid = Uid([0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]);
+    const DONGLE_UID: Uid = Uid([0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8]);
+
+    #[test]
+    fn link_parameters_known_answer() {
+        let (parameters, _) = derive(&SHARED_SECRET, KEYBOARD_UID, DONGLE_UID);
+
+        assert_eq!(
+            parameters.hop_seed,
+            [
+                0xf2, 0x04, 0x9d, 0x71, 0x86, 0xd3, 0xf3, 0xd5, 0x01, 0xa2, 0x3c, 0x28, 0x4e, 0xfa,
+                0x5e, 0xc0, 0xe0, 0x39, 0xd6, 0x62, 0x0a, 0x2a, 0x8c, 0x86, 0x0a, 0x39, 0xdb, 0x8c,
+                0x96, 0x0f, 0x93, 0x61,
+            ]
+        );
+        assert_eq!(
+            parameters.address,
+            Address {
+                base: 0xda82ed99,
+                prefix: 0x4e
+            }
+        );
+    }
+
+    #[test]
+    fn first_epoch_known_answer() {
+        let (_, keys) = derive(&SHARED_SECRET, KEYBOARD_UID, DONGLE_UID);
+
+        assert_eq!(keys.epoch(), 0);
+        assert_eq!(
+            keys.upstream_key(),
+            &[
+                0xfb, 0xb2, 0xb2, 0x57, 0x25, 0xfa, 0x41, 0x11, 0x02, 0x83, 0x67, 0xcb, 0xe2, 0x0c,
+                0x81, 0x27, 0x12, 0xbf, 0xcb, 0x0e, 0xa6, 0x0e, 0xd7, 0xbf, 0x08, 0x43, 0xd4, 0x34,
+                0xf6, 0xe5, 0x7d, 0xa3,
+            ]
+        );
+        assert_eq!(
+            keys.downstream_key(),
+            &[
+                0x58, 0x81, 0x4f, 0x27, 0xc1, 0xfb, 0x2e, 0xa3, 0x6a, 0xcd, 0x2e, 0x41, 0xe0, 0x09,
+                0x47, 0x99, 0xe7, 0x9e, 0x06, 0xb0, 0x21, 0xb1, 0x0e, 0x50, 0x50, 0x5b, 0x41, 0xf7,
+                0xa4, 0xf9, 0x2d, 0xe8,
+            ]
+        );
+    }
+
+    #[test]
+    fn rekey_known_answer() {
+        let (_, keys) = derive(&SHARED_SECRET, KEYBOARD_UID, DONGLE_UID);
+        let keys = keys.next();
+
+        assert_eq!(keys.epoch(), 1);
+        assert_eq!(
+            keys.upstream_key(),
+            &[
+                0xe8, 0x4c, 0xc7, 0x79, 0x9e, 0xee, 0x41, 0xff, 0xdb, 0x88, 0x7c, 0xb1, 0xbc, 0xfc,
+                0x97, 0x7e, 0x5f, 0x34, 0xda, 0xe8, 0xfd, 0x70, 0xee, 0x3d, 0x32, 0xa4, 0x70, 0x2d,
+                0xda, 0x54, 0x18, 0xa7,
+            ]
+        );
+        assert_eq!(
+            keys.downstream_key(),
+            &[
+                0x21, 0x76, 0xa1, 0xb9, 0xf7, 0x98, 0xe8, 0x1d, 0x33, 0xaf, 0xfb, 0x1a, 0x8f, 0xad,
+                0x94, 0x3a, 0xa5, 0xf2, 0x64, 0x77, 0xb2, 0xe5, 0x4b, 0x09, 0x47, 0x6e, 0xb7, 0x08,
+                0x66, 0x46, 0x80, 0xdf,
+            ]
+        );
+    }
+
+    #[test]
+    fn uids_are_bound_in_order() {
+        let (a, _) = derive(&SHARED_SECRET, KEYBOARD_UID, DONGLE_UID);
+        let (b, _) = derive(&SHARED_SECRET, DONGLE_UID, KEYBOARD_UID);
+
+        assert_ne!(a.hop_seed, b.hop_seed);
+    }
+}
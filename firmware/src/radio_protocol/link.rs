@@ -0,0 +1,768 @@
+//! # The links of connected keyboard halves
+//!
+//! Once a half has a session, the dongle runs the master frames, see [`DongleLinks`], and the
+//! half follows them from the syncs, see [`KeyboardLink`]. Pairing and bonds are left to the
+//! runners.
+//!
+//! The links only use the radio and the clock through [`RadioDriver`] and [`Clock`], so they also
+//! run against a simulated radio on the host.
+//!
+//! The slot timing is worked out from the airtimes of [`Mode::Nrf2Mbit`], the radio's default
+//! mode. The links only run in that mode, which is checked in debug builds.
+
+use super::afh::{ChannelAssessment, CHANNEL_MAP_UPDATE_DELAY_FRAMES};
+use super::crypto::{SHORT_TAG_LEN, TAG_LEN};
+use super::drift::SyncTracker;
+use super::hopping::SLOTS_PER_FRAME;
+use super::key_schedule::Address;
+use super::session::{self, Session};
+use super::state::{self, AckFrame, Command, StateFrame, StateSender};
+use super::sync::SyncFrame;
+use super::{Side, SlotProfile, Uid};
+use crate::clock::Clock;
+use crate::radio::{
+    airtime, Addresses, Error, Mode, Packet, RadioDriver, ADDRESS_AIRTIME, DEFAULT_ADDRESSES,
+    RX_RAMP_UP, TURNAROUND,
+};
+use fugit::{TimerDurationU64, TimerInstantU64};
+
+/// Slot timing that depends on the dongle's frame layout and the radio.
+impl SlotProfile {
+    /// How often the dongle starts a master frame, unless a reconnecting half's handshake runs
+    /// over.
+    pub const fn master_frame_period(self) -> TimerDurationU64<1_000_000> {
+        TimerDurationU64::micros(
+            2 * self.sync_slot_size().ticks()
+                + 2 * (SLOTS_PER_FRAME as u64 - 1) * self.slot_size().ticks()
+                + BEACON_GAP.ticks(),
+        )
+    }
+
+    /// Check that a frame sent late within the guard, and its ACK if the profile has them,
+    /// leave the dongle enough time to listen early within the guard in the next slot.
+    const fn fits_slot(self) -> bool {
+        let airtime = if self.acked() {
+            EXCHANGE_AIRTIME
+        } else {
+            SHORT_STATE_AIRTIME
+        };
+
+        2 * self.guard().ticks() + airtime.ticks() + SLOT_PROCESSING.ticks() + RX_RAMP_UP.ticks()
+            <= self.slot_size().ticks()
+    }
+
+    /// Check that a sync leaves the dongle enough time to listen early within the guard in the
+    /// next slot.
+    const fn fits_sync(self) -> bool {
+        airtime(SyncFrame::SIGNED_LEN).ticks()
+            + SLOT_PROCESSING.ticks()
+            + RX_RAMP_UP.ticks()
+            + self.guard().ticks()
+            <= self.sync_slot_size().ticks()
+    }
+}
+
+const _: () = {
+    assert!(SlotProfile::Standard.fits_slot() && SlotProfile::Standard.fits_sync());
+    assert!(SlotProfile::Fast.fits_slot() && SlotProfile::Fast.fits_sync());
+    assert!(SlotProfile::Turbo.fits_slot() && SlotProfile::Turbo.fits_sync());
+};
+
+/// Airtime of a state frame and an ACK with a command, with the radio's turnaround in between.
+const EXCHANGE_AIRTIME: TimerDurationU64<1_000_000> = TimerDurationU64::micros(
+    airtime(StateFrame::LEN + TAG_LEN).ticks()
+        + TURNAROUND.ticks()
+        + airtime(AckFrame::LEN_WITH_COMMAND + TAG_LEN).ticks(),
+);
+
+/// Airtime of a state frame with a truncated tag, on the profiles without ACKs.
+const SHORT_STATE_AIRTIME: TimerDurationU64<1_000_000> = airtime(StateFrame::LEN + SHORT_TAG_LEN);
+
+/// Time the dongle needs after a slot to handle the frame and prepare the next slot.
+const SLOT_PROCESSING: TimerDurationU64<1_000_000> = TimerDurationU64::micros(80);
+
+/// How many master frames without traffic before either end drops the session.
+pub const LINK_TIMEOUT_FRAMES: u32 = 10;
+
+/// Time reserved after every master frame for the dongle's beacon window.
+pub const BEACON_GAP: TimerDurationU64<1_000_000> = TimerDurationU64::millis(8);
+
+/// How long a keyboard half looks for sync before going back to reconnecting.
+pub const SYNC_TIMEOUT: TimerDurationU64<1_000_000> = TimerDurationU64::secs(1);
+
+/// How many syncs in a row a keyboard half may miss, keeping its schedule from the drift
+/// estimate, before it searches for the sync again.
+pub const SYNC_LOSS_FRAMES: u32 = 4;
+
+/// How long the dongle's channel survey measures every frequency.
+const SURVEY_DWELL: TimerDurationU64<1_000_000> = TimerDurationU64::millis(1);
+
+/// How long after its frame a keyboard half waits for the address of the dongle's ACK.
+const ACK_WINDOW: TimerDurationU64<1_000_000> = TimerDurationU64::micros(TURNAROUND.ticks() + 50);
+
+/// The pipe of a link's traffic, on the address derived for its session.
+pub(super) const LINK_PIPE: u8 = 1;
+
+/// Send and receive on the link pipe only, at the session's `address`.
+pub(super) fn use_link_address<R: RadioDriver>(radio: &mut R, address: &Address) {
+    let mut prefixes = DEFAULT_ADDRESSES.prefixes;
+    prefixes[LINK_PIPE as usize] = address.prefix;
+
+    radio.set_addresses(&Addresses {
+        base1: address.base.to_le_bytes(),
+        prefixes,
+        ..DEFAULT_ADDRESSES
+    });
+    radio.set_tx_pipe(LINK_PIPE);
+    radio.set_rx_pipes(1 << LINK_PIPE);
+}
+
+/// The dongle's end of the links, one per side.
+pub struct DongleLinks {
+    dongle_uid: Uid,
+    packet: Packet,
+    /// The halves' sessions, indexed by [`Side::index`].
+    sessions: [Option<Session>; 2],
+    frame_counter: u32,
+    frames_since_seen: [u32; 2],
+    assessment: ChannelAssessment,
+    pair_mode: bool,
+    /// The start of the next master frame.
+    slot_start_time: TimerInstantU64<1_000_000>,
+}
+
+impl DongleLinks {
+    /// Links without sessions, with the first master frame at `start`.
+    pub fn new(dongle_uid: Uid, start: TimerInstantU64<1_000_000>) -> Self {
+        Self {
+            dongle_uid,
+            packet: Packet::new(),
+            sessions: [None, None],
+            frame_counter: 0,
+            frames_since_seen: [0; 2],
+            assessment: ChannelAssessment::new(),
+            pair_mode: false,
+            slot_start_time: start,
+        }
+    }
+
+    /// The halves' sessions, indexed by [`Side::index`].
+    pub fn sessions(&self) -> &[Option<Session>; 2] {
+        &self.sessions
+    }
+
+    /// The halves' sessions, for pairing to put new ones in.
+    pub fn sessions_mut(&mut self) -> &mut [Option<Session>; 2] {
+        &mut self.sessions
+    }
+
+    /// The frame counter of the next master frame.
+    pub fn frame_counter(&self) -> u32 {
+        self.frame_counter
+    }
+
+    /// Announce pair mode in the syncs from the next master frame on.
+    pub fn set_pair_mode(&mut self, pair_mode: bool) {
+        self.pair_mode = pair_mode;
+    }
+
+    /// Start the next master frame at `at` at the earliest, after something else used the radio.
+    pub fn postpone(&mut self, at: TimerInstantU64<1_000_000>) {
+        self.slot_start_time = self.slot_start_time.max(at);
+    }
+
+    /// Measure the energy on every frequency, log it, and seed the channel assessment with it.
+    pub async fn survey<R: RadioDriver>(&mut self, radio: &mut R) {
+        let levels = radio.survey(SURVEY_DWELL).await;
+
+        for (frequency, level) in levels.iter().enumerate() {
+            defmt::info!(
+                "{} MHz: peak {} dBm, average {} dBm",
+                2400 + frequency,
+                level.peak,
+                level.average
+            );
+        }
+
+        self.assessment.seed(&levels);
+    }
+
+    /// Run one master frame with the slots of `profile`, then schedule channel map updates and
+    /// rekeys, and drop the sessions of halves that have gone silent.
+    pub async fn master_frame<R: RadioDriver, C: Clock>(
+        &mut self,
+        radio: &mut R,
+        profile: SlotProfile,
+    ) {
+        debug_assert_eq!(radio.mode(), Mode::Nrf2Mbit, "links only run at 2 Mbit/s");
+
+        for session in self.sessions.iter_mut().flatten() {
+            session.apply_channel_map_update(self.frame_counter);
+        }
+
+        let seen = dongle_master_frame::<R, C>(
+            radio,
+            &mut self.packet,
+            &mut self.slot_start_time,
+            self.dongle_uid,
+            self.frame_counter,
+            profile,
+            self.pair_mode,
+            &mut self.sessions,
+            &mut self.assessment,
+        )
+        .await;
+        self.frame_counter = self.frame_counter.wrapping_add(1);
+
+        // Keep the master frames periodic, so the halves can predict the syncs.
+        self.slot_start_time += BEACON_GAP;
+
+        self.assessment.end_frame(seen.contains(&true));
+        for session in self.sessions.iter_mut().flatten() {
+            if session.channel_map_update_pending() {
+                continue;
+            }
+
+            let channel_map = self.assessment.channel_map(session.channel_map());
+            if channel_map != *session.channel_map() {
+                session.schedule_channel_map(
+                    channel_map,
+                    self.frame_counter
+                        .wrapping_add(CHANNEL_MAP_UPDATE_DELAY_FRAMES),
+                );
+            }
+        }
+
+        let rekey = session::take_rekey_request();
+        for session in self.sessions.iter_mut().flatten() {
+            session.next_frame(rekey);
+        }
+
+        for side in Side::ALL {
+            let i = side.index();
+
+            if seen[i] {
+                self.frames_since_seen[i] = 0;
+            } else {
+                self.frames_since_seen[i] = self.frames_since_seen[i].saturating_add(1);
+
+                if self.frames_since_seen[i] > LINK_TIMEOUT_FRAMES && self.sessions[i].is_some() {
+                    defmt::warn!("Lost the {} half", side);
+                    self.sessions[i] = None;
+                }
+            }
+        }
+    }
+}
+
+/// Run one master frame with the slots of `profile`: each half's link gets every other slot,
+/// starting with its sync.
+///
+/// Returns which halves were heard from, indexed by [`Side::index`]. The outcome of every slot
+/// is recorded in `assessment`.
+#[allow(clippy::too_many_arguments)]
+async fn dongle_master_frame<R: RadioDriver, C: Clock>(
+    radio: &mut R,
+    packet: &mut Packet,
+    slot_start_time: &mut TimerInstantU64<1_000_000>,
+    dongle_uid: Uid,
+    frame_counter: u32,
+    profile: SlotProfile,
+    pair_mode: bool,
+    sessions: &mut [Option<Session>; 2],
+    assessment: &mut ChannelAssessment,
+) -> [bool; 2] {
+    let guard = profile.guard();
+
+    let mut hopping = sessions
+        .each_ref()
+        .map(|session| session.as_ref().map(Session::channel_hopping));
+
+    let mut response = Packet::new();
+    let mut correct_rxes = 0;
+    let mut missed_rxes = 0;
+    let mut seen = [false; 2];
+
+    for index in 0..SLOTS_PER_FRAME {
+        let slot_size = if index == 0 {
+            profile.sync_slot_size()
+        } else {
+            profile.slot_size()
+        };
+
+        for side in Side::ALL {
+            let (Some(session), Some(channel_hopping)) =
+                (&mut sessions[side.index()], &mut hopping[side.index()])
+            else {
+                *slot_start_time += slot_size;
+                continue;
+            };
+
+            let slot = channel_hopping.state();
+            let channel = channel_hopping.current_channel();
+            radio.set_frequency(channel);
+            use_link_address(radio, &session.parameters().address);
+            channel_hopping.next_channel();
+
+            if slot == 0 {
+                //
+                // 1. Send the link's sync packet at the desired time.
+                //
+                let (channel_map, channel_map_instant) =
+                    session.announced_channel_map(frame_counter);
+                let sync = SyncFrame {
+                    dongle_uid,
+                    frame_counter,
+                    timestamp: slot_start_time.ticks() as u32,
+                    key_epoch: session.key_epoch(),
+                    pair_mode,
+                    channel_map,
+                    channel_map_instant,
+                    slot_profile: profile,
+                    downstream: session.downstream().payload(side),
+                };
+                packet.copy_from_slice(&sync.encode());
+                if let Err(e) = session.seal_sync(frame_counter, packet) {
+                    defmt::warn!("Sync of frame {} not sealed: {}", frame_counter, e);
+                } else if let Err(e) = radio.send_at(packet, *slot_start_time).await {
+                    defmt::warn!("Sync of frame {} not sent: {}", frame_counter, e);
+                }
+
+                *slot_start_time += slot_size;
+                continue;
+            }
+
+            //
+            // 2. Look for the keyboard half's data in the link's other slots.
+            //
+            // Listen around the slot start, the half's frame has to begin within the guard. The
+            // window is on its address, which arrives a little after the frame begins.
+            let open_at = *slot_start_time - guard + ADDRESS_AIRTIME;
+            let close_at = *slot_start_time + guard + ADDRESS_AIRTIME;
+            let result = if profile.acked() {
+                // The ACK is sealed before the slot, the radio sends it right after an intact
+                // frame.
+                let ack = AckFrame {
+                    downstream: session.downstream().payload(side),
+                };
+                let mut buf = [0; AckFrame::LEN_WITH_COMMAND];
+                let len = ack.encode(&mut buf);
+                response.copy_from_slice(&buf[..len]);
+                if let Err(e) = session.seal(frame_counter, slot, &mut response) {
+                    defmt::warn!("Not listening in slot {}: {}", slot, e);
+                    *slot_start_time += slot_size;
+                    continue;
+                }
+
+                radio
+                    .recv_and_respond(packet, &mut response, open_at, close_at)
+                    .await
+            } else {
+                radio
+                    .recv_window(packet, open_at, close_at)
+                    .await
+                    .map(|_| ())
+            };
+
+            let mut received = false;
+            let mut heard = false;
+            match result {
+                Ok(()) => {
+                    heard = true;
+
+                    let opened = if profile.acked() {
+                        session.open(frame_counter, slot, packet)
+                    } else {
+                        session.open_short(frame_counter, slot, packet)
+                    };
+                    match opened {
+                        Ok(()) => {
+                            correct_rxes += 1;
+                            received = true;
+                            seen[side.index()] = true;
+
+                            if let Some(frame) = StateFrame::decode(packet) {
+                                session.downstream().acked(frame.downstream_ack);
+
+                                if session.accept_state(frame.sequence) {
+                                    defmt::debug!("{} half: {}", side, frame);
+                                    state::publish(side, frame.matrix);
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            defmt::debug!("Dropped frame from {} half: {}", side, e);
+                            missed_rxes += 1;
+                        }
+                    }
+                }
+                Err(Error::Crc(_)) => heard = true,
+                Err(_timeout) => {
+                    missed_rxes += 1;
+                }
+            };
+
+            // Frames that fail to authenticate count as lost, they are mostly corrupted ones.
+            // With ACKs a half only has to send in its keepalive slot, so silence elsewhere says
+            // nothing about the channel. Without them it sends in every slot.
+            if heard || slot == state::keepalive_slot(frame_counter) || !profile.acked() {
+                assessment.record(channel, received);
+            }
+
+            *slot_start_time += slot_size;
+        }
+    }
+
+    // Links without a session do not wait for their slots, keep the frame's length.
+    C::delay_until(*slot_start_time).await;
+
+    defmt::info!(
+        "This master frame got {} successful RXes and {} missed",
+        correct_rxes,
+        missed_rxes
+    );
+
+    seen
+}
+
+#[derive(Copy, Clone, Debug, defmt::Format)]
+enum KeyboardLinkState {
+    LookingForSync {
+        deadline: TimerInstantU64<1_000_000>,
+    },
+    /// Listening for the sync of frame `frame_counter` where the drift estimate expects it, the
+    /// frames since the last sync heard use `profile`.
+    ExpectingSync {
+        frame_counter: u32,
+        missed: u32,
+        profile: SlotProfile,
+    },
+    Synchronized {
+        frame_counter: u32,
+        missed: u32,
+        profile: SlotProfile,
+        /// When the sync started, or was due if it was missed. The dongle's slots start like it,
+        /// [`ADDRESS_AIRTIME`] before the sync's address was received.
+        sync_time: TimerInstantU64<1_000_000>,
+    },
+}
+
+/// Check a packet for the dongle's sync, and follow the link state it announces once its tag
+/// has been verified.
+///
+/// `None` if it is not an authentic sync from our dongle, keep listening. A half that can not
+/// verify the syncs anymore, e.g. because it lost track of the key epoch, misses them until it
+/// reconnects.
+fn handle_sync(session: &mut Session, packet: &mut Packet) -> Option<SyncFrame> {
+    let sync = SyncFrame::decode(packet)?;
+
+    if sync.dongle_uid != session.peer().uid {
+        defmt::debug!("Ignoring sync from dongle {}", sync.dongle_uid);
+        return None;
+    }
+
+    if let Err(e) = session.open_sync(sync.frame_counter, sync.key_epoch, packet) {
+        defmt::warn!("Ignoring sync of frame {}: {}", sync.frame_counter, e);
+        return None;
+    }
+
+    // Catches up on an update that should already be in effect, the rest of the frame is on
+    // the new hop sequence.
+    session.schedule_channel_map(sync.channel_map, sync.channel_map_instant);
+    session.apply_channel_map_update(sync.frame_counter);
+
+    Some(sync)
+}
+
+/// A keyboard half's end of the link, following the dongle's syncs and sending its state.
+pub struct KeyboardLink {
+    packet: Packet,
+    dongle: Option<Session>,
+    state: KeyboardLinkState,
+    sender: StateSender,
+    tracker: SyncTracker,
+    frames_without_ack: u32,
+    acks: u32,
+}
+
+impl Default for KeyboardLink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeyboardLink {
+    /// A link without a session.
+    pub fn new() -> Self {
+        Self {
+            packet: Packet::new(),
+            dongle: None,
+            state: KeyboardLinkState::LookingForSync {
+                deadline: TimerInstantU64::from_ticks(0),
+            },
+            sender: StateSender::new(),
+            tracker: SyncTracker::new(),
+            frames_without_ack: 0,
+            acks: 0,
+        }
+    }
+
+    /// The session with the dongle, `None` once the link is lost.
+    pub fn session(&self) -> Option<&Session> {
+        self.dongle.as_ref()
+    }
+
+    /// Start following the dongle with a new session, looking for its sync.
+    pub fn connect<R: RadioDriver, C: Clock>(&mut self, radio: &mut R, session: Session) {
+        use_link_address(radio, &session.parameters().address);
+        self.dongle = Some(session);
+        self.frames_without_ack = 0;
+        self.sender.start_session();
+        self.state = KeyboardLinkState::LookingForSync {
+            deadline: C::now() + SYNC_TIMEOUT,
+        };
+    }
+
+    /// Drop the session, e.g. because its bond has been forgotten.
+    pub fn disconnect(&mut self) {
+        self.dongle = None;
+    }
+
+    /// Listen for a sync, or send in the link's slots of one master frame.
+    ///
+    /// Drops the session when the link is lost. Commands from the dongle are passed to
+    /// `handle_command`.
+    pub async fn step<R: RadioDriver, C: Clock>(
+        &mut self,
+        radio: &mut R,
+        mut handle_command: impl FnMut(&mut R, Command),
+    ) {
+        let Some(session) = &mut self.dongle else {
+            return;
+        };
+        let packet = &mut self.packet;
+        debug_assert_eq!(radio.mode(), Mode::Nrf2Mbit, "links only run at 2 Mbit/s");
+
+        match self.state {
+            KeyboardLinkState::LookingForSync { deadline } => {
+                // The sync is sent in the first slot of the link's frame.
+                radio.set_frequency(session.channel_hopping().current_channel());
+                let (timestamp, _, _) = match C::timeout_at(deadline, radio.recv(packet)).await {
+                    Ok(Ok(v)) => v,
+                    Ok(Err(_)) => {
+                        defmt::info!("Radio receive error");
+                        return;
+                    }
+                    Err(_timeout) => {
+                        defmt::warn!("No sync from the dongle, reconnecting");
+                        self.dongle = None;
+                        return;
+                    }
+                };
+
+                let Some(sync) = handle_sync(session, packet) else {
+                    return;
+                };
+                if let Some(command) = sync.downstream.and_then(|d| self.sender.downstream(d)) {
+                    handle_command(radio, command);
+                }
+
+                defmt::info!(
+                    "Sync for frame {} found at {}",
+                    sync.frame_counter,
+                    timestamp.0
+                );
+
+                let sync_time = timestamp.0 - ADDRESS_AIRTIME;
+                self.tracker.sync_received(sync.timestamp, sync_time);
+
+                self.state = KeyboardLinkState::Synchronized {
+                    frame_counter: sync.frame_counter,
+                    missed: 0,
+                    profile: sync.slot_profile,
+                    sync_time,
+                };
+            }
+            KeyboardLinkState::ExpectingSync {
+                frame_counter,
+                missed,
+                profile,
+            } => {
+                let Some(window) = self
+                    .tracker
+                    .window(missed + 1, profile.master_frame_period())
+                else {
+                    self.state = KeyboardLinkState::LookingForSync {
+                        deadline: C::now() + SYNC_TIMEOUT,
+                    };
+                    return;
+                };
+
+                radio.set_frequency(session.channel_hopping().current_channel());
+
+                let timestamp = match radio
+                    .recv_window(
+                        packet,
+                        window.start() + ADDRESS_AIRTIME,
+                        window.end() + ADDRESS_AIRTIME,
+                    )
+                    .await
+                {
+                    // Keep listening for the rest of the window.
+                    Err(Error::Crc(_)) => return,
+                    Ok((timestamp, _, _)) => Some(timestamp),
+                    Err(_timeout) => None,
+                };
+
+                if let Some(timestamp) = timestamp {
+                    if let Some(sync) = handle_sync(session, packet) {
+                        if let Some(command) =
+                            sync.downstream.and_then(|d| self.sender.downstream(d))
+                        {
+                            handle_command(radio, command);
+                        }
+
+                        let sync_time = timestamp.0 - ADDRESS_AIRTIME;
+                        self.tracker.sync_received(sync.timestamp, sync_time);
+
+                        self.state = KeyboardLinkState::Synchronized {
+                            frame_counter: sync.frame_counter,
+                            missed: 0,
+                            profile: sync.slot_profile,
+                            sync_time,
+                        };
+                    }
+
+                    return;
+                }
+
+                if missed + 1 >= SYNC_LOSS_FRAMES {
+                    defmt::warn!("Missed {} syncs, searching again", missed + 1);
+                    self.state = KeyboardLinkState::LookingForSync {
+                        deadline: C::now() + SYNC_TIMEOUT,
+                    };
+                    return;
+                }
+
+                // Keep the schedule and the profile, the sync was due at the expected time.
+                defmt::debug!(
+                    "Missed the sync of frame {}, drift {} ppm",
+                    frame_counter,
+                    self.tracker.drift_ppm()
+                );
+                self.state = KeyboardLinkState::Synchronized {
+                    frame_counter,
+                    missed: missed + 1,
+                    profile,
+                    sync_time: window.expected,
+                };
+            }
+            KeyboardLinkState::Synchronized {
+                frame_counter,
+                missed,
+                profile,
+                sync_time,
+            } => {
+                let mut channel_hopping = session.channel_hopping();
+                channel_hopping.next_channel();
+                let mut got_ack = false;
+
+                loop {
+                    // The slots are laid out on the dongle's clock, late in the frame the drift
+                    // adds up to more than the Fast profile's guard.
+                    let slot = channel_hopping.state();
+                    let slot_offset = profile.slot_offset(session.peer().side, slot);
+                    let slot_start_time = sync_time + self.tracker.local_duration(slot_offset);
+                    radio.set_frequency(channel_hopping.current_channel());
+
+                    self.sender.poll_update();
+                    let keepalive = slot == state::keepalive_slot(frame_counter);
+
+                    // Without ACKs every slot gets the current state.
+                    if let Some(frame) = self.sender.frame(keepalive || !profile.acked()) {
+                        packet.copy_from_slice(&frame.encode());
+
+                        // Sealing fails if the sync's frame counter has gone backwards, which
+                        // would reuse a nonce.
+                        let sealed = if profile.acked() {
+                            session.seal(frame_counter, slot, packet)
+                        } else {
+                            session.seal_short(frame_counter, slot, packet)
+                        };
+                        if let Err(e) = sealed {
+                            defmt::warn!("Not sending in slot {}: {}", slot, e);
+                            break;
+                        }
+
+                        if !profile.acked() {
+                            if let Err(Error::TooLate) =
+                                radio.send_at(packet, slot_start_time).await
+                            {
+                                defmt::debug!("Missed slot {}", slot);
+                            }
+                        } else {
+                            // The dongle's radio answers right after the frame. If the slot has
+                            // already passed the frame stays unacked, and goes out in a later
+                            // one.
+                            match radio
+                                .send_at_and_recv(packet, slot_start_time, ACK_WINDOW)
+                                .await
+                            {
+                                Ok(_) => match session.open(frame_counter, slot, packet) {
+                                    Ok(()) => {
+                                        if let Some(ack) = AckFrame::decode(packet) {
+                                            self.acks += 1;
+                                            got_ack = true;
+                                            if let Some(command) = self.sender.ack(ack) {
+                                                handle_command(radio, command);
+                                            }
+                                        }
+                                    }
+                                    Err(e) => {
+                                        defmt::debug!("Dropped ACK in slot {}: {}", slot, e)
+                                    }
+                                },
+                                Err(Error::TooLate) => defmt::debug!("Missed slot {}", slot),
+                                Err(_) => {}
+                            };
+                        }
+                    }
+
+                    channel_hopping.next_channel();
+                    if channel_hopping.is_initial_state() {
+                        break;
+                    }
+                }
+
+                // Without ACKs, the dongle keeps sending the syncs only while it hears the half.
+                if !profile.acked() && missed == 0 {
+                    got_ack = true;
+                }
+
+                if got_ack {
+                    self.frames_without_ack = 0;
+                } else {
+                    self.frames_without_ack += 1;
+                }
+
+                if self.frames_without_ack > LINK_TIMEOUT_FRAMES {
+                    defmt::warn!("No ACKs from the dongle, reconnecting");
+                    self.dongle = None;
+                    return;
+                }
+
+                defmt::info!("Got {} acks last round", self.acks);
+                self.acks = 0;
+
+                // The next sync is on the new hop sequence if a channel map update is due.
+                session.apply_channel_map_update(frame_counter.wrapping_add(1));
+
+                self.state = KeyboardLinkState::ExpectingSync {
+                    frame_counter: frame_counter.wrapping_add(1),
+                    missed,
+                    profile,
+                };
+            }
+        }
+    }
+}
@@ -0,0 +1,699 @@
+//! # Pairing of keyboard halves to the dongle
+//!
+//! All pairing traffic happens on [`PAIRING_FREQUENCY`] without any channel hopping.
+//!
+//! 1. The dongle sends a [`Beacon`] (dongle ID + public key) and listens for a short while
+//!    after it. This happens once per master frame, in pair mode or as long as a bonded half is
+//!    not connected.
+//! 2. A keyboard half that hears a beacon answers with a [`Presentation`] (ID + side + public
+//!    key), after a random backoff so both halves can answer the same beacon. A half without a
+//!    bond only answers beacons in pair mode, a bonded half only answers beacons from its bonded
+//!    dongle and asks to reconnect.
+//! 3. The dongle answers with a [`Response`]. A new half is rejected if its side is already
+//!    bonded to another half, a reconnecting half is rejected if its ID does not match the bond
+//!    for its side. A half that pairs again, e.g. because it missed the end of its pairing,
+//!    replaces its earlier pairing.
+//! 4. On accept both ends run ECDH (P-256) to establish the shared secret. A new half first has
+//!    to type the passkey shown by the dongle, see [`passkey`], which authenticates the exchanged
+//!    public keys. The dongle sends no beacons until the entry has ended. When reconnecting both ends instead prove that they know the bond's link key
+//!    with a [`Confirm`] tag over the exchanged public keys.
+//! 5. The half always ends with a [`Confirm`], for a new half over the new link key. The dongle
+//!    only stores a new bond once it has it, so it never keeps a bond the half does not have. If
+//!    the confirm is lost the half's reconnect is refused and it pairs again.
+
+use super::bonds::{Bond, BondStore};
+use super::passkey;
+use super::session::{PairedPeer, Session};
+use super::{use_pairing_address, Side, Uid};
+use crate::clock::Clock;
+use crate::radio::{Packet, RadioDriver};
+use core::mem;
+use embedded_storage::nor_flash::NorFlash;
+use fugit::TimerDurationU64;
+use hmac::{Hmac, Mac};
+use p256_cortex_m4::{Keypair, PublicKey};
+use rand_chacha::rand_core::{CryptoRng, RngCore};
+use sha2::Sha256;
+
+pub mod frames;
+
+pub use frames::{Beacon, Confirm, PairingStatus, Presentation, Response};
+use frames::{CONFIRM_LEN, PUBLIC_KEY_LEN};
+
+/// The frequency all pairing traffic is sent on, 2400 MHz + `PAIRING_FREQUENCY`.
+/// This is above the BLE advertising channel at 2480 MHz and the top of Wi-Fi channel 13.
+pub const PAIRING_FREQUENCY: u8 = 81;
+
+/// How long the dongle listens for a presentation after each beacon.
+const PRESENTATION_WINDOW: TimerDurationU64<1_000_000> = TimerDurationU64::millis(5);
+
+/// The unit of the random backoff before a presentation, longer than a presentation's airtime.
+const PRESENTATION_BACKOFF: TimerDurationU64<1_000_000> = TimerDurationU64::micros(500);
+
+/// A presentation waits up to `PRESENTATION_BACKOFFS - 1` backoffs, so it still ends within the
+/// [`PRESENTATION_WINDOW`]. A power of two, so a random byte picks the backoffs without bias.
+const PRESENTATION_BACKOFFS: u8 = 8;
+
+/// How long a keyboard half waits for the dongle's response, and the dongle for a confirm.
+const RESPONSE_TIMEOUT: TimerDurationU64<1_000_000> = TimerDurationU64::millis(20);
+
+/// How long a rejected keyboard half waits before presenting itself again.
+const REJECT_BACKOFF: TimerDurationU64<1_000_000> = TimerDurationU64::secs(1);
+
+/// Which end a link key confirmation comes from.
+#[derive(Copy, Clone)]
+enum ConfirmRole {
+    Dongle,
+    Keyboard,
+}
+
+/// Link key confirmation over the public keys of a reconnect.
+fn confirm_mac(
+    link_key: &[u8; 32],
+    role: ConfirmRole,
+    keyboard_public_key: &[u8; PUBLIC_KEY_LEN],
+    dongle_public_key: &[u8; PUBLIC_KEY_LEN],
+) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(link_key).unwrap();
+    let label: &[u8] = match role {
+        ConfirmRole::Dongle => b"dongle confirm",
+        ConfirmRole::Keyboard => b"keyboard confirm",
+    };
+    mac.update(label);
+    mac.update(keyboard_public_key);
+    mac.update(dongle_public_key);
+    mac
+}
+
+fn confirm_tag(
+    link_key: &[u8; 32],
+    role: ConfirmRole,
+    keyboard_public_key: &[u8; PUBLIC_KEY_LEN],
+    dongle_public_key: &[u8; PUBLIC_KEY_LEN],
+) -> [u8; CONFIRM_LEN] {
+    let tag = confirm_mac(link_key, role, keyboard_public_key, dongle_public_key)
+        .finalize()
+        .into_bytes();
+    tag[..CONFIRM_LEN].try_into().unwrap()
+}
+
+fn confirm_is_valid(
+    link_key: &[u8; 32],
+    role: ConfirmRole,
+    keyboard_public_key: &[u8; PUBLIC_KEY_LEN],
+    dongle_public_key: &[u8; PUBLIC_KEY_LEN],
+    tag: &[u8; CONFIRM_LEN],
+) -> bool {
+    confirm_mac(link_key, role, keyboard_public_key, dongle_public_key)
+        .verify_truncated_left(tag)
+        .is_ok()
+}
+
+/// The dongle's answer to `presentation`, with `bond` for the presented side. `None` if a new
+/// half presents itself outside of pair mode, which is not answered.
+fn presentation_status(
+    presentation: &Presentation,
+    bond: Option<&Bond>,
+    pair_mode: bool,
+) -> Option<PairingStatus> {
+    let status = match (bond, presentation.reconnect) {
+        (_, false) if !pair_mode => return None,
+        // The half did not finish an earlier pairing, it is replaced.
+        (Some(bond), false) if bond.peer_uid == presentation.keyboard_uid => {
+            PairingStatus::Accepted
+        }
+        (Some(_), false) => PairingStatus::SideTaken,
+        (None, false) => PairingStatus::Accepted,
+        (None, true) => PairingStatus::NotBonded,
+        (Some(bond), true) if bond.peer_uid != presentation.keyboard_uid => PairingStatus::WrongId,
+        (Some(_), true) => PairingStatus::Accepted,
+    };
+
+    Some(status)
+}
+
+/// What the dongle keeps of an accepted presentation until the half has confirmed its link key.
+struct Handshake {
+    presentation: Presentation,
+    public_key: PublicKey,
+    keypair: Keypair,
+    dongle_public_key: [u8; PUBLIC_KEY_LEN],
+}
+
+/// A new half that is typing the passkey.
+struct PendingPairing {
+    handshake: Handshake,
+    entry: passkey::DongleEntry,
+}
+
+/// The dongle's end of pairing and reconnects.
+pub struct DonglePairing {
+    uid: Uid,
+    keypair: Keypair,
+    packet: Packet,
+    pending: Option<PendingPairing>,
+}
+
+impl DonglePairing {
+    /// Create the dongle's pairing handler with a fresh ephemeral key pair.
+    pub fn new<G: RngCore + CryptoRng>(uid: Uid, rng: &mut G) -> Self {
+        Self {
+            uid,
+            keypair: Keypair::random(&mut *rng),
+            packet: Packet::new(),
+            pending: None,
+        }
+    }
+
+    /// If a new half is typing the passkey, its entry goes on in the next beacon windows, also
+    /// after pair mode has ended.
+    pub fn entry_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Send one beacon and handle a presentation if one arrives after it, or step the passkey
+    /// entry of a new half instead while it goes on.
+    ///
+    /// New sessions are put in `sessions`, indexed by [`Side::index`].
+    pub async fn beacon_window<R: RadioDriver, C: Clock, F: NorFlash, G: RngCore + CryptoRng>(
+        &mut self,
+        radio: &mut R,
+        rng: &mut G,
+        pair_mode: bool,
+        bonds: &mut BondStore<F>,
+        sessions: &mut [Option<Session>; 2],
+    ) {
+        use_pairing_address(radio);
+        radio.set_frequency(PAIRING_FREQUENCY);
+
+        // The half typing the passkey has the pairing frequency to itself, there are no beacons
+        // until it is done.
+        if let Some(pending) = &mut self.pending {
+            let handshake = &pending.handshake;
+            let Some(result) = pending
+                .entry
+                .step::<R, C>(
+                    radio,
+                    &mut self.packet,
+                    &handshake.presentation.public_key,
+                    &handshake.dongle_public_key,
+                )
+                .await
+            else {
+                return;
+            };
+
+            let Some(PendingPairing { handshake, .. }) = self.pending.take() else {
+                return;
+            };
+
+            match result {
+                Ok(confirm) => self.finish(handshake, None, Some(confirm), bonds, sessions),
+                Err(e) => defmt::warn!(
+                    "{} half {} failed passkey entry: {}",
+                    handshake.presentation.side,
+                    handshake.presentation.keyboard_uid,
+                    e
+                ),
+            }
+
+            return;
+        }
+
+        let packet = &mut self.packet;
+        let dongle_public_key = self.keypair.public.to_compressed_sec1_bytes();
+
+        Beacon {
+            dongle_uid: self.uid,
+            pair_mode,
+            public_key: dongle_public_key,
+        }
+        .encode(packet);
+        if let Err(e) = radio.send_no_cca(packet).await {
+            defmt::warn!("Beacon not sent: {}", e);
+            return;
+        }
+
+        let presentation = match C::timeout_after(PRESENTATION_WINDOW, radio.recv(packet)).await {
+            Ok(Ok(_)) => Presentation::decode(packet),
+            _ => None,
+        };
+
+        let Some(presentation) = presentation else {
+            return;
+        };
+
+        // The beacons' key pair is used up by any presentation, whatever becomes of it. Every
+        // session gets its own ECDH.
+        let keypair = mem::replace(&mut self.keypair, Keypair::random(&mut *rng));
+
+        let Ok(public_key) = PublicKey::from_sec1_bytes(&presentation.public_key) else {
+            defmt::warn!("Invalid public key from {}", presentation.keyboard_uid);
+            return;
+        };
+
+        let side = presentation.side;
+        let bond = bonds.get(side).cloned();
+
+        let Some(status) = presentation_status(&presentation, bond.as_ref(), pair_mode) else {
+            return;
+        };
+
+        // Only a reconnecting half uses its bond, a half pairing again gets a new one.
+        let bond = bond.filter(|_| presentation.reconnect);
+
+        let confirm = match (&bond, presentation.reconnect, status) {
+            (Some(bond), true, PairingStatus::Accepted) => Some(confirm_tag(
+                &bond.link_key,
+                ConfirmRole::Dongle,
+                &presentation.public_key,
+                &dongle_public_key,
+            )),
+            _ => None,
+        };
+
+        Response {
+            keyboard_uid: presentation.keyboard_uid,
+            status,
+            confirm,
+        }
+        .encode(packet);
+        if let Err(e) = radio.send_no_cca(packet).await {
+            defmt::warn!("Response to {} not sent: {}", presentation.keyboard_uid, e);
+            return;
+        }
+
+        if status != PairingStatus::Accepted {
+            defmt::warn!(
+                "Rejected {} half {}: {}",
+                side,
+                presentation.keyboard_uid,
+                status
+            );
+            return;
+        }
+
+        let handshake = Handshake {
+            presentation,
+            public_key,
+            keypair,
+            dongle_public_key,
+        };
+
+        // New halves have to type the passkey before they are trusted, they confirm their link
+        // key once they are done.
+        if bond.is_none() {
+            self.pending = Some(PendingPairing {
+                handshake,
+                entry: passkey::DongleEntry::new::<C, G>(rng, presentation.keyboard_uid),
+            });
+            return;
+        }
+
+        let confirm = match C::timeout_after(RESPONSE_TIMEOUT, radio.recv(packet)).await {
+            Ok(Ok(_)) => Confirm::decode(packet),
+            _ => None,
+        };
+
+        self.finish(handshake, bond.as_ref(), confirm, bonds, sessions);
+    }
+
+    /// Check the half's `confirm` of the link key, of `bond` when reconnecting, and give it a
+    /// session if it proved that it knows the key. A new half's bond is stored first.
+    fn finish<F: NorFlash>(
+        &self,
+        handshake: Handshake,
+        bond: Option<&Bond>,
+        confirm: Option<Confirm>,
+        bonds: &mut BondStore<F>,
+        sessions: &mut [Option<Session>; 2],
+    ) {
+        let Handshake {
+            presentation,
+            public_key,
+            keypair,
+            dongle_public_key,
+        } = handshake;
+        let side = presentation.side;
+
+        let shared_secret = *keypair.secret.agree(&public_key).as_bytes();
+        let link_key = bond.map_or(shared_secret, |bond| bond.link_key);
+
+        let confirmed = confirm.is_some_and(|confirm| {
+            confirm.keyboard_uid == presentation.keyboard_uid
+                && confirm_is_valid(
+                    &link_key,
+                    ConfirmRole::Keyboard,
+                    &presentation.public_key,
+                    &dongle_public_key,
+                    &confirm.tag,
+                )
+        });
+
+        if !confirmed {
+            defmt::warn!(
+                "{} half {} failed to confirm",
+                side,
+                presentation.keyboard_uid
+            );
+            return;
+        }
+
+        if bond.is_none() {
+            let bond = Bond {
+                peer_uid: presentation.keyboard_uid,
+                side,
+                link_key: shared_secret,
+            };
+
+            if bonds.store(bond).is_err() {
+                defmt::error!("Failed to store bond");
+            }
+
+            defmt::info!("Paired {} half {}", side, presentation.keyboard_uid);
+        } else {
+            defmt::info!("Reconnected {} half {}", side, presentation.keyboard_uid);
+        }
+
+        let peer = PairedPeer {
+            uid: presentation.keyboard_uid,
+            side,
+            shared_secret,
+        };
+        sessions[side.index()] = Some(Session::dongle(peer, self.uid));
+    }
+}
+
+/// Connect to the dongle, reconnecting if this half has a bond and pairing otherwise.
+///
+/// Retries until a dongle accepts this half.
+pub async fn keyboard_connect<R: RadioDriver, C: Clock, F: NorFlash, G: RngCore + CryptoRng>(
+    radio: &mut R,
+    rng: &mut G,
+    keyboard_uid: Uid,
+    side: Side,
+    bonds: &mut BondStore<F>,
+) -> PairedPeer {
+    use_pairing_address(radio);
+
+    let mut packet = Packet::new();
+
+    loop {
+        let bond = bonds.get(side).cloned();
+
+        radio.set_frequency(PAIRING_FREQUENCY);
+
+        if radio.recv(&mut packet).await.is_err() {
+            continue;
+        }
+
+        let Some(beacon) = Beacon::decode(&packet) else {
+            continue;
+        };
+
+        match &bond {
+            // Bonded halves only talk to their own dongle.
+            Some(bond) if bond.peer_uid != beacon.dongle_uid => continue,
+            None if !beacon.pair_mode => continue,
+            _ => {}
+        }
+
+        let Ok(dongle_public_key) = PublicKey::from_sec1_bytes(&beacon.public_key) else {
+            defmt::warn!("Invalid public key in beacon from {}", beacon.dongle_uid);
+            continue;
+        };
+
+        // Every presentation gets its own ECDH, like every session on the dongle.
+        let keypair = Keypair::random(&mut *rng);
+        let keyboard_public_key = keypair.public.to_compressed_sec1_bytes();
+
+        Presentation {
+            keyboard_uid,
+            side,
+            reconnect: bond.is_some(),
+            public_key: keyboard_public_key,
+        }
+        .encode(&mut packet);
+
+        // Both halves hear the same beacons, without a backoff they would always collide.
+        let mut backoffs = [0];
+        rng.fill_bytes(&mut backoffs);
+        C::delay(PRESENTATION_BACKOFF * u32::from(backoffs[0] % PRESENTATION_BACKOFFS)).await;
+
+        if let Err(e) = radio.send_no_cca(&mut packet).await {
+            defmt::warn!("Presentation not sent: {}", e);
+            continue;
+        }
+
+        let response = match C::timeout_after(RESPONSE_TIMEOUT, radio.recv(&mut packet)).await {
+            Ok(Ok(_)) => Response::decode(&packet),
+            _ => None,
+        };
+
+        let Some(response) = response.filter(|r| r.keyboard_uid == keyboard_uid) else {
+            continue;
+        };
+
+        match (response.status, &bond) {
+            (PairingStatus::Accepted, None) => {
+                // Worked out before the passkey entry, the dongle waits for the confirm only
+                // briefly after the last round.
+                let shared_secret = *keypair.secret.agree(&dongle_public_key).as_bytes();
+
+                let passkey_ok = passkey::keyboard_exchange::<R, C, G>(
+                    radio,
+                    rng,
+                    &mut packet,
+                    keyboard_uid,
+                    &keyboard_public_key,
+                    &beacon.public_key,
+                )
+                .await;
+
+                if !passkey_ok {
+                    defmt::warn!("Passkey entry with dongle {} failed", beacon.dongle_uid);
+                    C::delay(REJECT_BACKOFF).await;
+                    continue;
+                }
+
+                // The dongle only stores the bond once it has this. If it is lost the dongle
+                // refuses the reconnect, and this half pairs again.
+                Confirm {
+                    keyboard_uid,
+                    tag: confirm_tag(
+                        &shared_secret,
+                        ConfirmRole::Keyboard,
+                        &keyboard_public_key,
+                        &beacon.public_key,
+                    ),
+                }
+                .encode(&mut packet);
+                if let Err(e) = radio.send_no_cca(&mut packet).await {
+                    defmt::warn!("Confirm not sent: {}", e);
+                }
+
+                let bond = Bond {
+                    peer_uid: beacon.dongle_uid,
+                    side,
+                    link_key: shared_secret,
+                };
+
+                if bonds.store(bond).is_err() {
+                    defmt::error!("Failed to store bond");
+                }
+
+                defmt::info!("Paired to dongle {}", beacon.dongle_uid);
+
+                return PairedPeer {
+                    uid: beacon.dongle_uid,
+                    side,
+                    shared_secret,
+                };
+            }
+            (PairingStatus::Accepted, Some(bond)) => {
+                let confirmed = response.confirm.is_some_and(|tag| {
+                    confirm_is_valid(
+                        &bond.link_key,
+                        ConfirmRole::Dongle,
+                        &keyboard_public_key,
+                        &beacon.public_key,
+                        &tag,
+                    )
+                });
+
+                if !confirmed {
+                    defmt::warn!("Dongle {} failed to confirm", beacon.dongle_uid);
+                    continue;
+                }
+
+                Confirm {
+                    keyboard_uid,
+                    tag: confirm_tag(
+                        &bond.link_key,
+                        ConfirmRole::Keyboard,
+                        &keyboard_public_key,
+                        &beacon.public_key,
+                    ),
+                }
+                .encode(&mut packet);
+                if let Err(e) = radio.send_no_cca(&mut packet).await {
+                    defmt::warn!("Confirm not sent: {}", e);
+                    continue;
+                }
+
+                let shared_secret = *keypair.secret.agree(&dongle_public_key).as_bytes();
+
+                defmt::info!("Reconnected to dongle {}", beacon.dongle_uid);
+
+                return PairedPeer {
+                    uid: beacon.dongle_uid,
+                    side,
+                    shared_secret,
+                };
+            }
+            (PairingStatus::NotBonded | PairingStatus::WrongId, Some(_)) if beacon.pair_mode => {
+                // The dongle does not know us anymore and the user is pairing, start over.
+                defmt::warn!(
+                    "Dongle {} does not know this half ({}), pairing again",
+                    beacon.dongle_uid,
+                    response.status
+                );
+
+                if bonds.forget(side).is_err() {
+                    defmt::error!("Failed to forget bond");
+                }
+            }
+            (status, _) => {
+                defmt::warn!("Rejected by dongle {}: {}", beacon.dongle_uid, status);
+                C::delay(REJECT_BACKOFF).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINK_KEY: [u8; 32] = [0x1c; 32];
+    const KEYBOARD_KEY: [u8; PUBLIC_KEY_LEN] = [0x02; PUBLIC_KEY_LEN];
+    const DONGLE_KEY: [u8; PUBLIC_KEY_LEN] = [0x03; PUBLIC_KEY_LEN];
+    const PAIRED: Uid = Uid([0x52; 8]);
+    const OTHER: Uid = Uid([0x4c; 8]);
+
+    fn presentation(keyboard_uid: Uid, reconnect: bool) -> Presentation {
+        Presentation {
+            keyboard_uid,
+            side: Side::Right,
+            reconnect,
+            public_key: KEYBOARD_KEY,
+        }
+    }
+
+    fn bond() -> Bond {
+        Bond {
+            peer_uid: PAIRED,
+            side: Side::Right,
+            link_key: LINK_KEY,
+        }
+    }
+
+    #[test]
+    fn confirm_tags_verify() {
+        let tag = confirm_tag(&LINK_KEY, ConfirmRole::Keyboard, &KEYBOARD_KEY, &DONGLE_KEY);
+
+        assert!(confirm_is_valid(
+            &LINK_KEY,
+            ConfirmRole::Keyboard,
+            &KEYBOARD_KEY,
+            &DONGLE_KEY,
+            &tag
+        ));
+    }
+
+    #[test]
+    fn bad_confirm_tags_are_rejected() {
+        let tag = confirm_tag(&LINK_KEY, ConfirmRole::Keyboard, &KEYBOARD_KEY, &DONGLE_KEY);
+
+        for index in [0, CONFIRM_LEN - 1] {
+            let mut tampered = tag;
+            tampered[index] ^= 1;
+            assert!(
+                !confirm_is_valid(
+                    &LINK_KEY,
+                    ConfirmRole::Keyboard,
+                    &KEYBOARD_KEY,
+                    &DONGLE_KEY,
+                    &tampered
+                ),
+                "byte {}",
+                index
+            );
+        }
+
+        // Another link key, the other end's tag, or other public keys.
+        assert!(!confirm_is_valid(
+            &[0x1d; 32],
+            ConfirmRole::Keyboard,
+            &KEYBOARD_KEY,
+            &DONGLE_KEY,
+            &tag
+        ));
+        assert!(!confirm_is_valid(
+            &LINK_KEY,
+            ConfirmRole::Dongle,
+            &KEYBOARD_KEY,
+            &DONGLE_KEY,
+            &tag
+        ));
+        assert!(!confirm_is_valid(
+            &LINK_KEY,
+            ConfirmRole::Keyboard,
+            &DONGLE_KEY,
+            &KEYBOARD_KEY,
+            &tag
+        ));
+    }
+
+    #[test]
+    fn paired_sides_are_taken() {
+        let bond = bond();
+
+        assert_eq!(
+            presentation_status(&presentation(OTHER, false), Some(&bond), true),
+            Some(PairingStatus::SideTaken)
+        );
+        assert_eq!(
+            presentation_status(&presentation(OTHER, true), Some(&bond), true),
+            Some(PairingStatus::WrongId)
+        );
+
+        // The paired half itself may pair again, or reconnect.
+        for reconnect in [false, true] {
+            assert_eq!(
+                presentation_status(&presentation(PAIRED, reconnect), Some(&bond), true),
+                Some(PairingStatus::Accepted)
+            );
+        }
+    }
+
+    #[test]
+    fn new_halves_only_pair_in_pair_mode() {
+        assert_eq!(
+            presentation_status(&presentation(OTHER, false), None, true),
+            Some(PairingStatus::Accepted)
+        );
+        assert_eq!(
+            presentation_status(&presentation(OTHER, false), None, false),
+            None
+        );
+        assert_eq!(
+            presentation_status(&presentation(OTHER, true), None, false),
+            Some(PairingStatus::NotBonded)
+        );
+        assert_eq!(
+            presentation_status(&presentation(PAIRED, true), Some(&bond()), false),
+            Some(PairingStatus::Accepted)
+        );
+    }
+}
@@ -0,0 +1,204 @@
+//! # Pairing frames
+//!
+//! The frames of the exchange described in [`super`], all sent on the default address.
+
+use crate::radio::Packet;
+use crate::radio_protocol::{Side, Uid};
+
+/// Size of a compressed SEC1 encoded P-256 public key.
+pub const PUBLIC_KEY_LEN: usize = 33;
+
+/// Size of the link key confirmation tags.
+pub const CONFIRM_LEN: usize = 16;
+
+/// Pairing frame types, always the first byte of a pairing frame.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+enum FrameKind {
+    Beacon = 0xb0,
+    Presentation = 0xb1,
+    Response = 0xb2,
+    Reconnect = 0xb3,
+    Confirm = 0xb4,
+}
+
+/// The dongle's "ready to pair" message.
+#[derive(Copy, Clone, Debug, defmt::Format)]
+pub struct Beacon {
+    pub dongle_uid: Uid,
+    /// Set if the dongle is in pair mode, otherwise only bonded halves may reconnect.
+    pub pair_mode: bool,
+    pub public_key: [u8; PUBLIC_KEY_LEN],
+}
+
+impl Beacon {
+    const LEN: usize = 1 + Uid::LEN + 1 + PUBLIC_KEY_LEN;
+
+    pub fn encode(&self, packet: &mut Packet) {
+        let mut buf = [0; Self::LEN];
+        buf[0] = FrameKind::Beacon as u8;
+        buf[1..9].copy_from_slice(&self.dongle_uid.0);
+        buf[9] = self.pair_mode as u8;
+        buf[10..].copy_from_slice(&self.public_key);
+        packet.copy_from_slice(&buf);
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() != Self::LEN || buf[0] != FrameKind::Beacon as u8 {
+            return None;
+        }
+
+        Some(Self {
+            dongle_uid: Uid(buf[1..9].try_into().unwrap()),
+            pair_mode: buf[9] != 0,
+            public_key: buf[10..].try_into().unwrap(),
+        })
+    }
+}
+
+/// A keyboard half presenting itself to the dongle.
+#[derive(Copy, Clone, Debug, defmt::Format)]
+pub struct Presentation {
+    pub keyboard_uid: Uid,
+    pub side: Side,
+    /// Set if the half has a bond with the dongle and wants to reconnect.
+    pub reconnect: bool,
+    pub public_key: [u8; PUBLIC_KEY_LEN],
+}
+
+impl Presentation {
+    const LEN: usize = 1 + Uid::LEN + 1 + PUBLIC_KEY_LEN;
+
+    pub fn encode(&self, packet: &mut Packet) {
+        let mut buf = [0; Self::LEN];
+        buf[0] = if self.reconnect {
+            FrameKind::Reconnect as u8
+        } else {
+            FrameKind::Presentation as u8
+        };
+        buf[1..9].copy_from_slice(&self.keyboard_uid.0);
+        buf[9] = self.side as u8;
+        buf[10..].copy_from_slice(&self.public_key);
+        packet.copy_from_slice(&buf);
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() != Self::LEN {
+            return None;
+        }
+
+        let reconnect = match buf[0] {
+            v if v == FrameKind::Presentation as u8 => false,
+            v if v == FrameKind::Reconnect as u8 => true,
+            _ => return None,
+        };
+
+        Some(Self {
+            keyboard_uid: Uid(buf[1..9].try_into().unwrap()),
+            side: Side::from_u8(buf[9])?,
+            reconnect,
+            public_key: buf[10..].try_into().unwrap(),
+        })
+    }
+}
+
+/// The dongle's answer to a presentation.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub enum PairingStatus {
+    /// The half is accepted, ECDH can be finished.
+    Accepted = 0,
+    /// The dongle already has a half bonded for this side.
+    SideTaken = 1,
+    /// The dongle has no bond for this side.
+    NotBonded = 2,
+    /// The dongle's bond for this side is with a different half.
+    WrongId = 3,
+}
+
+impl PairingStatus {
+    pub fn from_u8(val: u8) -> Option<Self> {
+        match val {
+            0 => Some(Self::Accepted),
+            1 => Some(Self::SideTaken),
+            2 => Some(Self::NotBonded),
+            3 => Some(Self::WrongId),
+            _ => None,
+        }
+    }
+}
+
+/// The dongle's answer to a presentation, addressed to the presenting half.
+#[derive(Copy, Clone, Debug, defmt::Format)]
+pub struct Response {
+    pub keyboard_uid: Uid,
+    pub status: PairingStatus,
+    /// The dongle's link key confirmation, only when accepting a reconnect.
+    pub confirm: Option<[u8; CONFIRM_LEN]>,
+}
+
+impl Response {
+    const LEN: usize = 1 + Uid::LEN + 1;
+
+    pub fn encode(&self, packet: &mut Packet) {
+        let mut buf = [0; Self::LEN + CONFIRM_LEN];
+        buf[0] = FrameKind::Response as u8;
+        buf[1..9].copy_from_slice(&self.keyboard_uid.0);
+        buf[9] = self.status as u8;
+
+        if let Some(confirm) = &self.confirm {
+            buf[Self::LEN..].copy_from_slice(confirm);
+            packet.copy_from_slice(&buf);
+        } else {
+            packet.copy_from_slice(&buf[..Self::LEN]);
+        }
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() < Self::LEN || buf[0] != FrameKind::Response as u8 {
+            return None;
+        }
+
+        let confirm = match buf.len() - Self::LEN {
+            0 => None,
+            CONFIRM_LEN => Some(buf[Self::LEN..].try_into().unwrap()),
+            _ => return None,
+        };
+
+        Some(Self {
+            keyboard_uid: Uid(buf[1..9].try_into().unwrap()),
+            status: PairingStatus::from_u8(buf[9])?,
+            confirm,
+        })
+    }
+}
+
+/// A reconnecting half's link key confirmation.
+#[derive(Copy, Clone, Debug, defmt::Format)]
+pub struct Confirm {
+    pub keyboard_uid: Uid,
+    pub tag: [u8; CONFIRM_LEN],
+}
+
+impl Confirm {
+    const LEN: usize = 1 + Uid::LEN + CONFIRM_LEN;
+
+    pub fn encode(&self, packet: &mut Packet) {
+        let mut buf = [0; Self::LEN];
+        buf[0] = FrameKind::Confirm as u8;
+        buf[1..9].copy_from_slice(&self.keyboard_uid.0);
+        buf[9..].copy_from_slice(&self.tag);
+        packet.copy_from_slice(&buf);
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() != Self::LEN || buf[0] != FrameKind::Confirm as u8 {
+            return None;
+        }
+
+        Some(Self {
+            keyboard_uid: Uid(buf[1..9].try_into().unwrap()),
+            tag: buf[9..].try_into().unwrap(),
+        })
+    }
+}
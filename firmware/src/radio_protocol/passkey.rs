@@ -0,0 +1,765 @@
+//! # Passkey entry against man-in-the-middle attacks when pairing
+//!
+//! When a new half is accepted the dongle picks a random passkey of [`PASSKEY_LEN`] digits in
+//! `1..=`[`MAX_DIGIT`] and blinks it on its LED, each digit as that many blinks. The user types
+//! it on the half by pressing the key in that column of the top row.
+//!
+//! The passkey is then checked one digit per round, with commitments over both public keys:
+//!
+//! 1. The keyboard half sends `Ck = HMAC(Nk, PKk || PKd || digit)` for a random nonce `Nk`.
+//! 2. The dongle answers with `Cd = HMAC(Nd, PKd || PKk || digit)`.
+//! 3. The keyboard half reveals `Nk`, the dongle checks `Ck` against its own digit.
+//! 4. The dongle reveals `Nd`, the keyboard half checks `Cd` against the typed digit.
+//!
+//! A man-in-the-middle has replaced at least one of the public keys, so it has to commit to each
+//! digit before learning it from the other end, and fails with a probability of
+//! `1 - 1 / MAX_DIGIT` per round. The bond is only stored once all rounds have succeeded.
+//!
+//! The dongle keeps running the master frames of connected halves while the user types, and
+//! only answers the half between them, see [`DongleEntry`]. The half resends every frame until
+//! it is answered.
+
+use super::pairing::Confirm;
+use super::Uid;
+use crate::clock::Clock;
+use crate::radio::{Packet, RadioDriver};
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use critical_section::Mutex;
+use embedded_hal::digital::OutputPin;
+use fugit::{ExtU64, TimerDurationU64, TimerInstantU64};
+use hmac::{Hmac, Mac};
+use rand_chacha::rand_core::{CryptoRng, RngCore};
+use sha2::Sha256;
+
+pub mod frames;
+
+use frames::{FrameKind, PasskeyFrame};
+
+/// Number of digits in a passkey.
+pub const PASSKEY_LEN: usize = 8;
+
+/// Digits are in `1..=MAX_DIGIT`, one per column of the top row of a half.
+pub const MAX_DIGIT: u8 = 6;
+
+/// How long the user has to type the full passkey.
+const PASSKEY_TIMEOUT: TimerDurationU64<1_000_000> = TimerDurationU64::secs(60);
+
+/// How long a step of the dongle's entry listens for the half, see [`DongleEntry::step`].
+const PASSKEY_WINDOW: TimerDurationU64<1_000_000> = TimerDurationU64::millis(5);
+
+/// How long the half waits for the dongle's answer before resending. Short enough that the half
+/// sends within every [`PASSKEY_WINDOW`].
+const ROUND_TIMEOUT: TimerDurationU64<1_000_000> = TimerDurationU64::millis(3);
+
+/// How long the dongle keeps listening after an answer, for the half's next frame, a resend if
+/// the answer was lost, or the confirm after the last round.
+const FOLLOW_UP: TimerDurationU64<1_000_000> = TimerDurationU64::millis(7);
+
+const NONCE_LEN: usize = 16;
+const COMMITMENT_LEN: usize = 16;
+
+/// Why a passkey entry failed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub enum PasskeyError {
+    /// The passkey was not typed within [`PASSKEY_TIMEOUT`], or the dongle did not get the
+    /// half's confirm after the last round.
+    Timeout,
+    /// A round's commitment did not match the other end's digit.
+    Mismatch,
+}
+
+/// A passkey, one digit per round.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub struct Passkey(pub [u8; PASSKEY_LEN]);
+
+impl Passkey {
+    /// A random passkey, without modulo bias.
+    pub fn random<G: RngCore + CryptoRng>(rng: &mut G) -> Self {
+        let mut digits = [0; PASSKEY_LEN];
+
+        for digit in &mut digits {
+            *digit = loop {
+                let mut byte = [0];
+                rng.fill_bytes(&mut byte);
+
+                // Largest multiple of MAX_DIGIT that fits in a byte.
+                if byte[0] < u8::MAX / MAX_DIGIT * MAX_DIGIT {
+                    break byte[0] % MAX_DIGIT + 1;
+                }
+            };
+        }
+
+        Self(digits)
+    }
+}
+
+/// The passkey the dongle is showing, if any.
+static DISPLAYED: Mutex<Cell<Option<Passkey>>> = Mutex::new(Cell::new(None));
+
+/// Set when a keyboard half is waiting for the user to type the passkey.
+static ENTRY_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// The digits typed so far, and how many there are.
+static ENTERED: Mutex<Cell<([u8; PASSKEY_LEN], usize)>> =
+    Mutex::new(Cell::new(([0; PASSKEY_LEN], 0)));
+
+fn show(passkey: Option<Passkey>) {
+    critical_section::with(|cs| DISPLAYED.borrow(cs).set(passkey));
+}
+
+/// The passkey the dongle is showing, if a pairing is waiting for it.
+pub fn displayed() -> Option<Passkey> {
+    critical_section::with(|cs| DISPLAYED.borrow(cs).get())
+}
+
+/// Show the passkey of an ongoing pairing on the dongle's LED, on the clock `C`, runs forever.
+pub async fn led_runner<C: Clock, L: OutputPin>(led: &mut L) -> ! {
+    loop {
+        let Some(passkey) = displayed() else {
+            C::delay(100.millis()).await;
+            continue;
+        };
+
+        for digit in passkey.0 {
+            for _ in 0..digit {
+                // The LED's pin cannot fail.
+                led.set_high().ok();
+                C::delay(200.millis()).await;
+                led.set_low().ok();
+                C::delay(300.millis()).await;
+            }
+
+            C::delay(1.secs()).await;
+        }
+
+        // Longer pause before showing the passkey again.
+        C::delay(2.secs()).await;
+    }
+}
+
+/// Feed a key press in the top row of the key matrix to passkey entry.
+///
+/// Presses are ignored unless a pairing is waiting for the passkey.
+pub fn key_pressed(column: u8) {
+    if !ENTRY_ACTIVE.load(Ordering::Relaxed) || column >= MAX_DIGIT {
+        return;
+    }
+
+    critical_section::with(|cs| {
+        let entered = ENTERED.borrow(cs);
+        let (mut digits, len) = entered.get();
+
+        if len < PASSKEY_LEN {
+            digits[len] = column + 1;
+            entered.set((digits, len + 1));
+        }
+    });
+}
+
+fn start_entry() {
+    critical_section::with(|cs| ENTERED.borrow(cs).set(([0; PASSKEY_LEN], 0)));
+    ENTRY_ACTIVE.store(true, Ordering::Relaxed);
+}
+
+fn stop_entry() {
+    ENTRY_ACTIVE.store(false, Ordering::Relaxed);
+}
+
+/// Wait for the user to type digit number `round`.
+async fn entered_digit<C: Clock>(round: usize, deadline: TimerInstantU64<1_000_000>) -> Option<u8> {
+    while C::now() < deadline {
+        let (digits, len) = critical_section::with(|cs| ENTERED.borrow(cs).get());
+
+        if round < len {
+            return Some(digits[round]);
+        }
+
+        C::delay(10.millis()).await;
+    }
+
+    None
+}
+
+fn commitment_mac(
+    nonce: &[u8; NONCE_LEN],
+    own_public_key: &[u8],
+    other_public_key: &[u8],
+    digit: u8,
+) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(nonce).unwrap();
+    mac.update(b"corne passkey");
+    mac.update(own_public_key);
+    mac.update(other_public_key);
+    mac.update(&[digit]);
+    mac
+}
+
+fn commitment(
+    nonce: &[u8; NONCE_LEN],
+    own_public_key: &[u8],
+    other_public_key: &[u8],
+    digit: u8,
+) -> [u8; COMMITMENT_LEN] {
+    let tag = commitment_mac(nonce, own_public_key, other_public_key, digit)
+        .finalize()
+        .into_bytes();
+    tag[..COMMITMENT_LEN].try_into().unwrap()
+}
+
+fn commitment_is_valid(
+    commitment: &[u8; COMMITMENT_LEN],
+    nonce: &[u8; NONCE_LEN],
+    own_public_key: &[u8],
+    other_public_key: &[u8],
+    digit: u8,
+) -> bool {
+    commitment_mac(nonce, own_public_key, other_public_key, digit)
+        .verify_truncated_left(commitment)
+        .is_ok()
+}
+
+/// The dongle's end of passkey entry with an accepted half.
+///
+/// The dongle keeps its links running during the entry, so it is stepped between the master
+/// frames with [`DongleEntry::step`], and the half resends its frames until a step answers them.
+pub struct DongleEntry {
+    keyboard_uid: Uid,
+    passkey: Passkey,
+    /// The dongle's nonce for every round.
+    nonces: [[u8; NONCE_LEN]; PASSKEY_LEN],
+    /// The round being checked, [`PASSKEY_LEN`] once all have passed.
+    round: usize,
+    /// The half's commitment of the round, once it has sent it.
+    keyboard_commitment: Option<[u8; COMMITMENT_LEN]>,
+    deadline: TimerInstantU64<1_000_000>,
+}
+
+impl DongleEntry {
+    /// Pick a random passkey for the half `keyboard_uid` and show it, on the clock `C`.
+    pub fn new<C: Clock, G: RngCore + CryptoRng>(rng: &mut G, keyboard_uid: Uid) -> Self {
+        let passkey = Passkey::random(rng);
+        let mut nonces = [[0; NONCE_LEN]; PASSKEY_LEN];
+        for nonce in &mut nonces {
+            rng.fill_bytes(nonce);
+        }
+
+        show(Some(passkey));
+
+        Self {
+            keyboard_uid,
+            passkey,
+            nonces,
+            round: 0,
+            keyboard_commitment: None,
+            deadline: C::now() + PASSKEY_TIMEOUT,
+        }
+    }
+
+    /// Answer the half's passkey frames for a [`PASSKEY_WINDOW`], and for as long as the half
+    /// keeps sending right after the answers.
+    ///
+    /// `None` while the entry goes on. Once all rounds have passed it ends with the half's
+    /// [`Confirm`], which the half sends right after the last round, or fails if it does not
+    /// arrive within the step. The passkey is shown until the entry has ended.
+    pub async fn step<R: RadioDriver, C: Clock>(
+        &mut self,
+        radio: &mut R,
+        packet: &mut Packet,
+        keyboard_public_key: &[u8],
+        dongle_public_key: &[u8],
+    ) -> Option<Result<Confirm, PasskeyError>> {
+        let result = self
+            .listen::<R, C>(radio, packet, keyboard_public_key, dongle_public_key)
+            .await;
+
+        if result.is_some() {
+            show(None);
+        }
+
+        result
+    }
+
+    async fn listen<R: RadioDriver, C: Clock>(
+        &mut self,
+        radio: &mut R,
+        packet: &mut Packet,
+        keyboard_public_key: &[u8],
+        dongle_public_key: &[u8],
+    ) -> Option<Result<Confirm, PasskeyError>> {
+        let mut listen_until = C::now() + PASSKEY_WINDOW;
+
+        loop {
+            let received = C::timeout_at(listen_until.min(self.deadline), radio.recv(packet)).await;
+
+            match received {
+                Ok(Ok(_)) => {}
+                Ok(Err(_)) => continue,
+                Err(_timeout) if C::now() >= self.deadline => {
+                    defmt::warn!("Passkey entry timed out");
+                    return Some(Err(PasskeyError::Timeout));
+                }
+                Err(_timeout) if self.round == PASSKEY_LEN => {
+                    defmt::warn!("No confirm after the passkey entry");
+                    return Some(Err(PasskeyError::Timeout));
+                }
+                Err(_timeout) => return None,
+            }
+
+            if let Some(confirm) = Confirm::decode(packet) {
+                if confirm.keyboard_uid == self.keyboard_uid && self.round == PASSKEY_LEN {
+                    return Some(Ok(confirm));
+                }
+
+                continue;
+            }
+
+            let Some(frame) = PasskeyFrame::decode(packet) else {
+                continue;
+            };
+
+            let reply = match self.answer(&frame, keyboard_public_key, dongle_public_key) {
+                Ok(Some(reply)) => reply,
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            };
+
+            reply.encode(packet);
+            // A lost reply is asked for again.
+            if let Err(e) = radio.send_no_cca(packet).await {
+                defmt::warn!("Passkey reply not sent: {}", e);
+            }
+
+            listen_until = listen_until.max(C::now() + FOLLOW_UP);
+        }
+    }
+
+    /// The answer to `frame` from the half, if it is one of the current or the last round.
+    ///
+    /// Fails if the half revealed a commitment to another digit than the round's.
+    fn answer(
+        &mut self,
+        frame: &PasskeyFrame,
+        keyboard_public_key: &[u8],
+        dongle_public_key: &[u8],
+    ) -> Result<Option<PasskeyFrame>, PasskeyError> {
+        if frame.keyboard_uid != self.keyboard_uid {
+            return Ok(None);
+        }
+
+        let reply = |kind, round: usize, value| PasskeyFrame {
+            kind,
+            keyboard_uid: self.keyboard_uid,
+            round: round as u8,
+            value,
+        };
+
+        // The half did not get the reveal that ended the last round.
+        if let Some(previous) = self.round.checked_sub(1) {
+            if frame.kind == FrameKind::Reveal && usize::from(frame.round) == previous {
+                return Ok(Some(reply(
+                    FrameKind::Reveal,
+                    previous,
+                    self.nonces[previous],
+                )));
+            }
+        }
+
+        let round = self.round;
+        if round == PASSKEY_LEN || usize::from(frame.round) != round {
+            return Ok(None);
+        }
+
+        let digit = self.passkey.0[round];
+        let nonce = self.nonces[round];
+
+        match frame.kind {
+            FrameKind::Commit => {
+                self.keyboard_commitment = Some(frame.value);
+                let own_commitment =
+                    commitment(&nonce, dongle_public_key, keyboard_public_key, digit);
+
+                Ok(Some(reply(FrameKind::Commit, round, own_commitment)))
+            }
+            FrameKind::Reveal => {
+                let Some(keyboard_commitment) = self.keyboard_commitment else {
+                    return Ok(None);
+                };
+
+                if !commitment_is_valid(
+                    &keyboard_commitment,
+                    &frame.value,
+                    keyboard_public_key,
+                    dongle_public_key,
+                    digit,
+                ) {
+                    defmt::warn!("Passkey mismatch in round {}", round);
+                    return Err(PasskeyError::Mismatch);
+                }
+
+                self.round += 1;
+                self.keyboard_commitment = None;
+
+                Ok(Some(reply(FrameKind::Reveal, round, nonce)))
+            }
+        }
+    }
+}
+
+/// A keyboard half's end of passkey entry, returns `true` if the typed passkey matched the
+/// dongle's.
+pub async fn keyboard_exchange<R: RadioDriver, C: Clock, G: RngCore + CryptoRng>(
+    radio: &mut R,
+    rng: &mut G,
+    packet: &mut Packet,
+    keyboard_uid: Uid,
+    keyboard_public_key: &[u8],
+    dongle_public_key: &[u8],
+) -> bool {
+    defmt::info!("Type the passkey shown by the dongle");
+
+    start_entry();
+    let accepted = keyboard_rounds::<R, C, G>(
+        radio,
+        rng,
+        packet,
+        keyboard_uid,
+        keyboard_public_key,
+        dongle_public_key,
+    )
+    .await;
+    stop_entry();
+
+    accepted
+}
+
+async fn keyboard_rounds<R: RadioDriver, C: Clock, G: RngCore + CryptoRng>(
+    radio: &mut R,
+    rng: &mut G,
+    packet: &mut Packet,
+    keyboard_uid: Uid,
+    keyboard_public_key: &[u8],
+    dongle_public_key: &[u8],
+) -> bool {
+    let deadline = C::now() + PASSKEY_TIMEOUT;
+
+    for round in 0..PASSKEY_LEN {
+        let Some(digit) = entered_digit::<C>(round, deadline).await else {
+            defmt::warn!("Passkey entry timed out");
+            return false;
+        };
+        let round = round as u8;
+
+        let mut nonce = [0; NONCE_LEN];
+        rng.fill_bytes(&mut nonce);
+
+        let own_commitment = commitment(&nonce, keyboard_public_key, dongle_public_key, digit);
+
+        let Some(dongle_commitment) = keyboard_request::<R, C>(
+            radio,
+            packet,
+            FrameKind::Commit,
+            keyboard_uid,
+            round,
+            own_commitment,
+            deadline,
+        )
+        .await
+        else {
+            return false;
+        };
+
+        let Some(dongle_nonce) = keyboard_request::<R, C>(
+            radio,
+            packet,
+            FrameKind::Reveal,
+            keyboard_uid,
+            round,
+            nonce,
+            deadline,
+        )
+        .await
+        else {
+            return false;
+        };
+
+        if !commitment_is_valid(
+            &dongle_commitment,
+            &dongle_nonce,
+            dongle_public_key,
+            keyboard_public_key,
+            digit,
+        ) {
+            defmt::warn!("Passkey mismatch in round {}", round);
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Send a frame to the dongle until it answers with the same kind of frame for the round.
+async fn keyboard_request<R: RadioDriver, C: Clock>(
+    radio: &mut R,
+    packet: &mut Packet,
+    kind: FrameKind,
+    keyboard_uid: Uid,
+    round: u8,
+    value: [u8; 16],
+    deadline: TimerInstantU64<1_000_000>,
+) -> Option<[u8; 16]> {
+    while C::now() < deadline {
+        PasskeyFrame {
+            kind,
+            keyboard_uid,
+            round,
+            value,
+        }
+        .encode(packet);
+        // Not sent is like not answered, the frame is sent again.
+        if let Err(e) = radio.send_no_cca(packet).await {
+            defmt::warn!("Passkey frame not sent: {}", e);
+        }
+
+        let answer = match C::timeout_after(ROUND_TIMEOUT, radio.recv(packet)).await {
+            Ok(Ok(_)) => PasskeyFrame::decode(packet),
+            _ => None,
+        };
+
+        if let Some(answer) =
+            answer.filter(|f| f.kind == kind && f.keyboard_uid == keyboard_uid && f.round == round)
+        {
+            return Some(answer.value);
+        }
+    }
+
+    defmt::warn!("Passkey entry timed out");
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_chacha::rand_core::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    const KEYBOARD_KEY: &[u8] = &[0x02; 33];
+    const DONGLE_KEY: &[u8] = &[0x03; 33];
+    const KEYBOARD_UID: Uid = Uid([0x52; 8]);
+    const NONCE: [u8; NONCE_LEN] = [0x9e; NONCE_LEN];
+    const PASSKEY: Passkey = Passkey([3, 1, 6, 2, 2, 5, 4, 1]);
+
+    /// The dongle's entry of `PASSKEY`, with the first step not taken yet.
+    fn entry() -> DongleEntry {
+        DongleEntry {
+            keyboard_uid: KEYBOARD_UID,
+            passkey: PASSKEY,
+            nonces: core::array::from_fn(|round| [round as u8; NONCE_LEN]),
+            round: 0,
+            keyboard_commitment: None,
+            deadline: TimerInstantU64::from_ticks(u64::MAX),
+        }
+    }
+
+    fn frame(kind: FrameKind, round: usize, value: [u8; 16]) -> PasskeyFrame {
+        PasskeyFrame {
+            kind,
+            keyboard_uid: KEYBOARD_UID,
+            round: round as u8,
+            value,
+        }
+    }
+
+    /// Play the half's end of `round` with `digit` typed, returns the dongle's commitment and
+    /// nonce.
+    fn round(
+        entry: &mut DongleEntry,
+        round: usize,
+        digit: u8,
+    ) -> Result<([u8; COMMITMENT_LEN], [u8; NONCE_LEN]), PasskeyError> {
+        let own_commitment = commitment(&NONCE, KEYBOARD_KEY, DONGLE_KEY, digit);
+
+        let committed = entry
+            .answer(
+                &frame(FrameKind::Commit, round, own_commitment),
+                KEYBOARD_KEY,
+                DONGLE_KEY,
+            )?
+            .unwrap();
+        let revealed = entry
+            .answer(
+                &frame(FrameKind::Reveal, round, NONCE),
+                KEYBOARD_KEY,
+                DONGLE_KEY,
+            )?
+            .unwrap();
+
+        assert_eq!(
+            (committed.kind, revealed.kind),
+            (FrameKind::Commit, FrameKind::Reveal)
+        );
+        Ok((committed.value, revealed.value))
+    }
+
+    #[test]
+    fn matching_digits_pass_the_round() {
+        for digit in 1..=MAX_DIGIT {
+            // The half commits with its public key first, the dongle checks with its own digit.
+            let committed = commitment(&NONCE, KEYBOARD_KEY, DONGLE_KEY, digit);
+            assert!(commitment_is_valid(
+                &committed,
+                &NONCE,
+                KEYBOARD_KEY,
+                DONGLE_KEY,
+                digit
+            ));
+        }
+    }
+
+    #[test]
+    fn wrong_digit_fails_the_round() {
+        let typed = 3;
+        let committed = commitment(&NONCE, KEYBOARD_KEY, DONGLE_KEY, typed);
+
+        for shown in (1..=MAX_DIGIT).filter(|&digit| digit != typed) {
+            assert!(
+                !commitment_is_valid(&committed, &NONCE, KEYBOARD_KEY, DONGLE_KEY, shown),
+                "digit {}",
+                shown
+            );
+        }
+    }
+
+    #[test]
+    fn commitments_bind_nonce_and_public_keys() {
+        let committed = commitment(&NONCE, KEYBOARD_KEY, DONGLE_KEY, 3);
+
+        // Revealing another nonce, or a man-in-the-middle's public key.
+        assert!(!commitment_is_valid(
+            &committed,
+            &[0x9f; NONCE_LEN],
+            KEYBOARD_KEY,
+            DONGLE_KEY,
+            3
+        ));
+        assert!(!commitment_is_valid(
+            &committed,
+            &NONCE,
+            &[0x04; 33],
+            DONGLE_KEY,
+            3
+        ));
+        // The dongle's commitment is not the half's.
+        assert!(!commitment_is_valid(
+            &committed,
+            &NONCE,
+            DONGLE_KEY,
+            KEYBOARD_KEY,
+            3
+        ));
+    }
+
+    #[test]
+    fn random_passkeys_use_every_digit() {
+        let mut rng = ChaCha8Rng::seed_from_u64(5);
+        let mut seen = [false; MAX_DIGIT as usize];
+
+        for _ in 0..20 {
+            for digit in Passkey::random(&mut rng).0 {
+                assert!((1..=MAX_DIGIT).contains(&digit));
+                seen[digit as usize - 1] = true;
+            }
+        }
+
+        assert!(seen.iter().all(|&seen| seen));
+    }
+
+    #[test]
+    fn dongle_passes_the_typed_passkey() {
+        let mut entry = entry();
+
+        for (i, digit) in PASSKEY.0.into_iter().enumerate() {
+            let (dongle_commitment, dongle_nonce) = round(&mut entry, i, digit).unwrap();
+
+            // The half checks the dongle's commitment against the typed digit.
+            assert!(commitment_is_valid(
+                &dongle_commitment,
+                &dongle_nonce,
+                DONGLE_KEY,
+                KEYBOARD_KEY,
+                digit
+            ));
+        }
+
+        assert_eq!(entry.round, PASSKEY_LEN);
+    }
+
+    #[test]
+    fn dongle_fails_a_wrong_digit() {
+        let mut entry = entry();
+        round(&mut entry, 0, PASSKEY.0[0]).unwrap();
+
+        let wrong = PASSKEY.0[1] % MAX_DIGIT + 1;
+        assert_eq!(round(&mut entry, 1, wrong), Err(PasskeyError::Mismatch));
+    }
+
+    #[test]
+    fn dongle_resends_lost_answers() {
+        let mut entry = entry();
+        let (dongle_commitment, dongle_nonce) = round(&mut entry, 0, PASSKEY.0[0]).unwrap();
+
+        // The reveal of the last round is answered again, with the same nonce.
+        let resent = entry
+            .answer(
+                &frame(FrameKind::Reveal, 0, NONCE),
+                KEYBOARD_KEY,
+                DONGLE_KEY,
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(resent.value, dongle_nonce);
+
+        // So is a commitment of the current round, but not one of an earlier round.
+        let own_commitment = commitment(&NONCE, KEYBOARD_KEY, DONGLE_KEY, PASSKEY.0[1]);
+        let commit = frame(FrameKind::Commit, 1, own_commitment);
+        let first = entry
+            .answer(&commit, KEYBOARD_KEY, DONGLE_KEY)
+            .unwrap()
+            .unwrap();
+        let second = entry
+            .answer(&commit, KEYBOARD_KEY, DONGLE_KEY)
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.value, second.value);
+
+        let stale = frame(FrameKind::Commit, 0, own_commitment);
+        assert!(entry
+            .answer(&stale, KEYBOARD_KEY, DONGLE_KEY)
+            .unwrap()
+            .is_none());
+        assert_ne!(first.value, dongle_commitment);
+    }
+
+    #[test]
+    fn dongle_ignores_other_halves_and_early_reveals() {
+        let mut entry = entry();
+        let own_commitment = commitment(&NONCE, KEYBOARD_KEY, DONGLE_KEY, PASSKEY.0[0]);
+
+        let other = PasskeyFrame {
+            keyboard_uid: Uid([0x4c; 8]),
+            ..frame(FrameKind::Commit, 0, own_commitment)
+        };
+        assert!(entry
+            .answer(&other, KEYBOARD_KEY, DONGLE_KEY)
+            .unwrap()
+            .is_none());
+
+        // A reveal before the commitment says nothing about the digit.
+        let reveal = frame(FrameKind::Reveal, 0, NONCE);
+        assert!(entry
+            .answer(&reveal, KEYBOARD_KEY, DONGLE_KEY)
+            .unwrap()
+            .is_none());
+        assert_eq!(entry.round, 0);
+    }
+}
@@ -0,0 +1,58 @@
+//! # Passkey frames
+//!
+//! The commitments and reveals of the rounds described in [`super`], on the default address.
+
+use crate::radio::Packet;
+use crate::radio_protocol::Uid;
+
+/// Passkey frame types.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub enum FrameKind {
+    /// A commitment to the round's digit.
+    Commit = 0xb5,
+    /// The nonce of the commitment.
+    Reveal = 0xb6,
+}
+
+/// A commitment or nonce of one passkey round, in either direction.
+#[derive(Copy, Clone, Debug, defmt::Format)]
+pub struct PasskeyFrame {
+    pub kind: FrameKind,
+    pub keyboard_uid: Uid,
+    pub round: u8,
+    /// The commitment or the nonce, depending on `kind`.
+    pub value: [u8; 16],
+}
+
+impl PasskeyFrame {
+    const LEN: usize = 1 + Uid::LEN + 1 + 16;
+
+    pub fn encode(&self, packet: &mut Packet) {
+        let mut buf = [0; Self::LEN];
+        buf[0] = self.kind as u8;
+        buf[1..9].copy_from_slice(&self.keyboard_uid.0);
+        buf[9] = self.round;
+        buf[10..].copy_from_slice(&self.value);
+        packet.copy_from_slice(&buf);
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() != Self::LEN {
+            return None;
+        }
+
+        let kind = match buf[0] {
+            v if v == FrameKind::Commit as u8 => FrameKind::Commit,
+            v if v == FrameKind::Reveal as u8 => FrameKind::Reveal,
+            _ => return None,
+        };
+
+        Some(Self {
+            kind,
+            keyboard_uid: Uid(buf[1..9].try_into().unwrap()),
+            round: buf[9],
+            value: buf[10..].try_into().unwrap(),
+        })
+    }
+}
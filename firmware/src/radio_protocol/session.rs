@@ -0,0 +1,536 @@
+//! # Encrypted session between a keyboard half and the dongle
+//!
+//! The dongle decides when to rekey, either every [`REKEY_INTERVAL_FRAMES`] or on
+//! [`request_rekey`], and announces its current key epoch in the sync. The sync carries a tag
+//! under the announced epoch's keys, a keyboard half that sees the next epoch in a sync only
+//! drops the old keys once the tag has been verified under the new ones.
+//!
+//! Channel map updates (see [`super::afh`]) work the same way: the dongle schedules a new map at
+//! a future frame counter and announces it in the sync until then, and both ends switch to it at
+//! the start of that master frame.
+
+use super::crypto::{Direction, Error, FrameNonce, LinkCipher, ReplayWindow};
+use super::hopping::{ChannelHopping, ChannelMap};
+use super::key_schedule::{self, KeySchedule, LinkParameters, REKEY_INTERVAL_FRAMES};
+use super::state::DownstreamSender;
+use super::{Side, Uid};
+use crate::radio::Packet;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Pending rekey request for all of the dongle's sessions.
+static REKEY_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Ask the dongle to rekey all sessions at the next master frame.
+pub fn request_rekey() {
+    REKEY_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Take a pending [`request_rekey`].
+pub fn take_rekey_request() -> bool {
+    REKEY_REQUESTED.swap(false, Ordering::Relaxed)
+}
+
+/// The result of a successful pairing or reconnect, as seen from one end.
+#[derive(Clone)]
+pub struct PairedPeer {
+    /// The ID of the other end.
+    pub uid: Uid,
+    /// The side of the keyboard half in this pairing.
+    pub side: Side,
+    /// The ECDH shared secret of this session.
+    pub shared_secret: [u8; 32],
+}
+
+/// Which end of a session this device is.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+enum Role {
+    Dongle,
+    Keyboard,
+}
+
+impl Role {
+    fn tx_direction(self) -> Direction {
+        match self {
+            Role::Dongle => Direction::Downstream,
+            Role::Keyboard => Direction::Upstream,
+        }
+    }
+
+    fn rx_direction(self) -> Direction {
+        match self {
+            Role::Dongle => Direction::Upstream,
+            Role::Keyboard => Direction::Downstream,
+        }
+    }
+}
+
+/// The ciphers of one key epoch.
+struct EpochCiphers {
+    keys: KeySchedule,
+    tx: LinkCipher,
+    rx: LinkCipher,
+}
+
+impl EpochCiphers {
+    fn new(keys: KeySchedule, role: Role) -> Self {
+        let upstream = LinkCipher::new(keys.upstream_key());
+        let downstream = LinkCipher::new(keys.downstream_key());
+
+        let (tx, rx) = match role {
+            Role::Dongle => (downstream, upstream),
+            Role::Keyboard => (upstream, downstream),
+        };
+
+        Self { keys, tx, rx }
+    }
+}
+
+/// An established session, sealing outgoing and opening incoming frames.
+pub struct Session {
+    peer: PairedPeer,
+    role: Role,
+    parameters: LinkParameters,
+    channel_hopping: ChannelHopping,
+    /// A channel map to switch to at the start of a master frame.
+    channel_map_update: Option<(ChannelMap, u32)>,
+    current: EpochCiphers,
+    frames_in_epoch: u32,
+    replay_window: ReplayWindow,
+    /// Sequence number of the last sealed frame, nonces must never be reused.
+    last_sealed: Option<u64>,
+    /// Sequence number of the last state frame passed on by the dongle.
+    last_state: Option<u8>,
+    downstream: DownstreamSender,
+}
+
+impl Session {
+    /// Start the dongle's end of a session from the result of pairing or a reconnect.
+    pub fn dongle(peer: PairedPeer, dongle_uid: Uid) -> Self {
+        let (parameters, keys) = key_schedule::derive(&peer.shared_secret, peer.uid, dongle_uid);
+        Self::new(peer, Role::Dongle, parameters, keys)
+    }
+
+    /// Start a keyboard half's end of a session from the result of pairing or a reconnect.
+    pub fn keyboard(peer: PairedPeer, keyboard_uid: Uid) -> Self {
+        let (parameters, keys) = key_schedule::derive(&peer.shared_secret, keyboard_uid, peer.uid);
+        Self::new(peer, Role::Keyboard, parameters, keys)
+    }
+
+    fn new(peer: PairedPeer, role: Role, parameters: LinkParameters, keys: KeySchedule) -> Self {
+        Self {
+            peer,
+            role,
+            channel_hopping: ChannelHopping::new(&parameters.hop_seed),
+            channel_map_update: None,
+            parameters,
+            current: EpochCiphers::new(keys, role),
+            frames_in_epoch: 0,
+            replay_window: ReplayWindow::new(),
+            last_sealed: None,
+            last_state: None,
+            downstream: DownstreamSender::new(),
+        }
+    }
+
+    /// The other end of the session.
+    pub fn peer(&self) -> &PairedPeer {
+        &self.peer
+    }
+
+    /// The session's constant parameters.
+    pub fn parameters(&self) -> &LinkParameters {
+        &self.parameters
+    }
+
+    /// The session's channel hopping sequence, at the first slot of a frame.
+    pub fn channel_hopping(&self) -> ChannelHopping {
+        self.channel_hopping.clone()
+    }
+
+    /// The channels the session currently hops on.
+    pub fn channel_map(&self) -> &ChannelMap {
+        self.channel_hopping.channel_map()
+    }
+
+    /// Check if a channel map update is scheduled.
+    pub fn channel_map_update_pending(&self) -> bool {
+        self.channel_map_update.is_some()
+    }
+
+    /// Switch to `channel_map` at the start of master frame `instant`, replacing any update that
+    /// is already scheduled.
+    ///
+    /// Invalid maps are ignored.
+    pub fn schedule_channel_map(&mut self, channel_map: ChannelMap, instant: u32) {
+        if !channel_map.is_valid() {
+            defmt::warn!("Ignoring invalid channel map {}", channel_map);
+            return;
+        }
+
+        if self.channel_map_update.is_none() && channel_map == *self.channel_map() {
+            return;
+        }
+
+        self.channel_map_update = Some((channel_map, instant));
+    }
+
+    /// The channel map and frame counter it takes effect at, as announced in the sync of master
+    /// frame `frame_counter`.
+    pub fn announced_channel_map(&self, frame_counter: u32) -> (ChannelMap, u32) {
+        self.channel_map_update
+            .unwrap_or((*self.channel_map(), frame_counter))
+    }
+
+    /// Apply a scheduled channel map update if master frame `frame_counter` is at or past its
+    /// instant.
+    pub fn apply_channel_map_update(&mut self, frame_counter: u32) {
+        let Some((channel_map, instant)) = self.channel_map_update else {
+            return;
+        };
+
+        // Wrapping comparison, the instant is never more than a few frames away.
+        if (frame_counter.wrapping_sub(instant) as i32) < 0 {
+            return;
+        }
+
+        self.channel_hopping.set_channel_map(channel_map);
+        self.channel_map_update = None;
+
+        defmt::info!(
+            "{} half hops on {} channels from frame {}",
+            self.peer.side,
+            channel_map.num_used(),
+            frame_counter
+        );
+    }
+
+    /// Dongle: check if a state frame carries a new state, and not a retransmission of one
+    /// that has already been passed on.
+    pub fn accept_state(&mut self, sequence: u8) -> bool {
+        if self.last_state == Some(sequence) {
+            return false;
+        }
+
+        self.last_state = Some(sequence);
+        true
+    }
+
+    /// Dongle: the commands for the half carried on the session's ACKs.
+    pub fn downstream(&mut self) -> &mut DownstreamSender {
+        &mut self.downstream
+    }
+
+    /// The current key epoch, as announced in the sync.
+    pub fn key_epoch(&self) -> u8 {
+        self.current.keys.epoch() as u8
+    }
+
+    /// Dongle: count a master frame, rekeying when the epoch is over or if `rekey` is set.
+    pub fn next_frame(&mut self, rekey: bool) {
+        self.frames_in_epoch += 1;
+
+        if rekey || self.frames_in_epoch >= REKEY_INTERVAL_FRAMES {
+            self.current = EpochCiphers::new(self.current.keys.next(), self.role);
+            self.frames_in_epoch = 0;
+
+            defmt::info!(
+                "Rekeyed {} half to epoch {}",
+                self.peer.side,
+                self.current.keys.epoch()
+            );
+        }
+    }
+
+    /// Dongle: append the tag to the sync of master frame `frame_counter`, under the current
+    /// keys.
+    ///
+    /// The sync takes the nonce of the link's first slot, which carries no other frame.
+    pub fn seal_sync(&mut self, frame_counter: u32, packet: &mut Packet) -> Result<(), Error> {
+        let nonce = self.tx_nonce(frame_counter, 0)?;
+        self.current.tx.sign(nonce, packet)?;
+        self.last_sealed = Some(nonce.sequence());
+
+        Ok(())
+    }
+
+    /// Keyboard: verify the tag of the sync of master frame `frame_counter`, announcing
+    /// `key_epoch`, and remove it.
+    ///
+    /// A sync under the next epoch's keys switches to them. Nothing changes if the sync does
+    /// not verify, e.g. if the dongle is more than one epoch ahead, and the session is lost
+    /// once the syncs have been missed for long enough.
+    pub fn open_sync(
+        &mut self,
+        frame_counter: u32,
+        key_epoch: u8,
+        packet: &mut Packet,
+    ) -> Result<(), Error> {
+        let nonce = FrameNonce {
+            frame_counter,
+            slot: 0,
+            direction: self.role.rx_direction(),
+        };
+        self.replay_window.check(nonce.sequence())?;
+
+        if key_epoch == self.key_epoch() {
+            self.current.rx.verify(nonce, packet)?;
+        } else if key_epoch == self.key_epoch().wrapping_add(1) {
+            let next = EpochCiphers::new(self.current.keys.next(), self.role);
+            next.rx.verify(nonce, packet)?;
+            self.current = next;
+        } else {
+            return Err(Error::Authentication);
+        }
+
+        self.replay_window.accept(nonce.sequence());
+
+        Ok(())
+    }
+
+    /// Seal the packet's payload for sending in `slot` of master frame `frame_counter`.
+    ///
+    /// Fails with [`Error::Replayed`] if the position is not after the last sealed frame, as
+    /// that would reuse a nonce.
+    pub fn seal(&mut self, frame_counter: u32, slot: u8, packet: &mut Packet) -> Result<(), Error> {
+        let nonce = self.tx_nonce(frame_counter, slot)?;
+        self.current.tx.seal(nonce, packet)?;
+        self.last_sealed = Some(nonce.sequence());
+
+        Ok(())
+    }
+
+    /// Like [`Self::seal`], with the tag truncated for the Turbo slot profile's state frames.
+    pub fn seal_short(
+        &mut self,
+        frame_counter: u32,
+        slot: u8,
+        packet: &mut Packet,
+    ) -> Result<(), Error> {
+        let nonce = self.tx_nonce(frame_counter, slot)?;
+        self.current.tx.seal_short(nonce, packet)?;
+        self.last_sealed = Some(nonce.sequence());
+
+        Ok(())
+    }
+
+    /// The nonce of a frame sent in `slot` of master frame `frame_counter`, if it is after the
+    /// last sealed frame.
+    fn tx_nonce(&self, frame_counter: u32, slot: u8) -> Result<FrameNonce, Error> {
+        let nonce = FrameNonce {
+            frame_counter,
+            slot,
+            direction: self.role.tx_direction(),
+        };
+
+        if self
+            .last_sealed
+            .is_some_and(|last| nonce.sequence() <= last)
+        {
+            return Err(Error::Replayed);
+        }
+
+        Ok(nonce)
+    }
+
+    /// Open a packet received in `slot` of master frame `frame_counter`.
+    pub fn open(&mut self, frame_counter: u32, slot: u8, packet: &mut Packet) -> Result<(), Error> {
+        let nonce = FrameNonce {
+            frame_counter,
+            slot,
+            direction: self.role.rx_direction(),
+        };
+
+        self.replay_window.check(nonce.sequence())?;
+        self.current.rx.open(nonce, packet)?;
+        self.replay_window.accept(nonce.sequence());
+
+        Ok(())
+    }
+
+    /// Open a packet sealed with [`Self::seal_short`] in `slot` of master frame `frame_counter`.
+    pub fn open_short(
+        &mut self,
+        frame_counter: u32,
+        slot: u8,
+        packet: &mut Packet,
+    ) -> Result<(), Error> {
+        let nonce = FrameNonce {
+            frame_counter,
+            slot,
+            direction: self.role.rx_direction(),
+        };
+
+        self.replay_window.check(nonce.sequence())?;
+        self.current.rx.open_short(nonce, packet)?;
+        self.replay_window.accept(nonce.sequence());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DONGLE_UID: Uid = Uid([0xd0; 8]);
+    const KEYBOARD_UID: Uid = Uid([0x4b; 8]);
+
+    fn sessions() -> (Session, Session) {
+        let peer = |uid| PairedPeer {
+            uid,
+            side: Side::Left,
+            shared_secret: [0x5e; 32],
+        };
+
+        (
+            Session::dongle(peer(KEYBOARD_UID), DONGLE_UID),
+            Session::keyboard(peer(DONGLE_UID), KEYBOARD_UID),
+        )
+    }
+
+    fn packet() -> Packet {
+        let mut packet = Packet::new();
+        packet.copy_from_slice(&[1, 2, 3]);
+        packet
+    }
+
+    #[test]
+    fn nonces_are_never_reused() {
+        let (_, mut keyboard) = sessions();
+
+        keyboard.seal(5, 10, &mut packet()).unwrap();
+        assert_eq!(keyboard.seal(5, 10, &mut packet()), Err(Error::Replayed));
+        assert_eq!(keyboard.seal(5, 9, &mut packet()), Err(Error::Replayed));
+        assert_eq!(keyboard.seal(4, 200, &mut packet()), Err(Error::Replayed));
+        keyboard.seal(5, 11, &mut packet()).unwrap();
+        keyboard.seal(6, 0, &mut packet()).unwrap();
+    }
+
+    #[test]
+    fn replayed_frames_are_rejected() {
+        let (mut dongle, mut keyboard) = sessions();
+
+        let mut sent = packet();
+        keyboard.seal(5, 10, &mut sent).unwrap();
+        let mut replayed = packet();
+        replayed.copy_from_slice(&sent);
+
+        dongle.open(5, 10, &mut sent).unwrap();
+        assert_eq!(&sent[..], &[1, 2, 3]);
+        assert_eq!(dongle.open(5, 10, &mut replayed), Err(Error::Replayed));
+    }
+
+    #[test]
+    fn short_frames_are_replay_checked() {
+        let (mut dongle, mut keyboard) = sessions();
+
+        let mut sent = packet();
+        keyboard.seal_short(5, 10, &mut sent).unwrap();
+        assert_eq!(
+            keyboard.seal_short(5, 10, &mut packet()),
+            Err(Error::Replayed)
+        );
+        let mut replayed = packet();
+        replayed.copy_from_slice(&sent);
+
+        dongle.open_short(5, 10, &mut sent).unwrap();
+        assert_eq!(&sent[..], &[1, 2, 3]);
+        assert_eq!(
+            dongle.open_short(5, 10, &mut replayed),
+            Err(Error::Replayed)
+        );
+    }
+
+    /// The dongle's sync of `frame_counter`, announcing its epoch, as sent.
+    fn sync(dongle: &mut Session, frame_counter: u32) -> Packet {
+        let mut packet = Packet::new();
+        packet.copy_from_slice(&[0xc0, dongle.key_epoch()]);
+        dongle.seal_sync(frame_counter, &mut packet).unwrap();
+        packet
+    }
+
+    #[test]
+    fn syncs_follow_rekeys() {
+        let (mut dongle, mut keyboard) = sessions();
+
+        let mut received = sync(&mut dongle, 1);
+        keyboard.open_sync(1, 0, &mut received).unwrap();
+        assert_eq!(&received[..], &[0xc0, 0]);
+
+        dongle.next_frame(true);
+        let mut received = sync(&mut dongle, 2);
+        keyboard.open_sync(2, 1, &mut received).unwrap();
+        assert_eq!(keyboard.key_epoch(), 1);
+
+        let mut sent = packet();
+        keyboard.seal(2, 1, &mut sent).unwrap();
+        dongle.open(2, 1, &mut sent).unwrap();
+    }
+
+    #[test]
+    fn tampered_syncs_are_ignored() {
+        let (mut dongle, mut keyboard) = sessions();
+
+        let genuine = sync(&mut dongle, 1);
+        let mut tampered = Packet::new();
+        tampered.copy_from_slice(&genuine);
+        tampered[1] ^= 1;
+        assert_eq!(
+            keyboard.open_sync(1, 0, &mut tampered),
+            Err(Error::Authentication)
+        );
+
+        // A sync announcing the next epoch does not switch the keys unless it verifies under them.
+        let mut forged = Packet::new();
+        forged.copy_from_slice(&genuine);
+        for (frame_counter, key_epoch) in [(1, 1), (2, 0), (1, 2)] {
+            assert_eq!(
+                keyboard.open_sync(frame_counter, key_epoch, &mut forged),
+                Err(Error::Authentication),
+                "frame {} epoch {}",
+                frame_counter,
+                key_epoch
+            );
+        }
+        assert_eq!(keyboard.key_epoch(), 0);
+
+        let mut sent = packet();
+        keyboard.seal(1, 1, &mut sent).unwrap();
+        dongle.open(1, 1, &mut sent).unwrap();
+
+        // Neither is a replayed one.
+        let mut replayed = Packet::new();
+        replayed.copy_from_slice(&genuine);
+        let mut genuine = genuine;
+        keyboard.open_sync(1, 0, &mut genuine).unwrap();
+        assert_eq!(
+            keyboard.open_sync(1, 0, &mut replayed),
+            Err(Error::Replayed)
+        );
+    }
+
+    #[test]
+    fn retransmitted_states_are_dropped() {
+        let (mut dongle, _) = sessions();
+
+        assert!(dongle.accept_state(254));
+        assert!(!dongle.accept_state(254));
+        assert!(dongle.accept_state(255));
+        assert!(!dongle.accept_state(255));
+
+        // Across the wrap around.
+        assert!(dongle.accept_state(0));
+        assert!(!dongle.accept_state(0));
+    }
+
+    #[test]
+    fn reconnect_passes_on_the_current_state() {
+        let (mut dongle, _) = sessions();
+        assert!(dongle.accept_state(3));
+
+        // The half resends its current state in a new session, the dongle may have missed it.
+        let (mut dongle, _) = sessions();
+        assert!(dongle.accept_state(3));
+        assert!(!dongle.accept_state(3));
+    }
+}
@@ -0,0 +1,264 @@
+//! # Packet sniffer
+//!
+//! A dongle built with the `sniffer` feature does not run a link, it captures every frame it
+//! receives with [`Radio::recv_continuous`] and queues them for the host, see [`next_capture`]
+//! and the record format in [`super::capture`]. It either sits on one frequency, or follows one
+//! link's hop sequence:
+//!
+//! 1. It listens on the first channel of the link's sequence for a sync, and moves on to the
+//!    next channel every [`SEARCH_DWELL`], as a link that has dropped channels starts its frames
+//!    elsewhere.
+//! 2. From the sync on, it listens through every slot of the link, from the guard before the
+//!    slot until the link's next slot, so it catches the half's frame and the dongle's ACK. The
+//!    receiver is restarted on the slot's channel [`SLOT_LEAD`] ahead of the guard, so it is
+//!    ready when a frame early by the whole guard starts. Like the halves it follows the slot
+//!    profile and channel map updates of the syncs.
+//! 3. After [`super::SYNC_LOSS_FRAMES`] missed syncs it searches again.
+//!
+//! Following needs the link's session parameters, from the shared secret of one of its ends,
+//! see [`super::key_schedule::derive`], and the side of its half, which the Turbo slot profile's
+//! layout depends on, see [`SlotProfile::slot_offset`].
+
+use super::capture::CaptureRecord;
+use super::hopping::{ChannelHopping, ChannelMap, NUM_CHANNELS, SLOTS_PER_FRAME};
+use super::key_schedule::{Address, LinkParameters};
+use super::link::{use_link_address, LINK_PIPE};
+use super::sync::SyncFrame;
+use super::{Side, SlotProfile, PAIRING_PIPE, SYNC_LOSS_FRAMES};
+use crate::bsp::Mono;
+use crate::radio::{continuous::RxRecord, RxRing};
+use crate::radio::{Radio, ADDRESS_AIRTIME, NUM_FREQUENCIES, RX_RAMP_UP};
+use crate::waker_registration::CriticalSectionWakerRegistration;
+use core::task::Poll;
+use rtic_monotonics::nrf::timer::fugit::{TimerDurationU64, TimerInstantU64};
+
+/// Number of captured frames queued for the host.
+const CAPTURE_QUEUE: usize = 32;
+
+/// How long the search for a link's sync listens on a channel, a bit over the longest master
+/// frame.
+pub const SEARCH_DWELL: TimerDurationU64<1_000_000> = TimerDurationU64::micros(
+    SlotProfile::Standard.master_frame_period().ticks() + SlotProfile::Standard.slot_size().ticks(),
+);
+
+/// How long before a slot's guard the sniffer restarts the receiver on the slot's channel: the
+/// radio's ramp-up, and the time for the task to wake up and stop the previous reception.
+pub const SLOT_LEAD: TimerDurationU64<1_000_000> =
+    TimerDurationU64::micros(RX_RAMP_UP.ticks() + 30);
+
+static CAPTURES: RxRing<RxRecord, CAPTURE_QUEUE> = RxRing::new();
+static CAPTURE_WAKER: CriticalSectionWakerRegistration = CriticalSectionWakerRegistration::new();
+
+/// What the sniffer captures.
+#[derive(Clone)]
+pub enum SniffTarget {
+    /// Sit on one frequency, 2400 MHz + `0..=100`, and capture the pairing traffic on the default
+    /// address, and a link's traffic if its address is given.
+    Frequency {
+        frequency: u8,
+        link: Option<Address>,
+    },
+    /// Follow the hop sequence of `side`'s link and capture its traffic.
+    Link {
+        parameters: LinkParameters,
+        side: Side,
+    },
+}
+
+/// Waits for the next captured frame, for the task that forwards them to the host.
+pub async fn next_capture() -> RxRecord {
+    core::future::poll_fn(|cx| {
+        CAPTURE_WAKER.register(cx.waker());
+
+        match CAPTURES.pop() {
+            Some(record) => Poll::Ready(record),
+            None => Poll::Pending,
+        }
+    })
+    .await
+}
+
+/// The number of captured frames lost because the host did not keep up, since the last call.
+pub fn take_dropped_captures() -> u32 {
+    CAPTURES.take_dropped()
+}
+
+/// The capture record of a received frame.
+pub fn capture_record(record: &RxRecord) -> CaptureRecord<'_> {
+    CaptureRecord {
+        timestamp: record.timestamp.0.ticks(),
+        frequency: record.frequency,
+        rssi: record.rssi.0,
+        crc_ok: record.crc_ok,
+        pipe: record.pipe.0,
+        payload: &record.packet,
+    }
+}
+
+fn capture(record: RxRecord) {
+    CAPTURES.push(record);
+    CAPTURE_WAKER.wake();
+}
+
+/// Main runner of the sniffer.
+pub async fn sniffer_runner(mut radio: Radio, target: SniffTarget) -> ! {
+    match target {
+        SniffTarget::Frequency { frequency, link } => {
+            assert!((frequency as usize) < NUM_FREQUENCIES);
+
+            if let Some(address) = link {
+                use_link_address(&mut radio, &address);
+            }
+            radio.set_rx_pipes(1 << PAIRING_PIPE | 1 << LINK_PIPE);
+            radio.set_frequency(frequency);
+
+            defmt::info!("Sniffing on {} MHz", 2400 + frequency as u16);
+
+            let mut rx = radio.recv_continuous();
+            loop {
+                capture(rx.next().await);
+            }
+        }
+        SniffTarget::Link { parameters, side } => follow_link(&mut radio, &parameters, side).await,
+    }
+}
+
+/// Capture everything received on the current frequency until `until`, and return the first
+/// intact sync among it with the time it started, [`ADDRESS_AIRTIME`] before its address was
+/// received. With `stop_at_sync` it returns right at the sync.
+async fn listen(
+    radio: &mut Radio,
+    until: TimerInstantU64<1_000_000>,
+    stop_at_sync: bool,
+) -> Option<(SyncFrame, TimerInstantU64<1_000_000>)> {
+    let mut sync = None;
+    let mut rx = radio.recv_continuous();
+
+    while let Ok(record) = Mono::timeout_at(until, rx.next()).await {
+        if sync.is_none() && record.crc_ok {
+            sync = SyncFrame::decode(&record.packet)
+                .map(|frame| (frame, record.timestamp.0 - ADDRESS_AIRTIME));
+        }
+
+        capture(record);
+
+        if stop_at_sync && sync.is_some() {
+            break;
+        }
+    }
+
+    sync
+}
+
+/// Follow the hop sequence of `side`'s link with `parameters`.
+async fn follow_link(radio: &mut Radio, parameters: &LinkParameters, side: Side) -> ! {
+    use_link_address(radio, &parameters.address);
+
+    // Kept at the first slot of a frame between frames, with the link's current channel map.
+    let mut hopping = ChannelHopping::new(&parameters.hop_seed);
+    let mut search_channel = hopping.current_channel();
+
+    loop {
+        //
+        // 1. Search for a sync.
+        //
+        radio.set_frequency(search_channel);
+        let Some((sync, mut sync_time)) = listen(radio, Mono::now() + SEARCH_DWELL, true).await
+        else {
+            search_channel = (search_channel + 1) % NUM_CHANNELS as u8;
+            continue;
+        };
+
+        defmt::info!("Following the link from frame {}", sync.frame_counter);
+
+        //
+        // 2. Follow the link's frames.
+        //
+        let mut frame_counter = sync.frame_counter;
+        let mut profile = sync.slot_profile;
+        let mut channel_map_update = Some((sync.channel_map, sync.channel_map_instant));
+        let mut missed = 0;
+        // The first slot of the sync's frame has been heard by the search.
+        let mut first_slot = 1;
+
+        loop {
+            apply_channel_map_update(&mut hopping, &mut channel_map_update, frame_counter);
+            let mut heard_sync = first_slot > 0;
+
+            for slot in 0..SLOTS_PER_FRAME as u32 {
+                if slot >= first_slot {
+                    let listen_from = slot_listen_start(sync_time, profile, side, slot);
+                    let listen_until = slot_listen_start(sync_time, profile, side, slot + 1);
+
+                    radio.set_frequency(hopping.current_channel());
+                    Mono::delay_until(listen_from).await;
+
+                    let heard = listen(radio, listen_until, false).await;
+                    if let (0, Some((sync, time))) = (slot, heard) {
+                        // Like the halves, the rest of the frame follows the sync.
+                        frame_counter = sync.frame_counter;
+                        profile = sync.slot_profile;
+                        sync_time = time;
+                        channel_map_update = Some((sync.channel_map, sync.channel_map_instant));
+                        apply_channel_map_update(
+                            &mut hopping,
+                            &mut channel_map_update,
+                            frame_counter,
+                        );
+                        heard_sync = true;
+                    }
+                }
+
+                hopping.next_channel();
+            }
+
+            if heard_sync {
+                missed = 0;
+            } else {
+                missed += 1;
+                if missed >= SYNC_LOSS_FRAMES {
+                    defmt::warn!("Missed {} syncs, searching again", missed);
+                    search_channel = hopping.current_channel();
+                    break;
+                }
+            }
+
+            // Keep the schedule when the sync is missed, it was due at the expected time.
+            frame_counter = frame_counter.wrapping_add(1);
+            sync_time += profile.master_frame_period();
+            first_slot = 0;
+        }
+    }
+}
+
+/// When to restart the receiver for `side`'s `slot` of the frame whose sync started at
+/// `sync_time`, see [`SLOT_LEAD`].
+fn slot_listen_start(
+    sync_time: TimerInstantU64<1_000_000>,
+    profile: SlotProfile,
+    side: Side,
+    slot: u32,
+) -> TimerInstantU64<1_000_000> {
+    sync_time + profile.slot_offset(side, slot as u8) - profile.guard() - SLOT_LEAD
+}
+
+/// Switch `hopping` to a pending channel map update once `frame_counter` reaches its instant.
+fn apply_channel_map_update(
+    hopping: &mut ChannelHopping,
+    update: &mut Option<(ChannelMap, u32)>,
+    frame_counter: u32,
+) {
+    let Some((channel_map, instant)) = *update else {
+        return;
+    };
+
+    // Wrapping comparison, the instant is never more than a few frames away.
+    if (frame_counter.wrapping_sub(instant) as i32) < 0 {
+        return;
+    }
+
+    if channel_map.is_valid() && channel_map != *hopping.channel_map() {
+        hopping.set_channel_map(channel_map);
+    }
+    *update = None;
+}
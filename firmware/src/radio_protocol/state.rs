@@ -0,0 +1,749 @@
+//! # Keyboard state frames
+//!
+//! A keyboard half sends its full key matrix, packed to 24 bits, in a [`StateFrame`]:
+//!
+//! - A new state gets the next sequence number and is sent in every slot of the link until the
+//!   dongle ACKs it, or until a newer state supersedes it.
+//! - The current state is also sent once per master frame in the link's [`keepalive_slot`], ACKed
+//!   or not, so the dongle hears from the half even when no keys change.
+//! - The dongle's radio answers every intact frame with an [`AckFrame`] prepared before the
+//!   slot, see [`crate::radio::RadioDriver::recv_and_respond`]. The ACK goes out before the frame
+//!   is authenticated, so it can not carry the frame's sequence number. It is bound to the slot
+//!   by its nonce instead, and ACKs whatever the half sent in that slot.
+//! - The dongle only passes on states with a new sequence number.
+//!
+//! ACKs can also carry a [`Command`] from the dongle to the half, with its own sequence number.
+//! The dongle keeps putting a command on the half's ACKs and syncs until a state frame
+//! acknowledges its sequence number, and only then moves on to the next command. A half sends a
+//! state frame in the next slot after getting a new command, so the acknowledgement does not
+//! have to wait for the keepalive.
+//!
+//! The Turbo slot profile has no room for ACKs, see [`super::SlotProfile::acked`]. There a half
+//! sends its current state in every slot of the link, and gets the commands from the syncs.
+//!
+//! Both frames are sealed with the session key, see [`super::crypto`].
+
+use super::hopping::SLOTS_PER_FRAME;
+use super::Side;
+use core::cell::Cell;
+use critical_section::Mutex;
+
+/// Frame types of the state and ACK frames.
+const STATE_KIND: u8 = 0xd0;
+const ACK_KIND: u8 = 0xd1;
+
+/// Flag bit set when the frame has been sent before.
+const FLAG_RETRANSMISSION: u8 = 1 << 0;
+
+/// Flag bit set when the frame is the master frame's keepalive.
+const FLAG_KEEPALIVE: u8 = 1 << 1;
+
+/// Flag bit set when the frame acknowledges a downstream command.
+const FLAG_DOWNSTREAM_ACK: u8 = 1 << 2;
+
+/// The latest key matrix from the keyboard half's matrix scan, not yet picked up by the radio.
+static PENDING: Mutex<Cell<Option<[u8; 3]>>> = Mutex::new(Cell::new(None));
+
+/// The latest key matrix of each half received by the dongle, indexed by [`Side::index`].
+static RECEIVED: Mutex<Cell<[[u8; 3]; 2]>> = Mutex::new(Cell::new([[0; 3]; 2]));
+
+/// Commands waiting to be sent to each half, the latest of each kind.
+static COMMANDS: Mutex<Cell<[[Option<Command>; Command::KINDS]; 2]>> =
+    Mutex::new(Cell::new([[None; Command::KINDS]; 2]));
+
+/// The state set by the dongle's commands on a keyboard half.
+static HOST_STATUS: Mutex<Cell<HostStatus>> = Mutex::new(Cell::new(HostStatus {
+    leds: 0,
+    layer: 0,
+    led_pattern: 0,
+}));
+
+/// Keyboard: hand a new key matrix from the matrix scan to the radio.
+pub fn update(matrix: [u8; 3]) {
+    critical_section::with(|cs| PENDING.borrow(cs).set(Some(matrix)));
+}
+
+fn take_update() -> Option<[u8; 3]> {
+    critical_section::with(|cs| PENDING.borrow(cs).take())
+}
+
+/// Dongle: the latest key matrix received from a half.
+pub fn key_state(side: Side) -> [u8; 3] {
+    critical_section::with(|cs| RECEIVED.borrow(cs).get()[side.index()])
+}
+
+/// Dongle: store a new key matrix received from a half.
+pub fn publish(side: Side, matrix: [u8; 3]) {
+    critical_section::with(|cs| {
+        let received = RECEIVED.borrow(cs);
+        let mut states = received.get();
+        states[side.index()] = matrix;
+        received.set(states);
+    });
+}
+
+/// Dongle: queue a command for a half, replacing a queued command of the same kind.
+pub fn send_command(side: Side, command: Command) {
+    critical_section::with(|cs| {
+        let commands = COMMANDS.borrow(cs);
+        let mut queued = commands.get();
+        queued[side.index()][command.kind() as usize - 1] = Some(command);
+        commands.set(queued);
+    });
+}
+
+fn take_command(side: Side) -> Option<Command> {
+    critical_section::with(|cs| {
+        let commands = COMMANDS.borrow(cs);
+        let mut queued = commands.get();
+        let command = queued[side.index()].iter_mut().find_map(Option::take);
+        commands.set(queued);
+        command
+    })
+}
+
+/// Keyboard: the state last set by the dongle's commands.
+pub fn host_status() -> HostStatus {
+    critical_section::with(|cs| HOST_STATUS.borrow(cs).get())
+}
+
+/// Keyboard: store the state set by a command, other commands are left to the caller.
+pub fn apply_command(command: Command) {
+    critical_section::with(|cs| {
+        let status = HOST_STATUS.borrow(cs);
+        let mut s = status.get();
+        match command {
+            Command::HostLeds(leds) => s.leds = leds,
+            Command::Layer(layer) => s.layer = layer,
+            Command::LedPattern(pattern) => s.led_pattern = pattern,
+            Command::TxPower(_) | Command::EnterBootloader => {}
+        }
+        status.set(s);
+    });
+}
+
+/// The link slot a keyboard half always sends in during master frame `frame_counter`.
+///
+/// It moves through the data slots from frame to frame, so the dongle's channel assessment (see
+/// [`super::afh`]) gets to measure every channel even on an idle link.
+pub fn keepalive_slot(frame_counter: u32) -> u8 {
+    1 + (frame_counter % (SLOTS_PER_FRAME as u32 - 1)) as u8
+}
+
+/// A keyboard half's key state.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub struct StateFrame {
+    /// Increases by one for every new state, wrapping.
+    pub sequence: u8,
+    /// The key matrix, one bit per key.
+    pub matrix: [u8; 3],
+    /// Set if the frame has been sent before.
+    pub retransmission: bool,
+    /// Set if the frame is sent as the master frame's keepalive.
+    pub keepalive: bool,
+    /// Sequence number of the last downstream command the half got.
+    pub downstream_ack: Option<u8>,
+}
+
+impl StateFrame {
+    /// Size of the encoded frame.
+    pub const LEN: usize = 1 + 1 + 3 + 1 + 1;
+
+    /// Encode the frame.
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let mut flags = 0;
+        if self.retransmission {
+            flags |= FLAG_RETRANSMISSION;
+        }
+        if self.keepalive {
+            flags |= FLAG_KEEPALIVE;
+        }
+        if self.downstream_ack.is_some() {
+            flags |= FLAG_DOWNSTREAM_ACK;
+        }
+
+        let mut buf = [0; Self::LEN];
+        buf[0] = STATE_KIND;
+        buf[1] = self.sequence;
+        buf[2..5].copy_from_slice(&self.matrix);
+        buf[5] = flags;
+        buf[6] = self.downstream_ack.unwrap_or(0);
+        buf
+    }
+
+    /// Decode a frame, `None` if it is not a state frame.
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() != Self::LEN || buf[0] != STATE_KIND {
+            return None;
+        }
+
+        Some(Self {
+            sequence: buf[1],
+            matrix: buf[2..5].try_into().unwrap(),
+            retransmission: buf[5] & FLAG_RETRANSMISSION != 0,
+            keepalive: buf[5] & FLAG_KEEPALIVE != 0,
+            downstream_ack: (buf[5] & FLAG_DOWNSTREAM_ACK != 0).then_some(buf[6]),
+        })
+    }
+}
+
+/// Host keyboard LED bits, in HID order.
+pub mod host_leds {
+    pub const NUM_LOCK: u8 = 1 << 0;
+    pub const CAPS_LOCK: u8 = 1 << 1;
+    pub const SCROLL_LOCK: u8 = 1 << 2;
+}
+
+/// A command from the dongle to a keyboard half.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Command {
+    /// The host's keyboard LEDs, see [`host_leds`].
+    HostLeds(u8),
+    /// The active layer.
+    Layer(u8),
+    /// Which pattern to show on the half's LEDs.
+    LedPattern(u8),
+    /// Transmit power in dBm.
+    TxPower(i8),
+    /// Reset into the bootloader.
+    EnterBootloader,
+}
+
+impl Command {
+    /// Number of command kinds.
+    const KINDS: usize = 5;
+
+    /// Size of an encoded command.
+    pub(super) const LEN: usize = 2;
+
+    /// The on-air kind of the command, `1..=KINDS`.
+    fn kind(&self) -> u8 {
+        match self {
+            Command::HostLeds(_) => 1,
+            Command::Layer(_) => 2,
+            Command::LedPattern(_) => 3,
+            Command::TxPower(_) => 4,
+            Command::EnterBootloader => 5,
+        }
+    }
+
+    pub(super) fn encode(&self) -> [u8; Self::LEN] {
+        let value = match *self {
+            Command::HostLeds(v) | Command::Layer(v) | Command::LedPattern(v) => v,
+            Command::TxPower(dbm) => dbm as u8,
+            Command::EnterBootloader => 0,
+        };
+
+        [self.kind(), value]
+    }
+
+    pub(super) fn decode(buf: &[u8]) -> Option<Self> {
+        let [kind, value] = *buf else {
+            return None;
+        };
+
+        Some(match kind {
+            1 => Command::HostLeds(value),
+            2 => Command::Layer(value),
+            3 => Command::LedPattern(value),
+            4 => Command::TxPower(value as i8),
+            5 => Command::EnterBootloader,
+            _ => return None,
+        })
+    }
+}
+
+/// The state set by the dongle's commands on a keyboard half.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub struct HostStatus {
+    /// The host's keyboard LEDs, see [`host_leds`].
+    pub leds: u8,
+    pub layer: u8,
+    pub led_pattern: u8,
+}
+
+/// A command on an ACK, with its sequence number.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub struct Downstream {
+    pub sequence: u8,
+    pub command: Command,
+}
+
+/// The dongle's ACK of the state frame sent in the same slot.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub struct AckFrame {
+    /// A command for the half.
+    pub downstream: Option<Downstream>,
+}
+
+impl AckFrame {
+    /// Size of an encoded frame without and with a command.
+    pub const LEN: usize = 1;
+    pub const LEN_WITH_COMMAND: usize = Self::LEN + 1 + Command::LEN;
+
+    /// Encode the frame into `buf`, returning the encoded length.
+    pub fn encode(&self, buf: &mut [u8; Self::LEN_WITH_COMMAND]) -> usize {
+        buf[0] = ACK_KIND;
+
+        let Some(downstream) = self.downstream else {
+            return Self::LEN;
+        };
+
+        buf[1] = downstream.sequence;
+        buf[2..].copy_from_slice(&downstream.command.encode());
+        Self::LEN_WITH_COMMAND
+    }
+
+    /// Decode a frame, `None` if it is not an ACK.
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() < Self::LEN || buf[0] != ACK_KIND {
+            return None;
+        }
+
+        let downstream = match buf.len() {
+            Self::LEN => None,
+            Self::LEN_WITH_COMMAND => Some(Downstream {
+                sequence: buf[1],
+                command: Command::decode(&buf[2..])?,
+            }),
+            _ => return None,
+        };
+
+        Some(Self { downstream })
+    }
+}
+
+/// Dongle: puts the queued commands for a half on its ACKs, one at a time.
+pub struct DownstreamSender {
+    in_flight: Option<Downstream>,
+    next_sequence: u8,
+}
+
+impl Default for DownstreamSender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DownstreamSender {
+    pub const fn new() -> Self {
+        Self {
+            in_flight: None,
+            next_sequence: 0,
+        }
+    }
+
+    /// Handle the downstream acknowledgement of a state frame.
+    pub fn acked(&mut self, downstream_ack: Option<u8>) {
+        if self
+            .in_flight
+            .is_some_and(|d| Some(d.sequence) == downstream_ack)
+        {
+            self.in_flight = None;
+        }
+    }
+
+    /// The command to put on the next ACK to `side`, if any.
+    pub fn payload(&mut self, side: Side) -> Option<Downstream> {
+        if self.in_flight.is_none() {
+            self.in_flight = take_command(side).map(|command| Downstream {
+                sequence: self.next_sequence,
+                command,
+            });
+
+            if self.in_flight.is_some() {
+                self.next_sequence = self.next_sequence.wrapping_add(1);
+            }
+        }
+
+        self.in_flight
+    }
+}
+
+/// Keyboard: decides which state frame, if any, to send in a slot.
+pub struct StateSender {
+    current: StateFrame,
+    acked: bool,
+    attempts: u32,
+}
+
+impl Default for StateSender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StateSender {
+    pub const fn new() -> Self {
+        Self {
+            current: StateFrame {
+                sequence: 0,
+                matrix: [0; 3],
+                retransmission: false,
+                keepalive: false,
+                downstream_ack: None,
+            },
+            acked: false,
+            attempts: 0,
+        }
+    }
+
+    /// Send the current state again until ACKed, and start over with the downstream commands.
+    pub fn start_session(&mut self) {
+        self.current.downstream_ack = None;
+        self.resend();
+    }
+
+    fn resend(&mut self) {
+        self.acked = false;
+        self.attempts = 0;
+    }
+
+    /// Pick up a new key matrix from [`update`], superseding the current state.
+    pub fn poll_update(&mut self) {
+        let Some(matrix) = take_update() else {
+            return;
+        };
+
+        if matrix != self.current.matrix {
+            self.current.sequence = self.current.sequence.wrapping_add(1);
+            self.current.matrix = matrix;
+            self.resend();
+        }
+    }
+
+    /// The frame to send in a slot, if any.
+    pub fn frame(&mut self, keepalive_slot: bool) -> Option<StateFrame> {
+        if self.acked && !keepalive_slot {
+            return None;
+        }
+
+        let frame = StateFrame {
+            retransmission: self.attempts > 0,
+            keepalive: self.acked,
+            ..self.current
+        };
+        self.attempts = self.attempts.saturating_add(1);
+
+        Some(frame)
+    }
+
+    /// Handle the dongle's ACK of the frame sent in this slot.
+    ///
+    /// Returns the ACK's command if it is a new one.
+    pub fn ack(&mut self, ack: AckFrame) -> Option<Command> {
+        self.acked = true;
+        self.downstream(ack.downstream?)
+    }
+
+    /// Handle a command from the dongle, on an ACK or a sync.
+    ///
+    /// Returns the command if it is a new one.
+    pub fn downstream(&mut self, downstream: Downstream) -> Option<Command> {
+        if self.current.downstream_ack == Some(downstream.sequence) {
+            return None;
+        }
+
+        // Acknowledge the command in the next slot.
+        self.current.downstream_ack = Some(downstream.sequence);
+        self.resend();
+
+        Some(downstream.command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Mutex, MutexGuard, PoisonError};
+    use std::vec::Vec;
+
+    /// The pending update and queued commands are process-wide, the tests take turns.
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    fn serial() -> MutexGuard<'static, ()> {
+        let guard = SERIAL.lock().unwrap_or_else(PoisonError::into_inner);
+        take_update();
+        while take_command(Side::Left).is_some() {}
+        guard
+    }
+
+    /// A sender that has sent `matrix` and had it ACKed.
+    fn acked_sender(matrix: [u8; 3]) -> StateSender {
+        let mut sender = StateSender::new();
+        update(matrix);
+        sender.poll_update();
+        sender.frame(false).unwrap();
+        assert_eq!(sender.ack(AckFrame { downstream: None }), None);
+        sender
+    }
+
+    #[test]
+    fn state_frame_round_trip() {
+        let frames = [
+            StateFrame {
+                sequence: 0,
+                matrix: [0; 3],
+                retransmission: false,
+                keepalive: false,
+                downstream_ack: None,
+            },
+            StateFrame {
+                sequence: 0xff,
+                matrix: [0x12, 0x34, 0x56],
+                retransmission: true,
+                keepalive: true,
+                downstream_ack: Some(0),
+            },
+            StateFrame {
+                sequence: 7,
+                matrix: [0xff; 3],
+                retransmission: false,
+                keepalive: true,
+                downstream_ack: Some(0xff),
+            },
+        ];
+
+        for frame in frames {
+            assert_eq!(StateFrame::decode(&frame.encode()), Some(frame));
+        }
+    }
+
+    #[test]
+    fn state_frame_rejects_other_frames() {
+        let buf = StateFrame {
+            sequence: 1,
+            matrix: [1, 2, 3],
+            retransmission: false,
+            keepalive: false,
+            downstream_ack: None,
+        }
+        .encode();
+
+        assert_eq!(StateFrame::decode(&buf[..StateFrame::LEN - 1]), None);
+        assert_eq!(StateFrame::decode(&[buf.as_slice(), &[0]].concat()), None);
+
+        let mut ack = buf;
+        ack[0] = ACK_KIND;
+        assert_eq!(StateFrame::decode(&ack), None);
+    }
+
+    #[test]
+    fn ack_frame_round_trip() {
+        let commands = [
+            Command::HostLeds(host_leds::CAPS_LOCK | host_leds::NUM_LOCK),
+            Command::Layer(3),
+            Command::LedPattern(0xff),
+            Command::TxPower(-8),
+            Command::EnterBootloader,
+        ];
+        let mut buf = [0; AckFrame::LEN_WITH_COMMAND];
+
+        let ack = AckFrame { downstream: None };
+        let len = ack.encode(&mut buf);
+        assert_eq!(len, AckFrame::LEN);
+        assert_eq!(AckFrame::decode(&buf[..len]), Some(ack));
+
+        for (sequence, command) in commands.into_iter().enumerate() {
+            let ack = AckFrame {
+                downstream: Some(Downstream {
+                    sequence: sequence as u8,
+                    command,
+                }),
+            };
+            let len = ack.encode(&mut buf);
+            assert_eq!(len, AckFrame::LEN_WITH_COMMAND);
+            assert_eq!(AckFrame::decode(&buf[..len]), Some(ack));
+        }
+    }
+
+    #[test]
+    fn ack_frame_rejects_other_frames() {
+        assert_eq!(AckFrame::decode(&[]), None);
+        assert_eq!(AckFrame::decode(&[STATE_KIND]), None);
+        assert_eq!(AckFrame::decode(&[ACK_KIND, 0]), None);
+        assert_eq!(AckFrame::decode(&[ACK_KIND, 0, 0, 0]), None);
+        assert_eq!(
+            AckFrame::decode(&[ACK_KIND, 0, Command::KINDS as u8 + 1, 0]),
+            None
+        );
+    }
+
+    #[test]
+    fn state_is_sent_until_acked() {
+        let _serial = serial();
+        let mut sender = StateSender::new();
+
+        update([1, 2, 3]);
+        sender.poll_update();
+        let first = sender.frame(false).unwrap();
+        assert_eq!((first.sequence, first.matrix), (1, [1, 2, 3]));
+        assert!(!first.retransmission && !first.keepalive);
+
+        let again = sender.frame(false).unwrap();
+        assert_eq!(again.sequence, 1);
+        assert!(again.retransmission);
+
+        sender.ack(AckFrame { downstream: None });
+        assert_eq!(sender.frame(false), None);
+
+        // The keepalive still goes out, as such.
+        let keepalive = sender.frame(true).unwrap();
+        assert_eq!((keepalive.sequence, keepalive.matrix), (1, [1, 2, 3]));
+        assert!(keepalive.keepalive);
+    }
+
+    #[test]
+    fn newer_state_supersedes() {
+        let _serial = serial();
+        let mut sender = StateSender::new();
+
+        update([1, 0, 0]);
+        sender.poll_update();
+        sender.frame(false).unwrap();
+
+        // A new state starts over, without waiting for the ACK of the old one.
+        update([2, 0, 0]);
+        sender.poll_update();
+        let frame = sender.frame(false).unwrap();
+        assert_eq!((frame.sequence, frame.matrix), (2, [2, 0, 0]));
+        assert!(!frame.retransmission);
+
+        // The same matrix again is not a new state.
+        sender.ack(AckFrame { downstream: None });
+        update([2, 0, 0]);
+        sender.poll_update();
+        assert_eq!(sender.frame(false), None);
+    }
+
+    #[test]
+    fn sequence_wraps() {
+        let _serial = serial();
+        let mut sender = StateSender::new();
+
+        for i in 1..=256u32 {
+            update([i as u8, (i >> 8) as u8, 0]);
+            sender.poll_update();
+        }
+
+        let frame = sender.frame(false).unwrap();
+        assert_eq!((frame.sequence, frame.matrix), (0, [0, 1, 0]));
+    }
+
+    #[test]
+    fn new_session_resends_the_state() {
+        let _serial = serial();
+        let mut sender = acked_sender([4, 5, 6]);
+        sender.ack(AckFrame {
+            downstream: Some(Downstream {
+                sequence: 9,
+                command: Command::Layer(1),
+            }),
+        });
+
+        sender.start_session();
+        let frame = sender.frame(false).unwrap();
+        assert_eq!((frame.sequence, frame.matrix), (1, [4, 5, 6]));
+        assert!(!frame.retransmission && !frame.keepalive);
+        assert_eq!(frame.downstream_ack, None);
+    }
+
+    #[test]
+    fn keepalive_slot_visits_every_data_slot() {
+        let mut slots: Vec<u8> = (0..SLOTS_PER_FRAME as u32 - 1)
+            .map(keepalive_slot)
+            .collect();
+        slots.sort_unstable();
+
+        assert_eq!(slots, (1..SLOTS_PER_FRAME as u8).collect::<Vec<_>>());
+        assert_eq!(
+            keepalive_slot(SLOTS_PER_FRAME as u32 - 1),
+            keepalive_slot(0)
+        );
+    }
+
+    #[test]
+    fn command_is_sent_until_acked() {
+        let _serial = serial();
+        let mut downstream = DownstreamSender::new();
+        assert_eq!(downstream.payload(Side::Left), None);
+
+        send_command(Side::Left, Command::Layer(2));
+        send_command(Side::Left, Command::HostLeds(host_leds::CAPS_LOCK));
+        let first = downstream.payload(Side::Left).unwrap();
+        assert_eq!(first, downstream.payload(Side::Left).unwrap());
+
+        // Only the command's own sequence number acknowledges it.
+        downstream.acked(None);
+        downstream.acked(Some(first.sequence.wrapping_add(1)));
+        assert_eq!(downstream.payload(Side::Left), Some(first));
+
+        downstream.acked(Some(first.sequence));
+        let second = downstream.payload(Side::Left).unwrap();
+        assert_eq!(second.sequence, first.sequence.wrapping_add(1));
+        assert_ne!(second.command, first.command);
+
+        downstream.acked(Some(second.sequence));
+        assert_eq!(downstream.payload(Side::Left), None);
+    }
+
+    #[test]
+    fn queued_command_is_replaced() {
+        let _serial = serial();
+        let mut downstream = DownstreamSender::new();
+
+        send_command(Side::Left, Command::Layer(1));
+        send_command(Side::Left, Command::Layer(4));
+        let sent = downstream.payload(Side::Left).unwrap();
+        assert_eq!(sent.command, Command::Layer(4));
+
+        downstream.acked(Some(sent.sequence));
+        assert_eq!(downstream.payload(Side::Left), None);
+    }
+
+    #[test]
+    fn half_acknowledges_commands() {
+        let _serial = serial();
+        let mut sender = acked_sender([1, 0, 0]);
+        let mut downstream = DownstreamSender::new();
+
+        send_command(Side::Left, Command::TxPower(-4));
+        let payload = downstream.payload(Side::Left);
+        let ack = AckFrame {
+            downstream: payload,
+        };
+
+        // A new command is handed over once, and acknowledged in the next slot.
+        assert_eq!(sender.ack(ack), Some(Command::TxPower(-4)));
+        let frame = sender.frame(false).unwrap();
+        assert_eq!(frame.downstream_ack, Some(payload.unwrap().sequence));
+
+        // The dongle keeps sending it until that frame gets through.
+        assert_eq!(sender.ack(ack), None);
+        assert_eq!(sender.frame(false), None);
+        downstream.acked(frame.downstream_ack);
+        assert_eq!(downstream.payload(Side::Left), None);
+    }
+
+    #[test]
+    fn commands_from_syncs_and_acks_count_once() {
+        let _serial = serial();
+        let mut sender = acked_sender([1, 0, 0]);
+        let command = Downstream {
+            sequence: 3,
+            command: Command::Layer(5),
+        };
+
+        // A sync carries the command the ACKs carry too.
+        assert_eq!(sender.downstream(command), Some(Command::Layer(5)));
+        let frame = sender.frame(false).unwrap();
+        assert_eq!(frame.downstream_ack, Some(3));
+        assert_eq!(
+            sender.ack(AckFrame {
+                downstream: Some(command)
+            }),
+            None
+        );
+        assert_eq!(sender.downstream(command), None);
+    }
+}
@@ -0,0 +1,225 @@
+//! # Sync frame
+//!
+//! The dongle sends a sync in the first slot of every link's frame. All fields are little endian:
+//!
+//! | Offset | Size | Field                                               |
+//! |--------|------|-----------------------------------------------------|
+//! | 0      | 1    | Frame kind, always `0xc0`                           |
+//! | 1      | 1    | Format version, [`SyncFrame::VERSION`]              |
+//! | 2      | 8    | Dongle ID                                           |
+//! | 10     | 4    | Frame counter                                       |
+//! | 14     | 4    | Dongle TX timestamp in µs                           |
+//! | 18     | 1    | Key epoch                                           |
+//! | 19     | 1    | Flags, bit 0 is pair mode, bit 1 a command          |
+//! | 20     | 11   | Channel map                                         |
+//! | 31     | 4    | Frame counter the channel map takes effect at       |
+//! | 35     | 1    | Slot profile of the frame, see [`SlotProfile`]      |
+//! | 36     | 1    | Command sequence number, 0 without a command        |
+//! | 37     | 2    | Command, zeros without one                          |
+//! | 39     | 16   | Tag, see [`Session::seal_sync`]                     |
+//!
+//! The command is the one the dongle puts on the half's ACKs, see [`super::state`], so it also
+//! reaches halves on a slot profile without ACKs.
+//!
+//! The sync is not encrypted, and neither is its command, but the tag authenticates it under the
+//! link's keys of the announced epoch. Frames with another kind, version or length are not
+//! syncs, and a keyboard half ignores syncs from any dongle but its own, and syncs that do not
+//! verify.
+//!
+//! [`Session::seal_sync`]: super::session::Session::seal_sync
+
+use super::crypto::TAG_LEN;
+use super::hopping::ChannelMap;
+use super::state::{Command, Downstream};
+use super::{SlotProfile, Uid};
+
+/// Frame type of the sync, kept apart from the pairing frames.
+const SYNC_KIND: u8 = 0xc0;
+
+/// Flag bit set when the dongle is in pair mode.
+const FLAG_PAIR_MODE: u8 = 1 << 0;
+
+/// Flag bit set when the sync carries a command.
+const FLAG_DOWNSTREAM: u8 = 1 << 1;
+
+/// The dongle's sync, sent at the start of every link's frame.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub struct SyncFrame {
+    pub dongle_uid: Uid,
+    /// The master frame's counter, increasing by one every master frame.
+    pub frame_counter: u32,
+    /// When the dongle scheduled the sync to be sent, in µs of its monotonic timer.
+    pub timestamp: u32,
+    /// The link's current key epoch.
+    pub key_epoch: u8,
+    /// Set if the dongle is in pair mode.
+    pub pair_mode: bool,
+    /// The link's channel map, the current one or the one it switches to at
+    /// `channel_map_instant`.
+    pub channel_map: ChannelMap,
+    pub channel_map_instant: u32,
+    /// The slot timing of the frame the sync starts.
+    pub slot_profile: SlotProfile,
+    /// A command for the half.
+    pub downstream: Option<Downstream>,
+}
+
+impl SyncFrame {
+    /// Version of the frame format.
+    pub const VERSION: u8 = 4;
+
+    /// Size of the encoded frame, without the tag.
+    pub const LEN: usize =
+        2 + Uid::LEN + 4 + 4 + 1 + 1 + ChannelMap::LEN + 4 + 1 + 1 + Command::LEN;
+
+    /// Size of the frame as sent, with the tag.
+    pub const SIGNED_LEN: usize = Self::LEN + TAG_LEN;
+
+    /// Encode the frame, the session appends the tag.
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let mut buf = [0; Self::LEN];
+        buf[0] = SYNC_KIND;
+        buf[1] = Self::VERSION;
+        buf[2..10].copy_from_slice(&self.dongle_uid.0);
+        buf[10..14].copy_from_slice(&self.frame_counter.to_le_bytes());
+        buf[14..18].copy_from_slice(&self.timestamp.to_le_bytes());
+        buf[18] = self.key_epoch;
+        buf[19] = if self.pair_mode { FLAG_PAIR_MODE } else { 0 };
+        buf[20..31].copy_from_slice(&self.channel_map.0);
+        buf[31..35].copy_from_slice(&self.channel_map_instant.to_le_bytes());
+        buf[35] = self.slot_profile as u8;
+        if let Some(downstream) = self.downstream {
+            buf[19] |= FLAG_DOWNSTREAM;
+            buf[36] = downstream.sequence;
+            buf[37..39].copy_from_slice(&downstream.command.encode());
+        }
+        buf
+    }
+
+    /// Decode a frame as sent, `None` if it is not a sync of this version or has an unknown slot
+    /// profile or command.
+    ///
+    /// The tag is not checked, that is up to the session.
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() != Self::SIGNED_LEN || buf[0] != SYNC_KIND || buf[1] != Self::VERSION {
+            return None;
+        }
+
+        let downstream = if buf[19] & FLAG_DOWNSTREAM != 0 {
+            Some(Downstream {
+                sequence: buf[36],
+                command: Command::decode(&buf[37..39])?,
+            })
+        } else {
+            None
+        };
+
+        Some(Self {
+            dongle_uid: Uid(buf[2..10].try_into().unwrap()),
+            frame_counter: u32::from_le_bytes(buf[10..14].try_into().unwrap()),
+            timestamp: u32::from_le_bytes(buf[14..18].try_into().unwrap()),
+            key_epoch: buf[18],
+            pair_mode: buf[19] & FLAG_PAIR_MODE != 0,
+            channel_map: ChannelMap(buf[20..31].try_into().unwrap()),
+            channel_map_instant: u32::from_le_bytes(buf[31..35].try_into().unwrap()),
+            slot_profile: SlotProfile::from_u8(buf[35])?,
+            downstream,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYNC: SyncFrame = SyncFrame {
+        dongle_uid: Uid([0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8]),
+        frame_counter: 0x1234_5678,
+        timestamp: 0xdead_beef,
+        key_epoch: 3,
+        pair_mode: true,
+        channel_map: ChannelMap::ALL,
+        channel_map_instant: 0x1234_5680,
+        slot_profile: SlotProfile::Fast,
+        downstream: Some(Downstream {
+            sequence: 0x42,
+            command: Command::Layer(2),
+        }),
+    };
+
+    /// The frame as sent, with a tag that is not checked.
+    fn signed(sync: &SyncFrame) -> [u8; SyncFrame::SIGNED_LEN] {
+        let mut buf = [0x5a; SyncFrame::SIGNED_LEN];
+        buf[..SyncFrame::LEN].copy_from_slice(&sync.encode());
+        buf
+    }
+
+    #[test]
+    fn encoding() {
+        assert_eq!(
+            SYNC.encode(),
+            [
+                0xc0, 0x04, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0x78, 0x56, 0x34, 0x12,
+                0xef, 0xbe, 0xad, 0xde, 0x03, 0x03, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+                0xff, 0xff, 0x0f, 0x80, 0x56, 0x34, 0x12, 0x01, 0x42, 0x02, 0x02,
+            ]
+        );
+    }
+
+    #[test]
+    fn roundtrip() {
+        assert_eq!(SyncFrame::decode(&signed(&SYNC)), Some(SYNC));
+
+        let sync = SyncFrame {
+            pair_mode: false,
+            slot_profile: SlotProfile::Standard,
+            downstream: None,
+            ..SYNC
+        };
+        assert_eq!(SyncFrame::decode(&signed(&sync)), Some(sync));
+        assert_eq!(&signed(&sync)[36..39], &[0; 3]);
+
+        let sync = SyncFrame {
+            slot_profile: SlotProfile::Turbo,
+            ..SYNC
+        };
+        assert_eq!(SyncFrame::decode(&signed(&sync)), Some(sync));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut buf = signed(&SYNC);
+        buf[1] = SyncFrame::VERSION + 1;
+
+        assert_eq!(SyncFrame::decode(&buf), None);
+    }
+
+    #[test]
+    fn rejects_unknown_slot_profiles() {
+        let mut buf = signed(&SYNC);
+        buf[35] = 0xff;
+
+        assert_eq!(SyncFrame::decode(&buf), None);
+    }
+
+    #[test]
+    fn rejects_unknown_commands() {
+        let mut buf = signed(&SYNC);
+        buf[37] = 0xff;
+
+        assert_eq!(SyncFrame::decode(&buf), None);
+    }
+
+    #[test]
+    fn rejects_other_frames() {
+        let mut buf = signed(&SYNC);
+        buf[0] = 0xb0;
+        assert_eq!(SyncFrame::decode(&buf), None);
+
+        let buf = signed(&SYNC);
+        assert_eq!(SyncFrame::decode(&buf[..SyncFrame::SIGNED_LEN - 1]), None);
+        // Without the tag.
+        assert_eq!(SyncFrame::decode(&buf[..SyncFrame::LEN]), None);
+        assert_eq!(SyncFrame::decode(&[]), None);
+    }
+}
@@ -0,0 +1,122 @@
+//! # Types shared by the frames
+//!
+//! Free of the radio and the monotonic, like the frame codecs, so the host tools can reuse them.
+
+use fugit::TimerDurationU64;
+
+/// Which keyboard half a device is.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Side {
+    Right = 0,
+    Left = 1,
+}
+
+impl Side {
+    /// Both sides, in index order.
+    pub const ALL: [Side; 2] = [Side::Right, Side::Left];
+
+    /// Index of this side in per-side tables.
+    pub const fn index(self) -> usize {
+        self as usize
+    }
+
+    /// Convert from the on-air representation.
+    pub fn from_u8(val: u8) -> Option<Self> {
+        match val {
+            0 => Some(Self::Right),
+            1 => Some(Self::Left),
+            _ => None,
+        }
+    }
+}
+
+/// Unique ID of a device, read from FICR at boot.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub struct Uid(pub [u8; 8]);
+
+impl Uid {
+    /// Size of the ID on-air.
+    pub const LEN: usize = 8;
+}
+
+/// The slot timing of the master frames, selected at runtime on the dongle with
+/// [`super::set_slot_profile`].
+///
+/// A state frame, the turnaround and an ACK with a command take 294 µs on air, and with the
+/// dongle's processing and RX ramp-up an ACKed slot needs 414 µs before any guard. The Turbo
+/// profile's 250 µs slots only fit a state frame with a truncated tag, so its frames go
+/// unacknowledged, see [`Self::acked`].
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub enum SlotProfile {
+    /// 1 ms slots, every half gets a slot every 2 ms.
+    Standard = 0,
+    /// 500 µs slots, every half gets a slot every 1 ms.
+    Fast = 1,
+    /// 250 µs slots, every half gets a slot every 500 µs and sends its state in all of them.
+    Turbo = 2,
+}
+
+impl SlotProfile {
+    /// The size of a slot.
+    pub const fn slot_size(self) -> TimerDurationU64<1_000_000> {
+        match self {
+            SlotProfile::Standard => TimerDurationU64::micros(1_000),
+            SlotProfile::Fast => TimerDurationU64::micros(500),
+            SlotProfile::Turbo => TimerDurationU64::micros(250),
+        }
+    }
+
+    /// The size of the slot a link's sync is sent in, the first of the link's frame. A sync is
+    /// longer than a Turbo slot, so it gets two.
+    pub const fn sync_slot_size(self) -> TimerDurationU64<1_000_000> {
+        match self {
+            SlotProfile::Standard | SlotProfile::Fast => self.slot_size(),
+            SlotProfile::Turbo => TimerDurationU64::micros(2 * self.slot_size().ticks()),
+        }
+    }
+
+    /// When `slot` of `side`'s link starts, after the start of the link's sync. The right link's
+    /// sync is followed by the left link's, and from then on the links take turns.
+    pub const fn slot_offset(self, side: Side, slot: u8) -> TimerDurationU64<1_000_000> {
+        if slot == 0 {
+            return TimerDurationU64::micros(0);
+        }
+
+        let first = match side {
+            Side::Right => 2 * self.sync_slot_size().ticks(),
+            Side::Left => self.sync_slot_size().ticks() + self.slot_size().ticks(),
+        };
+
+        TimerDurationU64::micros(first + (slot as u64 - 1) * 2 * self.slot_size().ticks())
+    }
+
+    /// How far a keyboard half's slot timing may be off from the dongle's. The dongle listens
+    /// for the half's frame this long before and after the slot start.
+    pub const fn guard(self) -> TimerDurationU64<1_000_000> {
+        match self {
+            SlotProfile::Standard => TimerDurationU64::micros(200),
+            SlotProfile::Fast => TimerDurationU64::micros(40),
+            SlotProfile::Turbo => TimerDurationU64::micros(15),
+        }
+    }
+
+    /// Whether the dongle ACKs the halves' state frames.
+    ///
+    /// Without ACKs a half sends its current state in every slot, sealed with a truncated tag,
+    /// and the commands for it only come with the syncs.
+    pub const fn acked(self) -> bool {
+        !matches!(self, SlotProfile::Turbo)
+    }
+
+    /// Convert from the on-air representation.
+    pub fn from_u8(val: u8) -> Option<Self> {
+        match val {
+            0 => Some(Self::Standard),
+            1 => Some(Self::Fast),
+            2 => Some(Self::Turbo),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! # P-256 for the host
+//!
+//! The firmware's pairing uses `p256-cortex-m4`, whose field arithmetic is Cortex-M4 assembly.
+//! This crate has the same name and the part of its API pairing uses, on the RustCrypto `p256`
+//! crate, so the sim can run pairing on the host.
+
+#![no_std]
+
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use rand_core::{CryptoRng, RngCore};
+
+/// A public key or secret key was not valid.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Error;
+
+/// Result of this crate's fallible operations.
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// A secret key and its public key.
+pub struct Keypair {
+    pub public: PublicKey,
+    pub secret: SecretKey,
+}
+
+impl Keypair {
+    /// A random key pair from `rng`.
+    pub fn random(rng: impl CryptoRng + RngCore) -> Self {
+        let secret = SecretKey(p256::SecretKey::random(&mut { rng }));
+        Self {
+            public: secret.public_key(),
+            secret,
+        }
+    }
+}
+
+/// A public key, a point on the curve.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PublicKey(p256::PublicKey);
+
+impl PublicKey {
+    /// Decode a compressed or uncompressed SEC1 point.
+    pub fn from_sec1_bytes(bytes: &[u8]) -> Result<Self> {
+        p256::PublicKey::from_sec1_bytes(bytes)
+            .map(Self)
+            .map_err(|_| Error)
+    }
+
+    /// The compressed SEC1 encoding of the point.
+    pub fn to_compressed_sec1_bytes(&self) -> [u8; 33] {
+        let mut bytes = [0; 33];
+        bytes.copy_from_slice(self.0.to_encoded_point(true).as_bytes());
+        bytes
+    }
+}
+
+/// A secret key, a scalar.
+pub struct SecretKey(p256::SecretKey);
+
+impl SecretKey {
+    /// The public key of this secret key.
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.0.public_key())
+    }
+
+    /// ECDH with the other end's public key.
+    pub fn agree(&self, other: &PublicKey) -> SharedSecret {
+        let shared = p256::ecdh::diffie_hellman(self.0.to_nonzero_scalar(), other.0.as_affine());
+        let mut bytes = [0; 32];
+        bytes.copy_from_slice(shared.raw_secret_bytes());
+        SharedSecret(bytes)
+    }
+}
+
+/// The x coordinate of the point both ends agree on.
+pub struct SharedSecret([u8; 32]);
+
+impl SharedSecret {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
@@ -0,0 +1,86 @@
+//! # Simulated flash
+//!
+//! A [`SimFlash`] is the two pages of NOR flash the bonds are kept in, in RAM, where writes can
+//! only clear bits like on the nRF's NVMC. Its clones share the pages, so bonds written by one run
+//! of a runner are there for the next, like after a reset.
+
+use embedded_storage::nor_flash::{ErrorType, NorFlash, NorFlashErrorKind, ReadNorFlash};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Size of a page, the nRF's.
+const PAGE: usize = 4096;
+
+/// Two flash pages in RAM, shared by its clones.
+#[derive(Clone)]
+pub struct SimFlash {
+    pages: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Default for SimFlash {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SimFlash {
+    /// Erased pages.
+    pub fn new() -> Self {
+        Self {
+            pages: Rc::new(RefCell::new(vec![0xff; 2 * PAGE])),
+        }
+    }
+
+    /// If neither page has been written since it was last erased.
+    pub fn is_erased(&self) -> bool {
+        self.pages.borrow().iter().all(|&byte| byte == 0xff)
+    }
+}
+
+impl ErrorType for SimFlash {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for SimFlash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let offset = offset as usize;
+        let pages = self.pages.borrow();
+        let stored = pages
+            .get(offset..offset + bytes.len())
+            .ok_or(NorFlashErrorKind::OutOfBounds)?;
+        bytes.copy_from_slice(stored);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        2 * PAGE
+    }
+}
+
+impl NorFlash for SimFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = PAGE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        let mut pages = self.pages.borrow_mut();
+        pages
+            .get_mut(from as usize..to as usize)
+            .ok_or(NorFlashErrorKind::OutOfBounds)?
+            .fill(0xff);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let offset = offset as usize;
+        let mut pages = self.pages.borrow_mut();
+        let stored = pages
+            .get_mut(offset..offset + bytes.len())
+            .ok_or(NorFlashErrorKind::OutOfBounds)?;
+        for (word, byte) in stored.iter_mut().zip(bytes) {
+            *word &= byte;
+        }
+        Ok(())
+    }
+}
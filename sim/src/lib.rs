@@ -0,0 +1,73 @@
+//! # Simulated radios for the firmware's links
+//!
+//! Runs the dongle's and the keyboard halves' radio runners, from pairing on, and the radio links
+//! they set up, see [`radio_protocol::link`], on the host under `cargo test`. The firmware's
+//! modules are included from `../firmware` below and run unchanged, on:
+//!
+//! - [`SimRadio`], which implements the firmware's [`radio::RadioDriver`] on a [`Medium`] shared
+//!   with the other simulated radios. Every frequency of the medium has its own loss, latency and
+//!   interference, see [`ChannelConditions`], and overlapping packets collide.
+//! - [`SimClock`], which implements the firmware's [`clock::Clock`] on the virtual time of a
+//!   [`Simulation`], the single-threaded executor that polls the ends, or [`DriftingClock`],
+//!   which runs off it like a crystal.
+//! - [`SimFlash`], a flash page in RAM for the bonds, which outlives a run like after a reset.
+//!
+//! Pairing's P-256 comes from `p256-host`, which stands in for the firmware's Cortex-M4 only
+//! `p256-cortex-m4`. The links can also be started from sessions made from a shared secret, like
+//! after pairing.
+
+pub mod flash;
+pub mod medium;
+pub mod sim_radio;
+pub mod time;
+
+pub use flash::SimFlash;
+pub use medium::{ChannelConditions, Medium, Transmission};
+pub use sim_radio::SimRadio;
+pub use time::{DriftingClock, SimClock, Simulation};
+
+/// The firmware's logs, which the sim drops.
+#[defmt::global_logger]
+struct Logger;
+
+// SAFETY: Nothing is written, so there is nothing to guard.
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {}
+    unsafe fn flush() {}
+    unsafe fn release() {}
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+defmt::timestamp!("{=u64:us}", <SimClock as clock::Clock>::now().ticks());
+
+/// The firmware's clock trait, which the links wait on.
+#[path = "../../firmware/src/clock.rs"]
+pub mod clock;
+
+/// The firmware's radio packet, the radio operations the links run on, the PHY modes, the
+/// timestamps of the radio's events and the ring the RADIO interrupt queues packets in.
+#[path = "../../firmware/src/radio"]
+pub mod radio {
+    mod driver;
+    mod mode;
+    mod packet;
+    mod ring;
+    mod timestamp;
+
+    pub use driver::{
+        airtime, Addresses, EnergyLevel, Error, Pipe, RadioDriver, Rssi, Timestamp, TxPower,
+        ADDRESS_AIRTIME, DEFAULT_ADDRESSES, NUM_FREQUENCIES, RX_RAMP_UP, TURNAROUND, TX_RAMP_UP,
+    };
+    pub use mode::{Mode, ModeConfig};
+    pub use packet::Packet;
+    pub use ring::RxRing;
+    pub use timestamp::extend_capture;
+}
+
+/// The firmware's radio protocol, from pairing to the runners.
+#[path = "../../firmware/src"]
+mod firmware {
+    pub mod radio_protocol;
+}
+
+pub use firmware::radio_protocol;
@@ -0,0 +1,268 @@
+//! # The shared radio medium
+//!
+//! Every packet sent by a [`crate::SimRadio`] is logged on its [`Medium`], and the other radios
+//! receive it from the log if they listen on its frequency and address while its address
+//! arrives. What arrives depends on the frequency's [`ChannelConditions`]:
+//!
+//! - A lost packet is not received at all, as if it was too weak.
+//! - Latency delays the packet for every receiver, so its timestamps and any response are late.
+//! - Interference corrupts the packet, it is received with a CRC error. An interferer also shows
+//!   up in energy surveys of the frequency.
+//!
+//! Packets sent at the same time on the same frequency collide, and are received with a CRC
+//! error. Whether a packet is lost or corrupted for a receiver is drawn from the medium's seed,
+//! the packet and the receiver, so a simulation comes out the same every time.
+
+use crate::radio::{self, airtime, EnergyLevel, Packet, NUM_FREQUENCIES};
+use std::cell::{Ref, RefCell};
+use std::rc::Rc;
+use std::task::Waker;
+
+/// Time on air of the preamble and address, the address event comes after them.
+pub(crate) const ADDRESS_AIRTIME: u64 = radio::ADDRESS_AIRTIME.ticks();
+
+/// Signal strength of every received packet.
+pub const RSSI_DBM: i8 = -50;
+
+/// Energy measured on a quiet frequency.
+const NOISE_FLOOR_DBM: i8 = -100;
+
+/// Energy of an interferer, as measured by a survey.
+const INTERFERER_DBM: i8 = -60;
+
+/// Packets are logged this long before they start at the most, see [`Inner::since`].
+const MAX_LEAD: u64 = 100;
+
+/// How packets on one frequency arrive.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ChannelConditions {
+    /// Probability that a packet is not received at all, `0.0..=1.0`.
+    pub loss: f64,
+    /// Delay of every packet, in µs.
+    pub latency: u64,
+    /// Probability that a received packet is corrupted, `0.0..=1.0`.
+    pub interference: f64,
+}
+
+impl ChannelConditions {
+    /// No loss, latency or interference.
+    pub const CLEAR: Self = Self {
+        loss: 0.,
+        latency: 0,
+        interference: 0.,
+    };
+}
+
+impl Default for ChannelConditions {
+    fn default() -> Self {
+        Self::CLEAR
+    }
+}
+
+/// A packet sent on the medium.
+#[derive(Clone, Debug)]
+pub struct Transmission {
+    /// The sending radio, see [`crate::SimRadio::id`].
+    pub sender: usize,
+    pub frequency: u8,
+    /// The address of the sending pipe, base first.
+    pub address: [u8; 5],
+    /// When the preamble starts, in µs.
+    pub start: u64,
+    /// When the CRC has been sent, in µs.
+    pub end: u64,
+    pub payload: Vec<u8>,
+}
+
+/// A packet as one radio receives it.
+pub(crate) struct Reception {
+    /// When the address arrived.
+    pub address_time: u64,
+    /// When the CRC arrived.
+    pub end: u64,
+    pub pipe: u8,
+    pub intact: bool,
+    pub payload: Vec<u8>,
+}
+
+struct Inner {
+    seed: u64,
+    conditions: [ChannelConditions; NUM_FREQUENCIES],
+    /// Every packet sent, in the order they were sent.
+    transmissions: Vec<Transmission>,
+    /// Receivers to wake on the next packet.
+    listeners: Vec<Waker>,
+    radios: usize,
+}
+
+/// The medium shared by the simulated radios, a handle that can be cloned.
+#[derive(Clone)]
+pub struct Medium(Rc<RefCell<Inner>>);
+
+impl Medium {
+    /// A medium with clear conditions on every frequency, drawing losses from `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self(Rc::new(RefCell::new(Inner {
+            seed,
+            conditions: [ChannelConditions::CLEAR; NUM_FREQUENCIES],
+            transmissions: Vec::new(),
+            listeners: Vec::new(),
+            radios: 0,
+        })))
+    }
+
+    /// Change the conditions on `frequency`, 2400 MHz + `0..=100`.
+    pub fn set_conditions(&self, frequency: u8, conditions: ChannelConditions) {
+        self.0.borrow_mut().conditions[frequency as usize] = conditions;
+    }
+
+    /// Change the conditions on every frequency.
+    pub fn set_all_conditions(&self, conditions: ChannelConditions) {
+        self.0.borrow_mut().conditions = [conditions; NUM_FREQUENCIES];
+    }
+
+    /// The conditions on `frequency`.
+    pub fn conditions(&self, frequency: u8) -> ChannelConditions {
+        self.0.borrow().conditions[frequency as usize]
+    }
+
+    /// Every packet sent so far, in the order they were sent.
+    pub fn transmissions(&self) -> Ref<'_, [Transmission]> {
+        Ref::map(self.0.borrow(), |inner| &inner.transmissions[..])
+    }
+
+    /// Attach a new radio, returns its ID.
+    pub(crate) fn attach(&self) -> usize {
+        let mut inner = self.0.borrow_mut();
+        inner.radios += 1;
+        inner.radios - 1
+    }
+
+    /// Log a packet, at the latest when its ramp-up starts.
+    pub(crate) fn transmit(&self, transmission: Transmission) {
+        let listeners = {
+            let mut inner = self.0.borrow_mut();
+            inner.transmissions.push(transmission);
+            std::mem::take(&mut inner.listeners)
+        };
+
+        for listener in listeners {
+            listener.wake();
+        }
+    }
+
+    /// Wake `waker` on the next packet.
+    pub(crate) fn listen(&self, waker: &Waker) {
+        self.0.borrow_mut().listeners.push(waker.clone());
+    }
+
+    /// The first packet that `receiver` receives on `frequency` on one of `pipes`, with its
+    /// address arriving between `from` and `until`.
+    ///
+    /// Packets logged later can still arrive earlier, until the time has passed `from`.
+    pub(crate) fn reception(
+        &self,
+        receiver: usize,
+        frequency: u8,
+        pipes: &[(u8, [u8; 5])],
+        from: u64,
+        until: u64,
+    ) -> Option<Reception> {
+        let inner = self.0.borrow();
+        let conditions = inner.conditions[frequency as usize];
+        let delay = conditions.latency + ADDRESS_AIRTIME;
+
+        let (first, candidates) = inner.since(from.saturating_sub(delay));
+        let (index, transmission, pipe) = candidates
+            .iter()
+            .enumerate()
+            .filter(|(_, tx)| tx.sender != receiver && tx.frequency == frequency)
+            .filter(|(_, tx)| (from..=until).contains(&(tx.start + delay)))
+            .filter_map(|(i, tx)| {
+                let (pipe, _) = pipes.iter().find(|(_, address)| *address == tx.address)?;
+                Some((first + i, tx, *pipe))
+            })
+            .filter(|(index, _, _)| inner.draw(*index, receiver, 0) >= conditions.loss)
+            .min_by_key(|(_, tx, _)| tx.start)?;
+
+        let (_, nearby) = inner.since(transmission.start.saturating_sub(max_airtime()));
+        let collided = nearby.iter().any(|other| {
+            !std::ptr::eq(other, transmission)
+                && other.sender != receiver
+                && other.frequency == frequency
+                && other.start < transmission.end
+                && transmission.start < other.end
+        });
+        let interfered = inner.draw(index, receiver, 1) < conditions.interference;
+
+        Some(Reception {
+            address_time: transmission.start + delay,
+            end: transmission.end + conditions.latency,
+            pipe,
+            intact: !collided && !interfered,
+            payload: transmission.payload.clone(),
+        })
+    }
+
+    /// The energy on `frequency` between `start` and `end`, as a survey measures it.
+    pub(crate) fn energy(&self, frequency: u8, start: u64, end: u64) -> EnergyLevel {
+        let inner = self.0.borrow();
+        let conditions = inner.conditions[frequency as usize];
+
+        let (_, nearby) = inner.since(start.saturating_sub(max_airtime()));
+        let busy = nearby
+            .iter()
+            .any(|tx| tx.frequency == frequency && tx.start < end && start < tx.end);
+
+        let mut level = EnergyLevel {
+            peak: NOISE_FLOOR_DBM,
+            average: NOISE_FLOOR_DBM,
+        };
+        if conditions.interference > 0. {
+            level.peak = INTERFERER_DBM;
+            level.average +=
+                ((INTERFERER_DBM - NOISE_FLOOR_DBM) as f64 * conditions.interference) as i8;
+        }
+        if busy {
+            level.peak = level.peak.max(RSSI_DBM);
+        }
+
+        level
+    }
+}
+
+impl Inner {
+    /// The packets that start at `start` or later, and a few before, with the index of the
+    /// first.
+    ///
+    /// Packets are logged in order, at most [`MAX_LEAD`] before they start, so their start times
+    /// are nearly sorted.
+    fn since(&self, start: u64) -> (usize, &[Transmission]) {
+        let first = self
+            .transmissions
+            .iter()
+            .rposition(|tx| tx.start + MAX_LEAD < start)
+            .map_or(0, |i| i + 1);
+
+        (first, &self.transmissions[first..])
+    }
+
+    /// A number in `0.0..1.0` for the packet at `index` and `receiver`, different per `salt`.
+    fn draw(&self, index: usize, receiver: usize, salt: u64) -> f64 {
+        // SplitMix64's finalizer over the inputs.
+        let mut x = self.seed
+            ^ (index as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)
+            ^ (receiver as u64).wrapping_mul(0xbf58_476d_1ce4_e5b9)
+            ^ salt.wrapping_mul(0x94d0_49bb_1331_11eb);
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        x ^= x >> 31;
+
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// The time on air of the longest packet.
+fn max_airtime() -> u64 {
+    airtime(Packet::CAPACITY as usize).ticks()
+}
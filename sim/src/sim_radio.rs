@@ -0,0 +1,485 @@
+//! # A simulated radio
+//!
+//! Implements the firmware's [`RadioDriver`] on a [`Medium`], with the timing of the nRF radio:
+//! packets start after the ramp-up, timestamps are taken when the address has been sent or
+//! received, and responses go out [`TURNAROUND`] after the end of the received packet. The TX
+//! power has no effect on the medium, and the radio is always in [`Mode::Nrf2Mbit`] like the
+//! links expect.
+//!
+//! A radio made with [`SimRadio::with_drift`] takes and gives its instants on a
+//! [`crate::DriftingClock`], like a device whose crystal is off.
+//!
+//! Scheduled operations start at their instants exactly. On the nRF a TIMER0 compare starts the
+//! radio through PPI, and a TIMER1 compare closes RX windows. Those are not modeled, the
+//! channels they use are checked at compile time in the firmware's `bsp.rs`.
+
+use crate::clock::Clock;
+use crate::medium::{Medium, Reception, Transmission, ADDRESS_AIRTIME, RSSI_DBM};
+use crate::radio::{
+    airtime, Addresses, EnergyLevel, Error, Mode, Packet, Pipe, RadioDriver, Rssi, Timestamp,
+    TxPower, DEFAULT_ADDRESSES, NUM_FREQUENCIES, RX_RAMP_UP, TURNAROUND, TX_RAMP_UP,
+};
+use crate::time::{self, to_local, to_simulated, SimClock};
+use fugit::{TimerDurationU64, TimerInstantU64};
+use std::future::poll_fn;
+use std::task::Poll;
+
+/// A radio on a [`Medium`], to run with [`SimClock`].
+pub struct SimRadio {
+    medium: Medium,
+    id: usize,
+    frequency: u8,
+    addresses: Addresses,
+    tx_pipe: u8,
+    rx_pipes: u8,
+    /// How fast the radio's clock runs, in ppm.
+    drift_ppm: i32,
+}
+
+impl SimRadio {
+    /// A new radio on `medium`, on 2400 MHz with the default addresses like after reset.
+    pub fn new(medium: &Medium) -> Self {
+        Self::with_drift(medium, 0)
+    }
+
+    /// A new radio on `medium` whose clock runs `ppm` fast, to run with a
+    /// [`crate::DriftingClock`] of the same drift.
+    pub fn with_drift(medium: &Medium, ppm: i32) -> Self {
+        Self {
+            medium: medium.clone(),
+            id: medium.attach(),
+            frequency: 0,
+            addresses: DEFAULT_ADDRESSES,
+            tx_pipe: 0,
+            rx_pipes: 1,
+            drift_ppm: ppm,
+        }
+    }
+
+    /// The radio's ID on the medium, the sender of its [`Transmission`]s.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// The on-air address of `pipe`, base first.
+    fn address(&self, pipe: u8) -> [u8; 5] {
+        let base = match pipe {
+            0 => self.addresses.base0,
+            _ => self.addresses.base1,
+        };
+        let prefix = self.addresses.prefixes[pipe as usize];
+
+        [base[0], base[1], base[2], base[3], prefix]
+    }
+
+    /// The simulation's time at the radio's `instant`.
+    fn simulated(&self, instant: TimerInstantU64<1_000_000>) -> u64 {
+        to_simulated(self.drift_ppm, instant.ticks())
+    }
+
+    /// The radio's instant at the simulation's `time`.
+    fn local(&self, time: u64) -> Timestamp {
+        Timestamp(TimerInstantU64::from_ticks(to_local(self.drift_ppm, time)))
+    }
+
+    /// The simulation's times of an RX window. Like the trigger, it opens as soon as the radio is
+    /// ready if `open_at` has passed, and only the window's end can be missed.
+    fn window(
+        &self,
+        open_at: TimerInstantU64<1_000_000>,
+        close_at: TimerInstantU64<1_000_000>,
+    ) -> Result<(u64, u64), Error> {
+        let now = SimClock::now().ticks();
+        let (open_at, close_at) = (self.simulated(open_at), self.simulated(close_at));
+        if close_at <= now {
+            return Err(Error::TooLate);
+        }
+
+        Ok((open_at.max(now + RX_RAMP_UP.ticks()), close_at))
+    }
+
+    /// Send `packet` starting at `start`, once it has been sent.
+    async fn transmit(&mut self, packet: &Packet, start: u64) -> (Timestamp, u64) {
+        // Logged as the ramp-up starts, so the receivers see it coming.
+        SimClock::delay_until(TimerInstantU64::from_ticks(start) - TX_RAMP_UP).await;
+
+        let end = start + airtime(packet.len() as usize).ticks();
+        self.medium.transmit(Transmission {
+            sender: self.id,
+            frequency: self.frequency,
+            address: self.address(self.tx_pipe),
+            start,
+            end,
+            payload: packet.to_vec(),
+        });
+        SimClock::delay_until(TimerInstantU64::from_ticks(end)).await;
+
+        (self.local(start + ADDRESS_AIRTIME), end)
+    }
+
+    /// Receive the first packet whose address arrives between `from` and `until`, once it has
+    /// been received.
+    async fn receive(&mut self, from: u64, until: u64) -> Option<Reception> {
+        let pipes: Vec<_> = (0..8)
+            .filter(|pipe| self.rx_pipes & (1 << pipe) != 0)
+            .map(|pipe| (pipe, self.address(pipe)))
+            .collect();
+
+        poll_fn(|cx| {
+            let now = SimClock::now().ticks();
+
+            let wake_at = match self
+                .medium
+                .reception(self.id, self.frequency, &pipes, from, until)
+            {
+                Some(reception) if reception.end <= now => return Poll::Ready(Some(reception)),
+                Some(reception) => reception.end,
+                None if until <= now => return Poll::Ready(None),
+                None => until,
+            };
+
+            // A packet sent later can still arrive earlier, or be the first one.
+            self.medium.listen(cx.waker());
+            if wake_at != u64::MAX {
+                time::wake_at(wake_at, cx.waker());
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Put a reception into `packet`, corrupted ones with an error.
+    fn received(
+        &self,
+        packet: &mut Packet,
+        reception: &Reception,
+    ) -> Result<(Timestamp, Rssi, Pipe), Error> {
+        packet.copy_from_slice(&reception.payload);
+
+        if !reception.intact {
+            if let Some(byte) = packet.first_mut() {
+                *byte ^= 0x55;
+            }
+            return Err(Error::Crc(0));
+        }
+
+        Ok((
+            self.local(reception.address_time),
+            Rssi(RSSI_DBM),
+            Pipe(reception.pipe),
+        ))
+    }
+}
+
+impl RadioDriver for SimRadio {
+    fn mode(&self) -> Mode {
+        Mode::Nrf2Mbit
+    }
+
+    fn set_frequency(&mut self, frequency: u8) {
+        assert!((frequency as usize) < NUM_FREQUENCIES);
+        self.frequency = frequency;
+    }
+
+    fn set_addresses(&mut self, addresses: &Addresses) {
+        self.addresses = *addresses;
+    }
+
+    fn set_tx_pipe(&mut self, pipe: u8) {
+        assert!(pipe < 8);
+        self.tx_pipe = pipe;
+    }
+
+    fn set_rx_pipes(&mut self, pipes: u8) {
+        self.rx_pipes = pipes;
+    }
+
+    fn set_txpower(&mut self, _power: TxPower) {}
+
+    async fn send_no_cca(&mut self, packet: &mut Packet) -> Result<Timestamp, Error> {
+        let start = SimClock::now() + TX_RAMP_UP;
+        Ok(self.transmit(packet, start.ticks()).await.0)
+    }
+
+    async fn send_at(
+        &mut self,
+        packet: &mut Packet,
+        at: TimerInstantU64<1_000_000>,
+    ) -> Result<Timestamp, Error> {
+        let at = self.simulated(at);
+        if at < (SimClock::now() + TX_RAMP_UP).ticks() {
+            return Err(Error::TooLate);
+        }
+
+        Ok(self.transmit(packet, at).await.0)
+    }
+
+    async fn recv(&mut self, packet: &mut Packet) -> Result<(Timestamp, Rssi, Pipe), Error> {
+        let from = SimClock::now() + RX_RAMP_UP;
+        let reception = self.receive(from.ticks(), u64::MAX).await;
+
+        self.received(packet, &reception.expect("no deadline"))
+    }
+
+    async fn recv_window(
+        &mut self,
+        packet: &mut Packet,
+        open_at: TimerInstantU64<1_000_000>,
+        close_at: TimerInstantU64<1_000_000>,
+    ) -> Result<(Timestamp, Rssi, Pipe), Error> {
+        let (open_at, close_at) = self.window(open_at, close_at)?;
+
+        match self.receive(open_at, close_at).await {
+            Some(reception) => self.received(packet, &reception),
+            None => Err(Error::Timeout),
+        }
+    }
+
+    async fn recv_and_respond(
+        &mut self,
+        packet: &mut Packet,
+        response: &mut Packet,
+        open_at: TimerInstantU64<1_000_000>,
+        close_at: TimerInstantU64<1_000_000>,
+    ) -> Result<(), Error> {
+        let (open_at, close_at) = self.window(open_at, close_at)?;
+
+        let reception = self
+            .receive(open_at, close_at)
+            .await
+            .ok_or(Error::Timeout)?;
+        self.received(packet, &reception)?;
+
+        self.transmit(response, reception.end + TURNAROUND.ticks())
+            .await;
+
+        Ok(())
+    }
+
+    async fn send_at_and_recv(
+        &mut self,
+        packet: &mut Packet,
+        at: TimerInstantU64<1_000_000>,
+        window: TimerDurationU64<1_000_000>,
+    ) -> Result<(Timestamp, Rssi, Pipe), Error> {
+        let at = self.simulated(at);
+        if at < (SimClock::now() + TX_RAMP_UP).ticks() {
+            return Err(Error::TooLate);
+        }
+
+        let (_, end) = self.transmit(packet, at).await;
+
+        // The window is too short for the drift to matter.
+        match self.receive(end, end + window.ticks()).await {
+            Some(reception) => self.received(packet, &reception),
+            None => Err(Error::Timeout),
+        }
+    }
+
+    async fn survey(
+        &mut self,
+        dwell: TimerDurationU64<1_000_000>,
+    ) -> [EnergyLevel; NUM_FREQUENCIES] {
+        let mut levels = [EnergyLevel {
+            peak: i8::MIN,
+            average: i8::MIN,
+        }; NUM_FREQUENCIES];
+
+        for (frequency, level) in levels.iter_mut().enumerate() {
+            let start = SimClock::now();
+            SimClock::delay(dwell).await;
+
+            *level = self
+                .medium
+                .energy(frequency as u8, start.ticks(), SimClock::now().ticks());
+        }
+
+        levels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::medium::ChannelConditions;
+    use crate::time::Simulation;
+    use fugit::ExtU64;
+
+    fn packet(payload: &[u8]) -> Packet {
+        let mut packet = Packet::new();
+        packet.copy_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn response_after_turnaround() {
+        let medium = Medium::new(0);
+        medium.set_conditions(
+            5,
+            ChannelConditions {
+                latency: 3,
+                ..ChannelConditions::CLEAR
+            },
+        );
+        let mut sender = SimRadio::new(&medium);
+        let mut responder = SimRadio::new(&medium);
+        sender.set_frequency(5);
+        responder.set_frequency(5);
+
+        let mut sent = None;
+        let mut answered = None;
+        let mut sim = Simulation::new();
+        let at = TimerInstantU64::from_ticks(1_000);
+        sim.spawn(async {
+            let mut request = packet(b"ping");
+            sent = Some(
+                sender
+                    .send_at_and_recv(&mut request, at, 100.micros())
+                    .await,
+            );
+            assert_eq!(&request[..], b"pong");
+        });
+        sim.spawn(async {
+            let mut request = Packet::new();
+            let mut response = packet(b"pong");
+            answered = Some(
+                responder
+                    .recv_and_respond(
+                        &mut request,
+                        &mut response,
+                        at - 50.micros(),
+                        at + 50.micros(),
+                    )
+                    .await,
+            );
+        });
+        sim.run_for(10.millis());
+        drop(sim);
+
+        assert_eq!(answered, Some(Ok(())));
+        // The response starts 3 µs late at the responder, and arrives another 3 µs later.
+        let end = 1_000 + airtime(4).ticks();
+        let address = end + 3 + TURNAROUND.ticks() + 3 + ADDRESS_AIRTIME;
+        let (timestamp, _, pipe) = sent.unwrap().unwrap();
+        assert_eq!(timestamp.0.ticks(), address);
+        assert_eq!(pipe, Pipe(0));
+    }
+
+    #[test]
+    fn collisions_corrupt_packets() {
+        let medium = Medium::new(0);
+        let mut a = SimRadio::new(&medium);
+        let mut b = SimRadio::new(&medium);
+        let mut receiver = SimRadio::new(&medium);
+
+        let mut received = None;
+        let mut sim = Simulation::new();
+        sim.spawn(async {
+            a.send_at(&mut packet(b"first"), TimerInstantU64::from_ticks(1_000))
+                .await
+                .unwrap();
+        });
+        sim.spawn(async {
+            b.send_at(&mut packet(b"second"), TimerInstantU64::from_ticks(1_020))
+                .await
+                .unwrap();
+        });
+        sim.spawn(async {
+            let mut packet = Packet::new();
+            received = Some(receiver.recv(&mut packet).await);
+        });
+        sim.run_for(10.millis());
+        drop(sim);
+
+        assert_eq!(received, Some(Err(Error::Crc(0))));
+        assert_eq!(medium.transmissions().len(), 2);
+    }
+
+    /// Answer a `request` sent at `sent_at` in a window from `open_at` to `close_at`, and
+    /// return the result and what was sent.
+    fn respond_in_window(
+        sent_at: u64,
+        open_at: u64,
+        close_at: u64,
+        wait: u64,
+    ) -> (Result<(), Error>, Vec<Transmission>) {
+        let medium = Medium::new(0);
+        let mut sender = SimRadio::new(&medium);
+        let mut responder = SimRadio::new(&medium);
+
+        let mut answered = None;
+        let mut sim = Simulation::new();
+        sim.spawn(async {
+            sender
+                .send_at(&mut packet(b"data"), TimerInstantU64::from_ticks(sent_at))
+                .await
+                .unwrap();
+        });
+        sim.spawn(async {
+            SimClock::delay_until(TimerInstantU64::from_ticks(wait)).await;
+            let mut request = Packet::new();
+            answered = Some(
+                responder
+                    .recv_and_respond(
+                        &mut request,
+                        &mut packet(b"ack"),
+                        TimerInstantU64::from_ticks(open_at),
+                        TimerInstantU64::from_ticks(close_at),
+                    )
+                    .await,
+            );
+        });
+        sim.run_for(10.millis());
+        drop(sim);
+
+        let transmissions = medium.transmissions().to_vec();
+        (answered.unwrap(), transmissions)
+    }
+
+    #[test]
+    fn window_bounds_the_address() {
+        // The address arrives at 1_024.
+        let (answered, sent) = respond_in_window(1_000, 900, 1_024, 0);
+        assert_eq!(answered, Ok(()));
+        assert_eq!(sent.len(), 2);
+
+        let (answered, sent) = respond_in_window(1_000, 900, 1_023, 0);
+        assert_eq!(answered, Err(Error::Timeout));
+        assert_eq!(sent.len(), 1);
+
+        let (answered, _) = respond_in_window(1_000, 1_025, 1_100, 0);
+        assert_eq!(answered, Err(Error::Timeout));
+    }
+
+    #[test]
+    fn late_window_opens_right_away() {
+        // Armed after the window opened, the receiver still ramps up in time for the packet.
+        let (answered, sent) = respond_in_window(1_000, 500, 1_100, 900);
+        assert_eq!(answered, Ok(()));
+        assert_eq!(sent.len(), 2);
+
+        // Only a window that has closed is missed.
+        let (answered, sent) = respond_in_window(1_000, 500, 800, 900);
+        assert_eq!(answered, Err(Error::TooLate));
+        assert_eq!(sent.len(), 1);
+    }
+
+    #[test]
+    fn too_late() {
+        let medium = Medium::new(0);
+        let mut radio = SimRadio::new(&medium);
+
+        let mut result = None;
+        let mut sim = Simulation::new();
+        sim.spawn(async {
+            SimClock::delay(1.millis()).await;
+            let at = SimClock::now() + 10.micros();
+            result = Some(radio.send_at(&mut packet(b"late"), at).await);
+        });
+        sim.run_for(10.millis());
+        drop(sim);
+
+        assert_eq!(result, Some(Err(Error::TooLate)));
+        assert!(medium.transmissions().is_empty());
+    }
+}
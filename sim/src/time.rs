@@ -0,0 +1,182 @@
+//! # Simulated time
+//!
+//! A [`Simulation`] polls its tasks on the current thread against a virtual µs clock, which only
+//! moves when every task is waiting, straight to the next timer. Seconds of radio traffic take
+//! milliseconds, and run the same way every time.
+//!
+//! [`SimClock`] is the virtual time itself. A [`DriftingClock`] runs a little fast or slow
+//! against it, like a device's crystal.
+
+use crate::clock::Clock;
+use fugit::{TimerDurationU64, TimerInstantU64};
+use std::cell::{Cell, RefCell};
+use std::future::{poll_fn, Future};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+
+thread_local! {
+    /// The current virtual time in µs.
+    static NOW: Cell<u64> = const { Cell::new(0) };
+
+    /// Wakers to wake once the virtual time reaches their instant.
+    static TIMERS: RefCell<Vec<(u64, Waker)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Wake `waker` once the virtual time reaches `at`.
+pub(crate) fn wake_at(at: u64, waker: &Waker) {
+    TIMERS.with_borrow_mut(|timers| timers.push((at, waker.clone())));
+}
+
+/// The clock of the simulation running on the current thread.
+pub struct SimClock;
+
+impl Clock for SimClock {
+    fn now() -> TimerInstantU64<1_000_000> {
+        TimerInstantU64::from_ticks(NOW.get())
+    }
+
+    async fn delay_until(instant: TimerInstantU64<1_000_000>) {
+        poll_fn(|cx| {
+            if NOW.get() >= instant.ticks() {
+                Poll::Ready(())
+            } else {
+                wake_at(instant.ticks(), cx.waker());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+/// The simulation's time as seen by a clock running `ppm` parts per million fast.
+pub(crate) fn to_local(ppm: i32, time: u64) -> u64 {
+    (time as i128 * (1_000_000 + ppm as i128) / 1_000_000) as u64
+}
+
+/// The simulation's time, rounded up, when a clock running `ppm` parts per million fast shows
+/// `local`.
+pub(crate) fn to_simulated(ppm: i32, local: u64) -> u64 {
+    let rate = 1_000_000 + ppm as i128;
+    ((local as i128 * 1_000_000 + rate - 1) / rate) as u64
+}
+
+/// A clock running `PPM` parts per million fast against the simulation's, slow if negative.
+///
+/// Pair it with a [`crate::SimRadio::with_drift`] of the same drift, so the radio's instants
+/// are on the same clock.
+pub struct DriftingClock<const PPM: i32>;
+
+impl<const PPM: i32> Clock for DriftingClock<PPM> {
+    fn now() -> TimerInstantU64<1_000_000> {
+        TimerInstantU64::from_ticks(to_local(PPM, NOW.get()))
+    }
+
+    async fn delay_until(instant: TimerInstantU64<1_000_000>) {
+        SimClock::delay_until(TimerInstantU64::from_ticks(to_simulated(
+            PPM,
+            instant.ticks(),
+        )))
+        .await
+    }
+}
+
+/// Set when a task's waker has been woken.
+struct Woken(AtomicBool);
+
+impl Wake for Woken {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+struct Task<'a> {
+    future: Pin<Box<dyn Future<Output = ()> + 'a>>,
+    woken: Arc<Woken>,
+    done: bool,
+}
+
+/// Tasks sharing the virtual time of [`SimClock`].
+///
+/// There is one virtual time per thread, a new simulation starts it over at 0.
+pub struct Simulation<'a> {
+    tasks: Vec<Task<'a>>,
+}
+
+impl Default for Simulation<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> Simulation<'a> {
+    /// A simulation without tasks, at time 0.
+    pub fn new() -> Self {
+        NOW.set(0);
+        TIMERS.with_borrow_mut(Vec::clear);
+
+        Self { tasks: Vec::new() }
+    }
+
+    /// Add a task, which is first polled by the next [`Simulation::run_until`].
+    ///
+    /// Its output is dropped, and it is dropped with the simulation if it has not completed.
+    pub fn spawn(&mut self, future: impl Future + 'a) {
+        self.tasks.push(Task {
+            future: Box::pin(async move {
+                future.await;
+            }),
+            woken: Arc::new(Woken(AtomicBool::new(true))),
+            done: false,
+        });
+    }
+
+    /// Run the tasks until the virtual time reaches `end`, or nothing is left to wake them.
+    pub fn run_until(&mut self, end: TimerInstantU64<1_000_000>) {
+        loop {
+            let mut polled = false;
+
+            for task in self.tasks.iter_mut().filter(|task| !task.done) {
+                if task.woken.0.swap(false, Ordering::Relaxed) {
+                    polled = true;
+
+                    let waker = Waker::from(task.woken.clone());
+                    let mut cx = Context::from_waker(&waker);
+                    task.done = task.future.as_mut().poll(&mut cx).is_ready();
+                }
+            }
+
+            if polled {
+                continue;
+            }
+
+            // Every task waits, skip to the next timer.
+            let next = TIMERS.with_borrow(|timers| timers.iter().map(|(at, _)| *at).min());
+            match next {
+                Some(at) if at <= end.ticks() => {
+                    NOW.set(NOW.get().max(at));
+
+                    let due = TIMERS.with_borrow_mut(|timers| {
+                        let (due, pending): (Vec<_>, Vec<_>) =
+                            timers.drain(..).partition(|(at, _)| *at <= NOW.get());
+                        *timers = pending;
+                        due
+                    });
+                    for (_, waker) in due {
+                        waker.wake();
+                    }
+                }
+                _ => {
+                    NOW.set(NOW.get().max(end.ticks()));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Run the tasks for `duration` of virtual time.
+    pub fn run_for(&mut self, duration: TimerDurationU64<1_000_000>) {
+        self.run_until(SimClock::now() + duration);
+    }
+}
@@ -0,0 +1,404 @@
+//! The dongle's and a keyboard half's ends of a link, on simulated radios.
+
+use corne_sim::clock::Clock;
+use corne_sim::radio::TURNAROUND;
+use corne_sim::radio_protocol::hopping::{ChannelMap, NUM_CHANNELS};
+use corne_sim::radio_protocol::link::{DongleLinks, KeyboardLink};
+use corne_sim::radio_protocol::session::{PairedPeer, Session};
+use corne_sim::radio_protocol::state::{self, Command};
+use corne_sim::radio_protocol::{Side, SlotProfile, Uid};
+use corne_sim::{ChannelConditions, DriftingClock, Medium, SimClock, SimRadio, Simulation};
+use fugit::{ExtU64, TimerInstantU64};
+use std::sync::{Mutex, PoisonError};
+
+/// The key states and commands are process-wide like on the devices, the tests take turns.
+static SERIAL: Mutex<()> = Mutex::new(());
+
+const DONGLE_UID: Uid = Uid([0xd0; 8]);
+const KEYBOARD_UID: Uid = Uid([0x4b; 8]);
+const SHARED_SECRET: [u8; 32] = [0x5e; 32];
+
+/// How a link ended up after a run.
+struct Outcome {
+    /// The channel map of the dongle's session with the half, if it is still up.
+    dongle: Option<ChannelMap>,
+    /// The channel map of the half's session with the dongle, if it is still up.
+    keyboard: Option<ChannelMap>,
+    /// The commands the half got.
+    commands: Vec<Command>,
+    /// Master frames the dongle ran.
+    frames: u32,
+}
+
+/// Run a dongle and its `side` half on `medium` for `seconds`, from sessions made at pairing.
+///
+/// `every_ms` is called at the start of every simulated millisecond, with its number. The dongle
+/// surveys the band first if `survey` is set.
+fn run_link(
+    medium: &Medium,
+    side: Side,
+    seconds: u64,
+    survey: bool,
+    every_ms: impl FnMut(u64),
+) -> Outcome {
+    run_drifting_link::<0>(
+        medium,
+        side,
+        seconds,
+        survey,
+        SlotProfile::Standard,
+        every_ms,
+    )
+}
+
+/// Like [`run_link`] on `profile`'s slots, with the half's crystal running `PPM` fast against
+/// the dongle's.
+fn run_drifting_link<const PPM: i32>(
+    medium: &Medium,
+    side: Side,
+    seconds: u64,
+    survey: bool,
+    profile: SlotProfile,
+    mut every_ms: impl FnMut(u64),
+) -> Outcome {
+    let _serial = SERIAL.lock().unwrap_or_else(PoisonError::into_inner);
+    state::publish(side, [0; 3]);
+
+    let mut dongle_radio = SimRadio::new(medium);
+    let mut keyboard_radio = SimRadio::with_drift(medium, PPM);
+
+    // The simulation starts at 0, the first master frame right after.
+    let mut links = DongleLinks::new(DONGLE_UID, TimerInstantU64::from_ticks(1_000));
+    links.sessions_mut()[side.index()] = Some(Session::dongle(
+        PairedPeer {
+            uid: KEYBOARD_UID,
+            side,
+            shared_secret: SHARED_SECRET,
+        },
+        DONGLE_UID,
+    ));
+    let keyboard_session = Session::keyboard(
+        PairedPeer {
+            uid: DONGLE_UID,
+            side,
+            shared_secret: SHARED_SECRET,
+        },
+        KEYBOARD_UID,
+    );
+
+    let mut keyboard = KeyboardLink::new();
+    let mut commands = Vec::new();
+
+    let mut sim = Simulation::new();
+    sim.spawn(async {
+        if survey {
+            links.survey(&mut dongle_radio).await;
+            links.postpone(SimClock::now() + 1.millis());
+        }
+
+        loop {
+            links
+                .master_frame::<_, SimClock>(&mut dongle_radio, profile)
+                .await;
+        }
+    });
+
+    sim.spawn(async {
+        keyboard.connect::<_, DriftingClock<PPM>>(&mut keyboard_radio, keyboard_session);
+
+        while keyboard.session().is_some() {
+            keyboard
+                .step::<_, DriftingClock<PPM>>(&mut keyboard_radio, |_, command| {
+                    commands.push(command)
+                })
+                .await;
+        }
+    });
+
+    for ms in 0..seconds * 1000 {
+        every_ms(ms);
+        sim.run_for(1.millis());
+    }
+    drop(sim);
+
+    Outcome {
+        dongle: links.sessions()[side.index()]
+            .as_ref()
+            .map(|session| *session.channel_map()),
+        keyboard: keyboard.session().map(|session| *session.channel_map()),
+        commands,
+        frames: links.frame_counter(),
+    }
+}
+
+/// The channels used by `map`.
+fn used_channels(map: &ChannelMap) -> Vec<u8> {
+    (0..NUM_CHANNELS as u8)
+        .filter(|&channel| map.is_used(channel))
+        .collect()
+}
+
+/// Change the key state every 2 ms, so the half sends in every slot.
+fn type_every_slot(ms: u64) {
+    if ms % 2 == 0 {
+        state::update([ms as u8, 0, 0]);
+    }
+}
+
+/// How many of the half's frames starting at a `counted` time the dongle did not answer.
+fn unanswered(medium: &Medium, counted: impl Fn(u64) -> bool) -> usize {
+    let transmissions = medium.transmissions();
+    // Radio 0 is the dongle's.
+    let (dongle, half): (Vec<_>, Vec<_>) = transmissions.iter().partition(|tx| tx.sender == 0);
+    // The run can end between a frame and its ACK.
+    let end = dongle.last().map_or(0, |tx| tx.start);
+
+    half.iter()
+        .filter(|frame| frame.start < end && counted(frame.start))
+        .filter(|frame| {
+            !dongle
+                .iter()
+                .any(|ack| ack.start == frame.end + TURNAROUND.ticks())
+        })
+        .count()
+}
+
+#[test]
+fn key_states_reach_the_dongle() {
+    let medium = Medium::new(1);
+    let matrix = [0x12, 0x34, 0x56];
+
+    let outcome = run_link(&medium, Side::Right, 3, false, |ms| {
+        if ms == 1000 {
+            state::update(matrix);
+        }
+    });
+
+    assert!(outcome.dongle.is_some());
+    assert!(outcome.keyboard.is_some());
+    // A master frame is 344 ms, ending with the beacon window.
+    assert_eq!(outcome.frames, 8);
+    assert_eq!(state::key_state(Side::Right), matrix);
+}
+
+#[test]
+fn commands_reach_the_half() {
+    let medium = Medium::new(2);
+
+    let outcome = run_link(&medium, Side::Left, 2, false, |ms| {
+        if ms == 1000 {
+            state::send_command(Side::Left, Command::Layer(3));
+        }
+    });
+
+    assert!(outcome.keyboard.is_some());
+    assert_eq!(outcome.commands, [Command::Layer(3)]);
+}
+
+#[test]
+fn link_survives_loss_and_interference() {
+    let medium = Medium::new(3);
+    medium.set_all_conditions(ChannelConditions {
+        loss: 0.2,
+        latency: 5,
+        interference: 0.1,
+    });
+    let matrix = [0x01, 0x80, 0x0f];
+
+    let outcome = run_link(&medium, Side::Right, 5, false, |ms| {
+        if ms == 2000 {
+            state::update(matrix);
+        }
+    });
+
+    assert!(outcome.dongle.is_some());
+    assert!(outcome.keyboard.is_some());
+    assert_eq!(state::key_state(Side::Right), matrix);
+}
+
+#[test]
+fn afh_drops_interfered_channels() {
+    let medium = Medium::new(4);
+    let noisy = [3, 17, 40, 41, 42, 66];
+    for frequency in noisy {
+        medium.set_conditions(
+            frequency,
+            ChannelConditions {
+                interference: 1.,
+                ..ChannelConditions::CLEAR
+            },
+        );
+    }
+
+    // Only slots the half sends in count, so keep it typing.
+    let outcome = run_link(&medium, Side::Right, 12, false, type_every_slot);
+
+    let dongle = outcome.dongle.expect("dongle lost the link");
+    let keyboard = outcome.keyboard.expect("half lost the link");
+    let used = used_channels(&dongle);
+    assert_eq!(used.len(), NUM_CHANNELS - noisy.len());
+    assert!(noisy.iter().all(|channel| !used.contains(channel)));
+    assert_eq!(used_channels(&keyboard), used);
+}
+
+#[test]
+fn survey_seeds_afh() {
+    let medium = Medium::new(5);
+    let noisy = [10, 11, 12, 13];
+    for frequency in noisy {
+        medium.set_conditions(
+            frequency,
+            ChannelConditions {
+                interference: 0.2,
+                ..ChannelConditions::CLEAR
+            },
+        );
+    }
+
+    // The map is announced in the syncs for a few frames before it is used.
+    let outcome = run_link(&medium, Side::Left, 5, true, |_| {});
+
+    let dongle = outcome.dongle.expect("dongle lost the link");
+    let keyboard = outcome.keyboard.expect("half lost the link");
+    let used = used_channels(&dongle);
+    assert_eq!(used.len(), NUM_CHANNELS - noisy.len());
+    assert!(noisy.iter().all(|channel| !used.contains(channel)));
+    assert_eq!(used_channels(&keyboard), used);
+}
+
+#[test]
+fn link_follows_drifting_crystals() {
+    // Crystals are specified to ±50 ppm. The half's slots are laid out from the drift estimate,
+    // so it hits the dongle's even at the end of a master frame.
+    let medium = Medium::new(8);
+    let fast = run_drifting_link::<50>(
+        &medium,
+        Side::Right,
+        4,
+        false,
+        SlotProfile::Standard,
+        type_every_slot,
+    );
+    assert!(fast.dongle.is_some());
+    assert!(fast.keyboard.is_some());
+    assert_eq!(unanswered(&medium, |_| true), 0);
+
+    let medium = Medium::new(9);
+    let slow = run_drifting_link::<-50>(
+        &medium,
+        Side::Right,
+        4,
+        false,
+        SlotProfile::Standard,
+        type_every_slot,
+    );
+    assert!(slow.dongle.is_some());
+    assert!(slow.keyboard.is_some());
+    assert_eq!(unanswered(&medium, |_| true), 0);
+}
+
+#[test]
+fn missed_syncs_keep_the_drifting_schedule() {
+    let medium = Medium::new(10);
+    let outage = 2_000_000..3_000_000;
+
+    // Nothing gets through for 3 master frames, the half keeps its schedule from the drift
+    // estimate and sends in the right slots once the channel clears.
+    let outcome =
+        run_drifting_link::<40>(&medium, Side::Left, 5, false, SlotProfile::Standard, |ms| {
+            let loss = if outage.contains(&(ms * 1000)) {
+                1.
+            } else {
+                0.
+            };
+            medium.set_all_conditions(ChannelConditions {
+                loss,
+                ..ChannelConditions::CLEAR
+            });
+            type_every_slot(ms);
+        });
+
+    assert!(outcome.dongle.is_some());
+    assert!(outcome.keyboard.is_some());
+    // A frame still on air when the outage starts is lost as well.
+    let lost = outage.start - 1_000..outage.end;
+    assert_eq!(unanswered(&medium, |start| !lost.contains(&start)), 0);
+}
+
+#[test]
+fn fast_slots_fit_their_guard() {
+    // The Fast profile's guard leaves no room for the preamble and address of the sync and the
+    // half's frame, the half has to lay out its slots from when the sync started.
+    let medium = Medium::new(11);
+    let outcome = run_drifting_link::<-50>(
+        &medium,
+        Side::Left,
+        2,
+        false,
+        SlotProfile::Fast,
+        type_every_slot,
+    );
+
+    assert!(outcome.dongle.is_some());
+    assert!(outcome.keyboard.is_some());
+    assert_eq!(unanswered(&medium, |_| true), 0);
+}
+
+#[test]
+fn turbo_slots_carry_states_and_commands() {
+    // Without ACKs the half sends in every slot, a new state gets through within one.
+    for (seed, side) in [(12, Side::Right), (13, Side::Left)] {
+        let medium = Medium::new(seed);
+        let mut last = [0; 3];
+
+        let outcome = run_drifting_link::<50>(&medium, side, 2, false, SlotProfile::Turbo, |ms| {
+            if ms == 1000 {
+                state::send_command(side, Command::Layer(3));
+            }
+            last = [ms as u8, (ms >> 8) as u8, 0x80];
+            state::update(last);
+        });
+
+        assert!(outcome.dongle.is_some(), "{side:?}");
+        assert!(outcome.keyboard.is_some(), "{side:?}");
+        // A master frame is 92.5 ms.
+        assert_eq!(outcome.frames, 21, "{side:?}");
+        assert_eq!(outcome.commands, [Command::Layer(3)], "{side:?}");
+        // The last state typed, or the one before if it came after the half's last slot.
+        let received = state::key_state(side);
+        let previous = [last[0].wrapping_sub(1), last[1], last[2]];
+        assert!(received == last || received == previous, "{side:?}");
+    }
+}
+
+#[test]
+fn latency_past_the_guard_loses_the_link() {
+    let medium = Medium::new(6);
+    medium.set_all_conditions(ChannelConditions {
+        latency: 150,
+        ..ChannelConditions::CLEAR
+    });
+
+    // The half follows the syncs, but its frames arrive after the dongle stopped listening.
+    let outcome = run_link(&medium, Side::Right, 6, false, |_| {});
+
+    assert!(outcome.dongle.is_none());
+    assert!(outcome.keyboard.is_none());
+    assert!(outcome.commands.is_empty());
+}
+
+#[test]
+fn silence_loses_the_link() {
+    let medium = Medium::new(7);
+    medium.set_all_conditions(ChannelConditions {
+        loss: 1.,
+        ..ChannelConditions::CLEAR
+    });
+
+    let outcome = run_link(&medium, Side::Left, 5, false, |_| {});
+
+    assert!(outcome.dongle.is_none());
+    assert!(outcome.keyboard.is_none());
+    // Radio 0 is the dongle's, the half never heard a sync to send in its slots.
+    assert!(medium.transmissions().iter().all(|tx| tx.sender == 0));
+}
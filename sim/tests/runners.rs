@@ -0,0 +1,258 @@
+//! The dongle's and the keyboard halves' radio runners, from pairing on, on simulated radios.
+
+use corne_sim::radio_protocol::bonds::BondStore;
+use corne_sim::radio_protocol::pairing::PAIRING_FREQUENCY;
+use corne_sim::radio_protocol::passkey::{self, Passkey, MAX_DIGIT};
+use corne_sim::radio_protocol::{
+    dongle_radio_runner, keyboard_radio_runner, state, PairButton, Side, Uid,
+};
+use corne_sim::{Medium, SimClock, SimFlash, SimRadio, Simulation};
+use fugit::ExtU64;
+use rand_chacha::rand_core::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use std::cell::Cell;
+use std::sync::{Mutex, PoisonError};
+
+/// The key states and passkey entry are process-wide like on the devices, the tests take turns.
+static SERIAL: Mutex<()> = Mutex::new(());
+
+const DONGLE_UID: Uid = Uid([0xd0; 8]);
+/// The halves' UIDs, indexed by [`Side::index`].
+const KEYBOARD_UIDS: [Uid; 2] = [Uid([0x52; 8]), Uid([0x4c; 8])];
+
+/// The dongle's button, held while the cell is set.
+struct Button<'a>(&'a Cell<bool>);
+
+impl PairButton for Button<'_> {
+    fn is_pressed(&mut self) -> bool {
+        self.0.get()
+    }
+}
+
+fn enter_bootloader() -> ! {
+    panic!("the dongle sent a half to the bootloader");
+}
+
+/// The flash pages the dongle and the halves keep their bonds in, kept over resets.
+struct Devices {
+    dongle_flash: SimFlash,
+    /// Indexed by [`Side::index`].
+    keyboard_flashes: [SimFlash; 2],
+}
+
+impl Devices {
+    fn new() -> Self {
+        Self {
+            dongle_flash: SimFlash::new(),
+            keyboard_flashes: [SimFlash::new(), SimFlash::new()],
+        }
+    }
+
+    /// Number of bonds the dongle, the right half and the left half have stored.
+    fn bonds(&self) -> [usize; 3] {
+        let count = |flash: &SimFlash| BondStore::new(flash.clone(), 0).bonds().count();
+        let [right, left] = &self.keyboard_flashes;
+        [count(&self.dongle_flash), count(right), count(left)]
+    }
+
+    /// Start the dongle and the `halves` from reset, and run them on `medium` for `seconds`.
+    ///
+    /// `every_ms` is called at the start of every simulated millisecond, with its number, and
+    /// sets if the dongle's button is held.
+    fn run(
+        &self,
+        medium: &Medium,
+        seed: u64,
+        seconds: u64,
+        halves: &[Side],
+        mut every_ms: impl FnMut(u64, &Cell<bool>),
+    ) {
+        let held = Cell::new(false);
+        let mut sim = Simulation::new();
+
+        sim.spawn(dongle_radio_runner::<_, SimClock, _, _, _>(
+            SimRadio::new(medium),
+            Button(&held),
+            ChaCha8Rng::seed_from_u64(seed),
+            BondStore::new(self.dongle_flash.clone(), 0),
+            DONGLE_UID,
+        ));
+
+        for &side in halves {
+            state::publish(side, [0; 3]);
+
+            sim.spawn(keyboard_radio_runner::<_, SimClock, _, _>(
+                SimRadio::new(medium),
+                side,
+                ChaCha8Rng::seed_from_u64(seed + 1 + side.index() as u64),
+                BondStore::new(self.keyboard_flashes[side.index()].clone(), 0),
+                KEYBOARD_UIDS[side.index()],
+                enter_bootloader,
+            ));
+        }
+
+        for ms in 0..seconds * 1000 {
+            every_ms(ms, &held);
+            sim.run_for(1.millis());
+        }
+    }
+}
+
+/// If the half with `radio` on `medium` sent on its link, off the pairing frequency, at or after
+/// `since` µs. Radio 0 is the dongle's, the halves' follow in the order they were started.
+fn sends_on_link(medium: &Medium, radio: usize, since: u64) -> bool {
+    medium
+        .transmissions()
+        .iter()
+        .any(|tx| tx.sender == radio && tx.start >= since && tx.frequency != PAIRING_FREQUENCY)
+}
+
+/// If the dongle got `matrix` from one of the halves. The halves share the process-wide matrix
+/// scan, the half that sends next picks up an update.
+fn dongle_got(matrix: [u8; 3]) -> bool {
+    Side::ALL
+        .iter()
+        .any(|&side| state::key_state(side) == matrix)
+}
+
+/// Hold the button for the first 4 s, and type what `typed` makes of every passkey `delay_ms`
+/// after the dongle starts showing it.
+fn pair(delay_ms: u64, typed: impl Fn(Passkey) -> Passkey) -> impl FnMut(u64, &Cell<bool>) {
+    let mut shown_at = None;
+
+    move |ms, held| {
+        held.set(ms < 4000);
+
+        match (passkey::displayed(), shown_at) {
+            (Some(_), None) => shown_at = Some(ms),
+            (Some(shown), Some(at)) if ms == at + delay_ms => {
+                for digit in typed(shown).0 {
+                    passkey::key_pressed(digit - 1);
+                }
+            }
+            (None, _) => shown_at = None,
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn halves_pair_and_reconnect_after_reset() {
+    let _serial = SERIAL.lock().unwrap_or_else(PoisonError::into_inner);
+    let devices = Devices::new();
+    let matrix = [0x24, 0x00, 0x81];
+
+    let medium = Medium::new(20);
+    let mut pairing = pair(300, |shown| shown);
+    devices.run(&medium, 1, 8, &Side::ALL, |ms, held| {
+        pairing(ms, held);
+        if ms == 7000 {
+            state::update(matrix);
+        }
+    });
+
+    assert_eq!(devices.bonds(), [2, 1, 1]);
+    assert!(sends_on_link(&medium, 1, 7_000_000));
+    assert!(sends_on_link(&medium, 2, 7_000_000));
+    assert!(dongle_got(matrix));
+
+    // All reset, the halves reconnect from their bonds without a passkey.
+    let medium = Medium::new(21);
+    let matrix = [0x00, 0x42, 0x18];
+    let mut shown = false;
+    devices.run(&medium, 4, 4, &Side::ALL, |ms, _| {
+        shown |= passkey::displayed().is_some();
+        if ms == 3000 {
+            state::update(matrix);
+        }
+    });
+
+    assert!(!shown);
+    assert_eq!(devices.bonds(), [2, 1, 1]);
+    assert!(sends_on_link(&medium, 1, 3_000_000));
+    assert!(sends_on_link(&medium, 2, 3_000_000));
+    assert!(dongle_got(matrix));
+}
+
+#[test]
+fn wrong_passkey_stores_no_bond() {
+    let _serial = SERIAL.lock().unwrap_or_else(PoisonError::into_inner);
+    let devices = Devices::new();
+
+    let medium = Medium::new(22);
+    let mut pairing = pair(300, |shown| {
+        Passkey(shown.0.map(|digit| digit % MAX_DIGIT + 1))
+    });
+    let mut shown = false;
+    devices.run(&medium, 7, 10, &Side::ALL, |ms, held| {
+        pairing(ms, held);
+        shown |= passkey::displayed().is_some();
+    });
+
+    assert!(shown);
+    assert_eq!(devices.bonds(), [0, 0, 0]);
+    assert!(devices.dongle_flash.is_erased());
+    for flash in &devices.keyboard_flashes {
+        assert!(flash.is_erased());
+    }
+}
+
+#[test]
+fn paired_half_keeps_its_link_in_pair_mode() {
+    let _serial = SERIAL.lock().unwrap_or_else(PoisonError::into_inner);
+    let devices = Devices::new();
+    let matrix = [0x11, 0x00, 0x42];
+
+    // Only the right half is around, pair mode waits for the left one until it times out.
+    let medium = Medium::new(23);
+    let mut pairing = pair(300, |shown| shown);
+    devices.run(&medium, 10, 10, &[Side::Right], |ms, held| {
+        pairing(ms, held);
+        if ms == 9000 {
+            state::update(matrix);
+        }
+    });
+
+    assert_eq!(devices.bonds(), [1, 1, 0]);
+    assert!(sends_on_link(&medium, 1, 9_000_000));
+    assert!(dongle_got(matrix));
+}
+
+#[test]
+fn connected_half_keeps_its_link_while_the_other_types() {
+    let _serial = SERIAL.lock().unwrap_or_else(PoisonError::into_inner);
+    let devices = Devices::new();
+
+    let medium = Medium::new(24);
+    let mut pairing = pair(300, |shown| shown);
+    devices.run(&medium, 13, 6, &[Side::Right], |ms, held| pairing(ms, held));
+    assert_eq!(devices.bonds(), [1, 1, 0]);
+
+    // The right half reconnects, and keeps sending while the user takes 6 s to type the passkey
+    // on the left half.
+    let medium = Medium::new(25);
+    let mut pairing = pair(6000, |shown| shown);
+    let mut shown_at = None;
+    let mut delivered = Vec::new();
+    devices.run(&medium, 16, 12, &Side::ALL, |ms, held| {
+        pairing(ms, held);
+
+        shown_at = shown_at.or(passkey::displayed().map(|_| ms));
+        let Some(at) = shown_at else {
+            return;
+        };
+
+        for (i, update) in [1000, 3000, 5000].into_iter().enumerate() {
+            let matrix = [0x10 << i, 0x00, 0x01 << i];
+            if ms == at + update {
+                state::update(matrix);
+            } else if ms == at + update + 500 {
+                delivered.push(passkey::displayed().is_some() && dongle_got(matrix));
+            }
+        }
+    });
+
+    assert_eq!(delivered, [true; 3]);
+    assert_eq!(devices.bonds(), [2, 1, 1]);
+    assert!(sends_on_link(&medium, 2, 11_000_000));
+}